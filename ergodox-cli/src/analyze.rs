@@ -0,0 +1,233 @@
+//! What's in a firmware image, for `analyze`: how big it is, how much of
+//! the ATmega32U4's application flash it takes, whether it runs into HalfKay
+//! and whether it starts the way an AVR program should.
+
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::halfkay::{APP_END, ATMEGA32U4};
+use crate::stamp;
+
+/// Where the reset vector (the first instruction, at address 0) sends the
+/// CPU.
+#[derive(Debug, PartialEq)]
+pub enum ResetVector {
+    /// A JMP or RJMP to this byte address, inside the image.
+    Jump(usize),
+    /// The image doesn't cover address 0, so the vector is whatever is
+    /// already in flash.
+    NotInImage,
+    /// Address 0 is erased flash; the CPU would run through 0xFF words.
+    Blank,
+    /// Not a jump, or one that leaves the image or the application region.
+    Bad(String),
+}
+
+impl ResetVector {
+    fn is_sane(&self) -> bool {
+        matches!(self, ResetVector::Jump(_))
+    }
+}
+
+impl fmt::Display for ResetVector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResetVector::Jump(target) => write!(f, "jump to 0x{:04X}", target),
+            ResetVector::NotInImage => write!(f, "not in the image (it doesn't start at 0)"),
+            ResetVector::Blank => write!(f, "erased flash"),
+            ResetVector::Bad(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Analysis {
+    pub base_address: u32,
+    pub bytes: usize,
+    /// Share of the application flash (below HalfKay) the image spans.
+    pub utilization: f64,
+    /// Pages with something other than 0xFF in them, which get written.
+    pub pages_used: usize,
+    pub pages_total: usize,
+    /// Whether the image reaches into the HalfKay region at 0x7E00.
+    pub overlaps_bootloader: bool,
+    pub reset_vector: ResetVector,
+    /// The CRC32 `flash` would stamp into the image, if it has a slot.
+    pub image_crc: Option<u32>,
+}
+
+pub fn analyze(base_address: u32, data: &[u8]) -> Analysis {
+    let page_size = ATMEGA32U4.page_size;
+    let base = base_address as usize;
+    let end = base + data.len();
+    // Pages are aligned to flash, not to the image
+    let pages_used = (base / page_size..end.div_ceil(page_size))
+        .filter(|&page| {
+            let start = (page * page_size).max(base) - base;
+            let stop = ((page + 1) * page_size).min(end) - base;
+            data[start..stop].iter().any(|&b| b != 0xFF)
+        })
+        .count();
+    Analysis {
+        base_address,
+        bytes: data.len(),
+        utilization: end as f64 / APP_END as f64 * 100.0,
+        pages_used,
+        pages_total: APP_END / page_size,
+        overlaps_bootloader: end > APP_END,
+        reset_vector: reset_vector(base, data),
+        image_crc: stamp::stamp(&mut data.to_vec()).ok().flatten(),
+    }
+}
+
+/// Decode the instruction at address 0. The ATmega32U4's vectors are
+/// 4-byte JMPs; a 2-byte RJMP works as well.
+fn reset_vector(base: usize, data: &[u8]) -> ResetVector {
+    if base != 0 || data.len() < 2 {
+        return ResetVector::NotInImage;
+    }
+    let word = |i: usize| {
+        data.get(2 * i..2 * i + 2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]]))
+    };
+    let first = word(0).unwrap();
+    let target = if first == 0xFFFF {
+        return ResetVector::Blank;
+    } else if first & 0xFE0E == 0x940C {
+        // JMP k: 1001 010k kkkk 110k, then the low 16 bits of k (in words)
+        let Some(low) = word(1) else {
+            return ResetVector::Bad("JMP cut off by the end of the image".to_string());
+        };
+        let high = ((first & 0x01F0) >> 3) | (first & 1);
+        (((high as usize) << 16) | low as usize) * 2
+    } else if first & 0xF000 == 0xC000 {
+        // RJMP k: 1100 kkkk kkkk kkkk, k signed and relative to the next word
+        let k = ((first << 4) as i16 >> 4) as isize;
+        match (1 + k).checked_mul(2).and_then(|t| usize::try_from(t).ok()) {
+            Some(target) => target,
+            None => return ResetVector::Bad("RJMP to before address 0".to_string()),
+        }
+    } else {
+        return ResetVector::Bad(format!("0x{:04X} is not a JMP or RJMP", first));
+    };
+    if target >= APP_END {
+        ResetVector::Bad(format!("jump to 0x{:04X}, past the application", target))
+    } else if target >= data.len() {
+        ResetVector::Bad(format!(
+            "jump to 0x{:04X}, past the end of the image",
+            target
+        ))
+    } else {
+        ResetVector::Jump(target)
+    }
+}
+
+impl Analysis {
+    /// Whether the image is unfit to flash as it is.
+    pub fn has_problems(&self) -> bool {
+        self.overlaps_bootloader || !self.reset_vector.is_sane()
+    }
+
+    /// The analysis as a JSON object, for `analyze --json`.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": if self.has_problems() { "problems" } else { "ok" },
+            "base_address": self.base_address,
+            "bytes": self.bytes,
+            "utilization_percent": self.utilization,
+            "pages_used": self.pages_used,
+            "pages_total": self.pages_total,
+            "overlaps_bootloader": self.overlaps_bootloader,
+            "reset_vector": self.reset_vector.to_string(),
+            "reset_vector_ok": self.reset_vector.is_sane(),
+            "image_crc32": self.image_crc.map(|c| format!("0x{:08X}", c)),
+        })
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Size          {} bytes at 0x{:04X}",
+            self.bytes, self.base_address
+        )?;
+        writeln!(
+            f,
+            "Utilization   {:.1}% of {} bytes of application flash",
+            self.utilization, APP_END
+        )?;
+        writeln!(
+            f,
+            "Pages         {} of {} in use ({} bytes each)",
+            self.pages_used, self.pages_total, ATMEGA32U4.page_size
+        )?;
+        writeln!(
+            f,
+            "Bootloader    {}",
+            if self.overlaps_bootloader {
+                "OVERLAPS HalfKay at 0x7E00"
+            } else {
+                "clear of HalfKay at 0x7E00"
+            }
+        )?;
+        match self.image_crc {
+            Some(crc) => writeln!(f, "Image CRC32   0x{:08X}", crc)?,
+            None => writeln!(f, "Image CRC32   no slot for it in this firmware")?,
+        }
+        write!(
+            f,
+            "Reset vector  {}{}",
+            self.reset_vector,
+            if self.reset_vector.is_sane() {
+                ""
+            } else {
+                " (PROBLEM)"
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A vector table whose reset entry is `jmp 0x00AC` (word 0x56), and
+    /// something there. `len` must reach past it.
+    fn image(len: usize) -> Vec<u8> {
+        let mut data = vec![0xFF; len];
+        data[..4].copy_from_slice(&[0x0C, 0x94, 0x56, 0x00]);
+        data[0xAC] = 0;
+        data
+    }
+
+    #[test]
+    fn counts_pages_and_decodes_the_reset_jmp() {
+        let analysis = analyze(0, &image(0x200));
+        assert_eq!((analysis.pages_used, analysis.pages_total), (2, 252));
+        assert_eq!(analysis.reset_vector, ResetVector::Jump(0xAC));
+        assert!(!analysis.has_problems());
+    }
+
+    #[test]
+    fn rjmp_is_relative_to_the_next_word() {
+        // rjmp .+4: k = 2, so the target is word 3
+        let mut data = image(0x100);
+        data[..2].copy_from_slice(&[0x02, 0xC0]);
+        assert_eq!(reset_vector(0, &data), ResetVector::Jump(6));
+        data[..2].copy_from_slice(&[0xFE, 0xCF]);
+        assert!(matches!(reset_vector(0, &data), ResetVector::Bad(_)));
+    }
+
+    #[test]
+    fn images_reaching_halfkay_or_starting_badly_are_problems() {
+        assert!(analyze(0, &image(APP_END + 1)).overlaps_bootloader);
+        assert_eq!(reset_vector(0, &[0xFF; 4]), ResetVector::Blank);
+        assert_eq!(reset_vector(0x100, &image(0x100)), ResetVector::NotInImage);
+        let mut data = image(0x100);
+        data[2] = 0x00;
+        data[3] = 0x40; // jmp to word 0x4000, byte 0x8000
+        assert!(analyze(0, &data).has_problems());
+    }
+}
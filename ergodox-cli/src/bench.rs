@@ -0,0 +1,315 @@
+//! Scan rate and key-to-report latency, for `bench`.
+//!
+//! The firmware times its own main loop and can inject a synthetic key
+//! press, timing it through debounce and the keymap until the report that
+//! carries it is sent (`firmware/src/bench.rs`). Everything is measured on
+//! the keyboard's clock; the only host-side number is the USB control round
+//! trip.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use ergodox_keymap::{COLS, ROWS};
+use serde_json::{json, Value};
+
+use crate::halfkay::BenchChannel;
+
+/// Size of the firmware's stats reply.
+const STATS_SIZE: usize = 16;
+
+/// Latency the firmware reports for an injected press that never reached a
+/// report.
+const NO_LATENCY: u16 = 0xFFFF;
+
+/// How long to wait for one injected press to come back.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pause between samples, so the injected key's release debounces and goes
+/// out before the next press.
+const SAMPLE_GAP: Duration = Duration::from_millis(30);
+
+/// The firmware's loop counters for one measurement window.
+#[derive(Debug, PartialEq)]
+pub struct LoopStats {
+    pub scans: u32,
+    pub ticks: u32,
+    pub min_ticks: u16,
+    pub max_ticks: u16,
+    /// Ticks from injection to report for the last injected press.
+    pub latency_ticks: Option<u16>,
+    /// Counts finished injections, wrapping.
+    pub samples: u8,
+    pub tick_us: u8,
+}
+
+impl LoopStats {
+    pub fn parse(bytes: &[u8]) -> Result<LoopStats> {
+        if bytes.len() != STATS_SIZE {
+            bail!(
+                "bench reply was {} bytes, expected {}",
+                bytes.len(),
+                STATS_SIZE
+            );
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let latency = u16_at(12);
+        Ok(LoopStats {
+            scans: u32_at(0),
+            ticks: u32_at(4),
+            min_ticks: u16_at(8),
+            max_ticks: u16_at(10),
+            latency_ticks: (latency != NO_LATENCY).then_some(latency),
+            samples: bytes[14],
+            tick_us: bytes[15],
+        })
+    }
+
+    fn ms(&self, ticks: f64) -> f64 {
+        ticks * self.tick_us as f64 / 1000.0
+    }
+}
+
+/// Min, mean and max of some measurements, in milliseconds.
+#[derive(Debug, PartialEq)]
+pub struct Spread {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl Spread {
+    fn of(values: &[f64]) -> Option<Spread> {
+        if values.is_empty() {
+            return None;
+        }
+        Some(Spread {
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "min_ms": self.min, "mean_ms": self.mean, "max_ms": self.max })
+    }
+}
+
+impl fmt::Display for Spread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} ms mean, {:.3} min, {:.3} max",
+            self.mean, self.min, self.max
+        )
+    }
+}
+
+/// Key-to-report latency over several injected presses.
+#[derive(Debug)]
+pub struct Latency {
+    pub row: u8,
+    pub col: u8,
+    pub spread: Option<Spread>,
+    /// Presses that never produced a report.
+    pub timeouts: usize,
+}
+
+/// Everything `bench` measured.
+#[derive(Debug)]
+pub struct Report {
+    pub seconds: f64,
+    pub scans: u32,
+    pub scan_rate_hz: f64,
+    pub loop_time: Option<Spread>,
+    pub latency: Option<Latency>,
+    pub round_trip: Spread,
+}
+
+/// Let the firmware count scans for `duration`, then, if `latency` is given
+/// as `(row, col, samples)`, time that many injected presses.
+pub fn run(
+    channel: &BenchChannel,
+    duration: Duration,
+    latency: Option<(u8, u8, usize)>,
+) -> Result<Report> {
+    // Start a fresh window; whatever was counted before is stale
+    channel.stats()?;
+    std::thread::sleep(duration);
+
+    let mut round_trips = Vec::new();
+    let started = Instant::now();
+    let stats = LoopStats::parse(&channel.stats()?)?;
+    round_trips.push(started.elapsed().as_secs_f64() * 1000.0);
+
+    let loop_ticks = stats.ticks as f64;
+    let seconds = stats.ms(loop_ticks) / 1000.0;
+    let loop_time = (stats.scans > 0).then(|| Spread {
+        min: stats.ms(stats.min_ticks as f64),
+        mean: stats.ms(loop_ticks / stats.scans as f64),
+        max: stats.ms(stats.max_ticks as f64),
+    });
+
+    let latency = match latency {
+        Some((row, col, samples)) => {
+            let mut times = Vec::new();
+            let mut timeouts = 0;
+            let mut last = stats.samples;
+            for _ in 0..samples {
+                channel.inject(row, col)?;
+                let sent = Instant::now();
+                let stats = loop {
+                    let started = Instant::now();
+                    let stats = LoopStats::parse(&channel.stats()?)?;
+                    round_trips.push(started.elapsed().as_secs_f64() * 1000.0);
+                    if stats.samples != last {
+                        break stats;
+                    }
+                    if sent.elapsed() > SAMPLE_TIMEOUT {
+                        bail!("the keyboard never finished the injected press");
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                };
+                last = stats.samples;
+                match stats.latency_ticks {
+                    Some(ticks) => times.push(stats.ms(ticks as f64)),
+                    None => timeouts += 1,
+                }
+                std::thread::sleep(SAMPLE_GAP);
+            }
+            Some(Latency {
+                row,
+                col,
+                spread: Spread::of(&times),
+                timeouts,
+            })
+        }
+        None => None,
+    };
+
+    Ok(Report {
+        seconds,
+        scans: stats.scans,
+        scan_rate_hz: if seconds > 0.0 {
+            stats.scans as f64 / seconds
+        } else {
+            0.0
+        },
+        loop_time,
+        latency,
+        round_trip: Spread::of(&round_trips).expect("at least one request was timed"),
+    })
+}
+
+/// The first modifier on the base layer of the built-in keymap: pressing
+/// and releasing it on its own doesn't type anything.
+pub fn default_key() -> Option<(u8, u8)> {
+    (0..ROWS)
+        .flat_map(|row| (0..COLS).map(move |col| (row, col)))
+        .find(|&(row, col)| ergodox_keymap::lookup(0, row, col).is_modifier())
+        .map(|(row, col)| (row as u8, col as u8))
+}
+
+impl Report {
+    /// The report as a JSON object, for `bench --json`.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": "ok",
+            "seconds": self.seconds,
+            "scans": self.scans,
+            "scan_rate_hz": self.scan_rate_hz,
+            "loop_time": self.loop_time.as_ref().map(Spread::to_json),
+            "latency": self.latency.as_ref().map(|l| json!({
+                "row": l.row,
+                "col": l.col,
+                "time": l.spread.as_ref().map(Spread::to_json),
+                "timeouts": l.timeouts,
+            })),
+            "usb_round_trip": self.round_trip.to_json(),
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Scan loop ({:.1} s, {} scans)", self.seconds, self.scans)?;
+        writeln!(f, "  rate            {:.1} Hz", self.scan_rate_hz)?;
+        if let Some(loop_time) = &self.loop_time {
+            writeln!(f, "  loop time       {}", loop_time)?;
+        }
+        if let Some(latency) = &self.latency {
+            writeln!(
+                f,
+                "Key-to-report latency (row {}, col {}; includes debounce)",
+                latency.row, latency.col
+            )?;
+            match &latency.spread {
+                Some(spread) => writeln!(f, "  latency         {}", spread)?,
+                None => writeln!(f, "  latency         no samples")?,
+            }
+            if latency.timeouts > 0 {
+                writeln!(
+                    f,
+                    "  {} presses never reached a report; is that key transparent or a layer key?",
+                    latency.timeouts
+                )?;
+            }
+        }
+        write!(f, "USB round trip    {}", self.round_trip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_bytes(latency: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&1000u32.to_le_bytes());
+        bytes.extend_from_slice(&250_000u32.to_le_bytes());
+        bytes.extend_from_slice(&240u16.to_le_bytes());
+        bytes.extend_from_slice(&300u16.to_le_bytes());
+        bytes.extend_from_slice(&latency.to_le_bytes());
+        bytes.extend_from_slice(&[7, 4]);
+        bytes
+    }
+
+    #[test]
+    fn stats_are_little_endian_counters() {
+        let stats = LoopStats::parse(&stats_bytes(1500)).unwrap();
+        assert_eq!((stats.scans, stats.ticks), (1000, 250_000));
+        assert_eq!((stats.min_ticks, stats.max_ticks), (240, 300));
+        assert_eq!(stats.latency_ticks, Some(1500));
+        assert_eq!((stats.samples, stats.tick_us), (7, 4));
+        // 250,000 ticks of 4 µs is a second
+        assert_eq!(stats.ms(stats.ticks as f64), 1000.0);
+    }
+
+    #[test]
+    fn timed_out_injection_has_no_latency() {
+        let stats = LoopStats::parse(&stats_bytes(0xFFFF)).unwrap();
+        assert_eq!(stats.latency_ticks, None);
+        assert!(LoopStats::parse(&stats_bytes(0)[1..]).is_err());
+    }
+
+    #[test]
+    fn spread_of_samples() {
+        let spread = Spread::of(&[2.0, 6.0, 4.0]).unwrap();
+        assert_eq!(
+            spread,
+            Spread {
+                min: 2.0,
+                mean: 4.0,
+                max: 6.0
+            }
+        );
+        assert_eq!(Spread::of(&[]), None);
+    }
+
+    #[test]
+    fn default_key_types_nothing_on_its_own() {
+        let (row, col) = default_key().unwrap();
+        assert!(ergodox_keymap::lookup(0, row as usize, col as usize).is_modifier());
+    }
+}
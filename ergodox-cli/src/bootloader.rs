@@ -0,0 +1,549 @@
+//! What every bootloader protocol has in common, and the flash loop that
+//! drives them.
+//!
+//! A protocol only has to say how big its pages are, how much flash it can
+//! write, how to find the device and how to write one page; skipping erased
+//! pages, progress, size checks and error reporting live here, once.
+
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::halfkay::Target;
+
+/// A bootloader protocol.
+pub trait Bootloader {
+    /// Bytes written per `write_page` call.
+    fn page_size(&self) -> usize;
+
+    /// Flash available to the application, from address 0.
+    fn flash_size(&self) -> usize;
+
+    /// Where the bootloader itself sits, if that's below `flash_size`.
+    /// Nothing is written there unless the caller insists, because a
+    /// bootloader that overwrites itself leaves a board only an ISP
+    /// programmer can bring back.
+    fn bootloader_region(&self) -> Range<usize> {
+        self.flash_size()..self.flash_size()
+    }
+
+    /// Whether the first write after connecting erases the whole chip, so
+    /// an interrupted flash can't pick up where it stopped: the pages it
+    /// wrote before would be gone.
+    fn erases_on_first_write(&self) -> bool {
+        false
+    }
+
+    /// Whether this bootloader is waiting on the bus.
+    fn detect(&self, target: &Target) -> Result<bool>;
+
+    /// Connect to the waiting bootloader.
+    fn open(&self, target: &Target) -> Result<Box<dyn Connection>>;
+}
+
+/// An open connection to a bootloader.
+pub trait Connection {
+    /// Get the flash ready for writing. Bootloaders that erase as they
+    /// write don't need to do anything.
+    fn erase(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write one page at `address`. `page` is `page_size` bytes, except
+    /// that the last one may be shorter.
+    fn write_page(&mut self, address: usize, page: &[u8]) -> Result<()>;
+
+    /// Transient errors recovered from so far.
+    fn retries(&self) -> usize {
+        0
+    }
+
+    /// Leave the bootloader and start the application.
+    fn reboot(&mut self) -> Result<()>;
+}
+
+/// Page counts from a completed flash.
+#[derive(Debug, Default)]
+pub struct FlashStats {
+    pub pages_written: usize,
+    /// All-0xFF pages that didn't need writing.
+    pub pages_skipped: usize,
+    /// Page writes that failed and were retried.
+    pub retries: usize,
+    /// The pages those were on.
+    pub retried_pages: Vec<RetriedPage>,
+}
+
+/// A page that was written only after failed attempts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetriedPage {
+    pub address: usize,
+    pub failures: usize,
+}
+
+/// Where a flash stopped for good: the first page that didn't get written.
+///
+/// It's the context of the error `flash` returns, so callers can downcast to
+/// it and offer to resume.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub address: usize,
+    pub pages_written: usize,
+    pub total_pages: usize,
+    /// Pages before it that needed retries.
+    pub retried_pages: Vec<RetriedPage>,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "failed to write page at address 0x{:04X} ({} of {} pages written); \
+             the flash is incomplete, so press reset and flash again",
+            self.address, self.pages_written, self.total_pages
+        )
+    }
+}
+
+/// How `flash` goes about writing an image.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// Continue an interrupted flash: nothing is erased and pages below
+    /// this address are taken as already written.
+    pub resume_at: Option<usize>,
+    /// Write pages in the bootloader's region instead of refusing.
+    pub overwrite_bootloader: bool,
+    /// Write all-0xFF pages instead of skipping them, and every page from
+    /// the end of the image to the end of application flash, so nothing of
+    /// a bigger image flashed before is left behind.
+    pub write_blank: bool,
+    /// Draw a progress bar.
+    pub show_progress: bool,
+}
+
+/// Write `data` at `base_address`, then start it.
+///
+/// Pages that are all 0xFF (erased flash) are skipped unless
+/// `options.write_blank` is set. Pages in the bootloader's region are
+/// refused unless `options.overwrite_bootloader` is set.
+pub fn flash(
+    loader: &dyn Bootloader,
+    target: &Target,
+    base_address: u32,
+    data: &[u8],
+    options: &WriteOptions,
+) -> Result<FlashStats> {
+    let WriteOptions {
+        resume_at,
+        overwrite_bootloader,
+        write_blank,
+        show_progress,
+    } = *options;
+    let page_size = loader.page_size();
+    let base = base_address as usize;
+    if base + data.len() > loader.flash_size() {
+        bail!(
+            "firmware too large: {} bytes at offset 0x{:04X} exceeds {} byte flash",
+            data.len(),
+            base_address,
+            loader.flash_size()
+        );
+    }
+    if !base.is_multiple_of(page_size) {
+        bail!(
+            "base address 0x{:04X} is not aligned to the {}-byte flash page",
+            base_address,
+            page_size
+        );
+    }
+    let region = loader.bootloader_region();
+    let padded;
+    let data = if write_blank && base + data.len() < region.start {
+        padded = [data, &vec![0xFF; region.start - base - data.len()]].concat();
+        &padded
+    } else {
+        data
+    };
+    if let Some((pages, count)) = bootloader_pages(&region, page_size, base, data, write_blank) {
+        if !overwrite_bootloader {
+            bail!(
+                "the image writes {} page{} at 0x{:04X}..0x{:04X}, inside the bootloader at \
+                 0x{:04X}..0x{:04X}; pass --force to write it anyway",
+                count,
+                if count == 1 { "" } else { "s" },
+                pages.start,
+                pages.end,
+                region.start,
+                region.end
+            );
+        }
+    }
+
+    let first_page = match resume_at {
+        Some(_) if loader.erases_on_first_write() => {
+            bail!(
+                "this bootloader erases the chip on its first write, so an interrupted flash \
+                 can't be resumed; flash again without --resume"
+            );
+        }
+        Some(address)
+            if address < base
+                || address >= base + data.len()
+                || !(address - base).is_multiple_of(page_size) =>
+        {
+            bail!(
+                "can't resume at 0x{:04X}: not a page of the image at 0x{:04X}",
+                address,
+                base_address
+            );
+        }
+        Some(address) => (address - base) / page_size,
+        None => 0,
+    };
+
+    let mut conn = loader.open(target)?;
+    if resume_at.is_none() {
+        conn.erase()?;
+    }
+
+    let total_pages = data.len().div_ceil(page_size);
+    let pb = progress_bar(total_pages, show_progress);
+    pb.set_position(first_page as u64);
+    let mut stats = FlashStats::default();
+    for (page_idx, chunk) in data.chunks(page_size).enumerate().skip(first_page) {
+        let address = base + page_idx * page_size;
+
+        if !write_blank && chunk.iter().all(|&b| b == 0xFF) {
+            stats.pages_skipped += 1;
+            pb.inc(1);
+            continue;
+        }
+
+        let retries = conn.retries();
+        if let Err(e) = conn.write_page(address, chunk) {
+            pb.abandon_with_message("Failed");
+            return Err(e.context(Interrupted {
+                address,
+                pages_written: stats.pages_written,
+                total_pages,
+                retried_pages: stats.retried_pages,
+            }));
+        }
+        if conn.retries() > retries {
+            stats.retried_pages.push(RetriedPage {
+                address,
+                failures: conn.retries() - retries,
+            });
+        }
+        stats.pages_written += 1;
+        pb.inc(1);
+    }
+    pb.finish_with_message("Flashed");
+
+    stats.retries = conn.retries();
+    conn.reboot()?;
+    Ok(stats)
+}
+
+/// The pages of `data` (at `base`) in `region` that would be written,
+/// skipping erased ones as `flash` does unless `write_blank` is set: the
+/// span from the first to the end of the last, and how many.
+fn bootloader_pages(
+    region: &Range<usize>,
+    page_size: usize,
+    base: usize,
+    data: &[u8],
+    write_blank: bool,
+) -> Option<(Range<usize>, usize)> {
+    let written: Vec<usize> = data
+        .chunks(page_size)
+        .enumerate()
+        .map(|(i, chunk)| (base + i * page_size, chunk))
+        .filter(|(address, chunk)| {
+            address + chunk.len() > region.start
+                && *address < region.end
+                && (write_blank || chunk.iter().any(|&b| b != 0xFF))
+        })
+        .map(|(address, _)| address)
+        .collect();
+    let (first, last) = (*written.first()?, *written.last()?);
+    Some((first..last + page_size, written.len()))
+}
+
+/// Leave the bootloader and start the application already in flash, without
+/// writing anything. Returns false if the bootloader isn't waiting.
+pub fn run(loader: &dyn Bootloader, target: &Target) -> Result<bool> {
+    if !loader.detect(target)? {
+        return Ok(false);
+    }
+    loader.open(target)?.reboot()?;
+    Ok(true)
+}
+
+/// A "Flashing [===>  ] 12/40 pages" bar, or a hidden one.
+fn progress_bar(total_pages: usize, show: bool) -> ProgressBar {
+    let pb = if show {
+        ProgressBar::new(total_pages as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} pages")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_message("Flashing");
+    pb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // ========================================================================
+    // The flash loop, against a fake bootloader that records what it's told
+    // ========================================================================
+
+    #[derive(Default)]
+    struct Log {
+        erased: bool,
+        pages: Vec<(usize, Vec<u8>)>,
+        rebooted: bool,
+    }
+
+    struct Fake {
+        log: Rc<RefCell<Log>>,
+        fail_at: Option<usize>,
+        /// A page that takes a retry to write.
+        retry_at: Option<usize>,
+        bootloader: Range<usize>,
+        erases_on_first_write: bool,
+    }
+
+    struct FakeConnection {
+        log: Rc<RefCell<Log>>,
+        fail_at: Option<usize>,
+        retry_at: Option<usize>,
+        retries: usize,
+    }
+
+    impl Bootloader for Fake {
+        fn page_size(&self) -> usize {
+            4
+        }
+
+        fn flash_size(&self) -> usize {
+            16
+        }
+
+        fn bootloader_region(&self) -> Range<usize> {
+            self.bootloader.clone()
+        }
+
+        fn erases_on_first_write(&self) -> bool {
+            self.erases_on_first_write
+        }
+
+        fn detect(&self, _target: &Target) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn open(&self, _target: &Target) -> Result<Box<dyn Connection>> {
+            Ok(Box::new(FakeConnection {
+                log: self.log.clone(),
+                fail_at: self.fail_at,
+                retry_at: self.retry_at,
+                retries: 0,
+            }))
+        }
+    }
+
+    impl Connection for FakeConnection {
+        fn erase(&mut self) -> Result<()> {
+            self.log.borrow_mut().erased = true;
+            Ok(())
+        }
+
+        fn write_page(&mut self, address: usize, page: &[u8]) -> Result<()> {
+            if self.fail_at == Some(address) {
+                bail!("stalled");
+            }
+            if self.retry_at == Some(address) {
+                self.retries += 1;
+            }
+            self.log.borrow_mut().pages.push((address, page.to_vec()));
+            Ok(())
+        }
+
+        fn retries(&self) -> usize {
+            self.retries
+        }
+
+        fn reboot(&mut self) -> Result<()> {
+            self.log.borrow_mut().rebooted = true;
+            Ok(())
+        }
+    }
+
+    fn fake(fail_at: Option<usize>) -> Fake {
+        Fake {
+            log: Rc::default(),
+            fail_at,
+            retry_at: None,
+            bootloader: 16..16,
+            erases_on_first_write: false,
+        }
+    }
+
+    #[test]
+    fn writes_pages_skipping_erased_ones_then_reboots() {
+        let loader = fake(None);
+        let data = [1, 2, 3, 4, 0xFF, 0xFF, 0xFF, 0xFF, 5, 6];
+        let stats = flash(&loader, &Target::Any, 4, &data, &WriteOptions::default()).unwrap();
+
+        assert_eq!((stats.pages_written, stats.pages_skipped), (2, 1));
+        let log = loader.log.borrow();
+        assert!(log.erased && log.rebooted);
+        assert_eq!(
+            log.pages,
+            vec![(4, vec![1, 2, 3, 4]), (12, vec![5, 6])],
+            "addresses offset by the base; the last page is short"
+        );
+    }
+
+    #[test]
+    fn failed_page_stops_without_rebooting() {
+        let loader = fake(Some(8));
+        let err = flash(&loader, &Target::Any, 0, &[1; 12], &WriteOptions::default()).unwrap_err();
+
+        assert!(format!("{err:#}").contains("0x0008 (2 of 3 pages written)"));
+        assert!(!loader.log.borrow().rebooted);
+    }
+
+    #[test]
+    fn pages_that_needed_retries_are_reported() {
+        let retried = [RetriedPage {
+            address: 4,
+            failures: 1,
+        }];
+        let mut loader = fake(Some(8));
+        loader.retry_at = Some(4);
+        let err = flash(&loader, &Target::Any, 0, &[1; 12], &WriteOptions::default()).unwrap_err();
+        let stop = err.downcast_ref::<Interrupted>().unwrap();
+        assert_eq!(stop.retried_pages, retried);
+
+        let mut loader = fake(None);
+        loader.retry_at = Some(4);
+        let stats = flash(&loader, &Target::Any, 0, &[1; 12], &WriteOptions::default()).unwrap();
+        assert_eq!(stats.retries, 1);
+        assert_eq!(stats.retried_pages, retried);
+    }
+
+    #[test]
+    fn resume_continues_at_the_failed_page_without_erasing() {
+        let loader = fake(Some(8));
+        let err = flash(&loader, &Target::Any, 0, &[1; 12], &WriteOptions::default()).unwrap_err();
+        let stop = err.downcast_ref::<Interrupted>().unwrap();
+        assert_eq!(stop.address, 8);
+
+        let loader = fake(None);
+        let resume = WriteOptions {
+            resume_at: Some(stop.address),
+            ..WriteOptions::default()
+        };
+        let stats = flash(&loader, &Target::Any, 0, &[1; 12], &resume).unwrap();
+        assert_eq!(stats.pages_written, 1);
+        let log = loader.log.borrow();
+        assert!(!log.erased && log.rebooted);
+        assert_eq!(log.pages, vec![(8, vec![1; 4])]);
+        let misaligned = WriteOptions {
+            resume_at: Some(6),
+            ..WriteOptions::default()
+        };
+        assert!(flash(&loader, &Target::Any, 0, &[1; 12], &misaligned).is_err());
+    }
+
+    #[test]
+    fn resume_is_refused_where_the_first_write_erases_the_chip() {
+        let mut loader = fake(None);
+        loader.erases_on_first_write = true;
+        let resume = WriteOptions {
+            resume_at: Some(8),
+            ..WriteOptions::default()
+        };
+        let err = flash(&loader, &Target::Any, 0, &[1; 12], &resume).unwrap_err();
+        assert!(err.to_string().contains("can't be resumed"));
+        assert!(loader.log.borrow().pages.is_empty());
+    }
+
+    #[test]
+    fn oversized_or_misaligned_images_are_rejected_before_opening() {
+        let loader = fake(None);
+        assert!(flash(&loader, &Target::Any, 0, &[0; 17], &WriteOptions::default()).is_err());
+        assert!(flash(&loader, &Target::Any, 2, &[0; 4], &WriteOptions::default()).is_err());
+        assert!(!loader.log.borrow().erased);
+    }
+    #[test]
+    fn pages_in_the_bootloader_are_refused_unless_forced() {
+        let mut loader = fake(None);
+        loader.bootloader = 8..16;
+        // The page at 8 is erased and skipped; the one at 12 isn't
+        let data = [
+            1, 1, 1, 1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 2,
+        ];
+        let err = flash(&loader, &Target::Any, 0, &data, &WriteOptions::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the image writes 1 page at 0x000C..0x0010, inside the bootloader at \
+             0x0008..0x0010; pass --force to write it anyway"
+        );
+        assert!(!loader.log.borrow().erased);
+
+        let force = WriteOptions {
+            overwrite_bootloader: true,
+            ..WriteOptions::default()
+        };
+        let stats = flash(&loader, &Target::Any, 0, &data, &force).unwrap();
+        assert_eq!(stats.pages_written, 2);
+        assert!(flash(
+            &loader,
+            &Target::Any,
+            0,
+            &data[..8],
+            &WriteOptions::default()
+        )
+        .is_ok());
+    }
+    #[test]
+    fn writing_blank_pages_clears_everything_up_to_the_bootloader() {
+        let mut loader = fake(None);
+        loader.bootloader = 12..16;
+        let options = WriteOptions {
+            write_blank: true,
+            ..WriteOptions::default()
+        };
+        let stats = flash(
+            &loader,
+            &Target::Any,
+            0,
+            &[1, 0xFF, 0xFF, 0xFF, 0xFF],
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!((stats.pages_written, stats.pages_skipped), (3, 0));
+        assert_eq!(
+            loader.log.borrow().pages,
+            vec![
+                (0, vec![1, 0xFF, 0xFF, 0xFF]),
+                (4, vec![0xFF; 4]),
+                (8, vec![0xFF; 4])
+            ]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+//! Keymap bundles (`.erk`): a keymap with everything needed to show it, in
+//! one file to hand to another ErgoDox owner.
+//!
+//! A bundle is TOML: a name and optionally an author and version, the
+//! keymap's `[[layer]]` tables as in a keymap file, custom legends and layer
+//! colors, and the geometry table under `[geometry]`:
+//!
+//! ```toml
+//! name = "Nordic QWERTY"
+//! author = "Jane Doe"
+//! version = "1.2"
+//!
+//! [[layer]]
+//! rows = [...]
+//!
+//! [[label]]
+//! layer = 1
+//! row = 2
+//! col = 8
+//! label = "IDE Run"
+//!
+//! [[color]]
+//! name = "blue"
+//! rgb = "#3a86ff"
+//!
+//! [geometry]
+//! left = [...]
+//! right = [...]
+//! ```
+//!
+//! Labels and colors are optional; colors go in layer order. `unpack`
+//! splits a bundle into a keymap file, a geometry table and a labels file
+//! with the `[[label]]` and `[[color]]` tables, which `pack` puts back
+//! together.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::{KeyLabel, LayerColor, Rgb, COLS, ROWS};
+use serde::{Deserialize, Serialize};
+
+use crate::codegen::Layer;
+use crate::geometry;
+use crate::keymap_file::{self, FileLayer};
+
+/// A keymap and what goes with it.
+pub struct Bundle {
+    pub name: String,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub layers: Vec<Layer>,
+    pub labels: Vec<KeyLabel>,
+    pub colors: Vec<LayerColor>,
+    /// The geometry table, as `geometry/ergodox.toml` spells it.
+    pub geometry: String,
+}
+
+/// The metadata, ahead of the tables when writing.
+#[derive(Serialize)]
+struct Meta {
+    name: String,
+    author: Option<String>,
+    version: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    name: String,
+    author: Option<String>,
+    version: Option<String>,
+    layer: Vec<FileLayer>,
+    #[serde(default)]
+    label: Vec<FileLabel>,
+    #[serde(default)]
+    color: Vec<FileColor>,
+    geometry: toml::Table,
+}
+
+/// A labels file: the `[[label]]` and `[[color]]` tables on their own.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Labels {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    label: Vec<FileLabel>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    color: Vec<FileColor>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLabel {
+    layer: usize,
+    row: usize,
+    col: usize,
+    label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileColor {
+    name: String,
+    /// `#rrggbb`.
+    rgb: String,
+}
+
+#[derive(Serialize)]
+struct GeometrySection {
+    geometry: toml::Table,
+}
+
+/// Whether `path` names a bundle, by its extension.
+pub fn is_bundle(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "erk")
+}
+
+/// Parse a bundle, checking its keymap, labels and geometry.
+pub fn parse(src: &str) -> Result<Bundle> {
+    let file: File = toml::from_str(src).context("invalid bundle TOML")?;
+    let layers = keymap_file::to_layers(&file.layer)?;
+    let (labels, colors) = to_labels(file.label, file.color, layers.len())?;
+    let geometry = toml::to_string(&file.geometry).context("re-encoding the geometry")?;
+    geometry::parse(&geometry).context("invalid [geometry]")?;
+    Ok(Bundle {
+        name: file.name,
+        author: file.author,
+        version: file.version,
+        layers,
+        labels,
+        colors,
+        geometry,
+    })
+}
+
+/// Render a bundle as TOML.
+pub fn to_toml(bundle: &Bundle) -> Result<String> {
+    let meta = Meta {
+        name: bundle.name.clone(),
+        author: bundle.author.clone(),
+        version: bundle.version.clone(),
+    };
+    let geometry = GeometrySection {
+        geometry: toml::from_str(&bundle.geometry).context("invalid geometry table")?,
+    };
+
+    let mut out =
+        String::from("# ErgoDox keymap bundle; `ergodox-cli unpack` splits it into files.\n");
+    out.push_str(&toml::to_string(&meta)?);
+    out.push('\n');
+    out.push_str(&keymap_file::to_toml(&bundle.layers));
+    let labels = labels_to_toml(&bundle.labels, &bundle.colors)?;
+    if !labels.is_empty() {
+        out.push('\n');
+        out.push_str(&labels);
+    }
+    out.push('\n');
+    out.push_str(&toml::to_string(&geometry)?);
+    Ok(out)
+}
+
+/// Parse a labels file for a keymap of `layer_count` layers.
+pub fn parse_labels(src: &str, layer_count: usize) -> Result<(Vec<KeyLabel>, Vec<LayerColor>)> {
+    let file: Labels = toml::from_str(src).context("invalid labels TOML")?;
+    to_labels(file.label, file.color, layer_count)
+}
+
+/// Render labels and colors as a labels file, empty if there are neither.
+pub fn labels_to_toml(labels: &[KeyLabel], colors: &[LayerColor]) -> Result<String> {
+    let file = Labels {
+        label: labels
+            .iter()
+            .map(|l| FileLabel {
+                layer: l.layer,
+                row: l.row,
+                col: l.col,
+                label: l.label.to_string(),
+            })
+            .collect(),
+        color: colors
+            .iter()
+            .map(|c| {
+                let Rgb(r, g, b) = c.rgb;
+                FileColor {
+                    name: c.name.to_string(),
+                    rgb: format!("#{r:02x}{g:02x}{b:02x}"),
+                }
+            })
+            .collect(),
+    };
+    Ok(toml::to_string(&file)?)
+}
+
+/// Check positions and colors. The strings are leaked: the renderers take
+/// `&'static str` legends, like the built-in keymap's, and a run loads at
+/// most one bundle.
+fn to_labels(
+    labels: Vec<FileLabel>,
+    colors: Vec<FileColor>,
+    layer_count: usize,
+) -> Result<(Vec<KeyLabel>, Vec<LayerColor>)> {
+    let labels = labels
+        .into_iter()
+        .map(|l| {
+            if l.layer >= layer_count || l.row >= ROWS || l.col >= COLS {
+                bail!(
+                    "label {:?}: no key at layer {} row {} col {}",
+                    l.label,
+                    l.layer,
+                    l.row,
+                    l.col
+                );
+            }
+            Ok(KeyLabel {
+                layer: l.layer,
+                row: l.row,
+                col: l.col,
+                label: l.label.leak(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if colors.len() > layer_count {
+        bail!(
+            "{} colors for a keymap of {layer_count} layers",
+            colors.len()
+        );
+    }
+    let colors = colors
+        .into_iter()
+        .map(|c| {
+            let rgb = parse_rgb(&c.rgb).with_context(|| {
+                format!("color {:?}: expected #rrggbb, got {:?}", c.name, c.rgb)
+            })?;
+            Ok(LayerColor {
+                name: c.name.leak(),
+                rgb,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((labels, colors))
+}
+
+fn parse_rgb(s: &str) -> Option<Rgb> {
+    let hex = s.strip_prefix('#').filter(|h| h.len() == 6)?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Rgb(channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::{KEY_LABELS, LAYERS, LAYER_COLORS};
+
+    fn builtin() -> Bundle {
+        Bundle {
+            name: "Built-in \"test\"".to_string(),
+            author: Some("Someone".to_string()),
+            version: None,
+            layers: LAYERS.to_vec(),
+            labels: vec![KeyLabel {
+                layer: 1,
+                row: 2,
+                col: 8,
+                label: "IDE Run",
+            }],
+            colors: LAYER_COLORS.to_vec(),
+            geometry: geometry::BUILTIN.to_string(),
+        }
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let bundle = builtin();
+        let parsed = parse(&to_toml(&bundle).unwrap()).unwrap();
+        assert_eq!(parsed.name, bundle.name);
+        assert_eq!(parsed.author, bundle.author);
+        assert_eq!(parsed.version, None);
+        assert_eq!(parsed.layers, bundle.layers);
+        assert_eq!(parsed.labels, bundle.labels);
+        assert_eq!(parsed.colors, bundle.colors);
+        assert_eq!(
+            geometry::parse(&parsed.geometry).unwrap().len(),
+            geometry::builtin().len()
+        );
+    }
+
+    #[test]
+    fn labels_files_round_trip() {
+        let src = labels_to_toml(KEY_LABELS, &LAYER_COLORS).unwrap();
+        let (labels, colors) = parse_labels(&src, LAYERS.len()).unwrap();
+        assert_eq!(labels, KEY_LABELS);
+        assert_eq!(colors, LAYER_COLORS);
+        assert_eq!(labels_to_toml(&[], &[]).unwrap(), "");
+    }
+
+    #[test]
+    fn labels_must_name_a_key_and_colors_a_layer() {
+        let err = parse_labels(
+            "[[label]]\nlayer = 9\nrow = 0\ncol = 0\nlabel = \"Copy\"\n",
+            2,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no key at layer 9"), "{err}");
+        let err = parse_labels("[[color]]\nname = \"red\"\nrgb = \"red\"\n", 2).unwrap_err();
+        assert!(err.to_string().contains("expected #rrggbb"), "{err}");
+    }
+}
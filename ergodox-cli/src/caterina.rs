@@ -0,0 +1,229 @@
+//! Caterina bootloader (Arduino Leonardo / Pro Micro), found on ErgoDox
+//! clones built around ATmega32U4 Arduino-style boards.
+//!
+//! Caterina shows up as a USB CDC serial port and speaks AVR109
+//! ("butterfly"), the protocol avrdude uses with `-c avr109`. Rather than
+//! go through the OS serial driver, we claim the CDC data interface and
+//! talk to its bulk endpoints directly. Every command is answered with a
+//! carriage return:
+//!
+//! - `P` / `L`: enter / leave programming mode
+//! - `e`: chip erase
+//! - `A hi lo`: set the (word) address
+//! - `B hi lo F data...`: write a block of flash at the current address
+//! - `E`: exit the bootloader and start the application
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use rusb::{DeviceHandle, Direction, GlobalContext, TransferType};
+
+use crate::bootloader::{Bootloader, Connection};
+use crate::halfkay::{find, Mode, Target};
+use crate::trace;
+
+/// Bootloader VID/PIDs of common Caterina boards.
+pub const IDS: &[(u16, u16)] = &[
+    (0x2341, 0x0036), // Arduino Leonardo
+    (0x2341, 0x0037), // Arduino Micro
+    (0x2A03, 0x0036), // Arduino Leonardo (arduino.org)
+    (0x1B4F, 0x9203), // SparkFun Pro Micro 3.3V
+    (0x1B4F, 0x9205), // SparkFun Pro Micro 5V
+];
+
+/// The 4 KB Caterina bootloader sits at the top of the 32 KB flash.
+const APP_END: usize = 0x7000;
+
+/// Flash page size; Caterina reports this as its block size.
+const BLOCK_SIZE: usize = 128;
+
+/// CDC interface classes.
+const CDC_COMM_CLASS: u8 = 0x02;
+const CDC_DATA_CLASS: u8 = 0x0A;
+
+/// CDC SET_CONTROL_LINE_STATE, raising DTR and RTS.
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Erasing the chip takes a few hundred milliseconds.
+const USB_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Whether a Caterina bootloader is waiting.
+pub fn detect(target: &Target) -> Result<bool> {
+    Ok(find(Mode::Caterina, target)?.is_some())
+}
+
+/// Caterina on an ATmega32U4.
+pub struct Caterina;
+
+impl Bootloader for Caterina {
+    fn page_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn flash_size(&self) -> usize {
+        APP_END
+    }
+
+    fn detect(&self, target: &Target) -> Result<bool> {
+        detect(target)
+    }
+
+    fn open(&self, target: &Target) -> Result<Box<dyn Connection>> {
+        let port = Port::open(target)?;
+        port.command(b"P")
+            .context("failed to enter programming mode")?;
+        Ok(Box::new(port))
+    }
+}
+
+impl Connection for Port {
+    fn erase(&mut self) -> Result<()> {
+        self.command(b"e").context("chip erase failed")
+    }
+
+    fn write_page(&mut self, address: usize, page: &[u8]) -> Result<()> {
+        self.command(&set_address_command(address))?;
+        self.command(&write_block_command(page))
+    }
+
+    /// The bootloader resets right after acknowledging `E`, so a missing
+    /// reply to it is fine.
+    fn reboot(&mut self) -> Result<()> {
+        self.command(b"L")
+            .context("failed to leave programming mode")?;
+        let _ = self.command(b"E");
+        Ok(())
+    }
+}
+
+/// `A`: set the address, given in 16-bit words, big-endian.
+fn set_address_command(byte_address: usize) -> [u8; 3] {
+    let [hi, lo] = ((byte_address / 2) as u16).to_be_bytes();
+    [b'A', hi, lo]
+}
+
+/// `B`: write a block of flash (`F`) at the current address.
+fn write_block_command(data: &[u8]) -> Vec<u8> {
+    let mut cmd = vec![b'B'];
+    cmd.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    cmd.push(b'F');
+    cmd.extend_from_slice(data);
+    cmd
+}
+
+/// The bootloader's serial port, as raw bulk endpoints.
+struct Port {
+    handle: DeviceHandle<GlobalContext>,
+    ep_in: u8,
+    ep_out: u8,
+}
+
+impl Port {
+    fn open(target: &Target) -> Result<Port> {
+        let Some(device) = find(Mode::Caterina, target)? else {
+            bail!(
+                "Caterina bootloader not found. Double-tap reset (it only waits 8 seconds) and try again."
+            );
+        };
+        let config = device
+            .active_config_descriptor()
+            .context("failed to read configuration descriptor")?;
+
+        let mut comm = None;
+        let mut data = None;
+        for interface in config.interfaces() {
+            for alt in interface.descriptors() {
+                match alt.class_code() {
+                    CDC_COMM_CLASS => comm = Some(alt.interface_number()),
+                    CDC_DATA_CLASS => {
+                        let bulk = |dir| {
+                            alt.endpoint_descriptors()
+                                .find(|e| {
+                                    e.transfer_type() == TransferType::Bulk && e.direction() == dir
+                                })
+                                .map(|e| e.address())
+                        };
+                        if let (Some(ep_in), Some(ep_out)) =
+                            (bulk(Direction::In), bulk(Direction::Out))
+                        {
+                            data = Some((alt.interface_number(), ep_in, ep_out));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let (data_interface, ep_in, ep_out) =
+            data.context("Caterina device has no CDC data interface")?;
+
+        let handle = device.open().context(
+            "failed to open Caterina bootloader (may need udev rules; see `ergodox-cli udev`)",
+        )?;
+        // The OS serial driver has the interface; borrow it (Linux only,
+        // elsewhere this fails and claiming below reports the problem)
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(data_interface).context(
+            "failed to claim the bootloader's serial interface (is a serial monitor open?)",
+        )?;
+        if let Some(comm) = comm {
+            let _ = trace::write_control(
+                &handle,
+                0x21,
+                CDC_SET_CONTROL_LINE_STATE,
+                0x03,
+                comm as u16,
+                &[],
+                USB_TIMEOUT,
+            );
+        }
+
+        Ok(Port {
+            handle,
+            ep_in,
+            ep_out,
+        })
+    }
+
+    /// Send a command and wait for the carriage return that acknowledges it.
+    fn command(&self, cmd: &[u8]) -> Result<()> {
+        trace::write_bulk(&self.handle, self.ep_out, cmd, USB_TIMEOUT)
+            .context("write to bootloader failed")?;
+        let mut buf = [0u8; 64];
+        let n = trace::read_bulk(&self.handle, self.ep_in, &mut buf, USB_TIMEOUT)
+            .context("no reply from bootloader")?;
+        if buf[..n] != *b"\r" {
+            bail!("unexpected reply from bootloader: {:02X?}", &buf[..n]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ========================================================================
+    // AVR109 commands
+    //
+    // Addresses are in 16-bit words, as the AVR's program memory is
+    // word-addressed; block sizes are in bytes.
+    // ========================================================================
+
+    #[test]
+    fn set_address_uses_word_address() {
+        assert_eq!(set_address_command(0x1C00), [b'A', 0x0E, 0x00]);
+    }
+
+    #[test]
+    fn write_block_is_size_then_memory_type_then_data() {
+        let cmd = write_block_command(&[0xAA; BLOCK_SIZE]);
+        assert_eq!(&cmd[..4], &[b'B', 0x00, 0x80, b'F']);
+        assert_eq!(cmd.len(), 4 + BLOCK_SIZE);
+    }
+
+    #[test]
+    fn known_boards_are_recognized() {
+        assert!(IDS.contains(&(0x1B4F, 0x9205)));
+        assert_eq!(Mode::of(0x2341, 0x0036), Some(Mode::Caterina));
+    }
+}
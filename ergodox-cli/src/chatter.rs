@@ -0,0 +1,187 @@
+//! Switch chatter, for `chatter`: how often each key's raw reading bounced
+//! back before the debouncer accepted it, against how often it was pressed.
+//!
+//! Every switch bounces a little, so the rate only means something next to
+//! the rest of the board. A key bouncing on several times as many presses as
+//! the median is flagged: its contacts are going, and once a bounce outlasts
+//! the debounce time it types double.
+
+use std::fmt;
+
+use ergodox_keymap::{Keycode, COLS, LAYERS, ROWS};
+use serde_json::{json, Value};
+
+use crate::heatmap::Counts;
+
+/// Presses plus bounces a key needs before its rate is judged.
+const MIN_SAMPLES: u64 = 20;
+
+/// Flagged keys bounce on at least this many times the median rate...
+const MEDIAN_FACTOR: f64 = 3.0;
+
+/// ...and on at least this share of their presses.
+const MIN_RATE: f64 = 0.1;
+
+#[derive(Debug)]
+pub struct KeyChatter {
+    pub row: usize,
+    pub col: usize,
+    pub presses: u64,
+    pub bounces: u64,
+    pub suspect: bool,
+}
+
+impl KeyChatter {
+    /// Bounces per press. A key that bounces without ever being pressed is
+    /// counted as if pressed once, so it still stands out.
+    pub fn rate(&self) -> f64 {
+        self.bounces as f64 / self.presses.max(1) as f64
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    /// Median bounces per press over the keys with enough samples.
+    pub median_rate: f64,
+    /// Keys that bounced at all, worst first.
+    pub keys: Vec<KeyChatter>,
+}
+
+pub fn report(presses: &Counts, bounces: &Counts) -> Report {
+    let mut keys: Vec<KeyChatter> = (0..ROWS)
+        .flat_map(|row| (0..COLS).map(move |col| (row, col)))
+        .map(|(row, col)| KeyChatter {
+            row,
+            col,
+            presses: presses[row][col],
+            bounces: bounces[row][col],
+            suspect: false,
+        })
+        .collect();
+
+    let mut rates: Vec<f64> = keys
+        .iter()
+        .filter(|k| k.presses + k.bounces >= MIN_SAMPLES)
+        .map(KeyChatter::rate)
+        .collect();
+    rates.sort_by(f64::total_cmp);
+    let median_rate = rates.get(rates.len() / 2).copied().unwrap_or(0.0);
+
+    let limit = (median_rate * MEDIAN_FACTOR).max(MIN_RATE);
+    keys.retain(|k| k.bounces > 0);
+    for key in &mut keys {
+        key.suspect = key.presses + key.bounces >= MIN_SAMPLES && key.rate() >= limit;
+    }
+    keys.sort_by(|a, b| b.rate().total_cmp(&a.rate()));
+    Report { median_rate, keys }
+}
+
+/// Layer-0 legend for a position.
+fn legend(row: usize, col: usize) -> &'static str {
+    match LAYERS[0][row][col] {
+        Keycode::Trans | Keycode::None => "",
+        kc => kc.display_name(),
+    }
+}
+
+impl Report {
+    pub fn suspects(&self) -> usize {
+        self.keys.iter().filter(|k| k.suspect).count()
+    }
+
+    /// The report as a JSON object, for `chatter --json`.
+    pub fn to_json(&self) -> Value {
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|k| {
+                json!({
+                    "row": k.row,
+                    "col": k.col,
+                    "key": legend(k.row, k.col),
+                    "presses": k.presses,
+                    "bounces": k.bounces,
+                    "bounces_per_press": k.rate(),
+                    "suspect": k.suspect,
+                })
+            })
+            .collect();
+        json!({
+            "median_bounces_per_press": self.median_rate,
+            "suspects": self.suspects(),
+            "keys": keys,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.keys.is_empty() {
+            return write!(f, "No bounces since power-on");
+        }
+        writeln!(
+            f,
+            "Bounces since power-on (median {:.3} per press)",
+            self.median_rate
+        )?;
+        writeln!(f, "  row  col  key           presses  bounces  per press")?;
+        for k in &self.keys {
+            writeln!(
+                f,
+                "  {:<3}  {:<3}  {:<12}  {:>7}  {:>7}  {:>9.3}{}",
+                k.row,
+                k.col,
+                legend(k.row, k.col),
+                k.presses,
+                k.bounces,
+                k.rate(),
+                if k.suspect { "  WORN?" } else { "" }
+            )?;
+        }
+        match self.suspects() {
+            0 => write!(f, "No switch stands out"),
+            n => write!(
+                f,
+                "{} switch{} bounce{} far more than the rest: raise DEBOUNCE_MS \
+                 in firmware/src/debounce.rs, or replace {}",
+                n,
+                if n == 1 { "" } else { "es" },
+                if n == 1 { "s" } else { "" },
+                if n == 1 { "it" } else { "them" }
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(value: u64) -> Counts {
+        [[value; COLS]; ROWS]
+    }
+
+    #[test]
+    fn keys_bouncing_well_above_the_median_are_flagged() {
+        let presses = board(100);
+        let mut bounces = board(2);
+        bounces[1][2] = 30;
+        let report = report(&presses, &bounces);
+        assert_eq!(report.median_rate, 0.02);
+        assert_eq!((report.keys[0].row, report.keys[0].col), (1, 2));
+        assert!(report.keys[0].suspect);
+        assert_eq!(report.suspects(), 1);
+    }
+
+    #[test]
+    fn a_few_bounces_on_a_rarely_used_key_are_not_enough() {
+        let mut presses = board(0);
+        let mut bounces = board(0);
+        presses[0][0] = 5;
+        bounces[0][0] = 4;
+        let report = report(&presses, &bounces);
+        assert_eq!(report.keys.len(), 1);
+        assert!(!report.keys[0].suspect);
+        assert!(report.to_string().contains("No switch stands out"));
+    }
+}
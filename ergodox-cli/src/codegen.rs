@@ -0,0 +1,92 @@
+//! Rust source generation for keymap layers.
+//!
+//! Imported or edited keymaps end up as a `LAYERS` table that can be pasted
+//! over the one in a keymap module under `ergodox-keymap/src/keymaps/`. The
+//! output uses the same conventions as the hand-written table: `___` for
+//! transparent keys and fully qualified `Keycode::` variants for everything
+//! else.
+//!
+//! The same table can also be emitted as a raw binary blob, for tooling
+//! that loads a keymap without compiling it in.
+
+use ergodox_keymap::{Keycode, COLS, ROWS};
+
+/// A single keymap layer, indexed `[row][col]`.
+pub type Layer = [[Keycode; COLS]; ROWS];
+
+/// Render `NUM_LAYERS` and the `LAYERS` table for the given layers.
+pub fn layers_to_rust(layers: &[Layer]) -> String {
+    let mut out = String::new();
+    out.push_str("/// Number of layers.\n");
+    out.push_str(&format!(
+        "pub const NUM_LAYERS: usize = {};\n\n",
+        layers.len()
+    ));
+    out.push_str("/// Keymap layers.\n");
+    out.push_str("pub const LAYERS: [[[Keycode; COLS]; ROWS]; NUM_LAYERS] = [\n");
+
+    for (i, layer) in layers.iter().enumerate() {
+        out.push_str(&format!("    // Layer {i}\n    [\n"));
+        for (r, row) in layer.iter().enumerate() {
+            let cells: Vec<String> = row.iter().map(|&kc| keycode_expr(kc)).collect();
+            out.push_str(&format!("        // Row {r}\n"));
+            out.push_str(&format!("        [{}],\n", cells.join(", ")));
+        }
+        out.push_str("    ],\n");
+    }
+
+    out.push_str("];\n");
+    out
+}
+
+/// Flatten layers into a binary blob: one keycode byte per position,
+/// layer-major, then row-major, with no header.
+pub fn layers_to_blob(layers: &[Layer]) -> Vec<u8> {
+    layers
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|&kc| kc as u8)
+        .collect()
+}
+
+/// Rust expression for a keycode, using the keymap's `___` alias for Trans.
+fn keycode_expr(kc: Keycode) -> String {
+    if kc == Keycode::Trans {
+        "___".to_string()
+    } else {
+        format!("Keycode::{:?}", kc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::LAYERS;
+
+    #[test]
+    fn num_layers_matches_input() {
+        let src = layers_to_rust(&LAYERS[..1]);
+        assert!(src.contains("pub const NUM_LAYERS: usize = 1;"));
+    }
+
+    #[test]
+    fn emits_one_row_array_per_matrix_row() {
+        let src = layers_to_rust(&LAYERS);
+        assert_eq!(src.matches("// Row ").count(), ROWS * LAYERS.len());
+    }
+
+    #[test]
+    fn blob_is_one_byte_per_position_in_table_order() {
+        let blob = layers_to_blob(&LAYERS);
+        assert_eq!(blob.len(), LAYERS.len() * ROWS * COLS);
+        assert_eq!(blob[ROWS * COLS + COLS + 2], LAYERS[1][1][2] as u8);
+    }
+
+    #[test]
+    fn transparent_keys_use_the_triple_underscore_alias() {
+        // Matches the hand-written table, where `___` is Keycode::Trans.
+        assert_eq!(keycode_expr(Keycode::Trans), "___");
+        assert_eq!(keycode_expr(Keycode::LCtrl), "Keycode::LCtrl");
+    }
+}
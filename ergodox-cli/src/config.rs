@@ -0,0 +1,138 @@
+//! User defaults from `~/.config/ergodox/config.toml`.
+//!
+//! Everything is optional, and a flag on the command line always wins over
+//! the file:
+//!
+//! ```toml
+//! firmware = "/home/me/ergodox/firmware.hex"   # for `flash`
+//! keymap = "/home/me/ergodox/keymap.toml"      # for `patch-keymap`
+//! language = "us"                              # legends in `layout`
+//!
+//! [flash]
+//! verify = true
+//! bootloader = "halfkay"
+//! mcu = "atmega32u4"
+//! transport = "libusb"                        # or "hidraw", on Linux
+//! base_address = 0
+//! log_file = "/home/me/ergodox/flash.log"     # every flash attempt
+//! ```
+//!
+//! Relative paths are taken from the current directory, as on the command
+//! line.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub firmware: Option<PathBuf>,
+    pub keymap: Option<String>,
+    pub language: Option<String>,
+    #[serde(default)]
+    pub flash: FlashConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlashConfig {
+    pub verify: Option<bool>,
+    pub bootloader: Option<String>,
+    pub mcu: Option<String>,
+    pub transport: Option<String>,
+    pub base_address: Option<u32>,
+    pub log_file: Option<PathBuf>,
+}
+
+/// Where the config file lives: `$XDG_CONFIG_HOME/ergodox/config.toml`,
+/// falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(dir()?.join("config.toml"))
+}
+
+/// The directory the config file lives in, which keeps saved profiles too.
+pub fn dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("ergodox"))
+}
+
+/// Load the config at `path`, or the default one if none is given. A
+/// missing default file is an empty config; a missing explicit one is an
+/// error.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Config::default())
+        }
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    parse(&contents).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn parse(contents: &str) -> Result<Config> {
+    Ok(toml::from_str(contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_is_optional() {
+        let config = parse("").unwrap();
+        assert!(config.firmware.is_none());
+        assert!(config.flash.verify.is_none());
+    }
+
+    #[test]
+    fn parses_the_documented_example() {
+        let config = parse(
+            r#"
+            firmware = "firmware.hex"
+            keymap = "keymap.toml"
+            language = "us"
+
+            [flash]
+            verify = true
+            bootloader = "dfu"
+            mcu = "at90usb1286"
+            base_address = 256
+            log_file = "flash.log"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.firmware.as_deref(), Some(Path::new("firmware.hex")));
+        assert_eq!(config.language.as_deref(), Some("us"));
+        assert_eq!(config.flash.verify, Some(true));
+        assert_eq!(config.flash.bootloader.as_deref(), Some("dfu"));
+        assert_eq!(config.flash.base_address, Some(256));
+        assert_eq!(
+            config.flash.log_file.as_deref(),
+            Some(Path::new("flash.log"))
+        );
+    }
+
+    #[test]
+    fn misspelled_keys_are_errors() {
+        // Silently ignoring `verfy = true` would be worse than failing
+        assert!(parse("[flash]\nverfy = true").is_err());
+    }
+
+    #[test]
+    fn missing_explicit_file_is_an_error() {
+        assert!(load(Some(Path::new("/nonexistent/ergodox.toml"))).is_err());
+    }
+}
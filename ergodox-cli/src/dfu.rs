@@ -0,0 +1,279 @@
+//! Atmel DFU bootloader (FLIP protocol), as shipped on stock ATmega32U4
+//! boards used by many ErgoDox clones.
+//!
+//! The bootloader speaks USB DFU 1.0, with Atmel's commands carried in
+//! DFU_DNLOAD requests. This follows what `dfu-programmer` sends:
+//!
+//! - erase:   `04 00 FF`
+//! - program: a 32-byte header `01 00 start_hi start_lo end_hi end_lo ...`,
+//!   the data, then a 16-byte DFU suffix
+//! - start:   `04 03 00`, followed by an empty DNLOAD that triggers the reset
+//!
+//! Every command is followed by DFU_GETSTATUS to check it worked.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use rusb::{DeviceHandle, GlobalContext};
+
+use crate::bootloader::{Bootloader, Connection};
+use crate::halfkay::{find, Mode, Target};
+use crate::trace;
+
+/// Atmel's vendor ID and the ATmega32U4 DFU bootloader's product ID.
+pub const VID: u16 = 0x03EB;
+pub const PID: u16 = 0x2FF4;
+
+/// The 4 KB DFU bootloader sits at the top of the 32 KB flash.
+const APP_END: usize = 0x7000;
+
+/// Largest block the bootloader accepts in one program command.
+const BLOCK_SIZE: usize = 1024;
+
+/// Program command header and DFU suffix sizes.
+const HEADER_SIZE: usize = 32;
+const SUFFIX_SIZE: usize = 16;
+
+/// DFU class requests (USB DFU 1.0, section 3).
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_ABORT: u8 = 6;
+
+/// bmRequestType for DFU requests: class, interface, out / in.
+const DFU_OUT: u8 = 0x21;
+const DFU_IN: u8 = 0xA1;
+
+/// DFU status and state codes we care about.
+const STATUS_OK: u8 = 0x00;
+const STATUS_ERR_NOTDONE: u8 = 0x09;
+const STATE_DFU_IDLE: u8 = 2;
+
+const USB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a chip erase may take before we give up.
+const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a DFU bootloader is waiting.
+pub fn detect(target: &Target) -> Result<bool> {
+    Ok(find(Mode::Dfu, target)?.is_some())
+}
+
+/// The Atmel DFU bootloader on an ATmega32U4.
+pub struct Dfu;
+
+impl Bootloader for Dfu {
+    fn page_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn flash_size(&self) -> usize {
+        APP_END
+    }
+
+    fn detect(&self, target: &Target) -> Result<bool> {
+        detect(target)
+    }
+
+    fn open(&self, target: &Target) -> Result<Box<dyn Connection>> {
+        let mut conn = DfuConnection::open(target)?;
+        conn.make_idle()?;
+        Ok(Box::new(conn))
+    }
+}
+
+/// Build a program command: header, data, DFU suffix.
+fn program_command(address: usize, data: &[u8]) -> Vec<u8> {
+    let end = address + data.len() - 1;
+    let mut msg = vec![0u8; HEADER_SIZE];
+    msg[0] = 0x01; // program start
+    msg[1] = 0x00; // flash, not EEPROM
+    msg[2..4].copy_from_slice(&(address as u16).to_be_bytes());
+    msg[4..6].copy_from_slice(&(end as u16).to_be_bytes());
+    msg.extend_from_slice(data);
+    // bcdDevice, idProduct, idVendor (all "any"), bcdDFU 1.10, "UFD", length, CRC (unchecked)
+    msg.extend_from_slice(&[
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x10, 0x01, b'U', b'F', b'D', 16, 0, 0, 0, 0,
+    ]);
+    debug_assert_eq!(msg.len(), HEADER_SIZE + data.len() + SUFFIX_SIZE);
+    msg
+}
+
+struct DfuConnection {
+    handle: DeviceHandle<GlobalContext>,
+    /// DFU block number, sent as wValue of each DNLOAD.
+    block: u16,
+}
+
+/// Reply to DFU_GETSTATUS.
+struct Status {
+    status: u8,
+    state: u8,
+}
+
+impl Connection for DfuConnection {
+    fn erase(&mut self) -> Result<()> {
+        trace::write_control(
+            &self.handle,
+            DFU_OUT,
+            DFU_DNLOAD,
+            self.block,
+            0,
+            &[0x04, 0x00, 0xFF],
+            USB_TIMEOUT,
+        )
+        .context("chip erase failed")?;
+        self.block = self.block.wrapping_add(1);
+
+        let deadline = Instant::now() + ERASE_TIMEOUT;
+        loop {
+            match self.get_status()?.status {
+                STATUS_OK => return Ok(()),
+                STATUS_ERR_NOTDONE if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                status => bail!("chip erase failed with DFU status 0x{:02X}", status),
+            }
+        }
+    }
+
+    fn write_page(&mut self, address: usize, page: &[u8]) -> Result<()> {
+        self.download(&program_command(address, page))
+    }
+
+    /// Reset into the application. The device drops off the bus, so errors
+    /// here are expected and ignored.
+    fn reboot(&mut self) -> Result<()> {
+        let _ = trace::write_control(
+            &self.handle,
+            DFU_OUT,
+            DFU_DNLOAD,
+            self.block,
+            0,
+            &[0x04, 0x03, 0x00],
+            USB_TIMEOUT,
+        );
+        let _ = trace::write_control(
+            &self.handle,
+            DFU_OUT,
+            DFU_DNLOAD,
+            self.block + 1,
+            0,
+            &[],
+            USB_TIMEOUT,
+        );
+        Ok(())
+    }
+}
+
+impl DfuConnection {
+    fn open(target: &Target) -> Result<DfuConnection> {
+        let Some(device) = find(Mode::Dfu, target)? else {
+            bail!("Atmel DFU bootloader not found. Press the reset button and try again.");
+        };
+        let handle = device.open().context(
+            "failed to open DFU bootloader (may need udev rules; see `ergodox-cli udev`)",
+        )?;
+        handle
+            .claim_interface(0)
+            .context("failed to claim the DFU interface")?;
+        Ok(DfuConnection { handle, block: 0 })
+    }
+
+    fn get_status(&self) -> Result<Status> {
+        let mut buf = [0u8; 6];
+        let n = trace::read_control(
+            &self.handle,
+            DFU_IN,
+            DFU_GETSTATUS,
+            0,
+            0,
+            &mut buf,
+            USB_TIMEOUT,
+        )
+        .context("DFU_GETSTATUS failed")?;
+        if n != buf.len() {
+            bail!("DFU_GETSTATUS reply was {} bytes, expected 6", n);
+        }
+        Ok(Status {
+            status: buf[0],
+            state: buf[4],
+        })
+    }
+
+    /// Clear any error left over from an earlier session and get back to
+    /// dfuIDLE, like dfu-programmer does before every command.
+    fn make_idle(&mut self) -> Result<()> {
+        let status = self.get_status()?;
+        if status.status != STATUS_OK {
+            trace::write_control(&self.handle, DFU_OUT, DFU_CLRSTATUS, 0, 0, &[], USB_TIMEOUT)
+                .context("DFU_CLRSTATUS failed")?;
+        }
+        if self.get_status()?.state != STATE_DFU_IDLE {
+            trace::write_control(&self.handle, DFU_OUT, DFU_ABORT, 0, 0, &[], USB_TIMEOUT)
+                .context("DFU_ABORT failed")?;
+        }
+        Ok(())
+    }
+
+    /// Send one DNLOAD and check the bootloader accepted it.
+    fn download(&mut self, data: &[u8]) -> Result<()> {
+        trace::write_control(
+            &self.handle,
+            DFU_OUT,
+            DFU_DNLOAD,
+            self.block,
+            0,
+            data,
+            USB_TIMEOUT,
+        )
+        .context("DFU_DNLOAD failed")?;
+        self.block = self.block.wrapping_add(1);
+        let status = self.get_status()?;
+        if status.status != STATUS_OK {
+            bail!("bootloader reported DFU status 0x{:02X}", status.status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ========================================================================
+    // FLIP program command
+    //
+    // Byte layout matches dfu-programmer's __atmel_flash_block() for 8-bit
+    // AVRs: a 32-byte header, the data, then a 16-byte DFU suffix.
+    // ========================================================================
+
+    #[test]
+    fn program_command_header_has_inclusive_big_endian_range() {
+        let msg = program_command(0x1C00, &[0xAA; 0x400]);
+        assert_eq!(&msg[..6], &[0x01, 0x00, 0x1C, 0x00, 0x1F, 0xFF]);
+        assert!(msg[6..HEADER_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(msg[HEADER_SIZE], 0xAA);
+        assert_eq!(msg.len(), HEADER_SIZE + 0x400 + SUFFIX_SIZE);
+    }
+
+    #[test]
+    fn program_command_ends_with_dfu_suffix() {
+        let msg = program_command(0, &[1, 2, 3]);
+        let suffix = &msg[msg.len() - SUFFIX_SIZE..];
+        assert_eq!(&suffix[8..11], b"UFD");
+        assert_eq!(suffix[11], SUFFIX_SIZE as u8);
+    }
+
+    #[test]
+    fn dfu_requests_are_class_interface_requests() {
+        // bmRequestType: direction (bit 7), type class (0x20), recipient interface (0x01)
+        assert_eq!(DFU_OUT, 0x20 | 0x01);
+        assert_eq!(DFU_IN, 0x80 | 0x20 | 0x01);
+    }
+
+    #[test]
+    fn application_stops_below_the_4k_bootloader() {
+        assert_eq!(APP_END, 32 * 1024 - 4 * 1024);
+    }
+}
@@ -0,0 +1,139 @@
+//! Per-position comparison of two keymaps.
+
+use ergodox_keymap::{Keycode, COLS, ROWS};
+
+use crate::codegen::Layer;
+
+/// One matrix position whose keycode differs between the two keymaps.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub layer: usize,
+    pub row: usize,
+    pub col: usize,
+    pub old: Keycode,
+    pub new: Keycode,
+}
+
+/// Compare two keymaps position by position.
+///
+/// A layer that exists on only one side is compared against an all-`Trans`
+/// layer, so adding or removing a layer shows up as its non-transparent keys.
+pub fn diff(old: &[Layer], new: &[Layer]) -> Vec<Change> {
+    const EMPTY: Layer = [[Keycode::Trans; COLS]; ROWS];
+
+    let mut changes = Vec::new();
+    for l in 0..old.len().max(new.len()) {
+        let before = old.get(l).unwrap_or(&EMPTY);
+        let after = new.get(l).unwrap_or(&EMPTY);
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                if before[row][col] != after[row][col] {
+                    changes.push(Change {
+                        layer: l,
+                        row,
+                        col,
+                        old: before[row][col],
+                        new: after[row][col],
+                    });
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Render changes as one table per layer.
+pub fn render(changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return "No changes.\n".to_string();
+    }
+
+    let old_w = changes
+        .iter()
+        .map(|c| label(c.old).chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+
+    let mut out = String::new();
+    let mut current = None;
+    for c in changes {
+        if current != Some(c.layer) {
+            if current.is_some() {
+                out.push('\n');
+            }
+            current = Some(c.layer);
+            out.push_str(&format!("Layer {}\n", c.layer));
+            out.push_str(&format!("  row col  {:<old_w$}    new\n", "old"));
+        }
+        out.push_str(&format!(
+            "  {:>3} {:>3}  {:<old_w$} \u{2192}  {}\n",
+            c.row,
+            c.col,
+            label(c.old),
+            label(c.new)
+        ));
+    }
+    out
+}
+
+/// Legend for a keycode, as shown on the layout, with names for the blanks.
+pub fn label(kc: Keycode) -> &'static str {
+    match kc {
+        Keycode::Trans => "___",
+        Keycode::None => "None",
+        _ => kc.display_name(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::LAYERS;
+
+    #[test]
+    fn identical_keymaps_have_no_changes() {
+        assert!(diff(&LAYERS, &LAYERS).is_empty());
+        assert_eq!(render(&[]), "No changes.\n");
+    }
+
+    #[test]
+    fn changed_position_is_reported_with_old_and_new() {
+        let mut new = LAYERS;
+        new[1][0][0] = Keycode::Escape;
+        assert_eq!(
+            diff(&LAYERS, &new),
+            [Change {
+                layer: 1,
+                row: 0,
+                col: 0,
+                old: LAYERS[1][0][0],
+                new: Keycode::Escape,
+            }]
+        );
+    }
+
+    #[test]
+    fn added_layer_compares_against_transparent() {
+        let mut extra = [[Keycode::Trans; COLS]; ROWS];
+        extra[2][3] = Keycode::A;
+        let new = [LAYERS[0], LAYERS[1], extra];
+
+        let changes = diff(&LAYERS, &new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].layer, changes[0].old), (2, Keycode::Trans));
+    }
+
+    #[test]
+    fn table_groups_rows_under_layer_headings() {
+        let mut new = LAYERS;
+        new[0][1][1] = Keycode::Z;
+        new[1][0][1] = Keycode::Trans;
+
+        let table = render(&diff(&LAYERS, &new));
+        assert!(table.contains("Layer 0\n"));
+        assert!(table.contains("Layer 1\n"));
+        assert!(table.contains("\u{2192}  Z\n"));
+        assert!(table.contains("\u{2192}  ___\n"));
+    }
+}
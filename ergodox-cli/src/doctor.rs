@@ -0,0 +1,363 @@
+//! Everything between this machine and the left half, checked in order, for
+//! `doctor`.
+//!
+//! Each check needs the ones before it: libusb has to work to see the
+//! keyboard, the keyboard has to be there to open it, and it has to answer
+//! vendor requests before it can report its firmware or scan its I2C bus. A
+//! check whose prerequisite failed is skipped rather than failed as well, so
+//! the first failure in the list is the one to fix, and each failure says
+//! what usually does.
+//!
+//! The reboot request is only tried with `--reboot`, since the only way to
+//! see it answered is to reboot: the keyboard goes into HalfKay, is started
+//! again from there, and is gone from the bus for a second or two.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::bootloader::{self, Bootloader};
+use crate::halfkay::{self, DebugChannel, Mode, Target};
+use crate::{i2c_scan, info, mode_name};
+
+/// How long the keyboard gets to show up as HalfKay after the reboot request.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not checked, because a check it needs failed or wasn't asked for.
+    Skip,
+}
+
+/// One line of the checklist.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// What was found, or why the check was skipped.
+    pub detail: String,
+    /// What to try, for a failure.
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            outcome: Outcome::Pass,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check {
+            name,
+            outcome: Outcome::Fail,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            outcome: Outcome::Skip,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "outcome": match self.outcome {
+                Outcome::Pass => "pass",
+                Outcome::Fail => "fail",
+                Outcome::Skip => "skip",
+            },
+            "detail": self.detail,
+            "fix": self.fix,
+        })
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mark = match self.outcome {
+            Outcome::Pass => "ok",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "skip",
+        };
+        write!(f, "{:<4}  {:<12}  {}", mark, self.name, self.detail)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n      fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check against `target`. `halfkay` starts the keyboard again
+/// after the reboot check, which only runs if `reboot` is set.
+pub fn run(target: &Target, halfkay: &dyn Bootloader, reboot: bool) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    match rusb::devices() {
+        Ok(_) => {
+            let v = rusb::version();
+            checks.push(Check::pass(
+                "libusb",
+                format!("libusb {}.{}.{}", v.major(), v.minor(), v.micro()),
+            ));
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "libusb",
+                format!("can't list USB devices: {e}"),
+                "install libusb 1.0 (libusb-1.0-0 on Debian and Ubuntu, libusb on Homebrew)",
+            ));
+            skip_rest(&mut checks, "libusb");
+            return checks;
+        }
+    }
+
+    let devices = match info::collect() {
+        Ok(devices) if !devices.is_empty() => devices,
+        Ok(_) => {
+            checks.push(Check::fail(
+                "device",
+                "no keyboard or bootloader on the bus",
+                "check the USB cable (charge-only cables carry no data) and try another \
+                 port; if the keyboard types but isn't listed, it's running other firmware",
+            ));
+            skip_rest(&mut checks, "device");
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "device",
+                format!("{e:#}"),
+                "unplug the keyboard, plug it in again and rerun",
+            ));
+            skip_rest(&mut checks, "device");
+            return checks;
+        }
+    };
+    let found: Vec<String> = devices
+        .iter()
+        .map(|d| format!("{} at {}", mode_name(d.mode), d.port_path()))
+        .collect();
+    checks.push(Check::pass("device", found.join(", ")));
+
+    let unreadable: Vec<String> = devices
+        .iter()
+        .filter(|d| d.strings_unreadable)
+        .map(|d| format!("{} at {}", mode_name(d.mode), d.port_path()))
+        .collect();
+    if unreadable.is_empty() {
+        checks.push(Check::pass("permissions", "every device can be opened"));
+    } else {
+        checks.push(Check::fail(
+            "permissions",
+            format!("can't open {}", unreadable.join(", ")),
+            permissions_fix(),
+        ));
+        skip_rest(&mut checks, "permissions");
+        return checks;
+    }
+
+    if !devices.iter().any(|d| d.mode == Mode::Keyboard) {
+        for name in ["firmware", "left half", "reboot"] {
+            checks.push(Check::skip(
+                name,
+                "no keyboard running; `ergodox-cli run` starts the firmware from the bootloader",
+            ));
+        }
+        return checks;
+    }
+
+    match halfkay::read_build_info(target) {
+        Ok(Some(info)) => {
+            let crc = match info.image_crc {
+                Some(crc) => format!("image CRC32 0x{:08X}", crc),
+                None => "image CRC32 not stamped".to_string(),
+            };
+            checks.push(Check::pass(
+                "firmware",
+                format!(
+                    "{} ({}, built {}), {}",
+                    info.version, info.git_hash, info.build_date, crc
+                ),
+            ));
+        }
+        Ok(None) => {
+            checks.push(Check::fail(
+                "firmware",
+                "the keyboard went away",
+                "check the USB cable and rerun",
+            ));
+            skip_rest(&mut checks, "firmware");
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "firmware",
+                format!("{e:#}"),
+                "flash the current firmware; older builds don't answer vendor requests",
+            ));
+            skip_rest(&mut checks, "firmware");
+            return checks;
+        }
+    }
+
+    checks.push(left_half(target));
+    checks.push(if reboot {
+        reboot_and_back(target, halfkay)
+    } else {
+        Check::skip(
+            "reboot",
+            "not tried; `doctor --reboot` restarts the keyboard to try it",
+        )
+    });
+    checks
+}
+
+/// The left half's health since power-on, and whether its expander answers
+/// a bus scan right now.
+fn left_half(target: &Target) -> Check {
+    const NAME: &str = "left half";
+    let counters = match halfkay::read_diagnostics(target) {
+        Ok(diag) => format!(
+            "{} dropout(s), {} I2C error(s) since power-on",
+            diag.left_half_losses, diag.i2c_errors
+        ),
+        Err(e) => return Check::fail(NAME, format!("{e:#}"), "flash the current firmware"),
+    };
+    let scan = match DebugChannel::open(target).and_then(|channel| i2c_scan::run(&channel)) {
+        Ok(scan) => scan,
+        Err(e) => return Check::fail(NAME, format!("{e:#}"), "flash the current firmware"),
+    };
+    if scan.stuck {
+        Check::fail(
+            NAME,
+            format!("the I2C bus is stuck; {counters}"),
+            "SDA or SCL is held low: check the TRRS cable and the left half's wiring for a short",
+        )
+    } else if !scan.has_left_half() {
+        Check::fail(
+            NAME,
+            format!("the MCP23018 doesn't answer; {counters}"),
+            "check that the TRRS cable is plugged in at both ends, then the MCP23018's \
+             power and I2C pins; `ergodox-cli i2c-scan` lists what does answer",
+        )
+    } else {
+        Check::pass(NAME, format!("the MCP23018 answers; {counters}"))
+    }
+}
+
+/// Send the reboot request, wait for HalfKay, and start the firmware again.
+fn reboot_and_back(target: &Target, halfkay: &dyn Bootloader) -> Check {
+    const NAME: &str = "reboot";
+    match halfkay::reboot_to_bootloader(target) {
+        Ok(true) => {}
+        Ok(false) => {
+            return Check::fail(
+                NAME,
+                "the keyboard went away",
+                "check the USB cable and rerun",
+            )
+        }
+        Err(e) => return Check::fail(NAME, format!("{e:#}"), "flash the current firmware"),
+    }
+    let deadline = std::time::Instant::now() + REBOOT_TIMEOUT;
+    while !halfkay.detect(target).unwrap_or(false) {
+        if std::time::Instant::now() > deadline {
+            return Check::fail(
+                NAME,
+                "no HalfKay bootloader showed up after the request",
+                "the request jumps to a Teensy's HalfKay; boards with DFU or Caterina need \
+                 their reset button pressed to flash",
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    match bootloader::run(halfkay, target) {
+        Ok(_) => Check::pass(
+            NAME,
+            "the keyboard rebooted into HalfKay and was started again",
+        ),
+        Err(e) => Check::fail(
+            NAME,
+            format!("rebooted into HalfKay, but starting the firmware failed: {e:#}"),
+            "run `ergodox-cli run`, or unplug the keyboard and plug it in again",
+        ),
+    }
+}
+
+/// Skip every check after `failed`.
+fn skip_rest(checks: &mut Vec<Check>, failed: &str) {
+    for name in ["device", "permissions", "firmware", "left half", "reboot"] {
+        if !checks.iter().any(|c| c.name == name) {
+            checks.push(Check::skip(name, format!("needs {failed}")));
+        }
+    }
+}
+
+/// How to let this user open the keyboard and its bootloaders.
+fn permissions_fix() -> String {
+    if cfg!(target_os = "linux") {
+        "install the udev rules with `sudo ergodox-cli udev --install` and plug the \
+         keyboard in again; `ergodox-cli udev` checks each device"
+            .to_string()
+    } else {
+        "run as an administrator, or with `--features hidapi` to reach the keyboard through \
+         its raw HID interface"
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_print_their_fix_underneath() {
+        let check = Check::fail(
+            "device",
+            "no keyboard or bootloader on the bus",
+            "check the cable",
+        );
+        assert_eq!(
+            check.to_string(),
+            "FAIL  device        no keyboard or bootloader on the bus\n      fix: check the cable"
+        );
+        assert_eq!(
+            Check::pass("libusb", "libusb 1.0.27").to_string(),
+            "ok    libusb        libusb 1.0.27"
+        );
+    }
+
+    #[test]
+    fn checks_after_a_failure_are_skipped_in_order() {
+        let mut checks = vec![
+            Check::pass("libusb", "libusb 1.0.27"),
+            Check::fail("device", "none", "plug it in"),
+        ];
+        skip_rest(&mut checks, "device");
+        let names: Vec<_> = checks.iter().map(|c| (c.name, c.outcome)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("libusb", Outcome::Pass),
+                ("device", Outcome::Fail),
+                ("permissions", Outcome::Skip),
+                ("firmware", Outcome::Skip),
+                ("left half", Outcome::Skip),
+                ("reboot", Outcome::Skip),
+            ]
+        );
+    }
+}
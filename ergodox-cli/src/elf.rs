@@ -0,0 +1,147 @@
+//! Read firmware from the ELF file `cargo build` leaves, so it can be
+//! flashed without converting it to Intel HEX first.
+
+use anyhow::{bail, Context, Result};
+
+use crate::hex::HexSegment;
+
+/// ELF file magic: 0x7F 'E' 'L' 'F'.
+pub const MAGIC: &[u8; 4] = b"\x7FELF";
+
+/// Program header type for loadable segments.
+const PT_LOAD: u32 = 1;
+
+/// Extract the loadable segments of a 32-bit little-endian ELF file, as
+/// produced by `cargo build` for AVR.
+///
+/// Segments are placed at their physical (load) address, which is where
+/// they live in flash. `.data` has a RAM virtual address but is stored in
+/// flash right after `.text` and copied over at startup, so the physical
+/// address is the one that matters for flashing.
+pub fn parse_elf(bytes: &[u8]) -> Result<Vec<HexSegment>> {
+    if bytes.len() < 52 || &bytes[..4] != MAGIC {
+        bail!("not an ELF file");
+    }
+    if bytes[4] != 1 {
+        bail!("only 32-bit ELF files are supported");
+    }
+    if bytes[5] != 1 {
+        bail!("only little-endian ELF files are supported");
+    }
+
+    let phoff = u32_at(bytes, 0x1C)? as usize;
+    let phentsize = u16_at(bytes, 0x2A)? as usize;
+    let phnum = u16_at(bytes, 0x2C)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        let p_type = u32_at(bytes, ph)?;
+        let p_offset = u32_at(bytes, ph + 4)? as usize;
+        let p_paddr = u32_at(bytes, ph + 12)?;
+        let p_filesz = u32_at(bytes, ph + 16)? as usize;
+
+        if p_type != PT_LOAD || p_filesz == 0 {
+            continue;
+        }
+
+        let data = bytes
+            .get(p_offset..p_offset + p_filesz)
+            .with_context(|| format!("program header {}: segment data out of bounds", i))?;
+        segments.push(HexSegment {
+            address: p_paddr,
+            data: data.to_vec(),
+        });
+    }
+
+    if segments.is_empty() {
+        bail!("ELF file has no loadable segments");
+    }
+    Ok(segments)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16> {
+    let b = bytes
+        .get(offset..offset + 2)
+        .context("ELF file truncated")?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    let b = bytes
+        .get(offset..offset + 4)
+        .context("ELF file truncated")?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal ELF32 LE file with the given (type, paddr, data)
+    /// program headers.
+    fn build_elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let phoff = 52usize;
+        let data_start = phoff + 32 * segments.len();
+
+        let mut elf = vec![0u8; data_start];
+        elf[..4].copy_from_slice(MAGIC);
+        elf[4] = 1; // ELFCLASS32
+        elf[5] = 1; // ELFDATA2LSB
+        elf[0x12..0x14].copy_from_slice(&83u16.to_le_bytes()); // EM_AVR
+        elf[0x1C..0x20].copy_from_slice(&(phoff as u32).to_le_bytes());
+        elf[0x2A..0x2C].copy_from_slice(&32u16.to_le_bytes());
+        elf[0x2C..0x2E].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        for (i, (p_type, paddr, data)) in segments.iter().enumerate() {
+            let offset = elf.len() as u32;
+            elf.extend_from_slice(data);
+            let ph = phoff + i * 32;
+            elf[ph..ph + 4].copy_from_slice(&p_type.to_le_bytes());
+            elf[ph + 4..ph + 8].copy_from_slice(&offset.to_le_bytes());
+            elf[ph + 8..ph + 12].copy_from_slice(&(0x80_0000 | paddr).to_le_bytes());
+            elf[ph + 12..ph + 16].copy_from_slice(&paddr.to_le_bytes());
+            elf[ph + 16..ph + 20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            elf[ph + 20..ph + 24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_load_segments_at_physical_address() {
+        let elf = build_elf(&[(PT_LOAD, 0x0000, &[1, 2, 3]), (PT_LOAD, 0x0100, &[4, 5])]);
+        let segments = parse_elf(&elf).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x0000);
+        assert_eq!(segments[0].data, vec![1, 2, 3]);
+        // paddr, not the 0x800000-based RAM vaddr
+        assert_eq!(segments[1].address, 0x0100);
+    }
+
+    #[test]
+    fn test_non_load_and_empty_segments_are_skipped() {
+        let elf = build_elf(&[
+            (PT_LOAD, 0, &[0xAA]),
+            (4, 0x200, &[1]),
+            (PT_LOAD, 0x300, &[]),
+        ]);
+        let segments = parse_elf(&elf).unwrap();
+        assert_eq!(segments.len(), 1);
+    }
+
+    #[test]
+    fn test_rejects_non_elf_and_64_bit() {
+        assert!(parse_elf(b":00000001FF").is_err());
+
+        let mut elf = build_elf(&[(PT_LOAD, 0, &[1])]);
+        elf[4] = 2; // ELFCLASS64
+        assert!(parse_elf(&elf).is_err());
+    }
+
+    #[test]
+    fn test_truncated_segment_is_an_error() {
+        let mut elf = build_elf(&[(PT_LOAD, 0, &[1, 2, 3, 4])]);
+        elf.truncate(elf.len() - 2);
+        assert!(parse_elf(&elf).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Build the firmware crate, for `ergodox-cli build`.
+//!
+//! This runs the same `cargo build --release` in `firmware/` that the
+//! Makefile runs in Docker, so it needs the nightly toolchain from
+//! `rust-toolchain.toml` and avr-gcc on this machine. Cargo reports what it
+//! built as JSON on stdout, which is where the ELF is found; its progress
+//! and any errors go straight to the terminal.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+/// The firmware crate's directory: `firmware/` in `start` or the nearest
+/// directory above it that has one.
+pub fn find_firmware_dir(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join("firmware"))
+        .find(|dir| dir.join("Cargo.toml").is_file())
+}
+
+/// Build the firmware in `dir` with `features`, returning the ELF's path.
+pub fn build(dir: &Path, features: &[String]) -> Result<PathBuf> {
+    let mut cargo = Command::new("cargo");
+    cargo
+        .current_dir(dir)
+        .args([
+            "build",
+            "--release",
+            "--message-format=json-render-diagnostics",
+        ])
+        .stdout(Stdio::piped());
+    if !features.is_empty() {
+        cargo.args(["--features", &features.join(",")]);
+    }
+    let output = cargo
+        .spawn()
+        .and_then(|child| child.wait_with_output())
+        .context("running cargo")?;
+    if !output.status.success() {
+        bail!("building the firmware failed");
+    }
+    let messages = String::from_utf8_lossy(&output.stdout);
+    artifact(&messages).context("cargo didn't say where it put the firmware")
+}
+
+/// The firmware executable among cargo's JSON messages.
+fn artifact(messages: &str) -> Option<PathBuf> {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|m| m["reason"] == "compiler-artifact" && m["target"]["name"] == "firmware")
+        .filter_map(|m| m["executable"].as_str().map(PathBuf::from))
+        .next_back()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_firmware_executable_among_cargo_messages() {
+        let messages = [
+            r#"{"reason":"compiler-artifact","target":{"name":"ergodox-keymap"},"executable":null}"#,
+            r#"{"reason":"compiler-artifact","target":{"name":"firmware"},"executable":"/t/firmware.elf"}"#,
+            r#"{"reason":"build-finished","success":true}"#,
+        ]
+        .join("\n");
+        assert_eq!(artifact(&messages), Some(PathBuf::from("/t/firmware.elf")));
+        assert_eq!(artifact(r#"{"reason":"build-finished"}"#), None);
+    }
+
+    #[test]
+    fn finds_the_firmware_crate_from_the_repository() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+        let from_cli = find_firmware_dir(Path::new(env!("CARGO_MANIFEST_DIR")));
+        assert_eq!(from_cli, Some(root.join("firmware")));
+    }
+}
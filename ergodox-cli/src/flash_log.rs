@@ -0,0 +1,194 @@
+//! The flash log, for `flash --log-file`: a line of JSON appended for every
+//! flash attempt, kept across runs.
+//!
+//! Flashing that fails now and then is usually a hub or a cable, and one
+//! failure says little. The log gathers the evidence: when each attempt
+//! was, which image (the CRC32 of the bytes written), which port the bootloader was on, how many
+//! pages went out, how it ended, and which pages needed retries. Grepping
+//! it for a port or a result shows what the failures have in common.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::bootloader::{FlashStats, Interrupted, RetriedPage};
+use crate::halfkay::{self, Mode, Target};
+use crate::mode_name;
+
+/// Where the bootloader `target` picks is plugged in, e.g.
+/// `bootloader 1-2.3`, if it can be found.
+pub fn device(target: &Target) -> Option<String> {
+    let device = halfkay::list().ok()?.into_iter().find(|d| {
+        d.mode != Mode::Keyboard
+            && match target {
+                Target::Any => true,
+                Target::Port { bus, ports } => d.bus == *bus && d.ports == *ports,
+            }
+    })?;
+    Some(format!("{} {}", mode_name(device.mode), device.port_path()))
+}
+
+/// The log line for an attempt at writing `data` through `protocol`, and
+/// at checking it when `verified` is there. No protocol means no bootloader
+/// could be reached, and `result` says why.
+pub fn entry(
+    protocol: Option<&str>,
+    device: Option<&str>,
+    data: &[u8],
+    result: &Result<FlashStats>,
+    verified: Option<&Result<u32>>,
+) -> Value {
+    let (outcome, pages_written, retried, failed_page, error) = match result {
+        Ok(stats) => (
+            if verified.is_some_and(|v| v.is_err()) {
+                "verify_failed"
+            } else {
+                "ok"
+            },
+            stats.pages_written,
+            &stats.retried_pages[..],
+            None,
+            verified
+                .and_then(|v| v.as_ref().err())
+                .map(|e| format!("{e:#}")),
+        ),
+        Err(e) => match e.downcast_ref::<Interrupted>() {
+            Some(stop) => (
+                "interrupted",
+                stop.pages_written,
+                &stop.retried_pages[..],
+                Some(format!("0x{:04X}", stop.address)),
+                Some(format!("{e:#}")),
+            ),
+            None => ("error", 0, &[][..], None, Some(format!("{e:#}"))),
+        },
+    };
+    json!({
+        "time": utc_now(),
+        "data_crc32": format!("0x{:08X}", crc32fast::hash(data)),
+        "bytes": data.len(),
+        "protocol": protocol,
+        "device": device,
+        "result": outcome,
+        "pages_written": pages_written,
+        "pages_skipped": result.as_ref().map_or(0, |stats| stats.pages_skipped),
+        "retried_pages": retried.iter().map(|&RetriedPage { address, failures }| {
+            json!({ "address": format!("0x{address:04X}"), "failures": failures })
+        }).collect::<Vec<_>>(),
+        "failed_page": failed_page,
+        "error": error,
+    })
+}
+
+/// Add `entry` to the log at `path`, creating it if need be.
+pub fn append(path: &Path, entry: &Value) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening flash log {}", path.display()))?;
+    writeln!(file, "{entry}").with_context(|| format!("writing flash log {}", path.display()))
+}
+
+/// The time now, as `utc` spells it.
+pub(crate) fn utc_now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    utc(secs)
+}
+
+/// `secs` since the epoch as an RFC 3339 UTC time.
+fn utc(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's days-to-civil, counting from 0000-03-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn times_are_utc_rfc3339() {
+        assert_eq!(utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc(1_791_218_096), "2026-10-05T16:34:56Z");
+    }
+
+    #[test]
+    fn interrupted_flashes_log_where_they_stopped() {
+        let retried_pages = vec![RetriedPage {
+            address: 0x80,
+            failures: 2,
+        }];
+        let result = Err(anyhow!("stalled").context(Interrupted {
+            address: 0x100,
+            pages_written: 2,
+            total_pages: 10,
+            retried_pages,
+        }));
+        let logged = entry(
+            Some("halfkay"),
+            Some("bootloader 1-2.3"),
+            &[0; 4],
+            &result,
+            None,
+        );
+        assert_eq!(logged["result"], "interrupted");
+        assert_eq!(logged["failed_page"], "0x0100");
+        assert_eq!(logged["pages_written"], 2);
+        assert_eq!(logged["retried_pages"][0]["address"], "0x0080");
+        assert_eq!(logged["retried_pages"][0]["failures"], 2);
+        assert_eq!(logged["device"], "bootloader 1-2.3");
+
+        let logged = entry(
+            Some("halfkay"),
+            None,
+            &[0; 4],
+            &Ok(FlashStats::default()),
+            None,
+        );
+        assert_eq!(logged["result"], "ok");
+        assert_eq!(logged["error"], Value::Null);
+    }
+
+    #[test]
+    fn a_failed_check_after_writing_is_logged_as_such() {
+        let mismatch = Err(anyhow!("CRC mismatch"));
+        let logged = entry(
+            Some("halfkay"),
+            None,
+            &[0; 4],
+            &Ok(FlashStats::default()),
+            Some(&mismatch),
+        );
+        assert_eq!(logged["result"], "verify_failed");
+        assert_eq!(logged["error"], "CRC mismatch");
+        assert_eq!(logged["data_crc32"], "0x2144DF1C");
+
+        let unreachable = Err(anyhow!("keyboard not found"));
+        let logged = entry(None, None, &[0; 4], &unreachable, None);
+        assert_eq!(logged["result"], "error");
+        assert_eq!(logged["protocol"], Value::Null);
+    }
+}
@@ -0,0 +1,174 @@
+//! Key positions from a geometry table, like the built-in
+//! `geometry/ergodox.toml`.
+//!
+//! The table lists each half's switches by matrix position, in key units
+//! from that half's top-left corner. Clones and other thumb clusters only
+//! need a different table, either built in here or passed to
+//! `layout --geometry`.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use ergodox_keymap::{COLS, ROWS};
+use serde::Deserialize;
+
+use crate::layout::{Key, GAP, HALF_GAP, S};
+
+/// The ErgoDox.
+pub(crate) const BUILTIN: &str = include_str!("../geometry/ergodox.toml");
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Geometry {
+    left: Vec<KeySpec>,
+    right: Vec<KeySpec>,
+}
+
+/// One switch, in key units.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeySpec {
+    matrix: (usize, usize),
+    x: f64,
+    y: f64,
+    #[serde(default = "one")]
+    w: f64,
+    #[serde(default = "one")]
+    h: f64,
+    /// Degrees clockwise.
+    #[serde(default)]
+    r: f64,
+    rx: Option<f64>,
+    ry: Option<f64>,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+impl KeySpec {
+    /// Top-left corner after turning the key's centre about the rotation
+    /// origin. Keys stay upright.
+    fn corner(&self) -> (f64, f64) {
+        let (cx, cy) = (self.x + self.w / 2.0, self.y + self.h / 2.0);
+        let (rx, ry) = (self.rx.unwrap_or(cx), self.ry.unwrap_or(cy));
+        let (sin, cos) = self.r.to_radians().sin_cos();
+        let (dx, dy) = (cx - rx, cy - ry);
+        let (cx, cy) = (rx + dx * cos - dy * sin, ry + dx * sin + dy * cos);
+        (cx - self.w / 2.0, cy - self.h / 2.0)
+    }
+}
+
+/// The built-in ErgoDox geometry.
+pub fn builtin() -> Vec<Key> {
+    parse(BUILTIN).expect("built-in geometry is valid")
+}
+
+/// Parse a geometry table into key positions, with the right half beside
+/// the left one.
+pub fn parse(contents: &str) -> Result<Vec<Key>> {
+    let geometry: Geometry = toml::from_str(contents)?;
+
+    let mut seen = HashSet::new();
+    for spec in geometry.left.iter().chain(&geometry.right) {
+        let (row, col) = spec.matrix;
+        if row >= ROWS || col >= COLS {
+            bail!("matrix position [{row}, {col}] is outside the {ROWS}x{COLS} matrix");
+        }
+        if !seen.insert(spec.matrix) {
+            bail!("matrix position [{row}, {col}] is listed twice");
+        }
+        if spec.w <= 0.0 || spec.h <= 0.0 {
+            bail!("key at [{row}, {col}] has no size");
+        }
+    }
+
+    let mut keys = place(&geometry.left, 0.0);
+    let left_width = keys.iter().map(|k| k.x + k.w).fold(0.0, f64::max);
+    keys.extend(place(&geometry.right, left_width + GAP + HALF_GAP));
+
+    // Shift so the top-left key sits at the origin, as KLE imports do
+    let min_x = keys.iter().map(|k| k.x).fold(f64::INFINITY, f64::min);
+    let min_y = keys.iter().map(|k| k.y).fold(f64::INFINITY, f64::min);
+    for key in &mut keys {
+        key.x -= min_x;
+        key.y -= min_y;
+    }
+    Ok(keys)
+}
+
+/// One half's keys in pixels, `bx` from the left.
+fn place(specs: &[KeySpec], bx: f64) -> Vec<Key> {
+    let keys: Vec<Key> = specs
+        .iter()
+        .map(|spec| {
+            let (x, y) = spec.corner();
+            Key {
+                x: x * S,
+                y: y * S,
+                w: spec.w * S - GAP,
+                h: spec.h * S - GAP,
+                row: spec.matrix.0,
+                col: spec.matrix.1,
+            }
+        })
+        .collect();
+    // Each half starts at its own left edge, however far a turned key moved
+    let min_x = keys.iter().map(|k| k.x).fold(f64::INFINITY, f64::min);
+    keys.into_iter()
+        .map(|k| Key {
+            x: k.x - min_x + bx,
+            ..k
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_geometry_parses() {
+        assert_eq!(builtin().len(), 76);
+    }
+
+    #[test]
+    fn sizes_are_in_key_units() {
+        let keys = parse(
+            "left = [{ matrix = [0, 0], x = 0, y = 0, w = 2 }]\n\
+             right = [{ matrix = [0, 7], x = 0, y = 0, h = 1.5 }]",
+        )
+        .unwrap();
+        assert_eq!((keys[0].w, keys[0].h), (2.0 * S - GAP, S - GAP));
+        assert_eq!(keys[1].h, 1.5 * S - GAP);
+        // The right half starts a gap past the left half's right edge
+        assert_eq!(keys[1].x, keys[0].w + GAP + HALF_GAP);
+    }
+
+    #[test]
+    fn rotation_turns_the_centre_about_the_origin() {
+        // Turned a quarter clockwise about its top-left corner, a 1u key's
+        // centre swings from right-below the origin to left-below it
+        let keys = parse(
+            "left = [\n\
+               { matrix = [0, 0], x = 0, y = 0 },\n\
+               { matrix = [0, 1], x = 0, y = 0, r = 90, rx = 0, ry = 0 },\n\
+             ]\n\
+             right = []",
+        )
+        .unwrap();
+        assert!((keys[0].x - S).abs() < 1e-9);
+        assert!(keys[1].x.abs() < 1e-9);
+        assert!((keys[1].y - keys[0].y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_duplicate_and_out_of_range_positions() {
+        let twice = "left = [{ matrix = [0, 0], x = 0, y = 0 }, \
+                     { matrix = [0, 0], x = 1, y = 0 }]\nright = []";
+        let err = parse(twice).err().unwrap();
+        assert!(err.to_string().contains("twice"));
+        let outside = format!("left = [{{ matrix = [{ROWS}, 0], x = 0, y = 0 }}]\nright = []");
+        assert!(parse(&outside).is_err());
+    }
+}
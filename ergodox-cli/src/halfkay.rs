@@ -1,21 +1,62 @@
 use anyhow::{bail, Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
 use rusb::{DeviceHandle, GlobalContext};
+use std::ops::Range;
 use std::time::Duration;
 
+use crate::bootloader::{Bootloader, Connection};
+use crate::codegen::Layer;
+#[cfg(feature = "hidapi")]
+use crate::rawhid;
+use crate::{caterina, dfu, hidraw, monitor, patch, trace};
+
 /// Teensy 2.0 HalfKay bootloader USB identifiers.
-const HALFKAY_VID: u16 = 0x16C0;
-const HALFKAY_PID: u16 = 0x0478;
+pub(crate) const HALFKAY_VID: u16 = 0x16C0;
+pub(crate) const HALFKAY_PID: u16 = 0x0478;
 
 /// Running keyboard USB identifiers (must match firmware device descriptor).
-const KEYBOARD_VID: u16 = 0x16C0;
-const KEYBOARD_PID: u16 = 0x047E;
+pub(crate) const KEYBOARD_VID: u16 = 0x16C0;
+pub(crate) const KEYBOARD_PID: u16 = 0x047E;
+
+/// The chip HalfKay is running on, which sets the page size and how page
+/// addresses are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mcu {
+    /// Flash page size in bytes; HalfKay writes one page per transfer.
+    pub page_size: usize,
+    /// Total flash size in bytes.
+    pub flash_size: usize,
+    /// HalfKay's own size, at the top of the flash.
+    pub bootloader_size: usize,
+}
 
-/// ATmega32U4 flash page size in bytes.
-const PAGE_SIZE: usize = 128;
+/// ATmega32U4, on the Teensy 2.0.
+pub const ATMEGA32U4: Mcu = Mcu {
+    page_size: 128,
+    flash_size: 32 * 1024,
+    bootloader_size: 512,
+};
 
-/// Total flash size of ATmega32U4 (32KB).
-const FLASH_SIZE: usize = 32768;
+/// AT90USB1286, on the Teensy++ 2.0 used by some original ErgoDox kits.
+pub const AT90USB1286: Mcu = Mcu {
+    page_size: 256,
+    flash_size: 128 * 1024,
+    bootloader_size: 1024,
+};
+
+impl Mcu {
+    /// Two address bytes can't reach past 64 KB, so on larger chips HalfKay
+    /// takes bits 8-23 of the (page-aligned) 3-byte address instead.
+    fn page_address_bytes(self, address: usize) -> [u8; 2] {
+        if self.flash_size > 0x10000 {
+            [(address >> 8) as u8, (address >> 16) as u8]
+        } else {
+            [address as u8, (address >> 8) as u8]
+        }
+    }
+}
+
+/// Start of the HalfKay bootloader; application flash is everything below.
+pub(crate) const APP_END: usize = 0x7E00;
 
 /// USB control transfer timeout.
 const USB_TIMEOUT: Duration = Duration::from_secs(2);
@@ -23,88 +64,349 @@ const USB_TIMEOUT: Duration = Duration::from_secs(2);
 /// Delay after each page write to allow flash programming.
 const PAGE_WRITE_DELAY: Duration = Duration::from_millis(5);
 
-/// Detect whether a Teensy in HalfKay bootloader mode is connected.
-pub fn detect() -> Result<bool> {
+/// Tries per page before giving up on a flash.
+const PAGE_WRITE_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry of a failed page write; doubles each time.
+const RETRY_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Which state a candidate device is in, told apart by product ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Teensy waiting in the HalfKay bootloader.
+    Bootloader,
+    /// Our firmware, running as a keyboard.
+    Keyboard,
+    /// Stock Atmel DFU bootloader (see `dfu.rs`).
+    Dfu,
+    /// Arduino Caterina bootloader (see `caterina.rs`).
+    Caterina,
+}
+
+impl Mode {
+    pub(crate) fn of(vid: u16, pid: u16) -> Option<Mode> {
+        match (vid, pid) {
+            (HALFKAY_VID, HALFKAY_PID) => Some(Mode::Bootloader),
+            (KEYBOARD_VID, KEYBOARD_PID) => Some(Mode::Keyboard),
+            (dfu::VID, dfu::PID) => Some(Mode::Dfu),
+            ids if caterina::IDS.contains(&ids) => Some(Mode::Caterina),
+            _ => None,
+        }
+    }
+}
+
+/// Which device to talk to when several are connected.
+///
+/// A specific device is pinned by its physical port rather than its bus
+/// address: the address changes every time the keyboard reboots into the
+/// bootloader and back, but the port it's plugged into doesn't.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Whichever device is there — an error if there's more than one.
+    #[default]
+    Any,
+    /// The device on this bus and port chain.
+    Port { bus: u8, ports: Vec<u8> },
+}
+
+impl Target {
+    fn matches(&self, device: &rusb::Device<GlobalContext>) -> bool {
+        match self {
+            Target::Any => true,
+            Target::Port { bus, ports } => {
+                device.bus_number() == *bus && device.port_numbers().ok().as_ref() == Some(ports)
+            }
+        }
+    }
+}
+
+/// A keyboard or bootloader found on the bus, for `list`.
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub ports: Vec<u8>,
+    pub mode: Mode,
+    pub serial: Option<String>,
+    /// Whether this user can open it; the serial number needs that.
+    pub openable: bool,
+}
+
+impl DeviceInfo {
+    /// Physical location in Linux sysfs notation, e.g. `1-2.3`.
+    pub fn port_path(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+}
+
+/// List every connected keyboard and bootloader.
+pub fn list() -> Result<Vec<DeviceInfo>> {
     let devices = rusb::devices().context("failed to enumerate USB devices")?;
+    let mut found = Vec::new();
     for device in devices.iter() {
         let desc = device
             .device_descriptor()
             .context("failed to read device descriptor")?;
-        if desc.vendor_id() == HALFKAY_VID && desc.product_id() == HALFKAY_PID {
-            return Ok(true);
-        }
+        let Some(mode) = Mode::of(desc.vendor_id(), desc.product_id()) else {
+            continue;
+        };
+        // Reading the serial needs the device open, which may not be
+        // permitted; list the device anyway.
+        let handle = device.open().ok();
+        let serial = handle
+            .as_ref()
+            .and_then(|h| h.read_serial_number_string_ascii(&desc).ok());
+        found.push(DeviceInfo {
+            bus: device.bus_number(),
+            address: device.address(),
+            ports: device.port_numbers().unwrap_or_default(),
+            mode,
+            serial,
+            openable: handle.is_some(),
+        });
     }
-    Ok(false)
+    Ok(found)
 }
 
-/// Open the Teensy HalfKay bootloader device.
-fn open_device() -> Result<DeviceHandle<GlobalContext>> {
+/// Resolve `--device BUS:ADDR` / `--serial` into a target that stays valid
+/// across reboots.
+pub fn resolve_target(device: Option<&str>, serial: Option<&str>) -> Result<Target> {
+    if device.is_none() && serial.is_none() {
+        return Ok(Target::Any);
+    }
+    let wanted = device.map(parse_bus_address).transpose()?;
+    let at_device =
+        |d: &DeviceInfo| wanted.is_none_or(|(bus, addr)| d.bus == bus && d.address == addr);
+
+    let devices = list()?;
+    let Some(info) = devices
+        .iter()
+        .find(|d| at_device(d) && serial.is_none_or(|s| d.serial.as_deref() == Some(s)))
+    else {
+        // Our firmware reports no serial number, so only a bootloader can
+        // be picked by one
+        if serial.is_some()
+            && devices
+                .iter()
+                .any(|d| d.mode == Mode::Keyboard && at_device(d))
+        {
+            bail!(
+                "--serial only finds bootloaders, and the keyboard firmware has no serial number; \
+                 use --device BUS:ADDR (see `ergodox-cli list`)"
+            );
+        }
+        bail!("no matching keyboard or bootloader found (see `ergodox-cli list`)");
+    };
+
+    Ok(Target::Port {
+        bus: info.bus,
+        ports: info.ports.clone(),
+    })
+}
+
+/// Parse `BUS:ADDR` as printed by `lsusb` and `list`, e.g. `001:012`.
+fn parse_bus_address(s: &str) -> Result<(u8, u8)> {
+    let (bus, addr) = s
+        .split_once(':')
+        .with_context(|| format!("invalid device {s:?}, expected BUS:ADDR"))?;
+    let parse = |v: &str| {
+        v.parse::<u8>()
+            .with_context(|| format!("invalid device {s:?}, expected BUS:ADDR"))
+    };
+    Ok((parse(bus)?, parse(addr)?))
+}
+
+/// Find the one device in `mode` matching `target`.
+pub(crate) fn find(mode: Mode, target: &Target) -> Result<Option<rusb::Device<GlobalContext>>> {
     let devices = rusb::devices().context("failed to enumerate USB devices")?;
+    let mut matching = Vec::new();
     for device in devices.iter() {
         let desc = device
             .device_descriptor()
             .context("failed to read device descriptor")?;
-        if desc.vendor_id() == HALFKAY_VID && desc.product_id() == HALFKAY_PID {
-            let handle = device.open().context(
-                "failed to open Teensy bootloader (may need root/sudo or udev rules)",
-            )?;
-            return Ok(handle);
+        if Mode::of(desc.vendor_id(), desc.product_id()) == Some(mode) && target.matches(&device) {
+            matching.push(device);
         }
     }
-    bail!("Teensy bootloader not found. Press the reset button on the Teensy and try again.");
+    for device in &matching {
+        trace::log(1, || {
+            format!(
+                "found {:?} at {:03}:{:03}",
+                mode,
+                device.bus_number(),
+                device.address()
+            )
+        });
+    }
+    if matching.is_empty() {
+        trace::log(2, || format!("no {:?} on the bus", mode));
+    }
+    if matching.len() > 1 {
+        bail!(
+            "{} matching devices connected; pick one with --device BUS:ADDR or --serial (see `ergodox-cli list`)",
+            matching.len()
+        );
+    }
+    Ok(matching.pop())
 }
 
-/// Flash firmware data to the Teensy via HalfKay protocol.
-///
-/// `base_address` is the starting address of the firmware image.
-/// `data` is the firmware binary, which will be split into 128-byte pages.
-pub fn flash(base_address: u32, data: &[u8]) -> Result<()> {
-    let handle = open_device()?;
+/// Detect whether a Teensy in HalfKay bootloader mode is connected.
+pub fn detect(target: &Target) -> Result<bool> {
+    Ok(find(Mode::Bootloader, target)?.is_some())
+}
 
-    let end_address = base_address as usize + data.len();
-    if end_address > FLASH_SIZE {
-        bail!(
-            "firmware too large: {} bytes at offset 0x{:04X} exceeds {} byte flash",
-            data.len(),
-            base_address,
-            FLASH_SIZE
-        );
+/// Open the Teensy HalfKay bootloader device.
+fn open_device(target: &Target) -> Result<DeviceHandle<GlobalContext>> {
+    match find(Mode::Bootloader, target)? {
+        Some(device) => device.open().context(
+            "failed to open Teensy bootloader (may need udev rules; see `ergodox-cli udev`)",
+        ),
+        None => bail!(
+            "Teensy bootloader not found. Press the reset button on the Teensy and try again."
+        ),
     }
+}
 
-    let total_pages = (data.len() + PAGE_SIZE - 1) / PAGE_SIZE;
-    let pb = ProgressBar::new(total_pages as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} pages")
-            .unwrap()
-            .progress_chars("=> "),
-    );
-    pb.set_message("Flashing");
+/// How HalfKay's page reports get to the bootloader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Control transfers through libusb.
+    Libusb,
+    /// Reports written to `/dev/hidrawN` (Linux; see `hidraw.rs`).
+    Hidraw,
+}
 
-    for (page_idx, chunk) in data.chunks(PAGE_SIZE).enumerate() {
-        let address = base_address as usize + page_idx * PAGE_SIZE;
+/// An open bootloader, on either transport.
+enum Port {
+    Usb(DeviceHandle<GlobalContext>),
+    Hidraw(std::fs::File),
+}
 
-        // Skip pages that are all 0xFF (erased flash)
-        if chunk.iter().all(|&b| b == 0xFF) {
-            pb.inc(1);
-            continue;
+impl Port {
+    fn open(target: &Target, transport: Transport) -> Result<Port> {
+        Ok(match transport {
+            Transport::Libusb => Port::Usb(open_device(target)?),
+            Transport::Hidraw => Port::Hidraw(hidraw::open(target, HALFKAY_VID, HALFKAY_PID)?),
+        })
+    }
+
+    /// Write a single page (address and data) as a HID output report.
+    fn write_page(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            Port::Usb(handle) => Ok(write_page(handle, buf)?),
+            Port::Hidraw(file) => Ok(hidraw::write_report(file, buf)?),
         }
+    }
+}
+
+/// PJRC's HalfKay bootloader, on a Teensy with the given chip.
+pub struct HalfKay {
+    pub mcu: Mcu,
+    pub transport: Transport,
+}
 
-        let buf = build_page_buffer(address, chunk);
-        write_page(&handle, &buf)
-            .with_context(|| format!("failed to write page at address 0x{:04X}", address))?;
+impl Bootloader for HalfKay {
+    fn page_size(&self) -> usize {
+        self.mcu.page_size
+    }
+
+    fn flash_size(&self) -> usize {
+        self.mcu.flash_size
+    }
+
+    fn bootloader_region(&self) -> Range<usize> {
+        self.mcu.flash_size - self.mcu.bootloader_size..self.mcu.flash_size
+    }
+
+    /// HalfKay has no erase command; it erases everything on the first
+    /// page written.
+    fn erases_on_first_write(&self) -> bool {
+        true
+    }
+
+    fn detect(&self, target: &Target) -> Result<bool> {
+        detect(target)
+    }
+
+    fn open(&self, target: &Target) -> Result<Box<dyn Connection>> {
+        Ok(Box::new(HalfKayConnection {
+            target: target.clone(),
+            transport: self.transport,
+            port: Port::open(target, self.transport)?,
+            mcu: self.mcu,
+            retries: 0,
+        }))
+    }
+}
 
+struct HalfKayConnection {
+    /// Kept to reopen the bootloader after a stall or disconnect.
+    target: Target,
+    transport: Transport,
+    port: Port,
+    mcu: Mcu,
+    retries: usize,
+}
+
+impl Connection for HalfKayConnection {
+    fn write_page(&mut self, address: usize, page: &[u8]) -> Result<()> {
+        let buf = build_page_buffer(self.mcu, address, page);
+        self.write_page_with_retry(&buf)
+            .with_context(|| format!("gave up after {} attempts", PAGE_WRITE_ATTEMPTS))?;
         std::thread::sleep(PAGE_WRITE_DELAY);
-        pb.inc(1);
+        Ok(())
+    }
+
+    fn retries(&self) -> usize {
+        self.retries
     }
 
-    pb.finish_with_message("Flashed");
+    /// Send reboot command to Teensy (write to address 0xFFFF).
+    fn reboot(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; 2 + self.mcu.page_size];
+        buf[0] = HALFKAY_REBOOT_ADDRESS as u8;
+        buf[1] = (HALFKAY_REBOOT_ADDRESS >> 8) as u8;
+        // Ignore errors on reboot — the device disconnects immediately
+        let _ = self.port.write_page(&buf);
+        Ok(())
+    }
+}
 
-    // Reboot the Teensy
-    reboot(&handle)?;
-    println!("Teensy rebooted. Firmware should be running.");
+impl HalfKayConnection {
+    /// Write a page, retrying transient USB errors with exponential backoff.
+    ///
+    /// A stalled pipe or a device that dropped off the bus usually means the
+    /// handle is stale, so those reopen the bootloader before the next try.
+    /// hidraw doesn't say which it was, so any error there reopens.
+    fn write_page_with_retry(&mut self, buf: &[u8]) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.port.write_page(buf) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt == PAGE_WRITE_ATTEMPTS => {
+                    return Err(e.context("page write failed"))
+                }
+                Err(e) => e,
+            };
 
-    Ok(())
+            std::thread::sleep(retry_delay(attempt));
+            let stale = match err.downcast_ref::<rusb::Error>() {
+                Some(e) => matches!(
+                    e,
+                    rusb::Error::Pipe | rusb::Error::NoDevice | rusb::Error::Io
+                ),
+                None => true,
+            };
+            if stale {
+                // Keep the old handle if the bootloader isn't back yet
+                if let Ok(reopened) = Port::open(&self.target, self.transport) {
+                    self.port = reopened;
+                }
+            }
+            self.retries += 1;
+            attempt += 1;
+        }
+    }
 }
 
 // HalfKay protocol constants — this is PJRC's standard bootloader protocol.
@@ -122,41 +424,29 @@ const HALFKAY_REPORT_VALUE: u16 = 0x0200;
 const HALFKAY_REBOOT_ADDRESS: u16 = 0xFFFF;
 
 /// Write a single page via HalfKay USB control transfer.
-fn write_page(handle: &DeviceHandle<GlobalContext>, buf: &[u8]) -> Result<()> {
-    handle
-        .write_control(
-            HALFKAY_REQUEST_TYPE,
-            HALFKAY_SET_REPORT,
-            HALFKAY_REPORT_VALUE,
-            0,
-            buf,
-            USB_TIMEOUT,
-        )
-        .context("USB control transfer failed")?;
-    Ok(())
-}
-
-/// Send reboot command to Teensy (write to address 0xFFFF).
-fn reboot(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
-    let mut buf = vec![0u8; 2 + PAGE_SIZE];
-    buf[0] = HALFKAY_REBOOT_ADDRESS as u8;
-    buf[1] = (HALFKAY_REBOOT_ADDRESS >> 8) as u8;
-    // Ignore errors on reboot — the device disconnects immediately
-    let _ = handle.write_control(
+fn write_page(handle: &DeviceHandle<GlobalContext>, buf: &[u8]) -> rusb::Result<()> {
+    trace::write_control(
+        handle,
         HALFKAY_REQUEST_TYPE,
         HALFKAY_SET_REPORT,
         HALFKAY_REPORT_VALUE,
         0,
-        &buf,
+        buf,
         USB_TIMEOUT,
-    );
+    )?;
     Ok(())
 }
 
+/// How long to wait after failed attempt number `attempt` (from 1).
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BACKOFF * 2u32.pow(attempt - 1)
+}
+
 /// Vendor USB control request type: host-to-device, vendor, device recipient.
 /// This is a standard USB bmRequestType value — it tells the device "this is a
 /// custom vendor command", as opposed to a standard or class request.
-const REBOOT_REQUEST_TYPE: u8 = 0x40;
+/// Shared by every request that sends something to the firmware.
+const VENDOR_OUT_REQUEST_TYPE: u8 = 0x40;
 
 /// Our custom bRequest value meaning "jump to bootloader". This is arbitrary —
 /// we own the entire 0x00..=0xFF bRequest space under vendor request type 0x40.
@@ -166,31 +456,546 @@ const REBOOT_REQUEST: u8 = 0xFF;
 
 /// Try to find the running keyboard and send a vendor request to jump to bootloader.
 /// Returns true if the keyboard was found and rebooted.
-pub fn reboot_to_bootloader() -> Result<bool> {
-    let devices = rusb::devices().context("failed to enumerate USB devices")?;
-    for device in devices.iter() {
-        let desc = device
-            .device_descriptor()
-            .context("failed to read device descriptor")?;
-        if desc.vendor_id() == KEYBOARD_VID && desc.product_id() == KEYBOARD_PID {
-            let handle = device
-                .open()
-                .context("failed to open keyboard device")?;
-            let _ = handle.write_control(REBOOT_REQUEST_TYPE, REBOOT_REQUEST, 0, 0, &[], USB_TIMEOUT);
-            return Ok(true);
+pub fn reboot_to_bootloader(target: &Target) -> Result<bool> {
+    let Some(link) = KeyboardLink::connect(target)? else {
+        return Ok(false);
+    };
+    let _ = link.write(REBOOT_REQUEST, 0);
+    Ok(true)
+}
+
+/// Vendor USB control request type: device-to-host, vendor, device recipient.
+/// Shared by every request that reads something back from the firmware.
+const VENDOR_IN_REQUEST_TYPE: u8 = 0xC0;
+
+/// Our custom bRequest value meaning "report the application flash CRC32".
+/// The firmware computes it once at boot and answers with 4 little-endian bytes.
+const FLASH_CRC_REQUEST: u8 = 0x01;
+
+/// How long to wait for the keyboard to re-enumerate after flashing.
+const REENUMERATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// CRC32 the firmware should report after booting `data` at `base_address`.
+///
+/// The firmware checksums the whole application region, so the image is
+/// placed at its address in a region of erased (0xFF) flash first.
+pub fn expected_flash_crc(base_address: u32, data: &[u8]) -> u32 {
+    let mut flash = vec![0xFFu8; APP_END];
+    let start = base_address as usize;
+    let end = (start + data.len()).min(APP_END);
+    if start < end {
+        flash[start..end].copy_from_slice(&data[..end - start]);
+    }
+    crc32fast::hash(&flash)
+}
+
+/// Wait for the freshly flashed keyboard to enumerate, ask it for the CRC32
+/// of its application flash and compare against the image that was sent.
+/// Returns the matching CRC.
+pub fn verify(target: &Target, base_address: u32, data: &[u8]) -> Result<u32> {
+    let expected = expected_flash_crc(base_address, data);
+
+    let deadline = std::time::Instant::now() + REENUMERATE_TIMEOUT;
+    let link = loop {
+        if let Some(link) = open_keyboard(target)? {
+            break link;
+        }
+        if std::time::Instant::now() > deadline {
+            bail!("keyboard did not re-enumerate after flashing; cannot verify");
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    let mut buf = [0u8; 4];
+    let n = link
+        .read(FLASH_CRC_REQUEST, 0, &mut buf)
+        .context("flash CRC request failed (firmware too old to support --verify?)")?;
+    if n != buf.len() {
+        bail!("flash CRC reply was {} bytes, expected {}", n, buf.len());
+    }
+
+    let actual = u32::from_le_bytes(buf);
+    if actual != expected {
+        bail!(
+            "verification failed: keyboard reports flash CRC32 0x{:08X}, image is 0x{:08X}",
+            actual,
+            expected
+        );
+    }
+    Ok(actual)
+}
+
+/// Our custom bRequest value meaning "drain queued debug events". The firmware
+/// answers with up to 63 bytes of three-byte events, or nothing.
+const DEBUG_EVENTS_REQUEST: u8 = 0x02;
+
+/// How many bytes to ask for per debug poll. The firmware never sends more
+/// than fits in one packet.
+const DEBUG_EVENTS_MAX: usize = 64;
+
+/// The running keyboard's debug event queue, for `monitor`.
+pub struct DebugChannel {
+    link: KeyboardLink,
+}
+
+impl DebugChannel {
+    /// Open the running keyboard's debug channel.
+    pub fn open(target: &Target) -> Result<DebugChannel> {
+        let Some(link) = KeyboardLink::connect(target)? else {
+            bail!("keyboard not found; is it plugged in and running our firmware?");
+        };
+        Ok(DebugChannel { link })
+    }
+
+    /// Take whatever events the firmware has queued since the last poll.
+    /// Returns the raw bytes; see `monitor::decode`.
+    pub fn poll(&self) -> Result<Vec<u8>> {
+        let mut buf = [0u8; DEBUG_EVENTS_MAX];
+        let n = self
+            .link
+            .read(DEBUG_EVENTS_REQUEST, 0, &mut buf)
+            .context("debug event request failed (keyboard unplugged, or firmware too old?)")?;
+        Ok(buf[..n].to_vec())
+    }
+
+    /// Have the firmware scan its I2C bus. The results come back as debug
+    /// events.
+    pub fn request_i2c_scan(&self) -> Result<()> {
+        self.link
+            .write(I2C_SCAN_REQUEST, 0)
+            .context("I2C scan request failed (firmware too old?)")?;
+        Ok(())
+    }
+}
+
+/// The running keyboard's raw HID interface, whose interrupt endpoint
+/// carries layer notices.
+const RAW_INTERFACE: u8 = 1;
+const NOTICE_ENDPOINT: u8 = 0x82;
+const NOTICE_SIZE: usize = 8;
+
+/// Our custom bRequest value (under `VENDOR_OUT_REQUEST_TYPE`) meaning "send the
+/// layer notice again", so a new listener needn't wait for a change.
+const RESEND_NOTICE_REQUEST: u8 = 0x0D;
+
+/// How long to wait for a notice left over from before `layer` started.
+/// One waiting in the endpoint goes out at the host's next poll of it.
+const STALE_NOTICE_WAIT: Duration = Duration::from_millis(20);
+
+/// The running keyboard's layer notices, for `layer`.
+pub struct NoticeChannel {
+    link: KeyboardLink,
+}
+
+impl NoticeChannel {
+    /// Open the running keyboard's raw HID interface to read notices.
+    pub fn open(target: &Target) -> Result<NoticeChannel> {
+        let Some(mut link) = KeyboardLink::connect(target)? else {
+            bail!("keyboard not found; is it plugged in and running our firmware?");
+        };
+        link.claim_raw_interface()?;
+        let channel = NoticeChannel { link };
+        // A notice already in the endpoint's bank would arrive ahead of the
+        // resent one and may be stale, so it's read and dropped first
+        while channel.next(STALE_NOTICE_WAIT)?.is_some() {}
+        channel
+            .link
+            .write(RESEND_NOTICE_REQUEST, 0)
+            .context("layer notice request failed (firmware too old?)")?;
+        Ok(channel)
+    }
+
+    /// Wait up to `timeout` for the next notice.
+    pub fn next(&self, timeout: Duration) -> Result<Option<monitor::LayerNotice>> {
+        let mut buf = [0u8; NOTICE_SIZE];
+        let n = match &self.link {
+            KeyboardLink::Usb(handle) => {
+                match handle.read_interrupt(NOTICE_ENDPOINT, &mut buf, timeout) {
+                    Ok(n) => n,
+                    Err(rusb::Error::Timeout) => return Ok(None),
+                    Err(e) => {
+                        return Err(e)
+                            .context("reading a layer notice failed (keyboard unplugged?)")
+                    }
+                }
+            }
+            #[cfg(feature = "hidapi")]
+            KeyboardLink::Hid(hid) => hid.read(&mut buf, timeout)?,
+        };
+        Ok(monitor::LayerNotice::decode(&buf[..n]))
+    }
+}
+
+/// Our custom bRequest value (under `VENDOR_OUT_REQUEST_TYPE`) meaning "scan the
+/// I2C bus". The firmware answers with events on the debug channel.
+const I2C_SCAN_REQUEST: u8 = 0x09;
+
+/// Our custom bRequest value meaning "report per-key press counts". The
+/// firmware answers with ROWS * COLS little-endian u16s, counted since power-on.
+const PRESS_COUNTS_REQUEST: u8 = 0x03;
+
+/// Read the running keyboard's per-key press counters. Returns the raw
+/// bytes; see `heatmap::from_keyboard`.
+pub fn read_press_counts(target: &Target) -> Result<Vec<u8>> {
+    read_key_counts(target, PRESS_COUNTS_REQUEST, "press")
+}
+
+/// Our custom bRequest value meaning "report the per-key bounce counts".
+/// The firmware answers like `PRESS_COUNTS_REQUEST`, with the counts of
+/// bounces its debouncer rejected.
+const BOUNCE_COUNTS_REQUEST: u8 = 0x08;
+
+/// Read the running keyboard's per-key bounce counters, for `chatter`.
+pub fn read_bounce_counts(target: &Target) -> Result<Vec<u8>> {
+    read_key_counts(target, BOUNCE_COUNTS_REQUEST, "bounce")
+}
+
+fn read_key_counts(target: &Target, request: u8, what: &str) -> Result<Vec<u8>> {
+    let Some(link) = open_keyboard(target)? else {
+        bail!("keyboard not found; is it plugged in and running our firmware?");
+    };
+    let mut buf = [0u8; ergodox_keymap::ROWS * ergodox_keymap::COLS * 2];
+    let n = link
+        .read(request, 0, &mut buf)
+        .with_context(|| format!("{what} counter request failed (firmware too old?)"))?;
+    Ok(buf[..n].to_vec())
+}
+
+/// Our custom bRequest value (under `VENDOR_OUT_REQUEST_TYPE`) meaning "zero the
+/// press counters".
+const CLEAR_PRESS_COUNTS_REQUEST: u8 = 0x07;
+
+/// Zero the running keyboard's press counters.
+pub fn clear_press_counts(target: &Target) -> Result<()> {
+    let Some(link) = open_keyboard(target)? else {
+        bail!("keyboard not found; is it plugged in and running our firmware?");
+    };
+    link.write(CLEAR_PRESS_COUNTS_REQUEST, 0)
+        .context("clearing the press counters failed (firmware too old?)")?;
+    Ok(())
+}
+
+/// Our custom bRequest value meaning "report the firmware build". The
+/// firmware answers with `<version> <git hash> <build date>` in ASCII, then
+/// optionally the image CRC and `reset=<cause>`.
+const BUILD_INFO_REQUEST: u8 = 0x04;
+
+/// What the running firmware says it is.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub build_date: String,
+    /// The image CRC32 stamped in when it was flashed (see `stamp.rs`).
+    pub image_crc: Option<u32>,
+    /// Why the keyboard last reset: `power-on`, `external`, `brown-out`,
+    /// `watchdog`, `jtag` or `unknown`. Older firmware doesn't say.
+    pub reset_cause: Option<String>,
+}
+
+impl BuildInfo {
+    fn parse(bytes: &[u8]) -> Result<BuildInfo> {
+        let text = std::str::from_utf8(bytes).context("build info is not UTF-8")?;
+        let fields: Vec<_> = text.split_whitespace().collect();
+        let [version, git_hash, build_date, ref rest @ ..] = fields[..] else {
+            bail!("unexpected build info {text:?}");
+        };
+        // Firmware that predates image CRCs, or an image flashed without
+        // one, leaves off the CRC; older firmware leaves off the reset cause
+        let (crc, reset_cause) = match *rest {
+            [] => (None, None),
+            [field] => match field.strip_prefix("reset=") {
+                Some(cause) => (None, Some(cause)),
+                None => (Some(field), None),
+            },
+            [crc, field] => match field.strip_prefix("reset=") {
+                Some(cause) => (Some(crc), Some(cause)),
+                None => bail!("unexpected build info {text:?}"),
+            },
+            _ => bail!("unexpected build info {text:?}"),
+        };
+        let image_crc = match crc {
+            Some(crc) => Some(
+                u32::from_str_radix(crc, 16)
+                    .with_context(|| format!("unexpected image CRC {crc:?}"))?,
+            ),
+            None => None,
+        };
+        Ok(BuildInfo {
+            version: version.to_string(),
+            git_hash: git_hash.to_string(),
+            build_date: build_date.to_string(),
+            image_crc,
+            reset_cause: reset_cause.map(str::to_string),
+        })
+    }
+}
+
+/// Ask the running keyboard which firmware build it's running. Returns None
+/// if no keyboard is connected.
+pub fn read_build_info(target: &Target) -> Result<Option<BuildInfo>> {
+    let Some(link) = open_keyboard(target)? else {
+        return Ok(None);
+    };
+    let mut buf = [0u8; 128];
+    let n = link
+        .read(BUILD_INFO_REQUEST, 0, &mut buf)
+        .context("build info request failed (firmware too old to report its version?)")?;
+    BuildInfo::parse(&buf[..n]).map(Some)
+}
+
+/// Our custom bRequest value meaning "report the left half's health". The
+/// firmware answers with 5 bytes; see `Diagnostics::parse`.
+const DIAGNOSTICS_REQUEST: u8 = 0x0A;
+
+/// The left half's health since power-on, for `health`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub left_half_online: bool,
+    /// Times the firmware gave up on the left half after too many I2C
+    /// errors in a row.
+    pub left_half_losses: u16,
+    /// Failed left-half scans, in all.
+    pub i2c_errors: u16,
+}
+
+impl Diagnostics {
+    /// `[online, losses (u16 LE), I2C errors (u16 LE)]`. Anything after
+    /// that is from newer firmware and ignored.
+    fn parse(bytes: &[u8]) -> Result<Diagnostics> {
+        let [online, l0, l1, e0, e1, ..] = *bytes else {
+            bail!(
+                "diagnostics reply was {} bytes, expected at least 5",
+                bytes.len()
+            );
+        };
+        Ok(Diagnostics {
+            left_half_online: online != 0,
+            left_half_losses: u16::from_le_bytes([l0, l1]),
+            i2c_errors: u16::from_le_bytes([e0, e1]),
+        })
+    }
+}
+
+/// Ask the running keyboard how its left half is doing.
+pub fn read_diagnostics(target: &Target) -> Result<Diagnostics> {
+    let Some(link) = open_keyboard(target)? else {
+        bail!("keyboard not found; is it plugged in and running our firmware?");
+    };
+    let mut buf = [0u8; 64];
+    let n = link
+        .read(DIAGNOSTICS_REQUEST, 0, &mut buf)
+        .context("diagnostics request failed (firmware too old?)")?;
+    Diagnostics::parse(&buf[..n])
+}
+
+/// Our custom bRequest value meaning "report the keystroke odometer". The
+/// firmware answers with the total and the count since power-on, as
+/// little-endian `u32`s.
+const ODOMETER_REQUEST: u8 = 0x0B;
+
+/// Keystrokes counted by the running keyboard, for `odometer`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Odometer {
+    /// Since the odometer was first flashed, give or take the last minute
+    /// before each unplug.
+    pub total: u32,
+    /// Since power-on.
+    pub session: u32,
+}
+
+impl Odometer {
+    fn parse(bytes: &[u8]) -> Result<Odometer> {
+        let [t0, t1, t2, t3, s0, s1, s2, s3, ..] = *bytes else {
+            bail!(
+                "odometer reply was {} bytes, expected at least 8",
+                bytes.len()
+            );
+        };
+        Ok(Odometer {
+            total: u32::from_le_bytes([t0, t1, t2, t3]),
+            session: u32::from_le_bytes([s0, s1, s2, s3]),
+        })
+    }
+}
+
+/// Read the running keyboard's keystroke odometer.
+pub fn read_odometer(target: &Target) -> Result<Odometer> {
+    let Some(link) = open_keyboard(target)? else {
+        bail!("keyboard not found; is it plugged in and running our firmware?");
+    };
+    let mut buf = [0u8; 64];
+    let n = link
+        .read(ODOMETER_REQUEST, 0, &mut buf)
+        .context("odometer request failed (firmware too old?)")?;
+    Odometer::parse(&buf[..n])
+}
+
+/// Our custom bRequest value meaning "send the keymap table", from the
+/// offset in wValue. The firmware answers with as much of the table between
+/// its markers as fits, and nothing past the end; see `patch::unpack`.
+const KEYMAP_REQUEST: u8 = 0x0C;
+
+/// Read the keymap the running keyboard uses, patches and all.
+pub fn read_keymap(target: &Target) -> Result<Vec<Layer>> {
+    let Some(link) = open_keyboard(target)? else {
+        bail!("keyboard not found; is it plugged in and running our firmware?");
+    };
+    let mut table = Vec::new();
+    loop {
+        let mut buf = [0u8; 64];
+        let n = link
+            .read(KEYMAP_REQUEST, table.len() as u16, &mut buf)
+            .context("keymap request failed (firmware too old?)")?;
+        if n == 0 {
+            break;
+        }
+        table.extend_from_slice(&buf[..n]);
+    }
+    patch::unpack(&table).context("unexpected keymap from the keyboard (firmware too old?)")
+}
+
+/// Our custom bRequest value meaning "report scan loop timing". The firmware
+/// answers with 16 bytes of counters and starts a new window; see
+/// `bench::LoopStats`.
+const BENCH_STATS_REQUEST: u8 = 0x05;
+
+/// Our custom bRequest value meaning "inject a key press", with the matrix
+/// row in the low byte of wValue and the column in the high byte.
+const INJECT_KEY_REQUEST: u8 = 0x06;
+
+/// The running keyboard's timing counters, for `bench`.
+pub struct BenchChannel {
+    link: KeyboardLink,
+}
+
+impl BenchChannel {
+    /// Open the running keyboard for benchmarking.
+    pub fn open(target: &Target) -> Result<BenchChannel> {
+        let Some(link) = KeyboardLink::connect(target)? else {
+            bail!("keyboard not found; is it plugged in and running our firmware?");
+        };
+        Ok(BenchChannel { link })
+    }
+
+    /// Read the loop counters since the last read. Returns the raw bytes;
+    /// see `bench::LoopStats::parse`.
+    pub fn stats(&self) -> Result<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        let n = self
+            .link
+            .read(BENCH_STATS_REQUEST, 0, &mut buf)
+            .context("bench request failed (firmware too old?)")?;
+        Ok(buf[..n].to_vec())
+    }
+
+    /// Have the firmware press the key at `row, col` until a report carrying
+    /// it goes out, then let go.
+    pub fn inject(&self, row: u8, col: u8) -> Result<()> {
+        self.link
+            .write(INJECT_KEY_REQUEST, u16::from_le_bytes([row, col]))
+            .context("key injection request failed (firmware too old?)")?;
+        Ok(())
+    }
+}
+
+/// Open the running keyboard, if it's on the bus and ready.
+fn open_keyboard(target: &Target) -> Result<Option<KeyboardLink>> {
+    if find(Mode::Keyboard, target)?.is_none() {
+        return Ok(None);
+    }
+    // The device can show up before it's ready to be opened
+    Ok(KeyboardLink::connect(target).ok().flatten())
+}
+
+/// How the vendor requests reach the running keyboard: libusb control
+/// transfers, or with the `hidapi` feature, feature reports on its raw HID
+/// interface where there is one (see `rawhid.rs`).
+enum KeyboardLink {
+    Usb(DeviceHandle<GlobalContext>),
+    #[cfg(feature = "hidapi")]
+    Hid(rawhid::RawHid),
+}
+
+impl KeyboardLink {
+    /// Connect to the running keyboard. None if it isn't on the bus.
+    fn connect(target: &Target) -> Result<Option<KeyboardLink>> {
+        #[cfg(feature = "hidapi")]
+        if let Some(hid) = rawhid::RawHid::open(target)? {
+            return Ok(Some(KeyboardLink::Hid(hid)));
+        }
+        let Some(device) = find(Mode::Keyboard, target)? else {
+            return Ok(None);
+        };
+        let handle = device
+            .open()
+            .context("failed to open keyboard (may need udev rules; see `ergodox-cli udev`)")?;
+        Ok(Some(KeyboardLink::Usb(handle)))
+    }
+
+    /// Vendor IN request `request`; the reply goes into `buf`.
+    fn read(&self, request: u8, value: u16, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            KeyboardLink::Usb(handle) => Ok(trace::read_control(
+                handle,
+                VENDOR_IN_REQUEST_TYPE,
+                request,
+                value,
+                0,
+                buf,
+                USB_TIMEOUT,
+            )?),
+            #[cfg(feature = "hidapi")]
+            KeyboardLink::Hid(hid) => hid.request(request, value, buf),
+        }
+    }
+
+    /// Take the raw HID interface, to read layer notices from it. hidapi
+    /// has it already.
+    fn claim_raw_interface(&mut self) -> Result<()> {
+        match self {
+            KeyboardLink::Usb(handle) => {
+                // Only the raw interface leaves the kernel's HID driver, so
+                // the keyboard keeps typing
+                let _ = handle.set_auto_detach_kernel_driver(true);
+                handle.claim_interface(RAW_INTERFACE).context(
+                    "failed to claim the keyboard's raw HID interface (firmware too old, or \
+                     build with --features hidapi)",
+                )
+            }
+            #[cfg(feature = "hidapi")]
+            KeyboardLink::Hid(_) => Ok(()),
+        }
+    }
+
+    /// Vendor OUT request `request`, with no data.
+    fn write(&self, request: u8, value: u16) -> Result<()> {
+        match self {
+            KeyboardLink::Usb(handle) => {
+                trace::write_control(
+                    handle,
+                    VENDOR_OUT_REQUEST_TYPE,
+                    request,
+                    value,
+                    0,
+                    &[],
+                    USB_TIMEOUT,
+                )?;
+                Ok(())
+            }
+            #[cfg(feature = "hidapi")]
+            KeyboardLink::Hid(hid) => hid.send(request, value),
         }
     }
-    Ok(false)
 }
 
 /// Build the page buffer that HalfKay expects: 2-byte little-endian address
-/// followed by PAGE_SIZE bytes of data. Unfilled bytes default to 0xFF
-/// (matching erased flash), so short final pages are safe.
-fn build_page_buffer(address: usize, data: &[u8]) -> Vec<u8> {
-    assert!(data.len() <= PAGE_SIZE);
-    let mut buf = vec![0xFFu8; 2 + PAGE_SIZE];
-    buf[0] = (address & 0xFF) as u8;
-    buf[1] = ((address >> 8) & 0xFF) as u8;
+/// followed by a page of data. Unfilled bytes default to 0xFF (matching
+/// erased flash), so short final pages are safe.
+fn build_page_buffer(mcu: Mcu, address: usize, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() <= mcu.page_size);
+    let mut buf = vec![0xFFu8; 2 + mcu.page_size];
+    buf[..2].copy_from_slice(&mcu.page_address_bytes(address));
     buf[2..2 + data.len()].copy_from_slice(data);
     buf
 }
@@ -251,9 +1056,9 @@ mod tests {
         //   bits 4-0: recipient     (0b00000 = device)
         //
         // 0b_0_10_00000 = 0x40
-        let direction = (REBOOT_REQUEST_TYPE >> 7) & 1;
-        let req_type = (REBOOT_REQUEST_TYPE >> 5) & 0b11;
-        let recipient = REBOOT_REQUEST_TYPE & 0b11111;
+        let direction = (VENDOR_OUT_REQUEST_TYPE >> 7) & 1;
+        let req_type = (VENDOR_OUT_REQUEST_TYPE >> 5) & 0b11;
+        let recipient = VENDOR_OUT_REQUEST_TYPE & 0b11111;
 
         assert_eq!(direction, 0, "direction should be host-to-device");
         assert_eq!(req_type, 0b10, "type should be 'vendor'");
@@ -291,13 +1096,21 @@ mod tests {
         assert_eq!(HALFKAY_REPORT_VALUE, 0x0200);
     }
 
+    #[test]
+    fn retries_back_off_exponentially_within_a_second() {
+        assert_eq!(retry_delay(1), Duration::from_millis(20));
+        assert_eq!(retry_delay(2), Duration::from_millis(40));
+        let total: Duration = (1..PAGE_WRITE_ATTEMPTS).map(retry_delay).sum();
+        assert!(total < Duration::from_secs(1), "{total:?}");
+    }
+
     #[test]
     fn page_buffer_is_two_byte_address_then_page_data() {
         // HalfKay page format: [address_lo, address_hi, data[0], data[1], ...]
         // Address is little-endian, matching the AVR's native byte order.
-        let buf = build_page_buffer(0x1A00, &[0xDE, 0xAD]);
+        let buf = build_page_buffer(ATMEGA32U4, 0x1A00, &[0xDE, 0xAD]);
 
-        assert_eq!(buf.len(), 2 + PAGE_SIZE, "always 2 + PAGE_SIZE bytes");
+        assert_eq!(buf.len(), 2 + 128, "always 2 + page size bytes");
         assert_eq!(buf[0], 0x00, "address low byte");
         assert_eq!(buf[1], 0x1A, "address high byte");
         assert_eq!(buf[2], 0xDE, "first data byte");
@@ -311,15 +1124,25 @@ mod tests {
     fn page_size_matches_atmega32u4_flash_page() {
         // The ATmega32U4 datasheet (section 28.5) specifies 128-byte flash
         // pages. HalfKay writes one page per USB transfer, so this must match.
-        assert_eq!(PAGE_SIZE, 128);
+        assert_eq!(ATMEGA32U4.page_size, 128);
+    }
+
+    #[test]
+    fn teensy_plus_plus_pages_carry_address_bits_8_to_23() {
+        // 128 KB needs a 17-bit address. Pages are 256 bytes, so the low
+        // byte is always zero and HalfKay drops it: [addr >> 8, addr >> 16].
+        let buf = build_page_buffer(AT90USB1286, 0x1_2300, &[0xDE]);
+        assert_eq!(buf.len(), 2 + 256);
+        assert_eq!(&buf[..3], &[0x23, 0x01, 0xDE]);
+        assert_eq!(AT90USB1286.flash_size, 128 * 1024);
     }
 
     #[test]
     fn flash_size_is_32kb() {
         // ATmega32U4 has 32KB of flash. The bootloader lives at the top
-        // (0x7E00-0x7FFF for HalfKay), but we rely on the address check
-        // rather than carving out the bootloader region explicitly.
-        assert_eq!(FLASH_SIZE, 32 * 1024);
+        // (0x7E00-0x7FFF for HalfKay), which `flash` won't write to.
+        assert_eq!(ATMEGA32U4.flash_size, 32 * 1024);
+        assert_eq!(ATMEGA32U4.flash_size - ATMEGA32U4.bootloader_size, APP_END);
     }
 
     #[test]
@@ -329,7 +1152,7 @@ mod tests {
         // confused with a real page write.
         assert_eq!(HALFKAY_REBOOT_ADDRESS, 0xFFFF);
         assert!(
-            HALFKAY_REBOOT_ADDRESS as usize >= FLASH_SIZE,
+            HALFKAY_REBOOT_ADDRESS as usize >= ATMEGA32U4.flash_size,
             "reboot sentinel must be outside writable flash"
         );
     }
@@ -339,7 +1162,7 @@ mod tests {
         // Erased NOR flash reads as all 0xFF. We skip these pages during
         // flashing because writing 0xFF to already-erased flash is a no-op
         // that just wastes time. This is why build_page_buffer pads with 0xFF.
-        let buf = build_page_buffer(0x0000, &[]);
+        let buf = build_page_buffer(ATMEGA32U4, 0x0000, &[]);
         // Data portion should be all 0xFF (erased)
         assert!(buf[2..].iter().all(|&b| b == 0xFF));
     }
@@ -361,12 +1184,246 @@ mod tests {
         // firmware STALLs the unknown request, and the CLI thinks it sent
         // the reboot successfully (write_control ignores errors).
         assert_eq!(
-            (REBOOT_REQUEST_TYPE, REBOOT_REQUEST),
+            (VENDOR_OUT_REQUEST_TYPE, REBOOT_REQUEST),
             (0x40, 0xFF),
             "must match firmware/src/hid.rs handle_setup() vendor request arm"
         );
     }
 
+    #[test]
+    fn flash_crc_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x01) => send flash_crc as 4 little-endian bytes
+        //
+        // 0xC0 is the device-to-host twin of the reboot request's 0x40.
+        assert_eq!(VENDOR_IN_REQUEST_TYPE, VENDOR_OUT_REQUEST_TYPE | 0x80);
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, FLASH_CRC_REQUEST),
+            (0xC0, 0x01),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn debug_events_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x02) => drain the debug event queue
+        //
+        // It never sends a full 64-byte packet, so asking for 64 bytes
+        // always gets everything it has to offer in one transfer.
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, DEBUG_EVENTS_REQUEST),
+            (0xC0, 0x02),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+        assert_eq!(DEBUG_EVENTS_MAX, 64);
+        assert_eq!((DEBUG_EVENTS_MAX - 1) % crate::monitor::EVENT_SIZE, 0);
+    }
+
+    #[test]
+    fn press_counts_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x03) => send per-key press counts
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, PRESS_COUNTS_REQUEST),
+            (0xC0, 0x03),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn diagnostics_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x0A) => send left-half health from debug.rs
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, DIAGNOSTICS_REQUEST),
+            (0xC0, 0x0A),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn diagnostics_are_online_losses_and_errors() {
+        // DebugLog::diagnostics() in firmware/src/debug.rs
+        let diag = Diagnostics::parse(&[0, 2, 0, 0x2C, 0x01]).unwrap();
+        assert!(!diag.left_half_online);
+        assert_eq!(diag.left_half_losses, 2);
+        assert_eq!(diag.i2c_errors, 300);
+        assert!(
+            Diagnostics::parse(&[1, 0, 0, 0, 0, 9])
+                .unwrap()
+                .left_half_online
+        );
+        assert!(Diagnostics::parse(&[1, 0]).is_err());
+    }
+
+    #[test]
+    fn odometer_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x0B) => send keystroke totals from debug.rs
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, ODOMETER_REQUEST),
+            (0xC0, 0x0B),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn odometer_is_total_then_session() {
+        // DebugLog::odometer() in firmware/src/debug.rs
+        let odo = Odometer::parse(&[0x40, 0x42, 0x0F, 0, 0xE8, 0x03, 0, 0]).unwrap();
+        assert_eq!(odo.total, 1_000_000);
+        assert_eq!(odo.session, 1000);
+        assert!(Odometer::parse(&[0; 4]).is_err());
+    }
+
+    #[test]
+    fn keymap_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x0C) => send the keymap table from keymap_table.rs
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, KEYMAP_REQUEST),
+            (0xC0, 0x0C),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn bounce_counts_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x08) => send per-key bounce counts from debounce.rs
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, BOUNCE_COUNTS_REQUEST),
+            (0xC0, 0x08),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn i2c_scan_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0x40, 0x09) => scan the I2C bus, reporting as debug events
+        assert_eq!(
+            (VENDOR_OUT_REQUEST_TYPE, I2C_SCAN_REQUEST),
+            (0x40, 0x09),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn clear_press_counts_request_must_match_firmware_setup_handler() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0x40, 0x07) => zero the per-key press counts
+        assert_eq!(
+            (VENDOR_OUT_REQUEST_TYPE, CLEAR_PRESS_COUNTS_REQUEST),
+            (0x40, 0x07),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn build_info_request_matches_firmware() {
+        // The firmware's handle_setup() in hid.rs matches on:
+        //   (0xC0, 0x04) => send BUILD_INFO from build_info.rs
+        assert_eq!(
+            (VENDOR_IN_REQUEST_TYPE, BUILD_INFO_REQUEST),
+            (0xC0, 0x04),
+            "must match firmware/src/hid.rs handle_setup() vendor request arm"
+        );
+    }
+
+    #[test]
+    fn build_info_is_version_hash_and_date() {
+        let info = BuildInfo::parse(b"0.1.0 1a2b3c4d-dirty 2024-05-01").unwrap();
+        assert_eq!(info.version, "0.1.0");
+        assert_eq!(info.git_hash, "1a2b3c4d-dirty");
+        assert_eq!(info.build_date, "2024-05-01");
+        assert_eq!(info.image_crc, None);
+        assert!(BuildInfo::parse(b"0.1.0").is_err());
+
+        let info = BuildInfo::parse(b"0.1.0 1a2b3c4d 2024-05-01 cbf43926").unwrap();
+        assert_eq!(info.image_crc, Some(0xCBF4_3926));
+        assert_eq!(info.reset_cause, None);
+    }
+
+    #[test]
+    fn build_info_may_end_with_the_reset_cause() {
+        // build_info::reply() in firmware/src/build_info.rs appends
+        // `reset=<cause>` after the optional CRC
+        let info = BuildInfo::parse(b"0.1.0 1a2b3c4d 2024-05-01 cbf43926 reset=watchdog").unwrap();
+        assert_eq!(info.image_crc, Some(0xCBF4_3926));
+        assert_eq!(info.reset_cause.as_deref(), Some("watchdog"));
+
+        let info = BuildInfo::parse(b"0.1.0 1a2b3c4d 2024-05-01 reset=power-on").unwrap();
+        assert_eq!(info.image_crc, None);
+        assert_eq!(info.reset_cause.as_deref(), Some("power-on"));
+
+        assert!(BuildInfo::parse(b"0.1.0 1a2b3c4d 2024-05-01 cbf43926 extra").is_err());
+    }
+
+    #[test]
+    fn flash_crc_covers_application_region_like_firmware() {
+        // The firmware CRCs 0x0000..flash::APP_END in firmware/src/flash.rs,
+        // with the standard CRC-32 (check value 0xCBF43926 for "123456789").
+        assert_eq!(APP_END, 0x7E00);
+        assert_eq!(crc32fast::hash(b"123456789"), 0xCBF4_3926);
+
+        // An empty image is just erased flash.
+        assert_eq!(
+            expected_flash_crc(0, &[]),
+            crc32fast::hash(&vec![0xFF; APP_END])
+        );
+    }
+
+    #[test]
+    fn flash_crc_places_image_at_its_base_address() {
+        let mut flash = vec![0xFFu8; APP_END];
+        flash[0x100..0x103].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            expected_flash_crc(0x100, &[1, 2, 3]),
+            crc32fast::hash(&flash)
+        );
+        assert_ne!(expected_flash_crc(0, &[1, 2, 3]), crc32fast::hash(&flash));
+    }
+
+    #[test]
+    fn mode_is_told_apart_by_product_id() {
+        assert_eq!(Mode::of(HALFKAY_VID, HALFKAY_PID), Some(Mode::Bootloader));
+        assert_eq!(Mode::of(KEYBOARD_VID, KEYBOARD_PID), Some(Mode::Keyboard));
+        assert_eq!(Mode::of(dfu::VID, dfu::PID), Some(Mode::Dfu));
+        assert_eq!(Mode::of(0x16C0, 0x0483), None, "Teensy serial, not ours");
+    }
+
+    // ========================================================================
+    // Device selection
+    // ========================================================================
+
+    #[test]
+    fn bus_address_parses_lsusb_style() {
+        assert_eq!(parse_bus_address("001:012").unwrap(), (1, 12));
+        assert_eq!(parse_bus_address("3:7").unwrap(), (3, 7));
+        assert!(parse_bus_address("3").is_err());
+        assert!(parse_bus_address("3:x").is_err());
+    }
+
+    #[test]
+    fn port_path_uses_sysfs_notation() {
+        let info = DeviceInfo {
+            bus: 1,
+            address: 9,
+            ports: vec![2, 3],
+            mode: Mode::Keyboard,
+            serial: None,
+            openable: true,
+        };
+        assert_eq!(info.port_path(), "1-2.3");
+    }
+
+    #[test]
+    fn no_selection_targets_any_device() {
+        assert_eq!(resolve_target(None, None).unwrap(), Target::Any);
+    }
+
     #[test]
     fn device_descriptor_vid_pid_must_match_firmware() {
         // The firmware's DEVICE_DESCRIPTOR in hid.rs has these bytes at
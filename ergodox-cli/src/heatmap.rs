@@ -0,0 +1,123 @@
+//! Per-key press counts for `heatmap`, from a CSV key log or from the
+//! keyboard's own counters.
+//!
+//! A key log is CSV with one of two shapes, told apart by the header:
+//!
+//! - one line per press, as written by `monitor --log`: `time,row,col`
+//! - pre-aggregated counts: `row,col,count`
+//!
+//! Any other columns are ignored. Without a header, lines are read as
+//! `row,col[,count]`.
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::{COLS, ROWS};
+
+pub type Counts = [[u64; COLS]; ROWS];
+
+/// Header line written by `monitor --log`.
+pub const LOG_HEADER: &str = "time,row,col";
+
+/// Sum up a CSV key log.
+pub fn parse_log(src: &str) -> Result<Counts> {
+    let mut lines = src
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+
+    let mut columns = (0, 1, Some(2));
+    let mut first = lines.next();
+    if let Some((_, header)) = first {
+        let names: Vec<&str> = header.split(',').map(str::trim).collect();
+        if names.iter().any(|n| n.parse::<f64>().is_err()) {
+            let find = |name: &str| names.iter().position(|n| n.eq_ignore_ascii_case(name));
+            let (Some(row), Some(col)) = (find("row"), find("col")) else {
+                bail!("key log header must name `row` and `col` columns");
+            };
+            columns = (row, col, find("count"));
+            first = None;
+        }
+    }
+
+    let mut counts = [[0u64; COLS]; ROWS];
+    for (i, line) in first.into_iter().chain(lines) {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |idx: usize| -> Result<u64> {
+            let value = fields.get(idx).context("missing column")?;
+            value
+                .parse()
+                .with_context(|| format!("not a number: {value:?}"))
+        };
+        let parsed = (|| {
+            let (row, col) = (field(columns.0)? as usize, field(columns.1)? as usize);
+            if row >= ROWS || col >= COLS {
+                bail!("row {row} col {col} is outside the {ROWS}x{COLS} matrix");
+            }
+            let count = match columns.2 {
+                Some(idx) if fields.len() > idx => field(idx)?,
+                _ => 1,
+            };
+            Ok((row, col, count))
+        })();
+        let (row, col, count) = parsed.with_context(|| format!("key log line {}", i + 1))?;
+        counts[row][col] += count;
+    }
+    Ok(counts)
+}
+
+/// Decode the keyboard's per-key counters, of presses or (for `chatter`) of
+/// bounces: ROWS * COLS little-endian u16s.
+pub fn from_keyboard(bytes: &[u8]) -> Result<Counts> {
+    if bytes.len() != ROWS * COLS * 2 {
+        bail!(
+            "per-key counter reply was {} bytes, expected {}",
+            bytes.len(),
+            ROWS * COLS * 2
+        );
+    }
+    let mut counts = [[0u64; COLS]; ROWS];
+    for (i, pair) in bytes.chunks(2).enumerate() {
+        counts[i / COLS][i % COLS] = u16::from_le_bytes([pair[0], pair[1]]) as u64;
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn monitor_log_counts_one_per_line() {
+        let log = format!("{LOG_HEADER}\n0.5,2,3\n0.7,2,3\n1.1,0,13\n");
+        let counts = parse_log(&log).unwrap();
+        assert_eq!(counts[2][3], 2);
+        assert_eq!(counts[0][13], 1);
+    }
+
+    #[test]
+    fn count_column_is_summed() {
+        let counts = parse_log("col,row,count\n3,2,40\n3,2,2\n").unwrap();
+        assert_eq!(counts[2][3], 42);
+    }
+
+    #[test]
+    fn headerless_lines_are_row_col_count() {
+        let counts = parse_log("1,1,5\n1,2\n").unwrap();
+        assert_eq!(counts[1][1], 5);
+        assert_eq!(counts[1][2], 1);
+    }
+
+    #[test]
+    fn out_of_range_position_names_the_line() {
+        let err = format!("{:#}", parse_log("row,col\n1,1\n9,1\n").unwrap_err());
+        assert!(err.contains("line 3"), "{err}");
+    }
+
+    #[test]
+    fn keyboard_counters_are_row_major_le() {
+        let mut bytes = vec![0u8; ROWS * COLS * 2];
+        let i = (COLS + 2) * 2; // row 1 col 2
+        bytes[i..i + 2].copy_from_slice(&300u16.to_le_bytes());
+        assert_eq!(from_keyboard(&bytes).unwrap()[1][2], 300);
+        assert!(from_keyboard(&bytes[1..]).is_err());
+    }
+}
@@ -1,5 +1,38 @@
 use anyhow::{bail, Context, Result};
 
+/// The largest image `flatten_segments` is normally allowed to make: the
+/// flash of the biggest board there's a bootloader for (the AT90USB1286's
+/// 128 KB). Anything bigger is a hex file with stray addresses, and
+/// allocating it could take gigabytes.
+pub const MAX_IMAGE_SIZE: usize = 128 * 1024;
+
+/// The widest gap `flatten_segments` normally fills between segments. A
+/// firmware image is one run of code and data from its base address; a
+/// record kilobytes past the rest is more likely a stray than a part of
+/// it, and filling the gap up to it would overwrite whatever was there.
+pub const DEFAULT_MAX_GAP: usize = 4 * 1024;
+
+/// How `flatten_segments` turns segments into one image.
+#[derive(Clone, Copy, Debug)]
+pub struct FlattenOptions {
+    /// The most bytes the image may span.
+    pub max_size: usize,
+    /// The widest gap between segments to fill.
+    pub max_gap: usize,
+    /// What gaps are filled with.
+    pub fill: u8,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        Self {
+            max_size: MAX_IMAGE_SIZE,
+            max_gap: DEFAULT_MAX_GAP,
+            fill: 0xFF, // erased flash
+        }
+    }
+}
+
 /// A parsed segment of data at a specific address from an Intel HEX file.
 #[derive(Debug, Clone)]
 pub struct HexSegment {
@@ -13,6 +46,9 @@ pub struct HexSegment {
 /// - 00: Data
 /// - 01: End of File
 /// - 02: Extended Segment Address
+/// - 03: Start Segment Address (ignored)
+/// - 04: Extended Linear Address
+/// - 05: Start Linear Address (ignored)
 pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
     let mut segments: Vec<HexSegment> = Vec::new();
     let mut base_address: u32 = 0;
@@ -34,10 +70,6 @@ pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
         }
 
         let byte_count = bytes[0] as usize;
-        let address = u16::from_be_bytes([bytes[1], bytes[2]]);
-        let record_type = bytes[3];
-        let data = &bytes[4..4 + byte_count];
-
         if bytes.len() != 5 + byte_count {
             bail!(
                 "line {}: expected {} data bytes, got {}",
@@ -46,6 +78,9 @@ pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
                 bytes.len() - 5
             );
         }
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let record_type = bytes[3];
+        let data = &bytes[4..4 + byte_count];
 
         // Verify checksum: sum of all bytes (including checksum) should be 0 mod 256
         let checksum: u8 = bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
@@ -60,8 +95,8 @@ pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
 
                 // Try to extend the last segment if this data is contiguous
                 if let Some(last) = segments.last_mut() {
-                    let last_end = last.address + last.data.len() as u32;
-                    if full_address == last_end {
+                    let last_end = last.address as u64 + last.data.len() as u64;
+                    if full_address as u64 == last_end {
                         last.data.extend_from_slice(data);
                         continue;
                     }
@@ -79,12 +114,36 @@ pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
             0x02 => {
                 // Extended segment address
                 if byte_count != 2 {
-                    bail!("line {}: extended segment address must be 2 bytes", line_num + 1);
+                    bail!(
+                        "line {}: extended segment address must be 2 bytes",
+                        line_num + 1
+                    );
                 }
                 base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
             }
+            0x04 => {
+                // Extended linear address: upper 16 bits of the address
+                if byte_count != 2 {
+                    bail!(
+                        "line {}: extended linear address must be 2 bytes",
+                        line_num + 1
+                    );
+                }
+                base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            0x03 | 0x05 => {
+                // Start segment/linear address: the entry point, which
+                // doesn't matter here — the bootloader always jumps to 0x0000
+                if byte_count != 4 {
+                    bail!("line {}: start address must be 4 bytes", line_num + 1);
+                }
+            }
             other => {
-                bail!("line {}: unsupported record type 0x{:02X}", line_num + 1, other);
+                bail!(
+                    "line {}: unsupported record type 0x{:02X}",
+                    line_num + 1,
+                    other
+                );
             }
         }
     }
@@ -93,21 +152,58 @@ pub fn parse_hex(input: &str) -> Result<Vec<HexSegment>> {
 }
 
 /// Flatten parsed HEX segments into a contiguous firmware image.
-/// Returns (base_address, data) where data is zero-filled for any gaps.
-pub fn flatten_segments(segments: &[HexSegment]) -> Result<(u32, Vec<u8>)> {
-    if segments.is_empty() {
+/// Returns (base_address, data) where data is `options.fill` for any gaps.
+///
+/// Segments that overlap are an error, since which one ends up in flash
+/// would be down to their order, and so are a gap wider than
+/// `options.max_gap` and an image spanning more than `options.max_size`
+/// bytes, which are checked before anything is allocated.
+pub fn flatten_segments(
+    segments: &[HexSegment],
+    options: &FlattenOptions,
+) -> Result<(u32, Vec<u8>)> {
+    let mut sorted: Vec<&HexSegment> = segments.iter().filter(|s| !s.data.is_empty()).collect();
+    if sorted.is_empty() {
         bail!("no data segments in HEX file");
     }
+    sorted.sort_by_key(|s| s.address);
 
-    let min_addr = segments.iter().map(|s| s.address).min().unwrap();
-    let max_addr = segments
-        .iter()
-        .map(|s| s.address + s.data.len() as u32)
-        .max()
-        .unwrap();
+    let end = |s: &HexSegment| s.address as u64 + s.data.len() as u64;
+    let mut reach = 0;
+    for pair in sorted.windows(2) {
+        reach = reach.max(end(pair[0]));
+        let start = pair[1].address as u64;
+        if start < end(pair[0]) {
+            bail!(
+                "data at 0x{:X} overlaps data at 0x{:X}..0x{:X}",
+                pair[1].address,
+                pair[0].address,
+                end(pair[0])
+            );
+        }
+        if start - reach > options.max_gap as u64 {
+            bail!(
+                "{} byte gap between 0x{:X} and data at 0x{:X}, more than the {} allowed",
+                start - reach,
+                reach,
+                start,
+                options.max_gap
+            );
+        }
+    }
 
-    let total_size = (max_addr - min_addr) as usize;
-    let mut image = vec![0xFFu8; total_size]; // 0xFF = erased flash
+    let min_addr = sorted[0].address;
+    let max_addr = sorted.iter().map(|s| end(s)).max().unwrap();
+    let total_size = max_addr - min_addr as u64;
+    if total_size > options.max_size as u64 {
+        bail!(
+            "image spans {} bytes from 0x{:X}, more than the {} allowed",
+            total_size,
+            min_addr,
+            options.max_size
+        );
+    }
+    let mut image = vec![options.fill; total_size as usize];
 
     for seg in segments {
         let offset = (seg.address - min_addr) as usize;
@@ -117,8 +213,60 @@ pub fn flatten_segments(segments: &[HexSegment]) -> Result<(u32, Vec<u8>)> {
     Ok((min_addr, image))
 }
 
+/// Encode segments as Intel HEX, the inverse of `parse_hex`.
+///
+/// Each segment becomes data records of up to 16 bytes, never crossing a
+/// 64 KB boundary, with an extended linear address record (04) whenever the
+/// upper 16 address bits change. Gaps between segments are left out, not
+/// filled. Ends with an EOF record.
+pub fn write_hex(segments: &[HexSegment]) -> String {
+    let mut out = String::new();
+    let mut upper = 0u16;
+    for seg in segments {
+        let mut address = seg.address;
+        let mut rest = seg.data.as_slice();
+        while !rest.is_empty() {
+            if (address >> 16) as u16 != upper {
+                upper = (address >> 16) as u16;
+                out.push_str(&record(0x04, 0, &upper.to_be_bytes()));
+            }
+            let to_boundary = 0x10000 - (address & 0xFFFF) as usize;
+            let (chunk, tail) = rest.split_at(rest.len().min(16).min(to_boundary));
+            out.push_str(&record(0x00, address as u16, chunk));
+            address += chunk.len() as u32;
+            rest = tail;
+        }
+    }
+    out.push_str(&record(0x01, 0, &[]));
+    out
+}
+
+/// One Intel HEX record line, checksum included.
+fn record(record_type: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |a, &b| a.wrapping_add(b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    let mut line = String::from(":");
+    for b in bytes {
+        line.push_str(&format!("{:02X}", b));
+    }
+    line.push('\n');
+    line
+}
+
 fn decode_hex_bytes(hex: &str) -> Result<Vec<u8>> {
-    if hex.len() % 2 != 0 {
+    // Slicing pairs of bytes below would split a multibyte character
+    if !hex.is_ascii() {
+        bail!("non-ASCII characters");
+    }
+    if !hex.len().is_multiple_of(2) {
         bail!("odd number of hex characters");
     }
     (0..hex.len())
@@ -158,6 +306,40 @@ mod tests {
         assert_eq!(segments[0].address, 0x1000);
     }
 
+    #[test]
+    fn test_parse_extended_linear_address() {
+        // avr-objcopy emits an 04 record with upper address 0x0000
+        let hex = ":020000040000FA\n\
+                   :04010000AABBCCDDED\n\
+                   :00000001FF\n";
+        let segments = parse_hex(hex).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].address, 0x0100);
+
+        let hex = ":020000040001F9\n\
+                   :04000000AABBCCDDEE\n\
+                   :00000001FF\n";
+        let segments = parse_hex(hex).unwrap();
+        assert_eq!(segments[0].address, 0x1_0000);
+    }
+
+    #[test]
+    fn test_start_address_records_are_skipped() {
+        let hex = ":0400000300001000E9\n\
+                   :0400000500000000F7\n\
+                   :04000000AABBCCDDEE\n\
+                   :00000001FF\n";
+        let segments = parse_hex(hex).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, vec![0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn test_unknown_record_type_is_rejected() {
+        let hex = ":00000006FA\n:00000001FF\n";
+        assert!(parse_hex(hex).is_err());
+    }
+
     #[test]
     fn test_checksum_error() {
         let hex = ":10000000000102030405060708090A0B0C0D0E0F00\n\
@@ -172,7 +354,57 @@ mod tests {
                    :00000001FF\n";
         let segments = parse_hex(hex).unwrap();
         assert_eq!(segments.len(), 1);
-        assert_eq!(segments[0].data, vec![0xAA, 0xBB, 0xCC, 0xDD, 0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(
+            segments[0].data,
+            vec![0xAA, 0xBB, 0xCC, 0xDD, 0x11, 0x22, 0x33, 0x44]
+        );
+    }
+
+    #[test]
+    fn test_write_simple_hex() {
+        let data: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            write_hex(&[HexSegment { address: 0, data }]),
+            ":10000000000102030405060708090A0B0C0D0E0F78\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_write_round_trips_across_64k() {
+        let data: Vec<u8> = (0..40).collect();
+        let hex = write_hex(&[HexSegment {
+            address: 0xFFF0,
+            data: data.clone(),
+        }]);
+        assert!(hex.contains(":020000040001F9\n"));
+        let (base, image) =
+            flatten_segments(&parse_hex(&hex).unwrap(), &FlattenOptions::default()).unwrap();
+        assert_eq!(base, 0xFFF0);
+        assert_eq!(image, data);
+    }
+
+    #[test]
+    fn test_write_splits_records_at_64k_and_keeps_gaps() {
+        let segments = [
+            HexSegment {
+                address: 0x10,
+                data: vec![0xAA; 4],
+            },
+            HexSegment {
+                address: 0xFFF8,
+                data: vec![0xBB; 16],
+            },
+        ];
+        let hex = write_hex(&segments);
+        // 8 bytes up to the boundary, then the upper address, then the rest
+        assert!(hex.contains(":08FFF800BBBBBBBBBBBBBBBB"));
+        assert!(hex.contains(":020000040001F9\n:08000000BBBBBBBBBBBBBBBB"));
+
+        let parsed = parse_hex(&hex).unwrap();
+        assert_eq!(parsed.first().unwrap().address, 0x10);
+        assert_eq!(parsed.first().unwrap().data, vec![0xAA; 4]);
+        let (base, image) = flatten_segments(&parsed[1..], &FlattenOptions::default()).unwrap();
+        assert_eq!((base, image), (0xFFF8, vec![0xBB; 16]));
     }
 
     #[test]
@@ -187,7 +419,7 @@ mod tests {
                 data: vec![0xCC, 0xDD],
             },
         ];
-        let (base, image) = flatten_segments(&segments).unwrap();
+        let (base, image) = flatten_segments(&segments, &FlattenOptions::default()).unwrap();
         assert_eq!(base, 0x100);
         assert_eq!(image.len(), 0x12);
         assert_eq!(image[0], 0xAA);
@@ -197,4 +429,70 @@ mod tests {
         assert_eq!(image[0x10], 0xCC);
         assert_eq!(image[0x11], 0xDD);
     }
+
+    #[test]
+    fn test_overlapping_records_are_rejected() {
+        // The second record rewrites 0x0002..0x0006
+        let hex = ":04000000AABBCCDDEE\n\
+                   :040002001122334450\n\
+                   :00000001FF\n";
+        let err =
+            flatten_segments(&parse_hex(hex).unwrap(), &FlattenOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("overlaps"), "{err}");
+    }
+
+    #[test]
+    fn test_far_apart_records_are_rejected_before_allocating() {
+        // Two bytes, 4 GB apart
+        let hex = ":01000000AA55\n\
+                   :02000004FFFFFC\n\
+                   :01FFFF00BB46\n\
+                   :00000001FF\n";
+        let segments = parse_hex(hex).unwrap();
+        assert_eq!(segments[1].address, 0xFFFF_FFFF);
+        let any_gap = FlattenOptions {
+            max_gap: usize::MAX,
+            ..FlattenOptions::default()
+        };
+        let err = flatten_segments(&segments, &any_gap).unwrap_err();
+        assert!(err.to_string().contains("spans"), "{err}");
+    }
+
+    #[test]
+    fn test_wide_gaps_are_rejected_and_narrow_ones_filled() {
+        let segments = vec![
+            HexSegment {
+                address: 0,
+                data: vec![0xAA],
+            },
+            HexSegment {
+                address: 0x7F00,
+                data: vec![0xBB],
+            },
+        ];
+        let err = flatten_segments(&segments, &FlattenOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("gap"), "{err}");
+
+        let options = FlattenOptions {
+            max_gap: 0x8000,
+            fill: 0x00,
+            ..FlattenOptions::default()
+        };
+        let (base, image) = flatten_segments(&segments, &options).unwrap();
+        assert_eq!((base, image.len()), (0, 0x7F01));
+        assert_eq!((image[1], image[0x7F00]), (0x00, 0xBB));
+    }
+
+    #[test]
+    fn test_short_record_is_an_error() {
+        let hex = ":10000000AABB00\n:00000001FF\n";
+        assert!(parse_hex(hex).is_err());
+    }
+
+    #[test]
+    fn test_non_ascii_is_an_error() {
+        assert!(parse_hex(":0100000\u{e9}00\n:00000001FF\n").is_err());
+        // Even length, with the character across a pair of hex digits
+        assert!(parse_hex(":0\u{e9}000000000\n:00000001FF\n").is_err());
+    }
 }
@@ -0,0 +1,134 @@
+//! HalfKay through Linux's hidraw driver (`--transport hidraw`).
+//!
+//! The kernel binds usbhid to the bootloader like to any HID device and
+//! exposes it as `/dev/hidrawN`. Writing a report there makes the kernel
+//! send the same SET_REPORT control transfer that libusb would, without
+//! detaching its driver, so a udev rule that hands the node to the logged-in
+//! user is all it takes to flash without root:
+//!
+//! ```text
+//! # /etc/udev/rules.d/49-halfkay.rules
+//! KERNEL=="hidraw*", ATTRS{idVendor}=="16c0", ATTRS{idProduct}=="0478", TAG+="uaccess"
+//! ```
+//!
+//! Detection still goes through libusb, which only reads descriptors and
+//! needs no permissions.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::halfkay::Target;
+
+/// Where the kernel lists hidraw nodes.
+const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+
+/// Open the hidraw node of the HalfKay bootloader matching `target`.
+pub fn open(target: &Target, vid: u16, pid: u16) -> Result<File> {
+    if !cfg!(target_os = "linux") {
+        bail!("the hidraw transport is only available on Linux");
+    }
+    let Some(node) = find(target, vid, pid)? else {
+        bail!(
+            "Teensy bootloader has no hidraw node. Press the reset button on the Teensy and try again."
+        );
+    };
+    OpenOptions::new().write(true).open(&node).with_context(|| {
+        format!(
+            "failed to open {} (add a udev rule for it; see `ergodox-cli udev`)",
+            node.display()
+        )
+    })
+}
+
+/// Send one report: hidraw wants the report ID first, 0 for a device
+/// without numbered reports like HalfKay.
+pub fn write_report(file: &mut File, report: &[u8]) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(1 + report.len());
+    buf.push(0);
+    buf.extend_from_slice(report);
+    file.write_all(&buf)
+}
+
+/// The `/dev/hidrawN` of the one matching device, if any.
+fn find(target: &Target, vid: u16, pid: u16) -> Result<Option<PathBuf>> {
+    let entries = match fs::read_dir(SYSFS_HIDRAW) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("failed to list hidraw devices"),
+    };
+    let mut matching = Vec::new();
+    for entry in entries {
+        let entry = entry.context("failed to list hidraw devices")?;
+        let device = entry.path().join("device");
+        let Ok(uevent) = fs::read_to_string(device.join("uevent")) else {
+            continue;
+        };
+        if !is_device(&uevent, vid, pid) {
+            continue;
+        }
+        let wanted = match target {
+            Target::Any => true,
+            Target::Port { bus, ports } => fs::canonicalize(&device)
+                .ok()
+                .and_then(|path| port_of(&path))
+                .is_some_and(|(b, p)| b == *bus && p == *ports),
+        };
+        if wanted {
+            matching.push(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    if matching.len() > 1 {
+        bail!(
+            "{} matching devices connected; pick one with --device BUS:ADDR or --serial (see `ergodox-cli list`)",
+            matching.len()
+        );
+    }
+    Ok(matching.pop())
+}
+
+/// Whether a hid device's uevent names this USB vendor and product, as in
+/// `HID_ID=0003:000016C0:00000478` (bus type 3 is USB).
+fn is_device(uevent: &str, vid: u16, pid: u16) -> bool {
+    let wanted = format!("HID_ID=0003:{:08X}:{:08X}", vid, pid);
+    uevent
+        .lines()
+        .any(|line| line.trim().eq_ignore_ascii_case(&wanted))
+}
+
+/// The USB bus and port chain from a sysfs device path: the last component
+/// shaped like `1-2.3` (bus 1, port 2, then port 3 on the hub there).
+fn port_of(path: &Path) -> Option<(u8, Vec<u8>)> {
+    path.iter().rev().find_map(|component| {
+        let (bus, ports) = component.to_str()?.split_once('-')?;
+        let bus = bus.parse().ok()?;
+        let ports = ports
+            .split('.')
+            .map(|p| p.parse().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some((bus, ports))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uevent_matches_on_usb_vendor_and_product() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:000016C0:00000478\nHID_NAME=Teensy\n";
+        assert!(is_device(uevent, 0x16C0, 0x0478));
+        assert!(!is_device(uevent, 0x16C0, 0x047E));
+    }
+
+    #[test]
+    fn port_comes_from_the_usb_device_in_the_sysfs_path() {
+        let path = Path::new(
+            "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.3/1-2.3:1.0/0003:16C0:0478.0005",
+        );
+        assert_eq!(port_of(path), Some((1, vec![2, 3])));
+        assert_eq!(port_of(Path::new("/sys/devices/virtual/misc")), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! What answers on the keyboard's I2C bus, for `i2c-scan`.
+//!
+//! The firmware probes every address and reports each device as a debug
+//! event, then a "done" event (`firmware/src/i2c_scan.rs`). With the left
+//! half working, the only device is its MCP23018 at 0x20 (0x20 to 0x27,
+//! depending on its address pins).
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use crate::halfkay::DebugChannel;
+use crate::monitor::{self, Event};
+
+/// A scan takes a few tens of milliseconds; this is plenty.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, PartialEq)]
+pub struct BusScan {
+    pub devices: Vec<u8>,
+    /// Something held the bus low, so the scan was cut short.
+    pub stuck: bool,
+}
+
+/// Ask for a scan and wait for its result.
+pub fn run(channel: &DebugChannel) -> Result<BusScan> {
+    // Events from before the request aren't ours
+    while !channel.poll()?.is_empty() {}
+    channel.request_i2c_scan()?;
+    let start = Instant::now();
+    let mut events = Vec::new();
+    while start.elapsed() < SCAN_TIMEOUT {
+        events.extend(monitor::decode(
+            &channel.poll()?,
+            start.elapsed().as_millis() as u32,
+        )?);
+        if let Some(scan) = collect(&events) {
+            return Ok(scan);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    bail!("the keyboard never finished the I2C scan");
+}
+
+/// The scan in a stream of events, once it's done.
+fn collect(events: &[Event]) -> Option<BusScan> {
+    let mut devices = Vec::new();
+    for event in events {
+        match *event {
+            Event::I2cDevice(addr) => devices.push(addr),
+            Event::I2cScanDone { stuck, .. } => return Some(BusScan { devices, stuck }),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// What a device at this address on an ErgoDox bus probably is.
+fn device_name(addr: u8) -> Option<&'static str> {
+    match addr {
+        0x20..=0x27 => Some("MCP23018 I/O expander (left half)"),
+        _ => None,
+    }
+}
+
+impl BusScan {
+    /// Whether the left half's expander answered.
+    pub fn has_left_half(&self) -> bool {
+        self.devices.iter().any(|&a| (0x20..=0x27).contains(&a))
+    }
+
+    /// The scan as a JSON object, for `i2c-scan --json`.
+    pub fn to_json(&self) -> Value {
+        let devices: Vec<_> = self
+            .devices
+            .iter()
+            .map(|&a| json!({ "address": a, "name": device_name(a) }))
+            .collect();
+        json!({
+            "devices": devices,
+            "bus_stuck": self.stuck,
+            "left_half_found": self.has_left_half(),
+        })
+    }
+}
+
+impl fmt::Display for BusScan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &addr in &self.devices {
+            match device_name(addr) {
+                Some(name) => writeln!(f, "0x{:02X}  {}", addr, name)?,
+                None => writeln!(f, "0x{:02X}  unknown device", addr)?,
+            }
+        }
+        if self.stuck {
+            write!(
+                f,
+                "The bus is stuck: SDA or SCL is held low. Check the TRRS cable \
+                 and the left half's wiring for a short."
+            )
+        } else if self.devices.is_empty() {
+            write!(
+                f,
+                "Nothing answered. Check that the TRRS cable is plugged in at both \
+                 ends, and the MCP23018's power and I2C pins."
+            )
+        } else if !self.has_left_half() {
+            write!(
+                f,
+                "No MCP23018 answered, so the left half can't be scanned."
+            )
+        } else {
+            write!(f, "The left half's MCP23018 is answering.")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_ends_at_the_done_event() {
+        let key = Event::Key(ergodox_keymap::KeyEvent {
+            row: 1,
+            col: 1,
+            pressed: true,
+            timestamp: 0,
+        });
+        let mut events = vec![key, Event::I2cDevice(0x20)];
+        assert_eq!(collect(&events), None);
+        events.push(Event::I2cScanDone {
+            found: 1,
+            stuck: false,
+        });
+        let scan = collect(&events).unwrap();
+        assert_eq!(scan.devices, vec![0x20]);
+        assert!(scan.has_left_half());
+    }
+
+    #[test]
+    fn empty_and_stuck_buses_say_what_to_check() {
+        let empty = BusScan {
+            devices: vec![],
+            stuck: false,
+        };
+        assert!(empty.to_string().contains("TRRS cable is plugged in"));
+        let stuck = BusScan {
+            devices: vec![],
+            stuck: true,
+        };
+        assert!(stuck.to_string().contains("held low"));
+    }
+}
@@ -0,0 +1,275 @@
+//! Everything the bus says about our keyboards and bootloaders, for `info`.
+//!
+//! `list` is enough to pick a device; this is for when one won't enumerate
+//! or a flash misbehaves: descriptor fields, strings, the negotiated speed
+//! and every interface with its endpoints. All of it comes from descriptors,
+//! so it works without permission to open the device; the strings need it
+//! and are left out otherwise.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+use rusb::{GlobalContext, Speed, TransferType};
+use serde_json::{json, Value};
+
+use crate::halfkay::Mode;
+
+pub struct Details {
+    pub bus: u8,
+    pub address: u8,
+    pub ports: Vec<u8>,
+    pub mode: Mode,
+    pub vid: u16,
+    pub pid: u16,
+    /// bcdUSB, e.g. `2.0.0`.
+    pub usb_version: String,
+    /// bcdDevice, e.g. `0.1.0`: on the keyboard, the firmware's crate version.
+    pub device_version: String,
+    /// The speed the host and device settled on.
+    pub speed: &'static str,
+    pub ep0_size: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// Whether opening the device for its strings failed, which usually
+    /// means permissions.
+    pub strings_unreadable: bool,
+    pub interfaces: Vec<Interface>,
+}
+
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+pub struct Endpoint {
+    pub address: u8,
+    pub transfer: &'static str,
+    pub max_packet: u16,
+    /// Polling interval, in frames for interrupt endpoints.
+    pub interval: u8,
+}
+
+/// Describe every connected keyboard and bootloader.
+pub fn collect() -> Result<Vec<Details>> {
+    let devices = rusb::devices().context("failed to enumerate USB devices")?;
+    let mut found = Vec::new();
+    for device in devices.iter() {
+        let desc = device
+            .device_descriptor()
+            .context("failed to read device descriptor")?;
+        let Some(mode) = Mode::of(desc.vendor_id(), desc.product_id()) else {
+            continue;
+        };
+        found.push(describe(&device, &desc, mode));
+    }
+    Ok(found)
+}
+
+fn describe(
+    device: &rusb::Device<GlobalContext>,
+    desc: &rusb::DeviceDescriptor,
+    mode: Mode,
+) -> Details {
+    let handle = device.open().ok();
+    let (manufacturer, product, serial) = match &handle {
+        Some(h) => (
+            h.read_manufacturer_string_ascii(desc).ok(),
+            h.read_product_string_ascii(desc).ok(),
+            h.read_serial_number_string_ascii(desc).ok(),
+        ),
+        None => (None, None, None),
+    };
+    let interfaces = match device.active_config_descriptor() {
+        Ok(config) => config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .map(|d| Interface {
+                number: d.interface_number(),
+                class: d.class_code(),
+                subclass: d.sub_class_code(),
+                protocol: d.protocol_code(),
+                endpoints: d
+                    .endpoint_descriptors()
+                    .map(|e| Endpoint {
+                        address: e.address(),
+                        transfer: transfer_name(e.transfer_type()),
+                        max_packet: e.max_packet_size(),
+                        interval: e.interval(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    Details {
+        bus: device.bus_number(),
+        address: device.address(),
+        ports: device.port_numbers().unwrap_or_default(),
+        mode,
+        vid: desc.vendor_id(),
+        pid: desc.product_id(),
+        usb_version: desc.usb_version().to_string(),
+        device_version: desc.device_version().to_string(),
+        speed: speed_name(device.speed()),
+        ep0_size: desc.max_packet_size(),
+        manufacturer,
+        product,
+        serial,
+        strings_unreadable: handle.is_none(),
+        interfaces,
+    }
+}
+
+fn speed_name(speed: Speed) -> &'static str {
+    match speed {
+        Speed::Low => "low speed (1.5 Mbit/s)",
+        Speed::Full => "full speed (12 Mbit/s)",
+        Speed::High => "high speed (480 Mbit/s)",
+        Speed::Super | Speed::SuperPlus => "SuperSpeed",
+        _ => "unknown speed",
+    }
+}
+
+fn transfer_name(transfer: TransferType) -> &'static str {
+    match transfer {
+        TransferType::Control => "control",
+        TransferType::Isochronous => "isochronous",
+        TransferType::Bulk => "bulk",
+        TransferType::Interrupt => "interrupt",
+    }
+}
+
+/// The interface's class in words, with what its subclass and protocol mean
+/// for the classes our devices use.
+fn class_name(class: u8, subclass: u8, protocol: u8) -> String {
+    match (class, subclass, protocol) {
+        (0x03, 0x01, 0x01) => "HID, boot keyboard".to_string(),
+        (0x03, 0x01, 0x02) => "HID, boot mouse".to_string(),
+        (0x03, _, _) => "HID".to_string(),
+        (0x02, 0x02, _) => "CDC ACM (serial)".to_string(),
+        (0x02, _, _) => "CDC".to_string(),
+        (0x0A, _, _) => "CDC data".to_string(),
+        (0xFE, 0x01, _) => "DFU".to_string(),
+        (0xFF, _, _) => "vendor specific".to_string(),
+        _ => format!("class 0x{:02X}", class),
+    }
+}
+
+impl Interface {
+    fn to_json(&self) -> Value {
+        let endpoints: Vec<_> = self
+            .endpoints
+            .iter()
+            .map(|e| {
+                json!({
+                    "address": e.address,
+                    "transfer": e.transfer,
+                    "max_packet_size": e.max_packet,
+                    "interval": e.interval,
+                })
+            })
+            .collect();
+        json!({
+            "number": self.number,
+            "class": self.class,
+            "subclass": self.subclass,
+            "protocol": self.protocol,
+            "description": class_name(self.class, self.subclass, self.protocol),
+            "endpoints": endpoints,
+        })
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "interface {}: {} ({:02X}/{:02X}/{:02X})",
+            self.number,
+            class_name(self.class, self.subclass, self.protocol),
+            self.class,
+            self.subclass,
+            self.protocol
+        )?;
+        for e in &self.endpoints {
+            write!(
+                f,
+                "\n  endpoint 0x{:02X} {:<3} {}, {} bytes",
+                e.address,
+                // Bit 7 of the endpoint address is the direction
+                if e.address & 0x80 != 0 { "IN" } else { "OUT" },
+                e.transfer,
+                e.max_packet
+            )?;
+            if e.transfer == "interrupt" {
+                write!(f, ", every {} ms", e.interval)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Details {
+    /// Physical location in Linux sysfs notation, as in `list`.
+    pub fn port_path(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+
+    /// Everything but the mode, which the caller names.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "bus": self.bus,
+            "address": self.address,
+            "port": self.port_path(),
+            "vid": format!("{:04x}", self.vid),
+            "pid": format!("{:04x}", self.pid),
+            "usb_version": self.usb_version,
+            "device_version": self.device_version,
+            "speed": self.speed,
+            "ep0_max_packet_size": self.ep0_size,
+            "manufacturer": self.manufacturer,
+            "product": self.product,
+            "serial": self.serial,
+            "interfaces": self.interfaces.iter().map(Interface::to_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_our_devices_use_have_names() {
+        assert_eq!(class_name(0x03, 0x01, 0x01), "HID, boot keyboard");
+        assert_eq!(class_name(0x03, 0x00, 0x00), "HID");
+        assert_eq!(class_name(0x02, 0x02, 0x01), "CDC ACM (serial)");
+        assert_eq!(class_name(0x42, 0x00, 0x00), "class 0x42");
+    }
+
+    #[test]
+    fn interface_lists_its_endpoints() {
+        let interface = Interface {
+            number: 0,
+            class: 0x03,
+            subclass: 0x01,
+            protocol: 0x01,
+            endpoints: vec![Endpoint {
+                address: 0x81,
+                transfer: "interrupt",
+                max_packet: 8,
+                interval: 1,
+            }],
+        };
+        assert_eq!(
+            interface.to_string(),
+            "interface 0: HID, boot keyboard (03/01/01)\n  \
+             endpoint 0x81 IN  interrupt, 8 bytes, every 1 ms"
+        );
+    }
+}
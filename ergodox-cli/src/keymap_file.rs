@@ -0,0 +1,211 @@
+//! Human-editable keymap files.
+//!
+//! A keymap file holds the same table as `LAYERS`, one `[[layer]]` per
+//! layer with six rows of fourteen keys. Keys are written with their
+//! `Keycode` variant names, and `___` marks a transparent key, just like
+//! the hand-written table:
+//!
+//! ```toml
+//! [[layer]]
+//! rows = [
+//!   ["Equal", "N1", "N2", ...],
+//!   ...
+//! ]
+//! ```
+//!
+//! JSON with the same structure is accepted too, for generated keymaps,
+//! and so is a keymap bundle (`bundle.rs`), for its layers.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::{Keycode, COLS, ROWS};
+use serde::Deserialize;
+
+use crate::bundle;
+use crate::codegen::Layer;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    layer: Vec<FileLayer>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FileLayer {
+    rows: Vec<Vec<String>>,
+}
+
+/// Parse a keymap file, picking JSON, a bundle or TOML by the file
+/// extension.
+pub fn parse(path: &Path, src: &str) -> Result<Vec<Layer>> {
+    if path.extension().is_some_and(|e| e == "json") {
+        parse_json(src)
+    } else if bundle::is_bundle(path) {
+        Ok(bundle::parse(src)?.layers)
+    } else {
+        parse_toml(src)
+    }
+}
+
+/// Parse a TOML keymap file.
+pub fn parse_toml(src: &str) -> Result<Vec<Layer>> {
+    let file: File = toml::from_str(src).context("invalid keymap TOML")?;
+    to_layers(&file.layer)
+}
+
+/// Parse a JSON keymap file: the TOML structure spelled as JSON,
+/// `{"layer": [{"rows": [[...], ...]}, ...]}`.
+pub fn parse_json(src: &str) -> Result<Vec<Layer>> {
+    let file: File = serde_json::from_str(src).context("invalid keymap JSON")?;
+    to_layers(&file.layer)
+}
+
+/// Check dimensions and resolve key names.
+pub(crate) fn to_layers(layers: &[FileLayer]) -> Result<Vec<Layer>> {
+    if layers.is_empty() {
+        bail!("keymap has no [[layer]] tables");
+    }
+
+    layers
+        .iter()
+        .enumerate()
+        .map(|(l, layer)| {
+            if layer.rows.len() != ROWS {
+                bail!(
+                    "layer {l}: expected {ROWS} rows, found {}",
+                    layer.rows.len()
+                );
+            }
+            let mut out = [[Keycode::Trans; COLS]; ROWS];
+            for (r, row) in layer.rows.iter().enumerate() {
+                if row.len() != COLS {
+                    bail!(
+                        "layer {l} row {r}: expected {COLS} keys, found {}",
+                        row.len()
+                    );
+                }
+                for (c, name) in row.iter().enumerate() {
+                    out[r][c] = keycode_from_name(name).with_context(|| {
+                        format!("layer {l} row {r} col {c}: unknown keycode {name:?}")
+                    })?;
+                }
+            }
+            Ok(out)
+        })
+        .collect()
+}
+
+/// Render layers as a TOML keymap file, with keys aligned in columns.
+pub fn to_toml(layers: &[Layer]) -> String {
+    let names: Vec<Vec<Vec<String>>> = layers
+        .iter()
+        .map(|layer| {
+            layer
+                .iter()
+                .map(|row| row.iter().map(|&kc| format!("{:?}", name_of(kc))).collect())
+                .collect()
+        })
+        .collect();
+
+    // One width per column across all layers keeps every layer aligned alike.
+    let mut widths = [0usize; COLS];
+    for row in names.iter().flatten() {
+        for (c, name) in row.iter().enumerate() {
+            widths[c] = widths[c].max(name.len());
+        }
+    }
+
+    let mut out = String::from("# ErgoDox keymap: six rows of fourteen keys per layer.\n");
+    out.push_str("# Keys use Keycode variant names; ___ is transparent.\n");
+    for (l, layer) in names.iter().enumerate() {
+        out.push_str(&format!("\n# Layer {l}\n[[layer]]\nrows = [\n"));
+        for row in layer {
+            let cells: String = row
+                .iter()
+                .enumerate()
+                .map(|(c, name)| format!("{:<w$}", format!("{name},"), w = widths[c] + 2))
+                .collect();
+            out.push_str(&format!(
+                "  [{}],\n",
+                cells.trim_end().trim_end_matches(',')
+            ));
+        }
+        out.push_str("]\n");
+    }
+    out
+}
+
+/// The file-format name of a keycode: its variant name, or `___` for Trans.
+fn name_of(kc: Keycode) -> String {
+    if kc == Keycode::Trans {
+        "___".to_string()
+    } else {
+        format!("{kc:?}")
+    }
+}
+
+/// Look up a keycode by its file-format name.
+pub fn keycode_from_name(name: &str) -> Option<Keycode> {
+    if name == "___" {
+        return Some(Keycode::Trans);
+    }
+    Keycode::ALL
+        .iter()
+        .copied()
+        .find(|kc| format!("{kc:?}") == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::LAYERS;
+
+    #[test]
+    fn builtin_keymap_round_trips() {
+        let parsed = parse_toml(&to_toml(&LAYERS)).unwrap();
+        assert_eq!(parsed, LAYERS);
+    }
+
+    #[test]
+    fn triple_underscore_is_transparent() {
+        assert_eq!(keycode_from_name("___"), Some(Keycode::Trans));
+        assert_eq!(keycode_from_name("Trans"), Some(Keycode::Trans));
+        assert_eq!(keycode_from_name("LCtrl"), Some(Keycode::LCtrl));
+        assert_eq!(keycode_from_name("lctrl"), None);
+    }
+
+    #[test]
+    fn columns_are_aligned() {
+        let src = to_toml(&LAYERS);
+        let rows: Vec<&str> = src.lines().filter(|l| l.starts_with("  [")).collect();
+        // The second key starts at the same column on every row.
+        let second_key = |l: &str| l.match_indices('"').nth(2).unwrap().0;
+        assert!(rows.iter().all(|r| second_key(r) == second_key(rows[0])));
+    }
+
+    #[test]
+    fn unknown_keycode_reports_its_position() {
+        let mut layer = [[Keycode::Trans; COLS]; ROWS];
+        layer[0][0] = Keycode::Equal;
+        let src = to_toml(&[layer]).replacen("\"Equal\"", "\"Equals\"", 1);
+        let err = format!("{:#}", parse_toml(&src).unwrap_err());
+        assert!(err.contains("unknown keycode \"Equals\""), "{err}");
+        assert!(err.contains("layer 0 row 0 col 0"), "{err}");
+    }
+
+    #[test]
+    fn json_uses_the_same_structure() {
+        let rows = vec![vec!["___"; COLS]; ROWS];
+        let json = serde_json::json!({ "layer": [{ "rows": rows }] });
+        let layers = parse(Path::new("keymap.json"), &json.to_string()).unwrap();
+        assert_eq!(layers, [[[Keycode::Trans; COLS]; ROWS]]);
+    }
+
+    #[test]
+    fn short_row_is_an_error() {
+        let src = "[[layer]]\nrows = [[\"A\"]]\n";
+        assert!(parse_toml(src).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Keymaps as text tables, for `keymap print`: a table per layer with the
+//! matrix rows down the side and the columns along the top, the halves
+//! split by a bar.
+//!
+//! Transparent keys on the upper layers show what they fall through to in
+//! parentheses, so each table reads as what you get on that layer, and
+//! `___` is a key that does nothing. Columns are as wide across every
+//! layer as their widest key, so the tables line up under each other.
+
+use ergodox_keymap::{Keycode, COLS, COLS_PER_HALF, ROWS};
+use serde_json::{json, Value};
+
+use crate::codegen::Layer;
+use crate::diff;
+
+/// The text of one cell.
+fn cell(layers: &[Layer], layer: usize, row: usize, col: usize) -> String {
+    let kc = layers[layer][row][col];
+    if layer > 0 && kc == Keycode::Trans {
+        match ergodox_keymap::lookup_in(layers, layer, row, col) {
+            Keycode::Trans => diff::label(Keycode::Trans).to_string(),
+            resolved => format!("({})", diff::label(resolved)),
+        }
+    } else {
+        diff::label(kc).to_string()
+    }
+}
+
+/// Render `which` of `layers` as aligned plain-text tables.
+pub fn render(layers: &[Layer], which: &[usize]) -> String {
+    let cells = cells(layers, which);
+    let mut widths = [0usize; COLS];
+    for (c, width) in widths.iter_mut().enumerate() {
+        *width = cells
+            .iter()
+            .flatten()
+            .map(|row| row[c].chars().count())
+            .chain([c.to_string().len()])
+            .max()
+            .unwrap_or(0);
+    }
+    let line = |first: &str, row: &[String]| {
+        let mut out = format!("{first:<2}");
+        for (c, text) in row.iter().enumerate() {
+            out.push_str(if c == COLS_PER_HALF { " | " } else { "  " });
+            out.push_str(&format!("{text:<w$}", w = widths[c]));
+        }
+        out.trim_end().to_string()
+    };
+
+    let header: Vec<String> = (0..COLS).map(|c| c.to_string()).collect();
+    let mut out = String::new();
+    for (i, (&layer, rows)) in which.iter().zip(&cells).enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&format!("Layer {layer}\n"));
+        out.push_str(&line("", &header));
+        out.push('\n');
+        for (r, row) in rows.iter().enumerate() {
+            out.push_str(&line(&r.to_string(), row));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Render `which` of `layers` as Markdown tables, for pasting into notes.
+pub fn render_markdown(layers: &[Layer], which: &[usize]) -> String {
+    let cells = cells(layers, which);
+    let mut out = String::new();
+    for (i, (&layer, rows)) in which.iter().zip(&cells).enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&format!("### Layer {layer}\n\n|   |"));
+        for c in 0..COLS {
+            out.push_str(&format!(" {c} |"));
+        }
+        out.push_str("\n|---|");
+        out.push_str(&"---|".repeat(COLS));
+        out.push('\n');
+        for (r, row) in rows.iter().enumerate() {
+            out.push_str(&format!("| {r} |"));
+            for text in row {
+                out.push_str(&format!(" {} |", markdown_code(text)));
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The same cells as JSON, for `keymap print --json`: per layer its number
+/// and its rows, each a list of cell texts.
+pub fn to_json(layers: &[Layer], which: &[usize]) -> Value {
+    which
+        .iter()
+        .zip(cells(layers, which))
+        .map(|(&layer, rows)| json!({ "layer": layer, "rows": rows }))
+        .collect()
+}
+
+fn cells(layers: &[Layer], which: &[usize]) -> Vec<Vec<Vec<String>>> {
+    which
+        .iter()
+        .map(|&layer| {
+            (0..ROWS)
+                .map(|row| (0..COLS).map(|col| cell(layers, layer, row, col)).collect())
+                .collect()
+        })
+        .collect()
+}
+
+/// A legend as a code span, so `___`, `*` and the like show as typed. A
+/// pipe would still end the table cell, and a backtick the span.
+fn markdown_code(text: &str) -> String {
+    let text = text.replace('|', "\\|");
+    if text.contains('`') {
+        format!("`` {text} ``")
+    } else {
+        format!("`{text}`")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: Layer = [[Keycode::Trans; COLS]; ROWS];
+
+    fn layers() -> Vec<Layer> {
+        let mut base = EMPTY;
+        base[1][1] = Keycode::Q;
+        let mut upper = EMPTY;
+        upper[1][2] = Keycode::Escape;
+        vec![base, upper]
+    }
+
+    #[test]
+    fn upper_layers_show_what_transparent_keys_fall_through_to() {
+        let text = render(&layers(), &[1]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Layer 1");
+        assert_eq!(lines.len(), 2 + ROWS);
+        let row1: Vec<&str> = lines[3].split_whitespace().collect();
+        assert_eq!(&row1[..4], ["1", "___", "(Q)", "Esc"]);
+        // Every row has its bar in the same place
+        let bars: Vec<_> = lines[1..].iter().map(|l| l.find('|')).collect();
+        assert!(bars.iter().all(|&b| b.is_some() && b == bars[0]));
+    }
+
+    #[test]
+    fn markdown_cells_are_code_with_pipes_escaped() {
+        assert_eq!(markdown_code("___"), "`___`");
+        assert_eq!(markdown_code("|"), "`\\|`");
+        assert_eq!(markdown_code("`"), "`` ` ``");
+        let md = render_markdown(&layers(), &[0, 1]);
+        assert!(md.starts_with("### Layer 0\n\n|   | 0 | 1 |"));
+        assert_eq!(md.matches("### Layer").count(), 2);
+        assert!(md.contains("| 1 | `___` | `(Q)` | `Esc` |"));
+    }
+
+    #[test]
+    fn json_has_the_same_cells_by_layer() {
+        let value = to_json(&layers(), &[1]);
+        assert_eq!(value[0]["layer"], 1);
+        assert_eq!(value[0]["rows"].as_array().unwrap().len(), ROWS);
+        assert_eq!(value[0]["rows"][1][1], "(Q)");
+        assert_eq!(value[0]["rows"][1][2], "Esc");
+    }
+}
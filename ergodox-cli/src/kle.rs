@@ -0,0 +1,507 @@
+//! Export the keymap as keyboard-layout-editor.com (KLE) JSON.
+//!
+//! KLE's serialized format is an array of rows. Each row is an array of
+//! property objects and legend strings: an object changes the cursor or the
+//! size of the next key, and a string emits a key at the cursor. The cursor
+//! moves right by the key width after each key, and down by one unit (back
+//! to x = 0) at the end of each row.
+//!
+//! The ErgoDox's column stagger doesn't fit KLE's row model, so every key is
+//! emitted as its own row with an explicit offset. KLE renders this exactly
+//! and will happily re-flow it if the user edits the layout.
+//!
+//! Importing goes the other way: any KLE file (ours, or a community layout
+//! for a clone board) is replayed into absolute key positions that the
+//! visualizer can render in place of the built-in geometry.
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::{Keycode, COLS, ROWS};
+use serde_json::{json, Map, Value};
+
+use crate::codegen::Layer;
+use crate::layout::{self, Key, GAP, S};
+
+/// KLE supports at most 12 legend positions per key.
+pub const MAX_LEGENDS: usize = 12;
+
+/// Generate KLE JSON for the given layers.
+///
+/// Layer N's legend goes into KLE legend slot N (with alignment `a: 0`,
+/// slots are 0 = top-left, 1 = bottom-left, 2 = top-right, 3 = bottom-right,
+/// and so on). Transparent keys on higher layers are left blank so each
+/// legend shows what that layer actually changes.
+pub fn export(layers: &[[[Keycode; COLS]; ROWS]]) -> String {
+    let keys = layout::build_keys();
+
+    let mut rows: Vec<Value> = vec![json!({ "name": "ErgoDox", "author": "ergodox-cli" })];
+
+    // KLE's cursor y at the start of the next row.
+    let mut cursor_y = 0.0;
+    for (i, key) in keys.iter().enumerate() {
+        let (x, y) = (key.x / S, key.y / S);
+
+        let mut props = Map::new();
+        if i == 0 {
+            // Alignment 0 maps legend index N straight to slot N. It
+            // persists for the rest of the layout.
+            props.insert("a".into(), json!(0));
+        }
+        props.insert("x".into(), json!(round(x)));
+        props.insert("y".into(), json!(round(y - cursor_y)));
+        let (w, h) = (units(key.w), units(key.h));
+        if w != 1.0 {
+            props.insert("w".into(), json!(w));
+        }
+        if h != 1.0 {
+            props.insert("h".into(), json!(h));
+        }
+
+        rows.push(json!([
+            Value::Object(props),
+            legends(layers, key.row, key.col)
+        ]));
+        cursor_y = y + 1.0;
+    }
+
+    serde_json::to_string_pretty(&Value::Array(rows)).expect("JSON values always serialize")
+}
+
+/// Newline-separated legends for one key, one slot per layer.
+fn legends(layers: &[[[Keycode; COLS]; ROWS]], row: usize, col: usize) -> String {
+    let mut labels: Vec<&str> = layers
+        .iter()
+        .take(MAX_LEGENDS)
+        .map(|layer| layer[row][col].display_name())
+        .collect();
+
+    // KLE treats missing trailing slots as empty; keep the output tidy.
+    while labels.last() == Some(&"") {
+        labels.pop();
+    }
+    labels.join("\n")
+}
+
+/// Key positions and legends read from a KLE file.
+pub struct KleImport {
+    /// Physical keys, in file order, with matrix positions assigned.
+    pub keys: Vec<Key>,
+    /// Legends for each key (parallel to `keys`), by KLE legend index.
+    pub legends: Vec<Vec<String>>,
+}
+
+/// KLE's running cursor while replaying a layout.
+struct Cursor {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+    /// Rotation angle in degrees and its origin, in key units.
+    r: f64,
+    rx: f64,
+    ry: f64,
+}
+
+/// Parse KLE JSON into absolute key positions.
+///
+/// Matrix positions come from a `"row,col"` first legend when present (the
+/// convention used by matrix-annotated KLE files). Otherwise each key takes
+/// the nearest unclaimed position from the built-in ErgoDox geometry, which
+/// handles our own exports and clones that only move keys around a little.
+///
+/// Rotated clusters are placed at their rotated center but drawn upright.
+pub fn import(json: &str) -> Result<KleImport> {
+    let doc: Value = serde_json::from_str(json).context("invalid JSON")?;
+    let rows = doc.as_array().context("KLE layout must be a JSON array")?;
+
+    let mut placed: Vec<(f64, f64, f64, f64, Vec<String>)> = Vec::new();
+    let mut c = Cursor {
+        x: 0.0,
+        y: 0.0,
+        w: 1.0,
+        h: 1.0,
+        r: 0.0,
+        rx: 0.0,
+        ry: 0.0,
+    };
+
+    for (row_idx, row) in rows.iter().enumerate() {
+        // A leading object holds keyboard metadata, not a row.
+        if row_idx == 0 && row.is_object() {
+            continue;
+        }
+        let items = row
+            .as_array()
+            .with_context(|| format!("row {row_idx} is not an array"))?;
+
+        for item in items {
+            match item {
+                Value::String(label) => {
+                    let (x, y) = rotate_center(&c);
+                    let legends = label.split('\n').map(str::to_string).collect();
+                    placed.push((x - c.w / 2.0, y - c.h / 2.0, c.w, c.h, legends));
+                    c.x += c.w;
+                    c.w = 1.0;
+                    c.h = 1.0;
+                }
+                Value::Object(props) => apply_props(&mut c, props)?,
+                other => bail!("row {row_idx}: unexpected item {other}"),
+            }
+        }
+
+        c.y += 1.0;
+        c.x = c.rx;
+    }
+
+    if placed.is_empty() {
+        bail!("KLE layout contains no keys");
+    }
+
+    // Shift so the top-left key sits at the origin, as the built-in layout does.
+    let min_x = placed.iter().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let min_y = placed.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+
+    let mut keys = Vec::with_capacity(placed.len());
+    let mut legends = Vec::with_capacity(placed.len());
+    for (x, y, w, h, labels) in placed {
+        keys.push(Key {
+            x: (x - min_x) * S,
+            y: (y - min_y) * S,
+            w: w * S - GAP,
+            h: h * S - GAP,
+            row: usize::MAX,
+            col: usize::MAX,
+        });
+        legends.push(labels);
+    }
+
+    assign_matrix(&mut keys, &legends)?;
+    Ok(KleImport { keys, legends })
+}
+
+/// Apply a KLE property object to the cursor.
+fn apply_props(c: &mut Cursor, props: &Map<String, Value>) -> Result<()> {
+    let get = |name: &str| -> Result<Option<f64>> {
+        match props.get(name) {
+            None => Ok(None),
+            Some(v) => v
+                .as_f64()
+                .map(Some)
+                .with_context(|| format!("property {name:?} is not a number")),
+        }
+    };
+
+    if let Some(r) = get("r")? {
+        c.r = r;
+    }
+    let rx = get("rx")?;
+    let ry = get("ry")?;
+    if let Some(rx) = rx {
+        c.rx = rx;
+    }
+    if let Some(ry) = ry {
+        c.ry = ry;
+    }
+    // Setting a rotation origin also moves the cursor there.
+    if rx.is_some() || ry.is_some() {
+        c.x = c.rx;
+        c.y = c.ry;
+    }
+    if let Some(x) = get("x")? {
+        c.x += x;
+    }
+    if let Some(y) = get("y")? {
+        c.y += y;
+    }
+    if let Some(w) = get("w")? {
+        c.w = w;
+    }
+    if let Some(h) = get("h")? {
+        c.h = h;
+    }
+    Ok(())
+}
+
+/// Center of the key at the cursor, after applying the cluster rotation.
+fn rotate_center(c: &Cursor) -> (f64, f64) {
+    let (cx, cy) = (c.x + c.w / 2.0, c.y + c.h / 2.0);
+    if c.r == 0.0 {
+        return (cx, cy);
+    }
+    let (sin, cos) = c.r.to_radians().sin_cos();
+    let (dx, dy) = (cx - c.rx, cy - c.ry);
+    (c.rx + dx * cos - dy * sin, c.ry + dx * sin + dy * cos)
+}
+
+/// Parse a `"row,col"` matrix annotation.
+fn matrix_legend(label: &str) -> Option<(usize, usize)> {
+    let (r, c) = label.split_once(',')?;
+    let (r, c) = (r.trim().parse().ok()?, c.trim().parse().ok()?);
+    (r < ROWS && c < COLS).then_some((r, c))
+}
+
+/// Give every imported key a matrix position (see [`import`]).
+fn assign_matrix(keys: &mut [Key], legends: &[Vec<String>]) -> Result<()> {
+    let mut taken = [[false; COLS]; ROWS];
+
+    for (key, labels) in keys.iter_mut().zip(legends) {
+        if let Some((r, c)) = labels.first().and_then(|l| matrix_legend(l)) {
+            if taken[r][c] {
+                bail!("matrix position {r},{c} is used by more than one key");
+            }
+            taken[r][c] = true;
+            key.row = r;
+            key.col = c;
+        }
+    }
+
+    // Compare in the same frame: imports are normalised to the origin.
+    let reference = layout::build_keys();
+    let ref_x = reference.iter().map(|k| k.x).fold(f64::INFINITY, f64::min);
+    let ref_y = reference.iter().map(|k| k.y).fold(f64::INFINITY, f64::min);
+    let ref_center = |k: &Key| (k.x - ref_x + k.w / 2.0, k.y - ref_y + k.h / 2.0);
+
+    for key in keys.iter_mut().filter(|k| k.row == usize::MAX) {
+        let center = (key.x + key.w / 2.0, key.y + key.h / 2.0);
+        let nearest = reference
+            .iter()
+            .filter(|r| !taken[r.row][r.col])
+            .min_by(|a, b| {
+                let da = distance(center, ref_center(a));
+                let db = distance(center, ref_center(b));
+                da.total_cmp(&db)
+            })
+            .context(
+                "more keys than ErgoDox matrix positions; annotate them with \"row,col\" legends",
+            )?;
+        taken[nearest.row][nearest.col] = true;
+        key.row = nearest.row;
+        key.col = nearest.col;
+    }
+    Ok(())
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Build keymap layers from imported legends: legend index N becomes layer N.
+///
+/// Legends are matched against [`Keycode::display_name`], so files produced
+/// by `export --format kle` round-trip. Anything unrecognised becomes
+/// transparent and is reported back as `(row, col, legend)`.
+pub fn skeleton(import: &KleImport) -> (Vec<Layer>, Vec<(usize, usize, String)>) {
+    let num_layers = import
+        .legends
+        .iter()
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut layers = vec![[[Keycode::Trans; COLS]; ROWS]; num_layers];
+    let mut unknown = Vec::new();
+
+    for (key, labels) in import.keys.iter().zip(&import.legends) {
+        if labels.first().is_some_and(|l| matrix_legend(l).is_some()) {
+            continue;
+        }
+        for (layer, label) in labels.iter().enumerate() {
+            if label.is_empty() {
+                continue;
+            }
+            match Keycode::ALL.iter().find(|kc| kc.display_name() == label) {
+                Some(&kc) => layers[layer][key.row][key.col] = kc,
+                None => unknown.push((key.row, key.col, label.clone())),
+            }
+        }
+    }
+
+    (layers, unknown)
+}
+
+/// Convert a key size in SVG pixels to KLE units (1u includes the gap).
+fn units(size: f64) -> f64 {
+    round((size + GAP) / S)
+}
+
+/// Round to 1/1000 of a unit — KLE only needs a few decimals, and this
+/// keeps float noise like 0.6499999 out of the exported file.
+fn round(v: f64) -> f64 {
+    (v * 1000.0).round() / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::LAYERS;
+
+    fn parsed() -> Vec<Value> {
+        serde_json::from_str::<Value>(&export(&LAYERS))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    // =========================================================================
+    // Document shape
+    // =========================================================================
+    //
+    // A KLE document is [metadata?, row, row, ...]. We emit one key per row,
+    // so there must be exactly 76 rows after the metadata object.
+
+    #[test]
+    fn first_element_is_metadata() {
+        assert!(parsed()[0].is_object());
+        assert_eq!(parsed()[0]["name"], "ErgoDox");
+    }
+
+    #[test]
+    fn one_row_per_physical_key() {
+        assert_eq!(parsed().len() - 1, 76);
+    }
+
+    #[test]
+    fn alignment_is_set_once_on_the_first_key() {
+        let rows = parsed();
+        assert_eq!(rows[1][0]["a"], 0);
+        assert!(rows[2][0].get("a").is_none());
+    }
+
+    // =========================================================================
+    // Geometry
+    // =========================================================================
+    //
+    // KLE y offsets are relative to the cursor, which sits one unit below the
+    // previous row. Replaying the offsets must reproduce the absolute layout.
+
+    #[test]
+    fn replayed_offsets_reproduce_absolute_positions() {
+        let keys = layout::build_keys();
+        let mut cursor_y = 0.0;
+        for (key, row) in keys.iter().zip(parsed().iter().skip(1)) {
+            let props = &row[0];
+            let y = cursor_y + props["y"].as_f64().unwrap();
+            assert!(
+                (y - key.y / S).abs() < 0.01,
+                "row {} col {}",
+                key.row,
+                key.col
+            );
+            assert!((props["x"].as_f64().unwrap() - key.x / S).abs() < 0.01);
+            cursor_y = y + 1.0;
+        }
+    }
+
+    #[test]
+    fn tall_keys_get_height_units() {
+        // The thumb cluster's 2u keys and the inner column's 1.5u keys.
+        let heights: Vec<f64> = parsed()
+            .iter()
+            .skip(1)
+            .filter_map(|row| row[0]["h"].as_f64())
+            .collect();
+        assert!(heights.contains(&2.0));
+        assert!(heights.contains(&1.5));
+    }
+
+    // =========================================================================
+    // Legends
+    // =========================================================================
+
+    #[test]
+    fn legends_are_one_slot_per_layer() {
+        // Row 0 col 1 is "1" on layer 0 and F1 on layer 1.
+        assert_eq!(legends(&LAYERS, 0, 1), "1\nF1");
+    }
+
+    #[test]
+    fn trailing_blank_legends_are_trimmed() {
+        // Row 1 col 1 is Q on layer 0 and transparent on layer 1.
+        assert_eq!(legends(&LAYERS, 1, 1), "Q");
+    }
+
+    // =========================================================================
+    // Import
+    // =========================================================================
+    //
+    // The importer replays KLE's cursor rules: x accumulates within a row,
+    // each row ends one unit lower, and w/h apply to the next key only.
+
+    #[test]
+    fn export_then_import_round_trips_geometry() {
+        // Imports are normalised to start at the origin, so compare
+        // positions relative to each layout's top-left corner.
+        let imported = import(&export(&LAYERS)).unwrap().keys;
+        let reference = layout::build_keys();
+        let origin = |keys: &[Key]| {
+            let x = keys.iter().map(|k| k.x).fold(f64::INFINITY, f64::min);
+            let y = keys.iter().map(|k| k.y).fold(f64::INFINITY, f64::min);
+            (x, y)
+        };
+        let (ax, ay) = origin(&imported);
+        let (bx, by) = origin(&reference);
+
+        assert_eq!(imported.len(), reference.len());
+        for (a, b) in imported.iter().zip(&reference) {
+            assert_eq!((a.row, a.col), (b.row, b.col));
+            assert!(((a.x - ax) - (b.x - bx)).abs() < 0.5);
+            assert!(((a.y - ay) - (b.y - by)).abs() < 0.5);
+            assert!((a.h - b.h).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn export_then_import_round_trips_keymap() {
+        let (layers, unknown) = skeleton(&import(&export(&LAYERS)).unwrap());
+        assert!(unknown.is_empty(), "unrecognised legends: {unknown:?}");
+        assert_eq!(layers.len(), LAYERS.len());
+        for key in layout::build_keys() {
+            for (l, layer) in LAYERS.iter().enumerate() {
+                assert_eq!(layers[l][key.row][key.col], layer[key.row][key.col]);
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_advances_by_key_width_and_resets_per_row() {
+        let kle = r#"[["0,0", {"w": 2}, "0,1", "0,2"], ["1,0"]]"#;
+        let keys = import(kle).unwrap().keys;
+        assert_eq!(keys[1].x, S);
+        assert_eq!(keys[2].x, 3.0 * S, "after a 2u key");
+        assert_eq!((keys[3].x, keys[3].y), (0.0, S), "next row");
+        assert_eq!(keys[2].w, U_PX, "width resets after one key");
+    }
+
+    #[test]
+    fn matrix_annotations_override_nearest_match() {
+        let kle = r#"[["5,13", "0,0"]]"#;
+        let keys = import(kle).unwrap().keys;
+        assert_eq!((keys[0].row, keys[0].col), (5, 13));
+        assert_eq!((keys[1].row, keys[1].col), (0, 0));
+    }
+
+    #[test]
+    fn duplicate_matrix_annotations_are_rejected() {
+        assert!(import(r#"[["1,1", "1,1"]]"#).is_err());
+    }
+
+    #[test]
+    fn rotation_swings_key_center_around_origin() {
+        // A 1u key at the rotation origin, rotated 90° clockwise (KLE's y
+        // axis points down): its center (0.5, 0.5) swings to (-0.5, 0.5).
+        let c = Cursor {
+            x: 0.0,
+            y: 0.0,
+            w: 1.0,
+            h: 1.0,
+            r: 90.0,
+            rx: 0.0,
+            ry: 0.0,
+        };
+        let (x, y) = rotate_center(&c);
+        assert!((x + 0.5).abs() < 1e-9 && (y - 0.5).abs() < 1e-9);
+    }
+
+    /// Width of a 1u key in SVG pixels.
+    const U_PX: f64 = S - GAP;
+}
@@ -1,259 +1,348 @@
 //! Generate an HTML/SVG visualization of the ErgoDox keymap.
 //! Each key is a purr-fectly positioned rectangle with its label. :3
 
-use ergodox_keymap::{Keycode, LAYERS, NUM_LAYERS};
+use std::collections::HashMap;
+
+pub use ergodox_keymap::typing::{self, Language};
+use ergodox_keymap::{
+    KeyLabel, Keycode, LayerColor, COLS, COLS_PER_HALF, KEY_LABELS, LAYERS, LAYER_COLORS, ROWS,
+};
+use serde_json::{json, Map, Value};
+
+use crate::bundle::Bundle;
+use crate::codegen;
+use crate::diff::{self, Change};
+use crate::geometry;
+use crate::pdf::{self, Font, Page, PageSize, Rgb};
 
 /// Physical key position and size for SVG rendering.
-struct Key {
-    x: f64,
-    y: f64,
-    w: f64,
-    h: f64,
-    row: usize,
-    col: usize,
+pub(crate) struct Key {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    pub row: usize,
+    pub col: usize,
 }
 
 /// Key unit size in SVG pixels.
 const U: f64 = 54.0;
 /// Gap between keys.
-const GAP: f64 = 4.0;
+pub(crate) const GAP: f64 = 4.0;
 /// Step: key + gap.
-const S: f64 = U + GAP;
+pub(crate) const S: f64 = U + GAP;
 /// Key corner radius.
 const R: f64 = 4.0;
 /// Spacing between left and right halves.
-const HALF_GAP: f64 = 60.0;
+pub(crate) const HALF_GAP: f64 = 60.0;
 /// Margin around the SVG content.
 const MARGIN: f64 = 20.0;
 
-/// Column stagger for the left half (y offset in units of S).
-/// Index 0 = outermost (pinky extra), index 6 = innermost.
-const STAGGER: [f64; 7] = [0.50, 0.25, 0.00, -0.15, 0.10, 0.40, 0.65];
+/// The keymap a rendering shows: its layers, custom legends and layer colors.
+pub(crate) struct Keymap<'a> {
+    pub layers: &'a [codegen::Layer],
+    pub labels: &'a [KeyLabel],
+    /// Indexed like `layers`; layers past the end get the title red.
+    pub colors: &'a [LayerColor],
+}
 
-/// Build all physical key positions for both halves.
-fn build_keys() -> Vec<Key> {
-    let mut keys = Vec::new();
+impl Keymap<'static> {
+    /// The keymap compiled into this binary.
+    pub(crate) fn builtin() -> Self {
+        Keymap {
+            layers: &LAYERS,
+            labels: KEY_LABELS,
+            colors: &LAYER_COLORS,
+        }
+    }
+}
 
-    // Left half at origin
-    build_half(&mut keys, true, 0.0, 0.0);
+impl<'a> Keymap<'a> {
+    /// Layers from a keymap file, for `layout --keymap`. They keep the
+    /// built-in layer colors, but not its labels, which name keys the file
+    /// may well have moved.
+    pub(crate) fn file(layers: &'a [codegen::Layer]) -> Self {
+        Keymap {
+            layers,
+            labels: &[],
+            colors: &LAYER_COLORS,
+        }
+    }
 
-    // Right half offset to the right
-    let right_x = 7.0 * S + HALF_GAP;
-    build_half(&mut keys, false, right_x, 0.0);
+    /// A bundle's layers, with its own labels and colors.
+    pub(crate) fn bundle(bundle: &'a Bundle) -> Self {
+        Keymap {
+            layers: &bundle.layers,
+            labels: &bundle.labels,
+            colors: &bundle.colors,
+        }
+    }
 
-    keys
+    fn color(&self, layer_idx: usize) -> ergodox_keymap::Rgb {
+        self.colors
+            .get(layer_idx)
+            .map_or(ergodox_keymap::Rgb(0xe9, 0x45, 0x60), |c| c.rgb)
+    }
 }
 
-/// Build key positions for one half of the ErgoDox.
-///
-/// Left half: local col 0 = outer (pinky), local col 6 = inner.
-/// Right half: local col 0 = inner, local col 6 = outer (mirrored).
-fn build_half(keys: &mut Vec<Key>, is_left: bool, bx: f64, by: f64) {
-    let col_offset: usize = if is_left { 0 } else { 7 };
-
-    // Stagger: left uses as-is, right reverses (inner col is on the left side)
-    let stagger: [f64; 7] = if is_left {
-        STAGGER
-    } else {
-        let mut s = STAGGER;
-        s.reverse();
-        s
-    };
+/// Build all physical key positions for both halves, from the built-in
+/// geometry table (see `geometry.rs`).
+pub(crate) fn build_keys() -> Vec<Key> {
+    geometry::builtin()
+}
+
+/// Compute the bounding box of all keys: (max_x + w, max_y + h).
+fn bbox(keys: &[Key]) -> (f64, f64) {
+    let mut max_x: f64 = 0.0;
+    let mut max_y: f64 = 0.0;
+    for k in keys {
+        max_x = max_x.max(k.x + k.w);
+        max_y = max_y.max(k.y + k.h);
+    }
+    (max_x, max_y)
+}
 
-    // Which local column is the inner extra column (1.5u tall keys, no row 2)?
-    let inner_lc: usize = if is_left { 6 } else { 0 };
+/// How element styles are expressed in the generated SVG.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Styling {
+    /// `class="..."` attributes, styled by the `<style>` block in the HTML page.
+    Css,
+    /// Presentation attributes on every element, for standalone SVG files.
+    /// Inkscape and most documentation pipelines ignore or mangle `<style>`.
+    Inline,
+}
+
+/// Style attributes for an element of the given class.
+fn style_attrs(styling: Styling, class: &str) -> String {
+    if styling == Styling::Css {
+        return format!(r#"class="{class}""#);
+    }
 
-    // --- Main section: rows 0-3, all columns except inner ---
-    for lc in 0..7 {
-        if lc == inner_lc {
-            continue;
+    let attrs = match class {
+        "key unused" => {
+            r##"fill="#0d1117" stroke="#21262d" stroke-width="1.5" stroke-dasharray="3 3""##
         }
-        for row in 0..4 {
-            keys.push(Key {
-                x: bx + lc as f64 * S,
-                y: by + (row as f64 + stagger[lc]) * S,
-                w: U,
-                h: U,
-                row,
-                col: col_offset + lc,
-            });
+        "key transparent" => {
+            r##"fill="#1a1a2e" stroke="#30365e" stroke-width="1.5" stroke-dasharray="2 2""##
         }
+        "key layer" => r##"fill="#2d1b4e" stroke="#e94560" stroke-width="2""##,
+        "key modifier" => r##"fill="#1b2e4e" stroke="#53a8b6" stroke-width="1.5""##,
+        "label" => {
+            r##"fill="#eeeeee" font-family="'JetBrains Mono', 'Fira Code', monospace" font-size="13" text-anchor="middle" dominant-baseline="middle""##
+        }
+        "label small" => {
+            r##"fill="#eeeeee" font-family="'JetBrains Mono', 'Fira Code', monospace" font-size="10" text-anchor="middle" dominant-baseline="middle""##
+        }
+        "label dim" => {
+            r##"fill="#9aa0b4" font-family="'JetBrains Mono', 'Fira Code', monospace" font-size="9" text-anchor="middle" dominant-baseline="middle""##
+        }
+        "layer-title" => {
+            r##"fill="#e94560" font-family="system-ui, -apple-system, sans-serif" font-size="16" font-weight="bold""##
+        }
+        _ => r##"fill="#16213e" stroke="#0f3460" stroke-width="1.5""##,
+    };
+    attrs.to_string()
+}
+
+/// The legends on a keycap. Empty strings are legends the key doesn't have.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Legends {
+    /// What a tap types.
+    pub tap: &'static str,
+    /// What it types with Shift, where that isn't just the capital letter.
+    pub shift: &'static str,
+    /// What it types with AltGr.
+    pub altgr: &'static str,
+}
+
+impl Legends {
+    /// Legends for `kc` when the host uses `language`. Keys without extra
+    /// symbols just get `display_name()`.
+    pub(crate) fn of(kc: Keycode, language: Language) -> Legends {
+        let (tap, shift, altgr) = typing::symbols(kc, language);
+        Legends { tap, shift, altgr }
     }
+}
 
-    // --- Inner column: rows 0 (1u), 1 (1.5u), 3 (1.5u) ---
-    // Align top with the adjacent column so it looks natural.
-    let inner_x = bx + inner_lc as f64 * S;
-    let adj_lc = if is_left { 5 } else { 1 };
-    let inner_top = stagger[adj_lc]; // start at same y as adjacent column
-    let h15u = 1.5 * U + 0.5 * GAP; // 1.5u key height
-
-    // Row 0: 1u
-    keys.push(Key {
-        x: inner_x,
-        y: by + inner_top * S,
-        w: U,
-        h: U,
-        row: 0,
-        col: col_offset + inner_lc,
-    });
-    // Row 1: 1.5u tall
-    keys.push(Key {
-        x: inner_x,
-        y: by + (inner_top + 1.0) * S,
-        w: U,
-        h: h15u,
-        row: 1,
-        col: col_offset + inner_lc,
-    });
-    // Row 3: 1.5u tall
-    keys.push(Key {
-        x: inner_x,
-        y: by + (inner_top + 2.5) * S,
-        w: U,
-        h: h15u,
-        row: 3,
-        col: col_offset + inner_lc,
-    });
-
-    // --- Bottom row: row 4, 5 keys ---
-    // Left: local cols 0-4 (matrix 0-4), Right: local cols 2-6 (matrix 9-13)
-    let bottom_start: usize = if is_left { 0 } else { 2 };
-    let bottom_end: usize = bottom_start + 5;
-    for lc in bottom_start..bottom_end {
-        keys.push(Key {
-            x: bx + lc as f64 * S,
-            y: by + (4.0 + stagger[lc]) * S,
-            w: U,
-            h: U,
-            row: 4,
-            col: col_offset + lc,
-        });
-    }
-
-    // --- Thumb cluster: row 5, 6 keys ---
-    build_thumb(keys, is_left, bx, by);
-}
-
-/// Build the 6-key thumb cluster for one half.
-///
-/// Arrangement (left half, from left to right):
-/// ```text
-///                  [s_top] [s1]
-/// [tall1        ] [tall2 ] [s2]
-/// [             ] [      ] [s3]
-/// ```
-/// - Column A: one 2u tall key
-/// - Column B: one 1u small key on top, one 2u tall key below
-/// - Column C: three 1u keys stacked
+/// How big a legend is drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LegendSize {
+    Normal,
+    Small,
+}
+
+impl LegendSize {
+    fn class(self) -> &'static str {
+        match self {
+            LegendSize::Normal => "label",
+            LegendSize::Small => "label small",
+        }
+    }
+}
+
+/// Where each legend goes on a key at (x, y) of size w by h: the centers of
+/// the text, in reading order.
 ///
-/// Right half is mirrored.
-fn build_thumb(keys: &mut Vec<Key>, is_left: bool, bx: f64, by: f64) {
-    let ty = by + 5.5 * S;
-    let h2u = 2.0 * U + GAP; // height of a 2u key
-
-    // (matrix_col, x, y, h)
-    let positions: [(usize, f64, f64, f64); 6] = if is_left {
-        // Left thumb cluster: tall keys on left, stacked smalls on right
-        let tx = bx + 4.0 * S;
-        [
-            (3, tx, ty + S, h2u),               // col A: tall1 (2u)
-            (5, tx + S, ty, U),                 // col B top: small above tall2
-            (2, tx + S, ty + S, h2u),           // col B bot: tall2 (2u)
-            (4, tx + 2.0 * S, ty, U),           // col C: small 1 (top)
-            (1, tx + 2.0 * S, ty + S, U),       // col C: small 2 (mid)
-            (0, tx + 2.0 * S, ty + 2.0 * S, U), // col C: small 3 (bot)
-        ]
+/// A key with only a tap legend keeps it in the middle. Shift and AltGr
+/// symbols go where keycaps print them: shifted top-left above the tap
+/// legend, AltGr bottom-right.
+fn place_legends(
+    (x, y, w, h): (f64, f64, f64, f64),
+    legends: &Legends,
+) -> Vec<(f64, f64, LegendSize, &'static str)> {
+    let mut placed = Vec::new();
+    let tap_size = if legends.tap.chars().count() > 3 {
+        LegendSize::Small
     } else {
-        // Right thumb cluster: mirrored — stacked smalls on left, tall keys on right
-        let tx = bx + GAP;
-        [
-            (9, tx, ty, U),                  // col C: small 1 (top)
-            (12, tx, ty + S, U),             // col C: small 2 (mid)
-            (13, tx, ty + 2.0 * S, U),       // col C: small 3 (bot)
-            (8, tx + S, ty, U),              // col B top: small above tall2
-            (11, tx + S, ty + S, h2u),       // col B bot: tall2 (2u)
-            (10, tx + 2.0 * S, ty + S, h2u), // col A: tall1 (2u)
-        ]
+        LegendSize::Normal
     };
 
-    for (col, x, y, h) in positions {
-        keys.push(Key {
-            x,
-            y,
-            w: U,
-            h,
-            row: 5,
-            col,
-        });
+    if legends.shift.is_empty() && legends.altgr.is_empty() {
+        placed.push((x + w / 2.0, y + h / 2.0 + 1.0, tap_size, legends.tap));
+    } else {
+        let (left, right) = (x + w * 0.3, x + w * 0.7);
+        placed.push((left, y + h * 0.3, LegendSize::Normal, legends.shift));
+        placed.push((left, y + h * 0.65, tap_size, legends.tap));
+        placed.push((right, y + h * 0.65, LegendSize::Small, legends.altgr));
     }
+
+    placed.retain(|&(_, _, _, text)| !text.is_empty());
+    placed
 }
 
-/// Compute the bounding box of all keys: (max_x + w, max_y + h).
-fn bbox(keys: &[Key]) -> (f64, f64) {
-    let mut max_x: f64 = 0.0;
-    let mut max_y: f64 = 0.0;
-    for k in keys {
-        max_x = max_x.max(k.x + k.w);
-        max_y = max_y.max(k.y + k.h);
+/// Style class and legends for a key on a given layer.
+///
+/// Transparent keys on higher layers show the legends they fall through to,
+/// so each rendered layer reads as "what you get while holding it". A label
+/// from the keymap's labels replaces the keycode's legends.
+fn key_face(
+    keymap: &Keymap,
+    layer_idx: usize,
+    key: &Key,
+    language: Language,
+) -> (&'static str, Legends) {
+    let kc = keymap.layers[layer_idx][key.row][key.col];
+
+    // For non-base layers, show the resolved key (fall-through)
+    let display_kc = if layer_idx > 0 && kc.is_transparent() {
+        ergodox_keymap::lookup_in(keymap.layers, layer_idx, key.row, key.col)
+    } else {
+        kc
+    };
+
+    let legends =
+        match ergodox_keymap::label_in(keymap.layers, keymap.labels, layer_idx, key.row, key.col) {
+            Some(label) => Legends {
+                tap: label,
+                ..Legends::default()
+            },
+            None => Legends::of(display_kc, language),
+        };
+    let is_transparent = layer_idx > 0 && kc.is_transparent();
+
+    let key_class = if kc == Keycode::Trans && layer_idx == 0 {
+        "key unused"
+    } else if is_transparent {
+        "key transparent"
+    } else if kc.is_layer() {
+        "key layer"
+    } else if kc.is_modifier() {
+        "key modifier"
+    } else {
+        "key"
+    };
+
+    (key_class, legends)
+}
+
+/// The layer whose color a key is outlined in, if any: a layer key takes
+/// the layer it switches to, and a key a higher layer defines itself takes
+/// that layer's.
+fn key_tint(keymap: &Keymap, layer_idx: usize, key: &Key) -> Option<usize> {
+    let kc = keymap.layers[layer_idx][key.row][key.col];
+    if kc.is_layer() {
+        Some(kc.layer_number()).filter(|&l| l < keymap.layers.len())
+    } else if layer_idx > 0 && !kc.is_transparent() {
+        Some(layer_idx)
+    } else {
+        None
     }
-    (max_x, max_y)
+}
+
+/// A layer's color as `#rrggbb`.
+fn layer_css_color(keymap: &Keymap, layer_idx: usize) -> String {
+    let ergodox_keymap::Rgb(r, g, b) = keymap.color(layer_idx);
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// A layer's color for the PDF writer.
+fn layer_pdf_color(keymap: &Keymap, layer_idx: usize) -> Rgb {
+    let ergodox_keymap::Rgb(r, g, b) = keymap.color(layer_idx);
+    Rgb(r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
+}
+
+/// Title shown above each rendered layer.
+fn layer_title(layer_idx: usize) -> String {
+    format!(
+        "Layer {layer_idx}{}",
+        if layer_idx == 0 {
+            " (Default)"
+        } else {
+            " (Fn)"
+        }
+    )
 }
 
 /// Render a single layer as an SVG group.
-fn render_layer(keys: &[Key], layer_idx: usize, y_offset: f64) -> String {
+fn render_layer(
+    keys: &[Key],
+    keymap: &Keymap,
+    layer_idx: usize,
+    language: Language,
+    y_offset: f64,
+    styling: Styling,
+) -> String {
     let mut svg = String::new();
 
     svg.push_str(&format!(
         r#"<g transform="translate({MARGIN}, {y_offset})">"#
     ));
 
-    // Layer title
+    // Layer title, in the layer's color. A style attribute beats both the
+    // class's CSS and the inline presentation attributes.
     svg.push_str(&format!(
-        r#"<text x="0" y="-10" class="layer-title">Layer {layer_idx}{}</text>"#,
-        if layer_idx == 0 {
-            " (Default)"
-        } else {
-            " (Fn)"
-        }
+        r#"<text x="0" y="-10" {} style="fill:{}">{}</text>"#,
+        style_attrs(styling, "layer-title"),
+        layer_css_color(keymap, layer_idx),
+        layer_title(layer_idx),
     ));
 
     for key in keys {
-        let kc = LAYERS[layer_idx][key.row][key.col];
-
-        // For non-base layers, show the resolved key (fall-through)
-        let display_kc = if layer_idx > 0 && kc.is_transparent() {
-            ergodox_keymap::lookup(layer_idx, key.row, key.col)
-        } else {
-            kc
+        let (key_class, legends) = key_face(keymap, layer_idx, key, language);
+        let tint = match key_tint(keymap, layer_idx, key) {
+            Some(layer) => format!(r#" style="stroke:{}""#, layer_css_color(keymap, layer)),
+            None => String::new(),
         };
-
-        let label = display_kc.display_name();
-        let is_transparent = layer_idx > 0 && kc.is_transparent();
-
-        let key_class = if kc == Keycode::Trans && layer_idx == 0 {
-            "key unused"
-        } else if is_transparent {
-            "key transparent"
-        } else if kc.is_layer() {
-            "key layer"
-        } else if kc.is_modifier() {
-            "key modifier"
-        } else {
-            "key"
+        // What the HTML page's popovers look keys up by
+        let data_key = match styling {
+            Styling::Css => format!(r#" data-key="{},{}""#, key.row, key.col),
+            Styling::Inline => String::new(),
         };
 
         svg.push_str(&format!(
-            r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{R}" class="{key_class}"/>"#,
-            key.x, key.y, key.w, key.h,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{R}" {}{tint}{data_key}/>"#,
+            key.x,
+            key.y,
+            key.w,
+            key.h,
+            style_attrs(styling, key_class),
         ));
 
-        if !label.is_empty() {
-            let font_class = if label.len() > 3 { " small" } else { "" };
+        for (cx, cy, size, text) in place_legends((key.x, key.y, key.w, key.h), &legends) {
             svg.push_str(&format!(
-                r#"<text x="{}" y="{}" class="label{font_class}">{}</text>"#,
-                key.x + key.w / 2.0,
-                key.y + key.h / 2.0 + 1.0,
-                html_escape(label),
+                r#"<text x="{cx}" y="{cy}" {}>{}</text>"#,
+                style_attrs(styling, size.class()),
+                html_escape(text),
             ));
         }
     }
@@ -269,102 +358,690 @@ fn html_escape(s: &str) -> String {
 }
 
 /// Generate the complete HTML document with inline SVG.
-pub fn generate_html() -> String {
-    let keys = build_keys();
-    let (content_w, content_h) = bbox(&keys);
-    let layer_height = content_h + 60.0;
+///
+/// One of `layers` is shown at a time, picked with buttons above the
+/// keyboard. Hovering a key, or clicking it to keep it open, pops up what it
+/// does on every layer, and which layer a transparent key falls through to.
+/// Without JavaScript the first layer is shown.
+pub fn generate_html(
+    keys: &[Key],
+    keymap: &Keymap,
+    layers: &[usize],
+    language: Language,
+) -> String {
+    let (content_w, content_h) = bbox(keys);
     let total_width = content_w + 2.0 * MARGIN;
-    let total_height = NUM_LAYERS as f64 * layer_height + 2.0 * MARGIN;
+    let total_height = content_h + 60.0 + 2.0 * MARGIN;
 
-    let mut html = format!(
+    let mut html = String::from(
         r#"<!DOCTYPE html>
 <html lang="en">
 <head>
 <meta charset="utf-8">
 <title>ErgoDox Layout</title>
 <style>
-  body {{
+  body {
     background: #1a1a2e;
     color: #eee;
     font-family: system-ui, -apple-system, sans-serif;
     display: flex;
-    justify-content: center;
+    flex-direction: column;
+    align-items: center;
     padding: 2em;
-  }}
-  svg {{
+  }
+  svg {
     filter: drop-shadow(0 2px 8px rgba(0,0,0,0.3));
-  }}
-  .key {{
+  }
+  .layer:not(.active) {
+    display: none;
+  }
+  #layers button {
+    background: #16213e;
+    color: #eee;
+    border: 2px solid #0f3460;
+    border-radius: 4px;
+    padding: 0.3em 0.8em;
+    margin: 0 0.2em;
+    font: inherit;
+    cursor: pointer;
+  }
+  #layers button.active {
+    border-color: currentColor;
+  }
+  #popover {
+    position: absolute;
+    background: #0d1117;
+    border: 1px solid #30365e;
+    border-radius: 4px;
+    padding: 0.4em 0.6em;
+    font-size: 13px;
+    pointer-events: none;
+  }
+  #popover th {
+    text-align: left;
+    padding-right: 0.8em;
+  }
+  #popover td {
+    font-family: "JetBrains Mono", "Fira Code", monospace;
+    padding-right: 0.8em;
+  }
+  #popover .note {
+    color: #9aa0b4;
+    font-family: system-ui, -apple-system, sans-serif;
+  }
+  .key {
     fill: #16213e;
     stroke: #0f3460;
     stroke-width: 1.5;
-  }}
-  .key:hover {{
+  }
+  .key:hover {
     fill: #1a1a5e;
     stroke: #e94560;
-  }}
-  .key.unused {{
+  }
+  .key.unused {
     fill: #0d1117;
     stroke: #21262d;
     stroke-dasharray: 3 3;
-  }}
-  .key.transparent {{
+  }
+  .key.transparent {
     fill: #1a1a2e;
     stroke: #30365e;
     stroke-dasharray: 2 2;
-  }}
-  .key.layer {{
+  }
+  .key.layer {
     fill: #2d1b4e;
     stroke: #e94560;
     stroke-width: 2;
-  }}
-  .key.modifier {{
+  }
+  .key.modifier {
     fill: #1b2e4e;
     stroke: #53a8b6;
     stroke-width: 1.5;
-  }}
-  .label {{
+  }
+  .label {
     fill: #eee;
     font-family: "JetBrains Mono", "Fira Code", monospace;
     font-size: 13px;
     text-anchor: middle;
     dominant-baseline: middle;
     pointer-events: none;
-  }}
-  .label.small {{
+  }
+  .label.small {
     font-size: 10px;
-  }}
-  .layer-title {{
+  }
+  .layer-title {
     fill: #e94560;
     font-family: system-ui, -apple-system, sans-serif;
     font-size: 16px;
     font-weight: bold;
-  }}
+  }
 </style>
 </head>
 <body>
-<svg width="{total_width}" height="{total_height}" xmlns="http://www.w3.org/2000/svg">
-"#
+<nav id="layers">
+"#,
     );
 
-    for layer_idx in 0..NUM_LAYERS {
-        let y_offset = MARGIN + layer_idx as f64 * layer_height + 30.0;
-        html.push_str(&render_layer(&keys, layer_idx, y_offset));
+    for &layer_idx in layers {
+        html.push_str(&format!(
+            r#"<button type="button" data-layer="{layer_idx}" style="color:{}">{}</button>"#,
+            layer_css_color(keymap, layer_idx),
+            layer_title(layer_idx),
+        ));
         html.push('\n');
     }
+    html.push_str(&format!(
+        r#"</nav>
+<svg width="{total_width}" height="{total_height}" xmlns="http://www.w3.org/2000/svg">
+"#
+    ));
 
-    html.push_str("</svg>\n</body>\n</html>\n");
+    for (i, &layer_idx) in layers.iter().enumerate() {
+        html.push_str(&format!(
+            r#"<g class="layer{}" data-layer="{layer_idx}">"#,
+            if i == 0 { " active" } else { "" },
+        ));
+        html.push_str(&render_layer(
+            keys,
+            keymap,
+            layer_idx,
+            language,
+            MARGIN + 30.0,
+            Styling::Css,
+        ));
+        html.push_str("</g>\n");
+    }
+
+    // `</` would end the script early
+    let data = key_bindings(keys, keymap, language)
+        .to_string()
+        .replace("</", "<\\/");
+    html.push_str(&format!(
+        r##"</svg>
+<table id="popover" hidden></table>
+<script>
+const bindings = {data};
+const groups = document.querySelectorAll("svg .layer");
+const buttons = document.querySelectorAll("#layers button");
+const popover = document.getElementById("popover");
+let pinned = null;
+
+function showLayer(layer) {{
+  groups.forEach(g => g.classList.toggle("active", g.dataset.layer === layer));
+  buttons.forEach(b => b.classList.toggle("active", b.dataset.layer === layer));
+}}
+
+function openPopover(key) {{
+  popover.replaceChildren();
+  for (const b of bindings[key.dataset.key]) {{
+    const row = popover.insertRow();
+    const title = document.createElement("th");
+    title.textContent = b.title;
+    title.style.color = b.color;
+    row.append(title);
+    row.insertCell().textContent = [b.tap, b.shift, b.altgr].filter(s => s).join(" ");
+    const note = row.insertCell();
+    note.className = "note";
+    note.textContent = b.from === null ? "" : "from layer " + b.from;
+  }}
+  const box = key.getBoundingClientRect();
+  popover.style.left = window.scrollX + box.left + "px";
+  popover.style.top = window.scrollY + box.bottom + 4 + "px";
+  popover.hidden = false;
+}}
+
+buttons.forEach(b => b.addEventListener("click", () => showLayer(b.dataset.layer)));
+document.querySelectorAll("svg [data-key]").forEach(key => {{
+  key.addEventListener("mouseenter", () => pinned || openPopover(key));
+  key.addEventListener("mouseleave", () => {{ if (!pinned) popover.hidden = true; }});
+  key.addEventListener("click", e => {{
+    e.stopPropagation();
+    pinned = pinned === key ? null : key;
+    openPopover(key);
+  }});
+}});
+document.addEventListener("click", () => {{
+  pinned = null;
+  popover.hidden = true;
+}});
+if (buttons.length) showLayer(buttons[0].dataset.layer);
+</script>
+</body>
+</html>
+"##
+    ));
     html
 }
 
+/// What each key does on every layer, for the HTML popovers, keyed by
+/// `"row,col"`. `from` is the layer a transparent key falls through to.
+fn key_bindings(keys: &[Key], keymap: &Keymap, language: Language) -> Value {
+    let mut bindings = Map::new();
+    for key in keys {
+        let per_layer: Vec<Value> = (0..keymap.layers.len())
+            .map(|layer_idx| {
+                let (_, legends) = key_face(keymap, layer_idx, key, language);
+                let from = (0..=layer_idx)
+                    .rev()
+                    .find(|&l| !keymap.layers[l][key.row][key.col].is_transparent())
+                    .unwrap_or(0);
+                json!({
+                    "title": layer_title(layer_idx),
+                    "color": layer_css_color(keymap, layer_idx),
+                    "tap": legends.tap,
+                    "shift": legends.shift,
+                    "altgr": legends.altgr,
+                    "from": (from != layer_idx).then_some(from),
+                })
+            })
+            .collect();
+        bindings.insert(format!("{},{}", key.row, key.col), Value::Array(per_layer));
+    }
+    Value::Object(bindings)
+}
+
+/// Generate a standalone SVG document of `layers` with all styles inlined
+/// as attributes.
+pub fn generate_svg(keys: &[Key], keymap: &Keymap, layers: &[usize], language: Language) -> String {
+    let (content_w, content_h) = bbox(keys);
+    let layer_height = content_h + 60.0;
+    let total_width = content_w + 2.0 * MARGIN;
+    let total_height = layers.len() as f64 * layer_height + 2.0 * MARGIN;
+
+    let mut svg = format!(
+        r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg width="{total_width}" height="{total_height}" viewBox="0 0 {total_width} {total_height}" xmlns="http://www.w3.org/2000/svg">
+<rect width="100%" height="100%" fill="#1a1a2e"/>
+"##
+    );
+
+    for (i, &layer_idx) in layers.iter().enumerate() {
+        let y_offset = MARGIN + i as f64 * layer_height + 30.0;
+        svg.push_str(&render_layer(
+            keys,
+            keymap,
+            layer_idx,
+            language,
+            y_offset,
+            Styling::Inline,
+        ));
+        svg.push('\n');
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// A cell in the ASCII rendering.
+enum AsciiCell {
+    Key(&'static str),
+    /// The lower part of a key taller than one row.
+    Continued,
+}
+
+/// Generate plain text of `layers`, for a terminal: per layer, both halves'
+/// main blocks side by side, then their thumb clusters.
+///
+/// Main-block keys sit at their matrix row and column, ignoring stagger;
+/// thumb keys go by position, a unit to a cell. Keys a layer leaves
+/// transparent are drawn empty, since the layer below is printed above it.
+pub fn generate_ascii(
+    keys: &[Key],
+    keymap: &Keymap,
+    layers: &[usize],
+    language: Language,
+) -> String {
+    const THUMB_ROW: usize = ROWS - 1;
+    const HALF_GAP: &str = "    ";
+
+    // Each key's (text row, text column) within its half
+    let half_x = |left: bool| {
+        keys.iter()
+            .filter(|k| (k.col < COLS_PER_HALF) == left)
+            .map(|k| k.x)
+            .fold(f64::INFINITY, f64::min)
+    };
+    let (left_x, right_x) = (half_x(true), half_x(false));
+    let thumb_y = keys
+        .iter()
+        .filter(|k| k.row == THUMB_ROW)
+        .map(|k| k.y)
+        .fold(f64::INFINITY, f64::min);
+    let cell = |key: &Key| {
+        let left = key.col < COLS_PER_HALF;
+        let x = key.x - if left { left_x } else { right_x };
+        let row = if key.row == THUMB_ROW {
+            // A blank line between the main block and the thumbs
+            THUMB_ROW + 1 + ((key.y - thumb_y) / S).round() as usize
+        } else {
+            key.row
+        };
+        (left, row, (x / S).round() as usize)
+    };
+    let text_rows = keys.iter().map(|k| cell(k).1 + 1).max().unwrap_or(0);
+    let text_cols = keys.iter().map(|k| cell(k).2 + 1).max().unwrap_or(0);
+
+    let legend = |layer_idx: usize, key: &Key| {
+        let kc = keymap.layers[layer_idx][key.row][key.col];
+        if kc == Keycode::Trans {
+            ""
+        } else {
+            key_face(keymap, layer_idx, key, language).1.tap
+        }
+    };
+    let width = layers
+        .iter()
+        .flat_map(|&l| keys.iter().map(move |k| legend(l, k).chars().count()))
+        .max()
+        .unwrap_or(0)
+        .max(3);
+
+    let mut out = String::new();
+    for (i, &layer_idx) in layers.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&layer_title(layer_idx));
+        out.push('\n');
+
+        let mut grid: HashMap<(bool, usize, usize), AsciiCell> = HashMap::new();
+        for key in keys {
+            let (left, row, col) = cell(key);
+            grid.insert((left, row, col), AsciiCell::Key(legend(layer_idx, key)));
+        }
+        for key in keys.iter().filter(|k| k.h > S) {
+            let (left, row, col) = cell(key);
+            grid.entry((left, row + 1, col))
+                .or_insert(AsciiCell::Continued);
+        }
+
+        for row in 0..text_rows {
+            let mut line = String::new();
+            for left in [true, false] {
+                if !left {
+                    line.push_str(HALF_GAP);
+                }
+                for col in 0..text_cols {
+                    line.push_str(&match grid.get(&(left, row, col)) {
+                        Some(AsciiCell::Key(text)) => format!("[{text:<width$}]"),
+                        Some(AsciiCell::Continued) => format!("[{:^width$}]", "^"),
+                        None => " ".repeat(width + 2),
+                    });
+                }
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Generate a standalone SVG of the base layer with each key filled on a
+/// cold-to-hot gradient by how often it was pressed.
+pub fn generate_heatmap_svg(
+    keys: &[Key],
+    counts: &[[u64; COLS]; ROWS],
+    language: Language,
+) -> String {
+    let (content_w, content_h) = bbox(keys);
+    let total_width = content_w + 2.0 * MARGIN;
+    let total_height = content_h + 2.0 * MARGIN + 30.0;
+    let max = keys
+        .iter()
+        .map(|k| counts[k.row][k.col])
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let total: u64 = keys.iter().map(|k| counts[k.row][k.col]).sum();
+
+    let mut svg = format!(
+        r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg width="{total_width}" height="{total_height}" viewBox="0 0 {total_width} {total_height}" xmlns="http://www.w3.org/2000/svg">
+<rect width="100%" height="100%" fill="#1a1a2e"/>
+<g transform="translate({MARGIN}, {})">
+<text x="0" y="-10" {}>Key presses ({total} total)</text>
+"##,
+        MARGIN + 30.0,
+        style_attrs(Styling::Inline, "layer-title"),
+    );
+
+    for key in keys {
+        let count = counts[key.row][key.col];
+        let (_, Legends { tap: label, .. }) = key_face(&Keymap::builtin(), 0, key, language);
+        svg.push_str(&format!(
+            r##"<rect x="{}" y="{}" width="{}" height="{}" rx="{R}" fill="{}" stroke="#0f3460" stroke-width="1.5"><title>{} ({}, {}): {}</title></rect>"##,
+            key.x,
+            key.y,
+            key.w,
+            key.h,
+            heat_color(count as f64 / max as f64),
+            html_escape(label),
+            key.row,
+            key.col,
+            count,
+        ));
+        svg.push_str(&format!(
+            r#"<text x="{}" y="{}" {}>{}</text>"#,
+            key.x + key.w / 2.0,
+            key.y + key.h / 2.0 - 6.0,
+            style_attrs(Styling::Inline, "label small"),
+            html_escape(label),
+        ));
+        svg.push_str(&format!(
+            r#"<text x="{}" y="{}" {}>{}</text>"#,
+            key.x + key.w / 2.0,
+            key.y + key.h / 2.0 + 10.0,
+            style_attrs(Styling::Inline, "label small"),
+            count,
+        ));
+        svg.push('\n');
+    }
+
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+/// Generate a standalone SVG of `layers` comparing two keymaps, for
+/// `layout --compare`. Keys in `changes` are outlined and show their old
+/// legend over the new one; the rest show their legend in `new`, dimmed.
+pub fn generate_compare_svg(
+    keys: &[Key],
+    new: &[codegen::Layer],
+    changes: &[Change],
+    layers: &[usize],
+) -> String {
+    let (content_w, content_h) = bbox(keys);
+    let layer_height = content_h + 60.0;
+    let total_width = content_w + 2.0 * MARGIN;
+    let total_height = layers.len() as f64 * layer_height + 2.0 * MARGIN;
+
+    let mut svg = format!(
+        r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg width="{total_width}" height="{total_height}" viewBox="0 0 {total_width} {total_height}" xmlns="http://www.w3.org/2000/svg">
+<rect width="100%" height="100%" fill="#1a1a2e"/>
+"##
+    );
+
+    for (i, &layer_idx) in layers.iter().enumerate() {
+        let changed = |key: &Key| {
+            changes
+                .iter()
+                .find(|c| (c.layer, c.row, c.col) == (layer_idx, key.row, key.col))
+        };
+        let count = keys.iter().filter(|&k| changed(k).is_some()).count();
+        svg.push_str(&format!(
+            r#"<g transform="translate({MARGIN}, {})">"#,
+            MARGIN + i as f64 * layer_height + 30.0
+        ));
+        svg.push_str(&format!(
+            r#"<text x="0" y="-10" {}>Layer {layer_idx}: {count} key{} changed</text>"#,
+            style_attrs(Styling::Inline, "layer-title"),
+            if count == 1 { "" } else { "s" },
+        ));
+        for key in keys {
+            let (cx, cy) = (key.x + key.w / 2.0, key.y + key.h / 2.0);
+            match changed(key) {
+                Some(change) => {
+                    svg.push_str(&format!(
+                        r##"<rect x="{}" y="{}" width="{}" height="{}" rx="{R}" fill="#3d1f2b" stroke="#f9c74f" stroke-width="2.5"/>"##,
+                        key.x, key.y, key.w, key.h,
+                    ));
+                    svg.push_str(&format!(
+                        r#"<text x="{cx}" y="{}" {} text-decoration="line-through">{}</text>"#,
+                        cy - 9.0,
+                        style_attrs(Styling::Inline, "label dim"),
+                        html_escape(diff::label(change.old)),
+                    ));
+                    svg.push_str(&format!(
+                        r#"<text x="{cx}" y="{}" {}>{}</text>"#,
+                        cy + 7.0,
+                        style_attrs(Styling::Inline, "label small"),
+                        html_escape(&format!("\u{2192} {}", diff::label(change.new))),
+                    ));
+                }
+                None => {
+                    let kc = new
+                        .get(layer_idx)
+                        .map_or(Keycode::Trans, |layer| layer[key.row][key.col]);
+                    svg.push_str(&format!(
+                        r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{R}" {} opacity="0.5"/>"#,
+                        key.x,
+                        key.y,
+                        key.w,
+                        key.h,
+                        style_attrs(Styling::Inline, "key"),
+                    ));
+                    svg.push_str(&format!(
+                        r#"<text x="{cx}" y="{cy}" {} opacity="0.5">{}</text>"#,
+                        style_attrs(Styling::Inline, "label small"),
+                        html_escape(diff::label(kc)),
+                    ));
+                }
+            }
+        }
+        svg.push_str("</g>\n");
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// `generate_compare_svg` on a bare HTML page, for `layout --compare
+/// --format html`.
+pub fn generate_compare_html(
+    keys: &[Key],
+    new: &[codegen::Layer],
+    changes: &[Change],
+    layers: &[usize],
+) -> String {
+    let svg = generate_compare_svg(keys, new, changes, layers);
+    let svg = svg.split_once('\n').map_or(svg.as_str(), |(_, rest)| rest);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>ErgoDox Layout Changes</title>
+</head>
+<body style="background: #1a1a2e; display: flex; justify-content: center; padding: 2em">
+{svg}</body>
+</html>
+"#
+    )
+}
+
+/// Heatmap fill for a key pressed `t` (0..=1) as often as the busiest key:
+/// the normal key color, through the accent red, to yellow.
+fn heat_color(t: f64) -> String {
+    const STOPS: [(f64, f64, f64); 3] = [
+        (22.0, 33.0, 62.0),   // #16213e
+        (233.0, 69.0, 96.0),  // #e94560
+        (249.0, 199.0, 79.0), // #f9c74f
+    ];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let i = (t as usize).min(STOPS.len() - 2);
+    let f = t - i as f64;
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+    let mix = |x: f64, y: f64| (x + (y - x) * f).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        mix(a.0, b.0),
+        mix(a.1, b.1),
+        mix(a.2, b.2)
+    )
+}
+
+/// Print-friendly (light) fill/stroke for each key class.
+fn pdf_key_style(class: &str) -> pdf::Style {
+    let (fill, stroke, line_width, dash) = match class {
+        "key unused" => (
+            Rgb(1.0, 1.0, 1.0),
+            Rgb(0.75, 0.75, 0.75),
+            0.5,
+            Some((2.0, 2.0)),
+        ),
+        "key transparent" => (
+            Rgb(0.95, 0.95, 0.95),
+            Rgb(0.6, 0.6, 0.6),
+            0.5,
+            Some((1.5, 1.5)),
+        ),
+        "key layer" => (Rgb(0.93, 0.88, 0.98), Rgb(0.55, 0.25, 0.7), 1.2, None),
+        "key modifier" => (Rgb(0.88, 0.94, 0.98), Rgb(0.2, 0.5, 0.6), 0.8, None),
+        _ => (Rgb(1.0, 1.0, 1.0), Rgb(0.2, 0.2, 0.2), 0.8, None),
+    };
+    pdf::Style {
+        fill,
+        stroke,
+        line_width,
+        dash,
+    }
+}
+
+/// Generate a printable PDF cheat-sheet with one of `layers` per landscape
+/// page.
+pub fn generate_pdf(
+    keys: &[Key],
+    keymap: &Keymap,
+    layers: &[usize],
+    language: Language,
+    page_size: PageSize,
+) -> Vec<u8> {
+    /// Page margin in points (half an inch).
+    const PAGE_MARGIN: f64 = 36.0;
+    /// Space reserved at the top of the page for the layer title.
+    const TITLE_SPACE: f64 = 40.0;
+
+    let (content_w, content_h) = bbox(keys);
+
+    // Scale the board (laid out in SVG pixels) to fill the printable area.
+    let avail_w = page_size.width - 2.0 * PAGE_MARGIN;
+    let avail_h = page_size.height - 2.0 * PAGE_MARGIN - TITLE_SPACE;
+    let scale = (avail_w / content_w).min(avail_h / content_h);
+    let ox = PAGE_MARGIN + (avail_w - content_w * scale) / 2.0;
+    let oy = PAGE_MARGIN + TITLE_SPACE;
+
+    let black = Rgb(0.0, 0.0, 0.0);
+    let pages: Vec<Page> = layers
+        .iter()
+        .map(|&layer_idx| {
+            let mut page = Page::new(page_size);
+            page.text(
+                PAGE_MARGIN,
+                PAGE_MARGIN + 16.0,
+                18.0,
+                Font::Bold,
+                layer_pdf_color(keymap, layer_idx),
+                &layer_title(layer_idx),
+            );
+
+            for key in keys {
+                let (key_class, legends) = key_face(keymap, layer_idx, key, language);
+                let (x, y) = (ox + key.x * scale, oy + key.y * scale);
+                let (w, h) = (key.w * scale, key.h * scale);
+                let mut style = pdf_key_style(key_class);
+                if let Some(layer) = key_tint(keymap, layer_idx, key) {
+                    style.stroke = layer_pdf_color(keymap, layer);
+                }
+                page.rect(x, y, w, h, &style);
+
+                // Placed in board units, then scaled like the key
+                for (cx, cy, size, text) in place_legends((key.x, key.y, key.w, key.h), &legends) {
+                    let size = match size {
+                        LegendSize::Normal => 11.0,
+                        LegendSize::Small => 8.0,
+                    };
+                    // Baseline sits a third of the cap height below the center.
+                    page.text_centered(
+                        ox + cx * scale,
+                        oy + cy * scale + size / 3.0,
+                        size,
+                        Font::Regular,
+                        black,
+                        text,
+                    );
+                }
+            }
+            page
+        })
+        .collect();
+
+    pdf::write_document(&pages)
+}
+
 // =============================================================================
 // Tests — literate contracts for the ErgoDox physical layout
 // =============================================================================
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ergodox_keymap::NUM_LAYERS;
     use std::collections::HashSet;
 
+    fn all_layers() -> Vec<usize> {
+        (0..NUM_LAYERS).collect()
+    }
+
     // =========================================================================
     // Physical key count
     // =========================================================================
@@ -512,8 +1189,293 @@ mod tests {
     // Half symmetry
     // =========================================================================
     //
-    // Each half should contribute exactly 38 keys. This ensures build_half()
-    // generates the same structure for both sides (mirrored, but same count).
+    // Each half should contribute exactly 38 keys. This ensures the geometry
+    // table lists the same structure for both sides (mirrored, but same count).
+
+    // =========================================================================
+    // Key legends
+    // =========================================================================
+    //
+    // Nordic keycaps carry up to three symbols: shifted top-left, plain
+    // bottom-left, AltGr bottom-right. The old two-character labels ("+?")
+    // are split back into those slots.
+
+    #[test]
+    fn nordic_symbol_keys_get_shift_and_altgr_legends() {
+        let legends = Legends::of(Keycode::Minus, Language::Nordic);
+        assert_eq!(
+            (legends.tap, legends.shift, legends.altgr),
+            ("+", "?", "\\")
+        );
+        assert_eq!(Legends::of(Keycode::N2, Language::Nordic).altgr, "@");
+        // Letters don't repeat themselves as a shifted legend
+        assert_eq!(Legends::of(Keycode::A, Language::Nordic).shift, "");
+        assert_eq!(Legends::of(Keycode::LCtrl, Language::Nordic).tap, "Ctrl");
+    }
+
+    #[test]
+    fn us_legends_follow_the_us_layout() {
+        let legends = Legends::of(Keycode::Semicolon, Language::Us);
+        assert_eq!((legends.tap, legends.shift), (";", ":"));
+        assert_eq!(Legends::of(Keycode::N2, Language::Us).shift, "@");
+        assert_eq!(Legends::of(Keycode::F1, Language::Us).tap, "F1");
+    }
+
+    #[test]
+    fn lone_tap_legend_stays_centered() {
+        let placed = place_legends(
+            (0.0, 0.0, 54.0, 54.0),
+            &Legends::of(Keycode::A, Language::Nordic),
+        );
+        assert_eq!(placed, vec![(27.0, 28.0, LegendSize::Normal, "A")]);
+    }
+
+    #[test]
+    fn shift_sits_above_tap_and_altgr_to_the_right() {
+        let placed = place_legends(
+            (0.0, 0.0, 100.0, 100.0),
+            &Legends::of(Keycode::N7, Language::Nordic),
+        );
+        let at = |text| placed.iter().find(|p| p.3 == text).unwrap();
+        let (shift, tap, altgr) = (at("/"), at("7"), at("{"));
+        assert_eq!(shift.0, tap.0, "same column");
+        assert!(shift.1 < tap.1, "shift above tap");
+        assert!(altgr.0 > tap.0 && altgr.1 == tap.1, "AltGr beside tap");
+    }
+
+    // =========================================================================
+    // Standalone SVG export
+    // =========================================================================
+    //
+    // The SVG export is meant to be dropped into docs or opened in Inkscape,
+    // neither of which reliably honours a <style> block. Every element must
+    // carry its own presentation attributes, and there must be no HTML shell.
+
+    #[test]
+    fn svg_export_is_a_bare_svg_document() {
+        let svg = generate_svg(
+            &build_keys(),
+            &Keymap::builtin(),
+            &all_layers(),
+            Language::Nordic,
+        );
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(!svg.contains("<html"), "no HTML wrapper");
+        assert!(!svg.contains("<style"), "no stylesheet");
+    }
+
+    #[test]
+    fn svg_export_has_no_class_attributes() {
+        // A leftover class="..." would render as black-on-black without the
+        // stylesheet, so inlining must cover every element we emit.
+        assert!(!generate_svg(
+            &build_keys(),
+            &Keymap::builtin(),
+            &all_layers(),
+            Language::Nordic
+        )
+        .contains("class="));
+    }
+
+    #[test]
+    fn inline_styles_fill_every_key_class() {
+        for class in [
+            "key",
+            "key unused",
+            "key transparent",
+            "key layer",
+            "key modifier",
+        ] {
+            assert!(
+                style_attrs(Styling::Inline, class).contains("fill="),
+                "{class} has no fill"
+            );
+        }
+    }
+
+    // =========================================================================
+    // Printable PDF cheat-sheet
+    // =========================================================================
+
+    #[test]
+    fn pdf_has_one_page_per_layer() {
+        let pdf = String::from_utf8_lossy(&generate_pdf(
+            &build_keys(),
+            &Keymap::builtin(),
+            &all_layers(),
+            Language::Nordic,
+            pdf::A4,
+        ))
+        .into_owned();
+        assert!(pdf.contains(&format!("/Count {NUM_LAYERS}")));
+        assert_eq!(pdf.matches("/Type /Page ").count(), NUM_LAYERS);
+    }
+
+    #[test]
+    fn layers_are_drawn_in_their_colors() {
+        let svg = generate_svg(
+            &build_keys(),
+            &Keymap::builtin(),
+            &all_layers(),
+            Language::Nordic,
+        );
+        for layer in 0..NUM_LAYERS {
+            assert!(svg.contains(&format!(
+                "style=\"fill:{}\"",
+                layer_css_color(&Keymap::builtin(), layer)
+            )));
+        }
+        // Layer 0's layer keys point at layer 1, in layer 1's color
+        let ly1 = build_keys()
+            .into_iter()
+            .find(|k| LAYERS[0][k.row][k.col] == Keycode::Layer1)
+            .unwrap();
+        assert_eq!(key_tint(&Keymap::builtin(), 0, &ly1), Some(1));
+        assert_eq!(layer_css_color(&Keymap::builtin(), 0), "#e94560");
+    }
+
+    #[test]
+    fn only_the_selected_layers_are_rendered() {
+        let pdf = generate_pdf(
+            &build_keys(),
+            &Keymap::builtin(),
+            &[1],
+            Language::Nordic,
+            pdf::A4,
+        );
+        let pdf = String::from_utf8_lossy(&pdf).into_owned();
+        assert!(pdf.contains("/Count 1"));
+
+        let svg = generate_svg(&build_keys(), &Keymap::builtin(), &[1], Language::Nordic);
+        assert!(svg.contains(&html_escape(&layer_title(1))));
+        assert!(!svg.contains(&html_escape(&layer_title(0))));
+    }
+
+    #[test]
+    fn pdf_uses_requested_paper_size() {
+        let pdf = String::from_utf8_lossy(&generate_pdf(
+            &build_keys(),
+            &Keymap::builtin(),
+            &all_layers(),
+            Language::Nordic,
+            pdf::LETTER,
+        ))
+        .into_owned();
+        assert!(pdf.contains("/MediaBox [0 0 792 612]"));
+    }
+
+    // =========================================================================
+    // Heatmap
+    // =========================================================================
+
+    #[test]
+    fn heatmap_runs_cold_to_hot() {
+        assert_eq!(heat_color(0.0), "#16213e");
+        assert_eq!(heat_color(0.5), "#e94560");
+        assert_eq!(heat_color(1.0), "#f9c74f");
+    }
+
+    #[test]
+    fn heatmap_colors_the_busiest_key_hottest() {
+        let mut counts = [[0u64; COLS]; ROWS];
+        counts[2][3] = 10;
+        counts[1][1] = 5;
+        let svg = generate_heatmap_svg(&build_keys(), &counts, Language::Nordic);
+        let keys_filled = |color: &str| svg.matches(&format!(r#"rx="{R}" fill="{color}""#)).count();
+        assert_eq!(keys_filled("#f9c74f"), 1);
+        assert_eq!(keys_filled("#e94560"), 1);
+        assert!(svg.contains("(15 total)"));
+    }
+
+    #[test]
+    fn compare_outlines_changed_keys_with_old_and_new_legends() {
+        let mut new = LAYERS;
+        new[0][2][1] = Keycode::Escape;
+        let changes = diff::diff(&LAYERS, &new);
+        let svg = generate_compare_svg(&build_keys(), &new, &changes, &[0]);
+        assert_eq!(svg.matches(r##"stroke="#f9c74f""##).count(), 1);
+        assert!(svg.contains(&format!(
+            ">{}</text>",
+            html_escape(diff::label(LAYERS[0][2][1]))
+        )));
+        assert!(svg.contains("\u{2192} Esc<"));
+        assert!(svg.contains("Layer 0: 1 key changed"));
+
+        let html = generate_compare_html(&build_keys(), &new, &changes, &[0]);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(!html.contains("<?xml"));
+    }
+
+    #[test]
+    fn html_shows_one_layer_at_a_time() {
+        let html = generate_html(&build_keys(), &Keymap::builtin(), &[0, 1], Language::Nordic);
+        assert_eq!(html.matches("<button").count(), 2);
+        assert_eq!(html.matches(r#"<g class="layer active""#).count(), 1);
+        assert_eq!(html.matches(r#"<g class="layer""#).count(), 1);
+        assert_eq!(html.matches("data-key=").count(), 2 * 76);
+    }
+
+    #[test]
+    fn popover_bindings_fall_through_transparent_keys() {
+        let keys = build_keys();
+        let key = keys
+            .iter()
+            .find(|k| LAYERS[1][k.row][k.col] == Keycode::Trans && k.row < 5)
+            .unwrap();
+        let bindings = key_bindings(&keys, &Keymap::builtin(), Language::Us);
+        let on_key = &bindings[format!("{},{}", key.row, key.col)];
+        assert_eq!(on_key.as_array().unwrap().len(), NUM_LAYERS);
+        assert_eq!(on_key[0]["from"], Value::Null);
+        assert_eq!(on_key[1]["from"], 0);
+        assert_eq!(on_key[1]["tap"], on_key[0]["tap"]);
+    }
+
+    #[test]
+    fn ascii_puts_the_halves_side_by_side_and_thumbs_below() {
+        let text = generate_ascii(&build_keys(), &Keymap::builtin(), &[0], Language::Us);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], layer_title(0));
+        // Five main rows, a blank line, three thumb rows
+        assert_eq!(lines.len(), 1 + 5 + 1 + 3);
+        assert_eq!(lines[6], "");
+        // The top row starts with the outer left key and ends with the
+        // outer right one
+        let first = Legends::of(LAYERS[0][0][0], Language::Us).tap;
+        let last = Legends::of(LAYERS[0][0][COLS - 1], Language::Us).tap;
+        assert!(lines[1].starts_with(&format!("[{first}")));
+        assert!(lines[1].trim_end_matches(']').trim_end().ends_with(last));
+        // Keys taller than a unit continue into the row below
+        let tall = build_keys().iter().filter(|k| k.h > S).count();
+        assert_eq!(text.matches('^').count(), tall);
+    }
+
+    #[test]
+    fn ascii_leaves_transparent_keys_empty() {
+        let text = generate_ascii(&build_keys(), &Keymap::builtin(), &[1], Language::Us);
+        let keys = build_keys();
+        let transparent = keys
+            .iter()
+            .filter(|k| LAYERS[1][k.row][k.col] == Keycode::Trans)
+            .count();
+        assert!(transparent > 0);
+        assert!(text.contains("[   "));
+    }
+
+    #[test]
+    fn renders_a_keymap_file_past_the_built_in_layers() {
+        const EMPTY: codegen::Layer = [[Keycode::Trans; COLS]; ROWS];
+        let mut layers = vec![LAYERS[0], EMPTY, EMPTY];
+        layers[2][1][1] = Keycode::Q;
+        let keymap = Keymap::file(&layers);
+
+        let text = generate_ascii(&build_keys(), &keymap, &[2], Language::Us);
+        assert!(text.starts_with("Layer 2 (Fn)\n"));
+        assert_eq!(text.matches("[Q").count(), 1);
+        // More layers than colors: the extra ones get the title red
+        let svg = generate_svg(&build_keys(), &keymap, &[2], Language::Us);
+        assert!(svg.contains(r#"style="fill:#e94560""#));
+    }
 
     #[test]
     fn each_half_has_38_keys() {
@@ -0,0 +1,334 @@
+//! Static checks for keymap mistakes the compiler can't catch.
+//!
+//! Every check mirrors how the firmware actually interprets the table:
+//! a layer key works from whichever layer is active when it's pressed
+//! (`LayerState`), the highest active layer wins, and `Trans` falls through
+//! to the layer below (`lookup`).
+
+use std::collections::HashSet;
+use std::fmt;
+
+use ergodox_keymap::{Keycode, COLS, ROWS};
+
+use crate::codegen::Layer;
+use crate::layout;
+
+/// A single lint finding, optionally tied to a matrix position.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub layer: usize,
+    pub pos: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some((row, col)) => write!(
+                f,
+                "layer {} row {row} col {col}: {}",
+                self.layer, self.message
+            ),
+            None => write!(f, "layer {}: {}", self.layer, self.message),
+        }
+    }
+}
+
+/// Run all checks over `layers`, in layer/row/col order.
+pub fn lint(layers: &[Layer]) -> Vec<Finding> {
+    let physical: HashSet<(usize, usize)> = layout::build_keys()
+        .iter()
+        .map(|k| (k.row, k.col))
+        .collect();
+
+    let mut findings = Vec::new();
+    let mut finding = |layer, pos, message: String| {
+        findings.push(Finding {
+            layer,
+            pos,
+            message,
+        })
+    };
+
+    // Reachability: layer 0, and whatever a layer key on a reachable layer
+    // leads to.
+    let mut reachable = vec![false; layers.len()];
+    let mut todo = vec![0];
+    while let Some(l) = todo.pop() {
+        if reachable[l] {
+            continue;
+        }
+        reachable[l] = true;
+        for kc in layers[l].iter().flatten() {
+            if kc.is_layer() && kc.layer_number() < layers.len() {
+                todo.push(kc.layer_number());
+            }
+        }
+    }
+    for (l, _) in reachable.iter().enumerate().filter(|(_, r)| !**r) {
+        finding(l, None, "unreachable: no layer key leads to it".into());
+    }
+
+    for (l, layer) in layers.iter().enumerate() {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let kc = layer[row][col];
+                let pos = Some((row, col));
+
+                if !physical.contains(&(row, col)) {
+                    if kc != Keycode::Trans && kc != Keycode::None {
+                        finding(
+                            l,
+                            pos,
+                            format!("{kc:?} is on a position with no physical switch"),
+                        );
+                    }
+                    continue;
+                }
+
+                if l == 0 && kc == Keycode::Trans {
+                    finding(
+                        l,
+                        pos,
+                        "Trans on layer 0 has nothing to fall through to; use None".into(),
+                    );
+                }
+
+                if !kc.is_layer() {
+                    continue;
+                }
+                let target = kc.layer_number();
+                if target >= layers.len() {
+                    finding(
+                        l,
+                        pos,
+                        format!("{kc:?} targets layer {target}, which doesn't exist"),
+                    );
+                } else if l != 0 && target <= l {
+                    finding(
+                        l,
+                        pos,
+                        format!("{kc:?} has no effect: layer {l} is already above it"),
+                    );
+                } else {
+                    // Holding the key activates `target`, so the same
+                    // position there is pressed too. Anything but Trans
+                    // (or the key itself) fires alongside the layer switch.
+                    let shadow = layers[target][row][col];
+                    if shadow != Keycode::Trans && shadow != kc {
+                        finding(
+                            l,
+                            pos,
+                            format!(
+                                "{kc:?} is shadowed by {shadow:?} on layer {target} while held"
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    for (l, group) in duplicate_modifiers(layers) {
+        let positions: Vec<String> = group
+            .positions
+            .iter()
+            .map(|(r, c)| format!("({r},{c})"))
+            .collect();
+        finding(
+            l,
+            Some(group.positions[0]),
+            format!(
+                "{:?} is bound {} times on this layer: {}",
+                group.modifier,
+                positions.len(),
+                positions.join(" ")
+            ),
+        );
+    }
+
+    findings
+}
+
+/// Positions sharing one modifier on a layer.
+struct ModifierGroup {
+    modifier: Keycode,
+    positions: Vec<(usize, usize)>,
+}
+
+/// Modifiers reachable from more than one position on the same layer.
+///
+/// Positions are resolved through `Trans` like `lookup` does, so a layer
+/// inherits its lower layers' modifiers. A group is only reported on the
+/// lowest layer that contributes to it, to avoid repeating layer 0's
+/// duplicates on every layer above.
+fn duplicate_modifiers(layers: &[Layer]) -> Vec<(usize, ModifierGroup)> {
+    let mut groups = Vec::new();
+
+    for l in 0..layers.len() {
+        for bit in 0..8u8 {
+            let modifier = Keycode::ALL
+                .iter()
+                .copied()
+                .find(|kc| kc.is_modifier() && kc.modifier_bit() == 1 << bit)
+                .expect("eight modifier keycodes");
+
+            let mut positions = Vec::new();
+            let mut defined_here = false;
+            for row in 0..ROWS {
+                for col in 0..COLS {
+                    let (kc, from) = resolve(layers, l, row, col);
+                    if kc == modifier {
+                        positions.push((row, col));
+                        defined_here |= from == l;
+                    }
+                }
+            }
+
+            if positions.len() > 1 && defined_here {
+                groups.push((
+                    l,
+                    ModifierGroup {
+                        modifier,
+                        positions,
+                    },
+                ));
+            }
+        }
+    }
+
+    groups
+}
+
+/// Resolve a position through `Trans`, returning the key and its layer.
+fn resolve(layers: &[Layer], layer: usize, row: usize, col: usize) -> (Keycode, usize) {
+    let mut l = layer;
+    loop {
+        let kc = layers[l][row][col];
+        if !kc.is_transparent() || l == 0 {
+            return (kc, l);
+        }
+        l -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A layer with every physical position set to None and everything else
+    /// Trans, so it starts out lint-clean.
+    fn blank() -> Layer {
+        let mut layer = [[Keycode::Trans; COLS]; ROWS];
+        for key in layout::build_keys() {
+            layer[key.row][key.col] = Keycode::None;
+        }
+        layer
+    }
+
+    fn messages(layers: &[Layer]) -> Vec<String> {
+        lint(layers).iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn blank_keymap_is_clean() {
+        assert!(lint(&[blank()]).is_empty());
+    }
+
+    #[test]
+    fn layer_without_a_key_leading_to_it_is_unreachable() {
+        let layers = [blank(), [[Keycode::Trans; COLS]; ROWS]];
+        assert_eq!(
+            messages(&layers),
+            ["layer 1: unreachable: no layer key leads to it"]
+        );
+    }
+
+    #[test]
+    fn layers_are_reachable_through_other_layers() {
+        let mut base = blank();
+        base[5][0] = Keycode::Layer1;
+        let mut upper = [[Keycode::Trans; COLS]; ROWS];
+        upper[5][1] = Keycode::Layer2;
+        let top = [[Keycode::Trans; COLS]; ROWS];
+        assert!(messages(&[base, upper, top]).is_empty());
+    }
+
+    #[test]
+    fn layer_key_shadowed_on_its_target_layer() {
+        let mut base = blank();
+        base[5][0] = Keycode::Layer1;
+        let mut upper = [[Keycode::Trans; COLS]; ROWS];
+        upper[5][0] = Keycode::A;
+
+        let found = messages(&[base, upper]);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("layer 0 row 5 col 0: Layer1 is shadowed by A"));
+    }
+
+    #[test]
+    fn layer_keys_to_lower_layers_have_no_effect() {
+        let mut base = blank();
+        base[5][0] = Keycode::Layer1;
+        let mut upper = [[Keycode::Trans; COLS]; ROWS];
+        upper[0][0] = Keycode::Layer1;
+
+        let found = messages(&[base, upper]);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("layer 1 is already above it"));
+    }
+
+    #[test]
+    fn layer_key_for_missing_layer() {
+        let mut base = blank();
+        base[5][0] = Keycode::Layer3;
+        assert!(messages(&[base])[0].contains("doesn't exist"));
+    }
+
+    #[test]
+    fn keys_on_positions_without_a_switch() {
+        // (2,6) is the gap under the left inner column's 1.5u key.
+        let mut base = blank();
+        assert_eq!(base[2][6], Keycode::Trans);
+        base[2][6] = Keycode::Q;
+        assert_eq!(
+            messages(&[base]),
+            ["layer 0 row 2 col 6: Q is on a position with no physical switch"]
+        );
+    }
+
+    #[test]
+    fn trans_on_layer_0() {
+        let mut base = blank();
+        base[0][0] = Keycode::Trans;
+        assert!(messages(&[base])[0].starts_with("layer 0 row 0 col 0: Trans on layer 0"));
+    }
+
+    #[test]
+    fn duplicate_modifiers_reported_once_on_the_lowest_layer() {
+        let mut base = blank();
+        base[4][0] = Keycode::LCtrl;
+        base[4][13] = Keycode::LCtrl;
+        base[5][0] = Keycode::Layer1;
+        let upper = [[Keycode::Trans; COLS]; ROWS];
+
+        let found = messages(&[base, upper]);
+        assert_eq!(
+            found,
+            ["layer 0 row 4 col 0: LCtrl is bound 2 times on this layer: (4,0) (4,13)"]
+        );
+    }
+
+    #[test]
+    fn duplicate_modifier_introduced_by_an_upper_layer() {
+        let mut base = blank();
+        base[4][0] = Keycode::LShift;
+        base[5][0] = Keycode::Layer1;
+        let mut upper = [[Keycode::Trans; COLS]; ROWS];
+        upper[3][0] = Keycode::LShift;
+
+        let found = messages(&[base, upper]);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("layer 1 row 3 col 0: LShift is bound 2 times"));
+    }
+}
@@ -1,89 +1,2505 @@
+mod analyze;
+mod bench;
+mod bootloader;
+mod bundle;
+mod caterina;
+mod chatter;
+mod codegen;
+mod config;
+mod dfu;
+mod diff;
+mod doctor;
+mod elf;
+mod firmware_build;
+mod flash_log;
+mod geometry;
 mod halfkay;
+mod heatmap;
 mod hex;
+mod hidraw;
+mod i2c_scan;
+mod info;
+mod keymap_file;
+mod keymap_text;
+mod kle;
 mod layout;
+mod lint;
+mod monitor;
+mod oryx;
+mod patch;
+mod pdf;
+mod profiles;
+mod qmk;
+#[cfg(feature = "hidapi")]
+mod rawhid;
+mod resume;
+mod serve;
+mod stamp;
+mod tester;
+mod trace;
+mod udev;
+mod update;
+mod xkb;
+mod zmk;
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
 
 #[derive(Parser)]
 #[command(name = "ergodox-cli")]
 #[command(about = "ErgoDox keyboard firmware flasher")]
 struct Cli {
+    /// Use the keyboard or bootloader at this USB BUS:ADDR (see `list`)
+    #[arg(long, global = true)]
+    device: Option<String>,
+    /// Use the bootloader with this USB serial number; the keyboard firmware
+    /// has none, so pick a running keyboard with --device
+    #[arg(long, global = true)]
+    serial: Option<String>,
+    /// Print results as JSON (one object per line) for scripts and GUIs
+    #[arg(long, global = true)]
+    json: bool,
+    /// Bootloader protocol to use; by default, whichever one is waiting
+    #[arg(long, global = true, value_enum)]
+    bootloader: Option<Bootloader>,
+    /// Microcontroller on the keyboard, which sets HalfKay's page size
+    /// [default: atmega32u4]
+    #[arg(long, global = true, value_enum)]
+    mcu: Option<Mcu>,
+    /// How to reach the HalfKay bootloader; `hidraw` works without root on
+    /// Linux, given a udev rule [default: libusb]
+    #[arg(long, global = true, value_enum)]
+    transport: Option<Transport>,
+    /// Host keyboard layout, which decides the legends drawn on keys
+    /// [default: nordic]
+    #[arg(long, global = true, value_enum)]
+    language: Option<Language>,
+    /// Read defaults from this file instead of ~/.config/ergodox/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Log USB transfers to stderr; repeat (`-vv`) to include their data
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    /// Flash a .hex firmware file to Teensy via HalfKay bootloader
+    /// Flash a firmware file via the keyboard's bootloader
     Flash {
-        /// Path to the Intel HEX firmware file
-        firmware: String,
+        /// Path to the firmware: Intel HEX, ELF, or raw binary (.bin), or
+        /// a keymap bundle (.erk) to patch into `firmware` from the config
+        /// file. Defaults to `firmware` from the config file
+        firmware: Option<String>,
+        /// Load address for raw binary files [default: 0]
+        #[arg(long, value_parser = parse_address)]
+        base_address: Option<u32>,
+        /// After flashing, wait for the keyboard to restart and compare its
+        /// flash CRC32 against the image
+        #[arg(long)]
+        verify: bool,
+        /// Don't verify, even if the config file says to
+        #[arg(long, conflicts_with = "verify")]
+        no_verify: bool,
+        /// Keep running: flash every time a bootloader appears, re-reading
+        /// the firmware file each time
+        #[arg(long)]
+        watch: bool,
+        /// Continue a flash that failed partway, from the first page that
+        /// didn't get written, instead of starting over
+        #[arg(long, conflicts_with = "watch")]
+        resume: bool,
+        /// Write pages that overlap the bootloader, which is refused
+        /// otherwise. A board whose bootloader is overwritten needs an ISP
+        /// programmer to recover
+        #[arg(long)]
+        force: bool,
+        /// Write blank (all 0xFF) pages too, and blank every page after the
+        /// image up to the bootloader, instead of skipping them. Use it when
+        /// flashing a smaller firmware over a bigger one, whose high pages
+        /// would otherwise be left in flash
+        #[arg(long)]
+        no_skip_blank: bool,
+        /// Append a line of JSON about every flash attempt to this file:
+        /// time, image CRC32, USB port, pages written, result and the pages
+        /// that needed retries. Defaults to `log_file` in the config file's
+        /// [flash] table, which covers the other flashing commands too
+        #[arg(long)]
+        log_file: Option<PathBuf>,
+        #[command(flatten)]
+        image: ImageArgs,
+    },
+    /// Replace the keymap inside a built firmware image and flash it, no
+    /// AVR toolchain needed
+    PatchKeymap {
+        /// Firmware built with a keymap table: Intel HEX, ELF, or raw binary
+        firmware: PathBuf,
+        /// TOML or JSON keymap file or keymap bundle (.erk), or REV:PATH to
+        /// read it from git. Defaults to `keymap` from the config file
+        keymap: Option<String>,
+        /// Write the patched image to this Intel HEX file
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Only write the patched image; don't flash it
+        #[arg(long, requires = "out")]
+        no_flash: bool,
+    },
+    /// Build the firmware (needs the AVR toolchain), write it as Intel HEX
+    /// and optionally flash it
+    Build {
+        /// TOML or JSON keymap file, or REV:PATH from git, to patch into
+        /// the built image instead of the keymap compiled into it
+        #[arg(long)]
+        keymap: Option<String>,
+        /// Firmware cargo features, e.g. `persist-counts`
+        #[arg(long, value_delimiter = ',')]
+        features: Vec<String>,
+        /// The firmware crate [default: `firmware/` here or in a parent
+        /// directory]
+        #[arg(long)]
+        firmware_dir: Option<PathBuf>,
+        /// Write the image to this Intel HEX file [default: firmware.hex
+        /// next to the firmware crate]
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Flash the image once it's built
+        #[arg(long)]
+        flash: bool,
+        /// After flashing, wait for the keyboard to restart and compare its
+        /// flash CRC32 against the image
+        #[arg(long, requires = "flash")]
+        verify: bool,
+        /// Don't verify, even if the config file says to
+        #[arg(long, conflicts_with = "verify")]
+        no_verify: bool,
+    },
+    /// Report a firmware image's size, flash use and layout problems
+    /// without flashing it
+    Analyze {
+        /// Intel HEX, ELF, or raw binary (.bin)
+        firmware: PathBuf,
+        /// Load address for raw binary files
+        #[arg(long, value_parser = parse_address, default_value = "0")]
+        base_address: u32,
+        #[command(flatten)]
+        image: ImageArgs,
     },
     /// Detect if a Teensy is connected in bootloader mode
     Detect,
-    /// Generate an HTML layout visualization of the keymap
-    Layout,
+    /// Leave the bootloader and start the firmware already on the Teensy
+    Run,
+    /// Print the CLI version and the firmware build running on the keyboard
+    Version,
+    /// List connected keyboards and bootloaders
+    List,
+    /// Print the udev rules that let you flash and talk to the keyboard
+    /// without root, and check whether the devices plugged in can be opened
+    Udev {
+        /// Write the rules to /etc/udev/rules.d/49-ergodox.rules and reload
+        /// udev; needs root
+        #[arg(long)]
+        install: bool,
+    },
+    /// Show everything the bus says about connected keyboards and
+    /// bootloaders: IDs, strings, speed, interfaces and endpoints
+    Info,
+    /// Check everything from libusb and device permissions to the left
+    /// half's I2C bus, and say how to fix what fails. Exits 1 if anything
+    /// does
+    Doctor {
+        /// Also check that the keyboard reboots into HalfKay when asked,
+        /// by rebooting it and starting it again
+        #[arg(long)]
+        reboot: bool,
+    },
+    /// Have the keyboard probe its I2C bus and list what answered; the
+    /// first check when the left half is dead. Exits 1 if the left half's
+    /// expander doesn't answer
+    I2cScan,
+    /// Show whether the left half is online, how often it dropped out and
+    /// how many I2C errors there were since power-on. Exits 1 if it's
+    /// offline
+    Health,
+    /// Show how many keys have been pressed, in all and since power-on
+    Odometer,
+    /// Print live debug events from the running keyboard: key presses with
+    /// matrix coordinates and what they type under `--language`, layer
+    /// changes and I2C errors
+    Monitor {
+        /// Also append every key press to this CSV file, for `heatmap`
+        #[arg(long)]
+        log: Option<PathBuf>,
+    },
+    /// Print the active layer, the layers toggled on and the host's lock
+    /// LEDs, now and each time they change, from the keyboard's layer
+    /// notices; for a status bar or a desktop widget
+    Layer,
+    /// Draw the board in the terminal and mark off each switch as it's
+    /// pressed, to check a fresh build
+    Test,
+    /// Measure the firmware's scan rate and loop time, and with `--latency`
+    /// how long a key press takes to reach a USB report
+    Bench {
+        /// Seconds to count scans for
+        #[arg(long, default_value_t = 5.0)]
+        seconds: f64,
+        /// Also time this many injected key presses. The key is really
+        /// pressed, so pick one that types nothing with `--key`
+        #[arg(long)]
+        latency: Option<usize>,
+        /// Matrix position to press for `--latency`, as ROW,COL [default:
+        /// the first modifier on the base layer]
+        #[arg(long, value_parser = parse_position, requires = "latency")]
+        key: Option<(u8, u8)>,
+    },
+    /// Show how often each switch bounced, from the keyboard's debouncer,
+    /// and flag the ones bouncing far more than the rest
+    Chatter,
+    /// Generate an HTML, SVG or PDF layout visualization of the keymap
+    Layout {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = LayoutFormat::Html)]
+        format: LayoutFormat,
+        /// Render with key positions from a KLE JSON file, or a `.toml`
+        /// geometry table like `geometry/ergodox.toml`, instead of the
+        /// built-in ErgoDox geometry
+        #[arg(long)]
+        geometry: Option<PathBuf>,
+        /// Paper size for PDF output
+        #[arg(long, value_enum, default_value_t = Paper::A4)]
+        paper: Paper,
+        /// Render this keymap file (TOML/JSON, or REV:PATH from git)
+        /// instead of the keymap built into this binary. A bundle (.erk)
+        /// brings its own labels, colors and geometry
+        #[arg(long, value_name = "FILE", conflicts_with = "compare")]
+        keymap: Option<String>,
+        /// Only render these layers, e.g. `0,2` (default: all)
+        #[arg(long, value_delimiter = ',')]
+        layers: Vec<usize>,
+        /// Write each layer to its own file, named after `--out` with
+        /// `-layerN` added before the extension
+        #[arg(long, requires = "out")]
+        per_layer_files: bool,
+        /// Write the output to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Open the generated file in the default browser
+        #[arg(long)]
+        open: bool,
+        /// Render the changes between two keymap files (TOML/JSON, or
+        /// REV:PATH from git) instead: changed keys highlighted, with old
+        /// and new legends. Only the layers with changes, unless `--layers`
+        #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+        compare: Option<Vec<String>>,
+    },
+    /// Import a layout from another tool and print it as a Rust `LAYERS` table
+    Import {
+        /// Source format
+        #[arg(long, value_enum)]
+        from: ImportFormat,
+        /// File to import
+        file: PathBuf,
+        /// Write the output to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Check the keymap for unreachable layers, shadowed layer keys and
+    /// other mistakes
+    Lint,
+    /// Generate the `LAYERS` table (or a binary blob) from a keymap file
+    Codegen {
+        /// TOML or JSON keymap file
+        file: PathBuf,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = CodegenFormat::Rust)]
+        format: CodegenFormat,
+        /// Write the output to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Write a module of keycode aliases named after a national layout's
+    /// legends, like `layout::nordic`, from the layout's XKB definition
+    XkbAliases {
+        /// XKB layout as FILE or FILE(VARIANT), e.g. `de` or `fr(bepo)`
+        layout: String,
+        /// Module name [default: the layout, e.g. `fr_bepo`]
+        #[arg(long)]
+        name: Option<String>,
+        /// Directory of XKB symbols files
+        #[arg(long, default_value = xkb::DEFAULT_SYMBOLS_DIR)]
+        symbols_dir: PathBuf,
+        /// Write the output to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Show per-position changes between two keymaps
+    Diff {
+        /// Old keymap: a TOML/JSON keymap file, REV:PATH to read it from git,
+        /// or a firmware image (HEX, ELF or BIN) to read its keymap table
+        old: String,
+        /// New keymap, given the same ways as the old one
+        new: String,
+    },
+    /// Look at a keymap
+    Keymap {
+        #[command(subcommand)]
+        action: KeymapCommand,
+    },
+    /// Render per-key press counts as a color gradient on the SVG layout
+    Heatmap {
+        /// CSV key log, as written by `monitor --log` (or `row,col,count`)
+        #[arg(long, required_unless_present = "from_keyboard")]
+        log: Option<PathBuf>,
+        /// Use the running keyboard's press counters (since power-on, or
+        /// since the last clear with the persist-counts firmware feature)
+        #[arg(long, conflicts_with = "log")]
+        from_keyboard: bool,
+        /// Zero the keyboard's press counters after reading them
+        #[arg(long, requires = "from_keyboard")]
+        clear: bool,
+        /// Write the SVG to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Export the keymap for use in other tools
+    Export {
+        /// Target format
+        #[arg(short, long, value_enum)]
+        format: ExportFormat,
+        /// Export the keymap the running keyboard uses instead of the
+        /// built-in one
+        #[arg(long)]
+        from_keyboard: bool,
+        /// Write the output to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Bundle a keymap with its geometry, labels and colors into one
+    /// `.erk` file to share
+    Pack {
+        /// TOML or JSON keymap file, or REV:PATH to read it from git
+        /// [default: the built-in keymap, with its labels and colors]
+        keymap: Option<String>,
+        /// Name of the layout
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        author: Option<String>,
+        #[arg(long)]
+        version: Option<String>,
+        /// Geometry table like `geometry/ergodox.toml` [default: the
+        /// built-in ErgoDox geometry]
+        #[arg(long)]
+        geometry: Option<PathBuf>,
+        /// Key labels and layer colors, as `unpack` writes them to
+        /// labels.toml [default: the built-in colors]
+        #[arg(long)]
+        labels: Option<PathBuf>,
+        /// Write the bundle to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Split a keymap bundle into keymap.toml, geometry.toml and
+    /// labels.toml, and print its name, author and version
+    Unpack {
+        /// Keymap bundle (.erk)
+        bundle: PathBuf,
+        /// Directory to write the files to [default: named after the
+        /// bundle]
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+    /// Keep firmware images under names, with their keymaps, and flash
+    /// them by name
+    Profiles {
+        #[command(subcommand)]
+        action: ProfileCommand,
+    },
+    /// Serve a local HTTP API for a browser configurator: devices, the
+    /// keymap, flashing and monitor events (see src/serve.rs)
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:7780")]
+        listen: String,
+        /// Origin of a web page allowed to call the API, e.g.
+        /// `https://configurator.example`; can be repeated
+        #[arg(long = "allow-origin", value_name = "ORIGIN")]
+        allow_origins: Vec<String>,
+        /// Firmware that `PUT /keymap` patches the keymap into [default:
+        /// `firmware` from the config file]
+        #[arg(long)]
+        firmware: Option<PathBuf>,
+    },
+    /// Download the newest firmware release from GitHub, show its release
+    /// notes and flash it
+    Update {
+        /// GitHub repository to take releases from, as OWNER/NAME
+        #[arg(long, default_value = update::DEFAULT_REPO)]
+        repo: String,
+        /// Flash without asking first
+        #[arg(short, long)]
+        yes: bool,
+        /// Flash even if the keyboard already runs the newest release
+        #[arg(long)]
+        force: bool,
+        /// After flashing, wait for the keyboard to restart and compare its
+        /// flash CRC32 against the image
+        #[arg(long)]
+        verify: bool,
+        /// Don't verify, even if the config file says to
+        #[arg(long, conflicts_with = "verify")]
+        no_verify: bool,
+    },
+    /// Generate a tab-completion script for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+        /// Write the script to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Generate the man page, in roff
+    Man {
+        /// Write the page to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Bootloader {
+    /// PJRC HalfKay (Teensy 2.0)
+    Halfkay,
+    /// Atmel DFU / FLIP (stock ATmega32U4)
+    Dfu,
+    /// Arduino Caterina / AVR109 (Pro Micro)
+    Caterina,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mcu {
+    /// ATmega32U4 (Teensy 2.0 and most clones)
+    Atmega32u4,
+    /// AT90USB1286 (Teensy++ 2.0)
+    At90usb1286,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Transport {
+    /// USB control transfers through libusb
+    Libusb,
+    /// Linux's /dev/hidraw* nodes
+    Hidraw,
+}
+
+/// The keyboard's microcontroller and how to reach its bootloader: what
+/// it takes to build a protocol implementation.
+#[derive(Clone, Copy)]
+struct Board {
+    mcu: Mcu,
+    transport: Transport,
+}
+
+/// How a firmware file's segments are laid out as one image.
+#[derive(Args)]
+struct ImageArgs {
+    /// Byte to fill the gaps between segments with
+    #[arg(long, value_parser = parse_byte, default_value = "0xFF")]
+    fill: u8,
+    /// Widest gap between segments to fill, in KB. A segment further from
+    /// the rest is taken for a stray record and refused
+    #[arg(long, value_name = "KB", default_value_t = hex::DEFAULT_MAX_GAP / 1024)]
+    max_gap: usize,
+}
+
+impl ImageArgs {
+    fn options(&self) -> hex::FlattenOptions {
+        hex::FlattenOptions {
+            max_gap: self.max_gap.saturating_mul(1024),
+            fill: self.fill,
+            ..hex::FlattenOptions::default()
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum KeymapCommand {
+    /// Print each layer as a text table, with what transparent keys fall
+    /// through to in parentheses
+    Print {
+        /// Print this keymap file or bundle (or REV:PATH from git) instead
+        /// of the built-in keymap
+        #[arg(long, value_name = "FILE")]
+        keymap: Option<String>,
+        /// Only print these layers, e.g. `0,2` (default: all)
+        #[arg(long, value_delimiter = ',')]
+        layers: Vec<usize>,
+        /// Print Markdown tables, for pasting into notes
+        #[arg(long)]
+        markdown: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// Save a firmware image as a profile, replacing any of the same name
+    Save {
+        /// Profile name: letters, digits, `-` and `_`
+        name: String,
+        /// Intel HEX, ELF or raw binary firmware [default: `firmware` from
+        /// the config file]
+        firmware: Option<PathBuf>,
+        /// Keymap bundle (.erk) to patch into the image and keep with it, or
+        /// REV:PATH to read it from git [default: the keymap in the image]
+        #[arg(long)]
+        bundle: Option<String>,
+        /// Load address for raw binary files [default: 0]
+        #[arg(long, value_parser = parse_address)]
+        base_address: Option<u32>,
+    },
+    /// List the saved profiles
+    List,
+    /// Flash a saved profile
+    Flash {
+        name: String,
+        /// After flashing, wait for the keyboard to restart and compare its
+        /// flash CRC32 against the image
+        #[arg(long)]
+        verify: bool,
+        /// Don't verify, even if the config file says to
+        #[arg(long, conflicts_with = "verify")]
+        no_verify: bool,
+    },
+}
+
+/// How `flash_and_report` writes an image, beyond where and what.
+#[derive(Clone, Copy, Default)]
+struct FlashOptions<'a> {
+    /// Check the flash CRC32 once the keyboard restarts.
+    verify: bool,
+    /// Continue an interrupted flash from this address (see `resume.rs`).
+    resume_at: Option<usize>,
+    /// Write pages in the bootloader's region too.
+    force: bool,
+    /// Write blank pages rather than skip them.
+    write_blank: bool,
+    /// Append a line about the attempt to this file (see `flash_log.rs`).
+    log_file: Option<&'a Path>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Language {
+    /// Swedish / Finnish
+    Nordic,
+    /// US English
+    Us,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LayoutFormat {
+    /// HTML page with an embedded SVG and a stylesheet
+    Html,
+    /// Standalone SVG with styles inlined as attributes
+    Svg,
+    /// Printable cheat-sheet, one layer per page
+    Pdf,
+    /// Plain text for the terminal
+    Ascii,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// keyboard-layout-editor.com JSON, one legend slot per layer
+    Kle,
+    /// Human-editable TOML keymap file
+    Toml,
+    /// QMK configurator `keymap.json` for the ErgoDox EZ
+    Qmk,
+    /// ZMK `.keymap` devicetree, in QMK's pretty layout order
+    Zmk,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CodegenFormat {
+    /// Rust source for `NUM_LAYERS` and the `LAYERS` static
+    Rust,
+    /// One keycode byte per position, layer by layer
+    Bin,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportFormat {
+    /// keyboard-layout-editor.com JSON; legend N becomes layer N
+    Kle,
+    /// QMK keymap.json, or keymap.c (best effort), for the ErgoDox EZ
+    Qmk,
+    /// Layout JSON from ZSA's Oryx configurator, for the ErgoDox EZ
+    Oryx,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Paper {
+    A4,
+    Letter,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Bootloader {
+    /// Detection order when `--bootloader` isn't given.
+    const ALL: [Bootloader; 3] = [Bootloader::Halfkay, Bootloader::Dfu, Bootloader::Caterina];
+
+    fn name(self) -> &'static str {
+        match self {
+            Bootloader::Halfkay => "HalfKay",
+            Bootloader::Dfu => "Atmel DFU",
+            Bootloader::Caterina => "Caterina",
+        }
+    }
+
+    fn detect(self, target: &halfkay::Target) -> Result<bool> {
+        match self {
+            Bootloader::Halfkay => halfkay::detect(target),
+            Bootloader::Dfu => dfu::detect(target),
+            Bootloader::Caterina => caterina::detect(target),
+        }
+    }
+
+    /// The protocol implementation for this bootloader on `board`.
+    fn protocol(self, board: Board) -> Result<Box<dyn bootloader::Bootloader>> {
+        self.check_mcu(board.mcu)?;
+        Ok(match self {
+            Bootloader::Halfkay => Box::new(halfkay::HalfKay {
+                mcu: board.mcu.chip(),
+                transport: board.transport.kind(),
+            }),
+            Bootloader::Dfu => Box::new(dfu::Dfu),
+            Bootloader::Caterina => Box::new(caterina::Caterina),
+        })
+    }
+
+    /// The DFU and Caterina implementations only know the ATmega32U4.
+    fn check_mcu(self, mcu: Mcu) -> Result<()> {
+        if self != Bootloader::Halfkay && mcu != Mcu::Atmega32u4 {
+            bail!(
+                "the {} bootloader is only supported on the ATmega32U4",
+                self.name()
+            );
+        }
+        Ok(())
+    }
 }
 
-fn main() -> Result<()> {
+impl Mcu {
+    fn chip(self) -> halfkay::Mcu {
+        match self {
+            Mcu::Atmega32u4 => halfkay::ATMEGA32U4,
+            Mcu::At90usb1286 => halfkay::AT90USB1286,
+        }
+    }
+}
+
+impl Transport {
+    fn kind(self) -> halfkay::Transport {
+        match self {
+            Transport::Libusb => halfkay::Transport::Libusb,
+            Transport::Hidraw => halfkay::Transport::Hidraw,
+        }
+    }
+}
+
+impl Language {
+    fn layout(self) -> layout::Language {
+        match self {
+            Language::Nordic => layout::Language::Nordic,
+            Language::Us => layout::Language::Us,
+        }
+    }
+}
+
+impl LayoutFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            LayoutFormat::Html => "html",
+            LayoutFormat::Svg => "svg",
+            LayoutFormat::Pdf => "pdf",
+            LayoutFormat::Ascii => "txt",
+        }
+    }
+}
+
+impl Shell {
+    fn generator(self) -> clap_complete::Shell {
+        match self {
+            Shell::Bash => clap_complete::Shell::Bash,
+            Shell::Zsh => clap_complete::Shell::Zsh,
+            Shell::Fish => clap_complete::Shell::Fish,
+        }
+    }
+}
+
+fn main() {
     let cli = Cli::parse();
+    let json = cli.json;
+    if let Err(e) = run(cli) {
+        if json {
+            emit(json!({ "status": "error", "error": format!("{:#}", e) }));
+        } else {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let json = cli.json;
+    trace::set_level(cli.verbose);
+    let config = config::load(cli.config.as_deref())?;
+    let bootloader = match cli.bootloader {
+        Some(bootloader) => Some(bootloader),
+        None => config_enum("flash.bootloader", config.flash.bootloader.as_deref())?,
+    };
+    let mcu = match cli.mcu {
+        Some(mcu) => mcu,
+        None => config_enum("flash.mcu", config.flash.mcu.as_deref())?.unwrap_or(Mcu::Atmega32u4),
+    };
+    let transport = match cli.transport {
+        Some(transport) => transport,
+        None => config_enum("flash.transport", config.flash.transport.as_deref())?
+            .unwrap_or(Transport::Libusb),
+    };
+    let board = Board { mcu, transport };
+    let language = match cli.language {
+        Some(language) => language,
+        None => config_enum("language", config.language.as_deref())?.unwrap_or(Language::Nordic),
+    }
+    .layout();
+    let target = || halfkay::resolve_target(cli.device.as_deref(), cli.serial.as_deref());
 
     match cli.command {
-        Command::Flash { firmware } => {
-            let contents =
-                fs::read_to_string(&firmware).with_context(|| format!("reading {}", firmware))?;
+        Command::Flash {
+            firmware,
+            base_address,
+            verify,
+            no_verify,
+            watch,
+            resume,
+            force,
+            no_skip_blank,
+            log_file,
+            image,
+        } => {
+            let log_file = log_file.or_else(|| config.flash.log_file.clone());
+            let state_path = resume::default_path();
+            let state = if resume {
+                let path = state_path
+                    .as_deref()
+                    .context("no home directory to keep flash progress in")?;
+                Some(resume::load(path)?.context("no interrupted flash to resume")?)
+            } else {
+                None
+            };
+            let base_firmware = config.firmware.clone();
+            let firmware = match (firmware, &state) {
+                (Some(firmware), _) => PathBuf::from(firmware),
+                (None, Some(state)) => state.firmware.clone(),
+                (None, None) => config
+                    .firmware
+                    .context("no firmware given, and no `firmware` default in the config file")?,
+            };
+            let base_address = base_address
+                .or(state.as_ref().map(|state| state.base_address))
+                .or(config.flash.base_address)
+                .unwrap_or(0);
+            let verify = verify || (!no_verify && config.flash.verify == Some(true));
+            let target = target()?;
+            if watch {
+                return watch_and_flash(
+                    &target,
+                    bootloader,
+                    board,
+                    &firmware,
+                    || {
+                        load_flash_image(
+                            &firmware,
+                            base_address,
+                            &image.options(),
+                            base_firmware.as_deref(),
+                        )
+                    },
+                    FlashOptions {
+                        verify,
+                        resume_at: None,
+                        force,
+                        write_blank: no_skip_blank,
+                        log_file: log_file.as_deref(),
+                    },
+                    json,
+                );
+            }
 
-            let segments = hex::parse_hex(&contents).context("parsing Intel HEX file")?;
-            let (base_address, data) =
-                hex::flatten_segments(&segments).context("flattening HEX segments")?;
+            let (base_address, mut data) = load_flash_image(
+                &firmware,
+                base_address,
+                &image.options(),
+                base_firmware.as_deref(),
+            )?;
+            if !json {
+                println!(
+                    "Firmware: {} bytes at base address 0x{:04X}",
+                    data.len(),
+                    base_address
+                );
+            }
+            stamp_image(&mut data, json)?;
 
-            println!(
-                "Firmware: {} bytes at base address 0x{:04X}",
-                data.len(),
-                base_address
-            );
+            let kind = enter_bootloader(&target, bootloader, &data, log_file.as_deref(), json)?;
+            let resume_at = match &state {
+                Some(state) => {
+                    state.check(kind.name(), base_address, &data)?;
+                    if !json {
+                        println!("Resuming at 0x{:04X}", state.next_address);
+                    }
+                    Some(state.next_address)
+                }
+                None => None,
+            };
+            let options = FlashOptions {
+                verify,
+                resume_at,
+                force,
+                write_blank: no_skip_blank,
+                log_file: log_file.as_deref(),
+            };
+            let result = flash_and_report(&target, kind, board, base_address, &data, options, json);
+            let resumable = kind
+                .protocol(board)
+                .is_ok_and(|loader| !loader.erases_on_first_write());
+            if let Some(path) = &state_path {
+                let stop = result
+                    .as_ref()
+                    .err()
+                    .and_then(|e| e.downcast_ref::<bootloader::Interrupted>())
+                    .filter(|_| resumable);
+                if let Some(stop) = stop {
+                    let firmware = fs::canonicalize(&firmware).unwrap_or(firmware);
+                    let state = resume::FlashState::new(
+                        &firmware,
+                        kind.name(),
+                        base_address,
+                        &data,
+                        stop.address,
+                    );
+                    match resume::save(path, &state) {
+                        Ok(()) if !json => eprintln!(
+                            "Progress saved; `ergodox-cli flash --resume` continues from 0x{:04X}.",
+                            stop.address
+                        ),
+                        Ok(()) => {}
+                        Err(e) => eprintln!("warning: {:#}", e),
+                    }
+                } else if result.is_ok() {
+                    resume::clear(path)?;
+                }
+            }
+            result?;
+        }
+        Command::PatchKeymap {
+            firmware,
+            keymap,
+            out,
+            no_flash,
+        } => {
+            let (base_address, mut data) =
+                load_firmware(&firmware, 0, &hex::FlattenOptions::default())?;
+            let keymap = match keymap {
+                Some(keymap) => keymap,
+                None => config
+                    .keymap
+                    .context("no keymap given, and no `keymap` default in the config file")?,
+            };
+            let layers = load_keymap(&keymap)?;
+            let table = patch::patch_keymap(&mut data, &layers)
+                .with_context(|| format!("patching {}", firmware.display()))?;
+            if !json {
+                println!(
+                    "Patched {} keymap layers at 0x{:04X}",
+                    layers.len(),
+                    base_address as usize + table.offset
+                );
+            }
+            stamp_image(&mut data, json)?;
+
+            if let Some(out) = out {
+                let segment = hex::HexSegment {
+                    address: base_address,
+                    data: data.clone(),
+                };
+                write_output(Some(&out), hex::write_hex(&[segment]).as_bytes())?;
+            }
+            if !no_flash {
+                let target = target()?;
+                let log_file = config.flash.log_file.as_deref();
+                let kind = enter_bootloader(&target, bootloader, &data, log_file, json)?;
+                let options = FlashOptions {
+                    log_file,
+                    ..FlashOptions::default()
+                };
+                flash_and_report(&target, kind, board, base_address, &data, options, json)?;
+            }
+        }
+        Command::Build {
+            keymap,
+            features,
+            firmware_dir,
+            out,
+            flash,
+            verify,
+            no_verify,
+        } => {
+            let dir = match firmware_dir {
+                Some(dir) => dir,
+                None => firmware_build::find_firmware_dir(&std::env::current_dir()?).context(
+                    "no firmware crate here or above; run from the repository or pass --firmware-dir",
+                )?,
+            };
+            let elf = firmware_build::build(&dir, &features)?;
+            let (base_address, mut data) = load_firmware(&elf, 0, &hex::FlattenOptions::default())?;
+            if !json {
+                println!("Built {} ({} bytes)", elf.display(), data.len());
+            }
+            if let Some(keymap) = keymap {
+                let layers = load_keymap(&keymap)?;
+                patch::patch_keymap(&mut data, &layers)
+                    .with_context(|| format!("patching {}", elf.display()))?;
+                if !json {
+                    println!("Patched {} keymap layers from {keymap}", layers.len());
+                }
+            }
+            stamp_image(&mut data, json)?;
+
+            let out = match out {
+                Some(out) => out,
+                None => dir.parent().unwrap_or(&dir).join("firmware.hex"),
+            };
+            let segment = hex::HexSegment {
+                address: base_address,
+                data: data.clone(),
+            };
+            write_output(Some(&out), hex::write_hex(&[segment]).as_bytes())?;
+            if !json {
+                println!("Wrote {}", out.display());
+            }
 
-            if !halfkay::detect()? {
-                // Try to reboot running keyboard into bootloader
-                if halfkay::reboot_to_bootloader()? {
-                    println!("Rebooting keyboard into bootloader...");
-                    // Wait for bootloader to appear
-                    let mut found = false;
-                    for _ in 0..50 {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        if halfkay::detect()? {
-                            found = true;
-                            break;
+            if flash {
+                let verify = verify || (!no_verify && config.flash.verify == Some(true));
+                let target = target()?;
+                let log_file = config.flash.log_file.as_deref();
+                let kind = enter_bootloader(&target, bootloader, &data, log_file, json)?;
+                let options = FlashOptions {
+                    verify,
+                    resume_at: None,
+                    log_file,
+                    ..FlashOptions::default()
+                };
+                flash_and_report(&target, kind, board, base_address, &data, options, json)?;
+            } else if json {
+                emit(json!({ "status": "ok", "hex": out }));
+            }
+        }
+        Command::Analyze {
+            firmware,
+            base_address,
+            image,
+        } => {
+            let (base_address, data) = load_firmware(&firmware, base_address, &image.options())?;
+            let analysis = analyze::analyze(base_address, &data);
+            if json {
+                emit(analysis.to_json());
+            } else {
+                println!("{analysis}");
+            }
+            if analysis.has_problems() {
+                std::process::exit(1);
+            }
+        }
+        Command::Doctor { reboot } => {
+            let halfkay = Bootloader::Halfkay.protocol(board)?;
+            let checks = doctor::run(&target()?, &*halfkay, reboot);
+            let passed = checks.iter().all(|c| c.outcome != doctor::Outcome::Fail);
+            if json {
+                emit(json!({
+                    "status": "ok",
+                    "passed": passed,
+                    "checks": checks.iter().map(doctor::Check::to_json).collect::<Vec<_>>(),
+                }));
+            } else {
+                for check in &checks {
+                    println!("{check}");
+                }
+            }
+            if !passed {
+                std::process::exit(1);
+            }
+        }
+        Command::I2cScan => {
+            let channel = halfkay::DebugChannel::open(&target()?)?;
+            let scan = i2c_scan::run(&channel)?;
+            if json {
+                emit(scan.to_json());
+            } else {
+                println!("{scan}");
+            }
+            if !scan.has_left_half() {
+                std::process::exit(1);
+            }
+        }
+        Command::Detect => {
+            let detected = waiting_bootloader(&target()?, bootloader)?;
+            if json {
+                emit(json!({
+                    "status": "ok",
+                    "bootloader": detected.is_some(),
+                    "protocol": detected.map(|kind| kind.name()),
+                }));
+            } else if let Some(kind) = detected {
+                println!("Bootloader detected ({} mode).", kind.name());
+            } else {
+                println!("Bootloader not detected.");
+                println!("Press the reset button on the keyboard to enter bootloader mode.");
+            }
+        }
+        Command::Run => {
+            let target = target()?;
+            let booted = match waiting_bootloader(&target, bootloader)? {
+                Some(kind) => bootloader::run(&*kind.protocol(board)?, &target)?,
+                None => false,
+            };
+            if json {
+                emit(json!({ "status": "ok", "booted": booted }));
+            } else if booted {
+                println!("Keyboard rebooted into the existing firmware.");
+            } else {
+                println!("Bootloader not detected; nothing to do.");
+            }
+        }
+        Command::Version => {
+            let cli_version = env!("CARGO_PKG_VERSION");
+            let firmware = halfkay::read_build_info(&target()?)?;
+            if json {
+                emit(json!({
+                    "status": "ok",
+                    "cli": cli_version,
+                    "firmware": firmware.map(|info| json!({
+                        "version": info.version,
+                        "git_hash": info.git_hash,
+                        "build_date": info.build_date,
+                        "image_crc32": info.image_crc.map(|c| format!("0x{:08X}", c)),
+                        "reset_cause": info.reset_cause,
+                    })),
+                }));
+            } else {
+                println!("ergodox-cli {}", cli_version);
+                match firmware {
+                    Some(info) => {
+                        println!(
+                            "firmware    {} ({}, built {})",
+                            info.version, info.git_hash, info.build_date
+                        );
+                        match info.image_crc {
+                            Some(crc) => println!("image CRC32 0x{:08X}", crc),
+                            None => println!("image CRC32 (not stamped)"),
+                        }
+                        if let Some(cause) = &info.reset_cause {
+                            println!("last reset  {}", reset_description(cause));
                         }
                     }
-                    if !found {
-                        eprintln!("Teensy bootloader not detected after reboot.");
-                        eprintln!("Press the reset button on the Teensy and try again.");
-                        std::process::exit(1);
+                    None => println!("firmware    (keyboard not connected)"),
+                }
+            }
+        }
+        Command::List => {
+            let devices = halfkay::list()?;
+            if json {
+                let devices: Vec<_> = devices.iter().map(device_json).collect();
+                emit(json!({ "status": "ok", "devices": devices }));
+                return Ok(());
+            }
+            if devices.is_empty() {
+                println!("No keyboards or bootloaders found.");
+            }
+            for d in devices {
+                println!(
+                    "{:03}:{:03}  port {:<10} {:<10}  serial {}",
+                    d.bus,
+                    d.address,
+                    d.port_path(),
+                    mode_name(d.mode),
+                    d.serial.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Command::Udev { install } => {
+            let path = Path::new(udev::RULES_PATH);
+            if install {
+                udev::install(path)?;
+            }
+            let installed = path.exists();
+            if !json {
+                if install {
+                    eprintln!("Installed {} and reloaded udev.", path.display());
+                } else {
+                    print!("{}", udev::rules());
+                    eprintln!(
+                        "\nTo install: sudo ergodox-cli udev --install (or save the above as {})",
+                        path.display()
+                    );
+                }
+            }
+            let devices = udev::diagnose()?;
+            if json {
+                let devices: Vec<_> = devices
+                    .iter()
+                    .map(|d| {
+                        json!({
+                            "mode": mode_name(d.mode),
+                            "port_path": d.port_path,
+                            "node": d.node,
+                            "permissions": d.permissions.map(|p| format!("{p:o}")),
+                            "openable": d.openable,
+                            "fix": d.fix(installed),
+                        })
+                    })
+                    .collect();
+                emit(json!({
+                    "status": "ok",
+                    "rules": udev::rules(),
+                    "path": path,
+                    "installed": installed,
+                    "devices": devices,
+                }));
+            } else {
+                udev::report(&devices, installed);
+            }
+        }
+        Command::Info => {
+            let devices = info::collect()?;
+            if json {
+                let devices: Vec<_> = devices
+                    .iter()
+                    .map(|d| {
+                        let mut device = d.to_json();
+                        device["mode"] = json!(mode_name(d.mode));
+                        device
+                    })
+                    .collect();
+                emit(json!({ "status": "ok", "devices": devices }));
+                return Ok(());
+            }
+            if devices.is_empty() {
+                println!("No keyboards or bootloaders found.");
+            }
+            for (i, d) in devices.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println!(
+                    "{:03}:{:03}  port {}  {}",
+                    d.bus,
+                    d.address,
+                    d.port_path(),
+                    mode_name(d.mode)
+                );
+                println!(
+                    "  ID {:04x}:{:04x}, USB {}, device {}, {}, EP0 {} bytes",
+                    d.vid, d.pid, d.usb_version, d.device_version, d.speed, d.ep0_size
+                );
+                if d.strings_unreadable {
+                    println!(
+                        "  strings       (can't open the device; `ergodox-cli udev` checks why)"
+                    );
+                } else {
+                    let show = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+                    println!("  manufacturer  {}", show(&d.manufacturer));
+                    println!("  product       {}", show(&d.product));
+                    println!("  serial        {}", show(&d.serial));
+                }
+                for interface in &d.interfaces {
+                    for line in interface.to_string().lines() {
+                        println!("  {line}");
+                    }
+                }
+            }
+        }
+        Command::Monitor { log } => {
+            let mut log = log.map(open_key_log).transpose()?;
+            let target = target()?;
+            let channel = halfkay::DebugChannel::open(&target)?;
+            // The keymap the keyboard runs, or if it can't say, the one
+            // this CLI was built with
+            let layers =
+                halfkay::read_keymap(&target).unwrap_or_else(|_| ergodox_keymap::LAYERS.to_vec());
+            let mut typed = monitor::Typed::new(layers, language);
+            // Whatever piled up before we attached is stale
+            while !channel.poll()?.is_empty() {}
+            if !json {
+                println!("Monitoring keyboard debug events (Ctrl-C to stop)");
+            }
+
+            let start = std::time::Instant::now();
+            loop {
+                let elapsed = start.elapsed();
+                let events = monitor::decode(&channel.poll()?, elapsed.as_millis() as u32)?;
+                let time = elapsed.as_secs_f64();
+                for event in events {
+                    let typed = typed.follow(event);
+                    if let (Some(log), monitor::Event::Key(key)) = (&mut log, event) {
+                        if key.pressed {
+                            writeln!(log, "{:.3},{},{}", time, key.row, key.col)?;
+                        }
+                    }
+                    if json {
+                        let mut value = event.to_json();
+                        value["time"] = json!(time);
+                        if let Some(c) = typed {
+                            value["char"] = json!(c.to_string());
+                        }
+                        emit(value);
+                    } else if let Some(c) = typed {
+                        println!("[{:>9.3}] {}  {:?}", time, event, c);
+                    } else {
+                        println!("[{:>9.3}] {}", time, event);
                     }
+                }
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+        }
+        Command::Layer => {
+            let channel = halfkay::NoticeChannel::open(&target()?)?;
+            loop {
+                let Some(notice) = channel.next(std::time::Duration::from_secs(1))? else {
+                    continue;
+                };
+                if json {
+                    emit(notice.to_json());
                 } else {
-                    eprintln!("Teensy bootloader not detected and keyboard not found.");
-                    eprintln!("Press the reset button on the Teensy and try again.");
-                    std::process::exit(1);
+                    println!("{notice}");
                 }
             }
+        }
+        Command::Test => {
+            if json {
+                bail!("`test` is interactive; use `monitor --json` for machine-readable events");
+            }
+            let channel = halfkay::DebugChannel::open(&target()?)?;
+            while !channel.poll()?.is_empty() {}
+
+            let mut tester = tester::Tester::new();
+            let start = std::time::Instant::now();
+            let mut redraw = true;
+            loop {
+                if redraw {
+                    print!("\x1b[H\x1b[2J{}", tester.render(true));
+                    println!("Press every key once (Ctrl-C to stop).");
+                    std::io::stdout().flush()?;
+                }
+                if tester.done() {
+                    println!("All {} switches work.", tester.total());
+                    break;
+                }
 
-            halfkay::flash(base_address, &data)?;
+                let events = monitor::decode(&channel.poll()?, start.elapsed().as_millis() as u32)?;
+                redraw = !events.is_empty();
+                for event in events {
+                    tester.apply(event);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
         }
-        Command::Detect => {
-            if halfkay::detect()? {
-                println!("Teensy bootloader detected (HalfKay mode).");
+        Command::Bench {
+            seconds,
+            latency,
+            key,
+        } => {
+            if !seconds.is_finite() || seconds <= 0.0 {
+                bail!("--seconds must be positive");
+            }
+            let latency = match latency {
+                Some(samples) => {
+                    let (row, col) = key
+                        .or_else(bench::default_key)
+                        .context("the keymap has no modifier to press; pick a key with --key")?;
+                    Some((row, col, samples))
+                }
+                None => None,
+            };
+            let channel = halfkay::BenchChannel::open(&target()?)?;
+            if !json {
+                println!("Counting scans for {seconds} s...");
+            }
+            let report = bench::run(
+                &channel,
+                std::time::Duration::from_secs_f64(seconds),
+                latency,
+            )?;
+            if json {
+                emit(report.to_json());
+            } else {
+                println!("{report}");
+            }
+        }
+        Command::Health => {
+            let diag = halfkay::read_diagnostics(&target()?)?;
+            if json {
+                emit(json!({
+                    "status": "ok",
+                    "left_half_online": diag.left_half_online,
+                    "left_half_losses": diag.left_half_losses,
+                    "i2c_errors": diag.i2c_errors,
+                }));
+            } else {
+                println!(
+                    "left half   {}",
+                    if diag.left_half_online {
+                        "online"
+                    } else {
+                        "OFFLINE (the LED flashes once a second)"
+                    }
+                );
+                println!("dropouts    {}", diag.left_half_losses);
+                println!("I2C errors  {}", diag.i2c_errors);
+                if !diag.left_half_online || diag.left_half_losses > 0 {
+                    println!("Check the TRRS cable, then run `i2c-scan` to see what answers.");
+                }
+            }
+            if !diag.left_half_online {
+                std::process::exit(1);
+            }
+        }
+        Command::Odometer => {
+            let odo = halfkay::read_odometer(&target()?)?;
+            if json {
+                emit(json!({
+                    "status": "ok",
+                    "total": odo.total,
+                    "since_power_on": odo.session,
+                }));
+            } else {
+                println!("total           {} keystrokes", odo.total);
+                println!("since power-on  {} keystrokes", odo.session);
+            }
+        }
+        Command::Chatter => {
+            let target = target()?;
+            let presses = heatmap::from_keyboard(&halfkay::read_press_counts(&target)?)?;
+            let bounces = heatmap::from_keyboard(&halfkay::read_bounce_counts(&target)?)?;
+            let report = chatter::report(&presses, &bounces);
+            if json {
+                emit(report.to_json());
+            } else {
+                println!("{report}");
+            }
+        }
+        Command::Layout {
+            format,
+            geometry,
+            paper,
+            keymap,
+            layers,
+            per_layer_files,
+            out,
+            open,
+            compare,
+        } => {
+            let comparison = match compare.as_deref() {
+                Some([old, new]) => {
+                    if let LayoutFormat::Pdf | LayoutFormat::Ascii = format {
+                        bail!("--compare renders HTML or SVG only");
+                    }
+                    let (old, new) = (load_keymap(old)?, load_keymap(new)?);
+                    let changes = diff::diff(&old, &new);
+                    Some((new, changes))
+                }
+                _ => None,
+            };
+            // A bundle brings its own labels, colors and geometry
+            let (drafted, bundled) = match keymap.as_deref() {
+                Some(spec) if bundle::is_bundle(Path::new(spec)) => {
+                    (None, Some(load_bundle(spec)?))
+                }
+                Some(spec) => (Some(load_keymap(spec)?), None),
+                None => (None, None),
+            };
+            let keys = match geometry {
+                Some(path) if path.extension().is_some_and(|e| e == "toml") => {
+                    geometry::parse(&read_file(&path)?)
+                        .with_context(|| format!("reading geometry from {}", path.display()))?
+                }
+                Some(path) => {
+                    kle::import(&read_file(&path)?)
+                        .with_context(|| format!("importing geometry from {}", path.display()))?
+                        .keys
+                }
+                None => match &bundled {
+                    Some(bundle) => geometry::parse(&bundle.geometry)?,
+                    None => layout::build_keys(),
+                },
+            };
+            let keymap = match (&drafted, &bundled) {
+                (Some(layers), _) => layout::Keymap::file(layers),
+                (_, Some(bundle)) => layout::Keymap::bundle(bundle),
+                (None, None) => layout::Keymap::builtin(),
+            };
+            let layer_count = match &comparison {
+                Some((new, _)) => new.len(),
+                None => keymap.layers.len(),
+            };
+            check_layers(&layers, layer_count)?;
+            let layers = match &comparison {
+                _ if !layers.is_empty() => layers,
+                Some((_, changes)) => {
+                    let mut changed: Vec<usize> = changes.iter().map(|c| c.layer).collect();
+                    changed.dedup();
+                    if changed.is_empty() {
+                        eprintln!("No changes.");
+                        changed.push(0);
+                    }
+                    changed
+                }
+                None => (0..layer_count).collect(),
+            };
+            let render = |layers: &[usize]| match (format, &comparison) {
+                (LayoutFormat::Html, Some((new, changes))) => {
+                    layout::generate_compare_html(&keys, new, changes, layers).into_bytes()
+                }
+                (_, Some((new, changes))) => {
+                    layout::generate_compare_svg(&keys, new, changes, layers).into_bytes()
+                }
+                (LayoutFormat::Html, None) => {
+                    layout::generate_html(&keys, &keymap, layers, language).into_bytes()
+                }
+                (LayoutFormat::Svg, None) => {
+                    layout::generate_svg(&keys, &keymap, layers, language).into_bytes()
+                }
+                (LayoutFormat::Ascii, None) => {
+                    layout::generate_ascii(&keys, &keymap, layers, language).into_bytes()
+                }
+                (LayoutFormat::Pdf, None) => layout::generate_pdf(
+                    &keys,
+                    &keymap,
+                    layers,
+                    language,
+                    match paper {
+                        Paper::A4 => pdf::A4,
+                        Paper::Letter => pdf::LETTER,
+                    },
+                ),
+            };
+
+            // --open without --out still needs a file for the browser to load
+            let out = match (out, open) {
+                (None, true) => Some(
+                    std::env::temp_dir().join(format!("ergodox-layout.{}", format.extension())),
+                ),
+                (out, _) => out,
+            };
+
+            let outputs: Vec<(Option<PathBuf>, Vec<u8>)> = match (&out, per_layer_files) {
+                (Some(path), true) => layers
+                    .iter()
+                    .map(|&layer| (Some(per_layer_path(path, layer)), render(&[layer])))
+                    .collect(),
+                _ => vec![(out, render(&layers))],
+            };
+            for (path, rendered) in outputs {
+                write_output(path.as_deref(), &rendered)?;
+                if let (Some(path), true) = (path, open) {
+                    open_in_browser(&path)?;
+                }
+            }
+        }
+        Command::Import { from, file, out } => {
+            let contents = read_file(&file)?;
+            let layers = match from {
+                ImportFormat::Kle => {
+                    let imported = kle::import(&contents).context("parsing KLE layout")?;
+                    let (layers, unknown) = kle::skeleton(&imported);
+                    for (row, col, label) in unknown {
+                        eprintln!("warning: row {row} col {col}: unknown legend {label:?}, left transparent");
+                    }
+                    layers
+                }
+                ImportFormat::Qmk => {
+                    let imported = if file.extension().is_some_and(|e| e == "c") {
+                        qmk::import_c(&contents).context("parsing QMK keymap.c")?
+                    } else {
+                        qmk::import_json(&contents).context("parsing QMK keymap.json")?
+                    };
+                    for warning in imported.warnings {
+                        eprintln!("warning: {warning}");
+                    }
+                    imported.layers
+                }
+                ImportFormat::Oryx => {
+                    let imported = oryx::import(&contents).context("parsing Oryx layout")?;
+                    for warning in imported.warnings {
+                        eprintln!("warning: {warning}");
+                    }
+                    imported.layers
+                }
+            };
+            write_output(out.as_deref(), codegen::layers_to_rust(&layers).as_bytes())?;
+        }
+        Command::Lint => {
+            let findings = lint::lint(&ergodox_keymap::LAYERS);
+            if json {
+                let list: Vec<_> = findings
+                    .iter()
+                    .map(|f| {
+                        json!({
+                            "layer": f.layer,
+                            "row": f.pos.map(|(r, _)| r),
+                            "col": f.pos.map(|(_, c)| c),
+                            "message": f.message,
+                        })
+                    })
+                    .collect();
+                let status = if findings.is_empty() {
+                    "ok"
+                } else {
+                    "problems"
+                };
+                emit(json!({ "status": status, "findings": list }));
+            } else {
+                for finding in &findings {
+                    println!("warning: {finding}");
+                }
+                if findings.is_empty() {
+                    println!("No problems found.");
+                } else {
+                    eprintln!("{} problem(s) found.", findings.len());
+                }
+            }
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Codegen { file, format, out } => {
+            let layers = keymap_file::parse(&file, &read_file(&file)?)
+                .with_context(|| format!("parsing keymap {}", file.display()))?;
+            let generated = match format {
+                CodegenFormat::Rust => codegen::layers_to_rust(&layers).into_bytes(),
+                CodegenFormat::Bin => codegen::layers_to_blob(&layers),
+            };
+            write_output(out.as_deref(), &generated)?;
+        }
+        Command::XkbAliases {
+            layout,
+            name,
+            symbols_dir,
+            out,
+        } => {
+            let name = name.unwrap_or_else(|| layout.replace(['(', '-'], "_").replace(')', ""));
+            let read = |file: &str| read_file(&symbols_dir.join(file));
+            let module = xkb::aliases(&layout, &name, &read)?;
+            write_output(out.as_deref(), module.as_bytes())?;
+        }
+        Command::Diff { old, new } => {
+            let old = load_keymap_or_firmware(&old)?;
+            let new = load_keymap_or_firmware(&new)?;
+            let changes = diff::diff(&old, &new);
+            if json {
+                let list: Vec<_> = changes
+                    .iter()
+                    .map(|c| {
+                        json!({
+                            "layer": c.layer,
+                            "row": c.row,
+                            "col": c.col,
+                            "old": format!("{:?}", c.old),
+                            "new": format!("{:?}", c.new),
+                        })
+                    })
+                    .collect();
+                emit(json!({ "status": "ok", "changes": list }));
+            } else {
+                print!("{}", diff::render(&changes));
+            }
+        }
+        Command::Keymap {
+            action:
+                KeymapCommand::Print {
+                    keymap,
+                    layers,
+                    markdown,
+                },
+        } => {
+            let table = match keymap.as_deref() {
+                Some(spec) => load_keymap(spec)?,
+                None => ergodox_keymap::LAYERS.to_vec(),
+            };
+            check_layers(&layers, table.len())?;
+            let layers = if layers.is_empty() {
+                (0..table.len()).collect()
+            } else {
+                layers
+            };
+            if json {
+                emit(json!({ "status": "ok", "layers": keymap_text::to_json(&table, &layers) }));
+            } else if markdown {
+                print!("{}", keymap_text::render_markdown(&table, &layers));
+            } else {
+                print!("{}", keymap_text::render(&table, &layers));
+            }
+        }
+        Command::Heatmap {
+            log,
+            from_keyboard: _,
+            clear,
+            out,
+        } => {
+            let counts = match log {
+                Some(path) => heatmap::parse_log(&read_file(&path)?)
+                    .with_context(|| format!("reading {}", path.display()))?,
+                None => heatmap::from_keyboard(&halfkay::read_press_counts(&target()?)?)?,
+            };
+            let svg = layout::generate_heatmap_svg(&layout::build_keys(), &counts, language);
+            write_output(out.as_deref(), svg.as_bytes())?;
+            // Only once the heatmap is safely written
+            if clear {
+                halfkay::clear_press_counts(&target()?)?;
+            }
+        }
+        Command::Export {
+            format,
+            from_keyboard,
+            out,
+        } => {
+            let layers = if from_keyboard {
+                halfkay::read_keymap(&target()?)?
+            } else {
+                ergodox_keymap::LAYERS.to_vec()
+            };
+            let exported = match format {
+                ExportFormat::Kle => kle::export(&layers),
+                ExportFormat::Toml => keymap_file::to_toml(&layers),
+                ExportFormat::Qmk => qmk::export_json(&layers),
+                ExportFormat::Zmk => zmk::export(&layers),
+            };
+            write_output(out.as_deref(), exported.as_bytes())?;
+        }
+        Command::Pack {
+            keymap,
+            name,
+            author,
+            version,
+            geometry,
+            labels,
+            out,
+        } => {
+            let layers = match &keymap {
+                Some(spec) => load_keymap(spec)?,
+                None => ergodox_keymap::LAYERS.to_vec(),
+            };
+            let (labels, colors) = match (labels, &keymap) {
+                (Some(path), _) => bundle::parse_labels(&read_file(&path)?, layers.len())
+                    .with_context(|| format!("reading labels from {}", path.display()))?,
+                (None, None) => (
+                    ergodox_keymap::KEY_LABELS.to_vec(),
+                    ergodox_keymap::LAYER_COLORS.to_vec(),
+                ),
+                // What `layout --keymap` shows a keymap file in
+                (None, Some(_)) => (
+                    Vec::new(),
+                    ergodox_keymap::LAYER_COLORS
+                        .iter()
+                        .take(layers.len())
+                        .copied()
+                        .collect(),
+                ),
+            };
+            let geometry = match geometry {
+                Some(path) => {
+                    let contents = read_file(&path)?;
+                    geometry::parse(&contents)
+                        .with_context(|| format!("reading geometry from {}", path.display()))?;
+                    contents
+                }
+                None => geometry::BUILTIN.to_string(),
+            };
+            let bundle = bundle::Bundle {
+                name,
+                author,
+                version,
+                layers,
+                labels,
+                colors,
+                geometry,
+            };
+            write_output(out.as_deref(), bundle::to_toml(&bundle)?.as_bytes())?;
+        }
+        Command::Unpack { bundle: path, dir } => {
+            let bundle = bundle::parse(&read_file(&path)?)
+                .with_context(|| format!("reading bundle {}", path.display()))?;
+            let dir = match dir {
+                Some(dir) => dir,
+                None => PathBuf::from(path.file_stem().context("bundle path has no file name")?),
+            };
+            fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+            let mut files = vec![
+                ("keymap.toml", keymap_file::to_toml(&bundle.layers)),
+                ("geometry.toml", bundle.geometry.clone()),
+            ];
+            let labels = bundle::labels_to_toml(&bundle.labels, &bundle.colors)?;
+            if !labels.is_empty() {
+                files.push(("labels.toml", labels));
+            }
+            for (name, contents) in &files {
+                write_output(Some(&dir.join(name)), contents.as_bytes())?;
+            }
+
+            if json {
+                emit(json!({
+                    "name": bundle.name,
+                    "author": bundle.author,
+                    "version": bundle.version,
+                    "files": files.iter().map(|(name, _)| dir.join(name)).collect::<Vec<_>>(),
+                }));
             } else {
-                println!("Teensy bootloader not detected.");
-                println!("Press the reset button on the Teensy to enter bootloader mode.");
+                println!("{}", bundle.name);
+                if let Some(author) = &bundle.author {
+                    println!("Author:  {author}");
+                }
+                if let Some(version) = &bundle.version {
+                    println!("Version: {version}");
+                }
+            }
+        }
+        Command::Profiles { action } => {
+            let root = profiles::default_dir().context("no home directory to keep profiles in")?;
+            match action {
+                ProfileCommand::Save {
+                    name,
+                    firmware,
+                    bundle,
+                    base_address,
+                } => {
+                    let firmware = match firmware {
+                        Some(firmware) => firmware,
+                        None => config.firmware.context(
+                            "no firmware given, and no `firmware` default in the config file",
+                        )?,
+                    };
+                    let base_address = base_address.or(config.flash.base_address).unwrap_or(0);
+                    let (base_address, mut data) =
+                        load_firmware(&firmware, base_address, &hex::FlattenOptions::default())?;
+                    let bundle = match bundle {
+                        Some(spec) => {
+                            let bundle = load_bundle(&spec)?;
+                            patch::patch_keymap(&mut data, &bundle.layers)
+                                .with_context(|| format!("patching {}", firmware.display()))?;
+                            Some(bundle)
+                        }
+                        None => profiles::bundle_from_image(&name, &data)?,
+                    };
+                    let firmware = fs::canonicalize(&firmware).unwrap_or(firmware);
+                    let dir = profiles::save(
+                        &root,
+                        &name,
+                        &firmware,
+                        base_address,
+                        &data,
+                        bundle.as_ref(),
+                    )?;
+                    if json {
+                        emit(json!({ "status": "ok", "profile": name, "dir": dir }));
+                    } else {
+                        println!("Saved {name} ({} bytes) in {}", data.len(), dir.display());
+                        if bundle.is_none() {
+                            println!("No keymap table in the image, so no keymap.erk.");
+                        }
+                    }
+                }
+                ProfileCommand::List => {
+                    let profiles = profiles::list(&root)?;
+                    if json {
+                        let profiles: Vec<_> = profiles
+                            .iter()
+                            .map(|p| {
+                                json!({
+                                    "name": p.name,
+                                    "saved": p.info.saved,
+                                    "source": p.info.source,
+                                    "bytes": p.info.bytes,
+                                    "crc32": format!("0x{:08X}", p.info.crc32),
+                                    "keymap": p.bundle.as_ref().map(|b| json!({
+                                        "name": b.name,
+                                        "author": b.author,
+                                        "version": b.version,
+                                        "layers": b.layers.len(),
+                                    })),
+                                })
+                            })
+                            .collect();
+                        emit(json!({ "dir": root, "profiles": profiles }));
+                    } else if profiles.is_empty() {
+                        println!("No profiles in {}.", root.display());
+                    } else {
+                        let width = profiles.iter().map(|p| p.name.len()).max().unwrap_or(0);
+                        for p in &profiles {
+                            let keymap = match &p.bundle {
+                                Some(b) => format!("  {} layers", b.layers.len()),
+                                None => String::new(),
+                            };
+                            println!(
+                                "{:<width$}  {}  {:>6} bytes  CRC32 0x{:08X}{keymap}",
+                                p.name, p.info.saved, p.info.bytes, p.info.crc32
+                            );
+                        }
+                    }
+                }
+                ProfileCommand::Flash {
+                    name,
+                    verify,
+                    no_verify,
+                } => {
+                    let (base_address, mut data) = profiles::load(&root, &name)?;
+                    if !json {
+                        println!(
+                            "Profile {name}: {} bytes at base address 0x{:04X}",
+                            data.len(),
+                            base_address
+                        );
+                    }
+                    stamp_image(&mut data, json)?;
+                    let verify = verify || (!no_verify && config.flash.verify == Some(true));
+                    let target = target()?;
+                    let log_file = config.flash.log_file.as_deref();
+                    let kind = enter_bootloader(&target, bootloader, &data, log_file, json)?;
+                    let options = FlashOptions {
+                        verify,
+                        log_file,
+                        ..FlashOptions::default()
+                    };
+                    flash_and_report(&target, kind, board, base_address, &data, options, json)?;
+                }
+            }
+        }
+        Command::Serve {
+            listen,
+            allow_origins,
+            firmware,
+        } => {
+            let firmware = firmware.or(config.firmware);
+            serve::Server::new(
+                target()?,
+                bootloader,
+                board,
+                firmware,
+                allow_origins,
+                &config.flash,
+            )
+            .run(&listen)?;
+        }
+        Command::Update {
+            repo,
+            yes,
+            force,
+            verify,
+            no_verify,
+        } => {
+            let verify = verify || (!no_verify && config.flash.verify == Some(true));
+            let release = update::latest(&repo)?;
+            let mcu = board.mcu.to_possible_value().expect("no skipped MCUs");
+            let asset = release.firmware_for(mcu.get_name())?;
+            let target = target()?;
+            // Not being able to ask the keyboard is no reason not to update it
+            let running = halfkay::read_build_info(&target)
+                .ok()
+                .flatten()
+                .map(|info| info.version);
+
+            if !force
+                && running
+                    .as_deref()
+                    .is_some_and(|v| update::is_running(&release.tag_name, v))
+            {
+                if json {
+                    emit(json!({ "status": "up_to_date", "release": release.tag_name }));
+                } else {
+                    println!("Already running {}.", release.tag_name);
+                }
+                return Ok(());
             }
+            if !json {
+                println!("Newest release: {} ({})", release.tag_name, asset.name);
+                if let Some(version) = &running {
+                    println!("Running:        {version}");
+                }
+                if let Some(notes) = release.body.as_deref().map(str::trim) {
+                    if !notes.is_empty() {
+                        println!("\n{notes}\n");
+                    }
+                }
+            }
+            if !yes {
+                if json {
+                    bail!("--json can't ask before flashing; add --yes");
+                }
+                if !confirm(&format!("Flash {}?", release.tag_name))? {
+                    println!("Not flashed.");
+                    return Ok(());
+                }
+            }
+
+            let bytes = update::fetch(&asset.browser_download_url)
+                .with_context(|| format!("downloading {}", asset.name))?;
+            // Parsed where it is rather than saved first, so there's no file
+            // in a shared temp directory for someone else to swap out
+            let contents = String::from_utf8(bytes)
+                .with_context(|| format!("{} is not Intel HEX", asset.name))?;
+            let segments =
+                hex::parse_hex(&contents).with_context(|| format!("parsing {}", asset.name))?;
+            let (base_address, mut data) =
+                hex::flatten_segments(&segments, &hex::FlattenOptions::default())
+                    .with_context(|| format!("flattening {}", asset.name))?;
+            stamp_image(&mut data, json)?;
+            let log_file = config.flash.log_file.as_deref();
+            let kind = enter_bootloader(&target, bootloader, &data, log_file, json)?;
+            let options = FlashOptions {
+                verify,
+                resume_at: None,
+                log_file,
+                ..FlashOptions::default()
+            };
+            flash_and_report(&target, kind, board, base_address, &data, options, json)?;
+        }
+        Command::Completions { shell, out } => {
+            let mut script = Vec::new();
+            clap_complete::generate(
+                shell.generator(),
+                &mut Cli::command(),
+                "ergodox-cli",
+                &mut script,
+            );
+            write_output(out.as_deref(), &script)?;
         }
-        Command::Layout => {
-            print!("{}", layout::generate_html());
+        Command::Man { out } => {
+            let mut page = Vec::new();
+            clap_mangen::Man::new(Cli::command().version(env!("CARGO_PKG_VERSION")))
+                .render(&mut page)
+                .context("rendering the man page")?;
+            write_output(out.as_deref(), &page)?;
         }
     }
 
     Ok(())
 }
+
+/// The bootloader that's waiting: `choice` if given, else the first one
+/// found.
+fn waiting_bootloader(
+    target: &halfkay::Target,
+    choice: Option<Bootloader>,
+) -> Result<Option<Bootloader>> {
+    let candidates = match choice {
+        Some(kind) => vec![kind],
+        None => Bootloader::ALL.to_vec(),
+    };
+    for kind in candidates {
+        if kind.detect(target)? {
+            return Ok(Some(kind));
+        }
+    }
+    Ok(None)
+}
+
+/// Make sure a bootloader is waiting, rebooting the running keyboard into
+/// it if needed, and return which one it is.
+///
+/// Only HalfKay can be entered from the firmware: its jump to the
+/// bootloader assumes a Teensy's bootloader address, so DFU and Caterina
+/// boards need their reset button pressed. Not getting there counts as a
+/// failed attempt at flashing `data` in the flash log.
+fn enter_bootloader(
+    target: &halfkay::Target,
+    choice: Option<Bootloader>,
+    data: &[u8],
+    log_file: Option<&Path>,
+    json: bool,
+) -> Result<Bootloader> {
+    let result = reach_bootloader(target, choice, json);
+    if let (Err(e), Some(path)) = (&result, log_file) {
+        let result = Err(anyhow::anyhow!("{e:#}"));
+        log_flash(path, &flash_log::entry(None, None, data, &result, None));
+    }
+    result
+}
+
+fn reach_bootloader(
+    target: &halfkay::Target,
+    choice: Option<Bootloader>,
+    json: bool,
+) -> Result<Bootloader> {
+    if let Some(kind) = waiting_bootloader(target, choice)? {
+        return Ok(kind);
+    }
+    if let Some(kind) = choice.filter(|&kind| kind != Bootloader::Halfkay) {
+        bail!(
+            "{} bootloader not detected. Press the reset button on the keyboard and try again.",
+            kind.name()
+        );
+    }
+    if !halfkay::reboot_to_bootloader(target)? {
+        bail!(
+            "Bootloader not detected and keyboard not found. \
+             Press the reset button on the keyboard and try again."
+        );
+    }
+
+    if !json {
+        println!("Rebooting keyboard into bootloader...");
+    }
+    for _ in 0..50 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        if halfkay::detect(target)? {
+            return Ok(Bootloader::Halfkay);
+        }
+    }
+    bail!(
+        "Teensy bootloader not detected after reboot. \
+         Press the reset button on the Teensy and try again."
+    );
+}
+
+/// Flash (and optionally verify) an image that's already been loaded, then
+/// report the result.
+fn flash_and_report(
+    target: &halfkay::Target,
+    kind: Bootloader,
+    board: Board,
+    base_address: u32,
+    data: &[u8],
+    options: FlashOptions,
+    json: bool,
+) -> Result<()> {
+    let FlashOptions {
+        verify,
+        resume_at,
+        force,
+        write_blank,
+        log_file,
+    } = options;
+    if verify && kind != Bootloader::Halfkay {
+        // The firmware's CRC covers everything below HalfKay's 0x7E00, which
+        // on DFU and Caterina boards includes part of the bootloader
+        bail!("--verify only works with the HalfKay bootloader");
+    }
+    if verify && board.mcu != Mcu::Atmega32u4 {
+        // Likewise, the CRC region is the ATmega32U4's
+        bail!("--verify only works on the ATmega32U4");
+    }
+    let loader = kind.protocol(board)?;
+    let write = bootloader::WriteOptions {
+        resume_at,
+        overwrite_bootloader: force,
+        write_blank,
+        show_progress: !json,
+    };
+    let device = log_file.and_then(|_| flash_log::device(target));
+    let result = bootloader::flash(&*loader, target, base_address, data, &write);
+    if let (Ok(stats), false) = (&result, json) {
+        println!(
+            "Wrote {} page(s), skipped {} blank.",
+            stats.pages_written, stats.pages_skipped
+        );
+        if stats.retries > 0 {
+            println!(
+                "Recovered from {} USB error(s) while flashing.",
+                stats.retries
+            );
+        }
+        println!("Keyboard rebooted. Firmware should be running.");
+    }
+    let verified = (verify && result.is_ok()).then(|| halfkay::verify(target, base_address, data));
+    if let Some(path) = log_file {
+        let entry = flash_log::entry(
+            Some(kind.name()),
+            device.as_deref(),
+            data,
+            &result,
+            verified.as_ref(),
+        );
+        log_flash(path, &entry);
+    }
+    let stats = result?;
+    let crc = verified.transpose()?;
+    if let (Some(crc), false) = (crc, json) {
+        println!("Verified: flash CRC32 0x{:08X} matches the image.", crc);
+    }
+
+    if json {
+        emit(json!({
+            "status": "ok",
+            "protocol": kind.name(),
+            "base_address": base_address,
+            "bytes": data.len(),
+            "pages_written": stats.pages_written,
+            "pages_skipped": stats.pages_skipped,
+            "retries": stats.retries,
+            "image_crc32": stamp::read(data).map(|c| format!("0x{:08X}", c)),
+            "verified_crc32": crc.map(|c| format!("0x{:08X}", c)),
+        }));
+    }
+    Ok(())
+}
+
+/// Add `entry` to the flash log at `path`. Not being able to is worth a
+/// warning, not failing the flash over.
+fn log_flash(path: &Path, entry: &serde_json::Value) {
+    if let Err(e) = flash_log::append(path, entry) {
+        eprintln!("warning: {:#}", e);
+    }
+}
+
+/// Flash whenever a bootloader shows up, until interrupted.
+///
+/// The firmware file at `path` is re-read with `load` for every flash, so
+/// the loop is: rebuild, press reset, repeat. Errors are reported and the
+/// watch carries on.
+fn watch_and_flash(
+    target: &halfkay::Target,
+    choice: Option<Bootloader>,
+    board: Board,
+    path: &Path,
+    load: impl Fn() -> Result<(u32, Vec<u8>)>,
+    options: FlashOptions,
+    json: bool,
+) -> Result<()> {
+    let poll = std::time::Duration::from_millis(100);
+    if !json {
+        println!(
+            "Watching for bootloader; press reset to flash {} (Ctrl-C to stop)",
+            path.display()
+        );
+    }
+
+    loop {
+        let kind = loop {
+            if let Some(kind) = waiting_bootloader(target, choice)? {
+                break kind;
+            }
+            std::thread::sleep(poll);
+        };
+
+        let result = load().and_then(|(base_address, mut data)| {
+            if !json {
+                println!(
+                    "Firmware: {} bytes at base address 0x{:04X}",
+                    data.len(),
+                    base_address
+                );
+            }
+            stamp_image(&mut data, json)?;
+            flash_and_report(target, kind, board, base_address, &data, options, json)
+        });
+        if let Err(e) = result {
+            if json {
+                emit(json!({ "status": "error", "error": format!("{:#}", e) }));
+            } else {
+                eprintln!("error: {:#}", e);
+            }
+        }
+
+        // Don't flash the same bootloader session twice: wait for it to go
+        // away (rebooted into the new firmware, or unplugged).
+        while kind.detect(target)? {
+            std::thread::sleep(poll);
+        }
+        if !json {
+            println!("Waiting for next reset...");
+        }
+    }
+}
+
+/// Stamp an image that's about to be flashed with its CRC32 (see
+/// `stamp.rs`) and say what it is.
+fn stamp_image(data: &mut [u8], json: bool) -> Result<()> {
+    if let Some(crc) = stamp::stamp(data)? {
+        if !json {
+            println!("Image CRC32: 0x{:08X}", crc);
+        }
+    }
+    Ok(())
+}
+
+/// Load a firmware image as (base address, contiguous data).
+///
+/// ELF files are recognised by their magic bytes, so `cargo build` output
+/// works without an extension; `.bin` files are raw images loaded at
+/// `bin_base_address`; anything else is parsed as Intel HEX. ELF and HEX
+/// segments are laid out as `image` says.
+fn load_firmware(
+    path: &Path,
+    bin_base_address: u32,
+    image: &hex::FlattenOptions,
+) -> Result<(u32, Vec<u8>)> {
+    let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    if bytes.starts_with(elf::MAGIC) {
+        let segments = elf::parse_elf(&bytes).context("parsing ELF file")?;
+        return hex::flatten_segments(&segments, image).context("flattening ELF segments");
+    }
+    if path.extension().is_some_and(|e| e == "bin") {
+        if bytes.is_empty() {
+            bail!("{} is empty", path.display());
+        }
+        return Ok((bin_base_address, bytes));
+    }
+
+    let contents = String::from_utf8(bytes)
+        .with_context(|| format!("{} is neither ELF nor Intel HEX", path.display()))?;
+    let segments = hex::parse_hex(&contents).context("parsing Intel HEX file")?;
+    hex::flatten_segments(&segments, image).context("flattening HEX segments")
+}
+
+/// Load what `flash` writes: a firmware file, or for a keymap bundle
+/// `base_firmware` with the bundle's keymap patched in.
+fn load_flash_image(
+    path: &Path,
+    bin_base_address: u32,
+    image: &hex::FlattenOptions,
+    base_firmware: Option<&Path>,
+) -> Result<(u32, Vec<u8>)> {
+    if !bundle::is_bundle(path) {
+        return load_firmware(path, bin_base_address, image);
+    }
+    let base_firmware = base_firmware.context(
+        "a bundle is flashed by patching it into `firmware` from the config file, and there \
+         is none; use `patch-keymap FIRMWARE BUNDLE`",
+    )?;
+    let bundle = bundle::parse(&read_file(path)?)
+        .with_context(|| format!("reading bundle {}", path.display()))?;
+    let (base_address, mut data) = load_firmware(base_firmware, bin_base_address, image)?;
+    patch::patch_keymap(&mut data, &bundle.layers)
+        .with_context(|| format!("patching {}", base_firmware.display()))?;
+    Ok((base_address, data))
+}
+
+/// Parse a byte given in decimal or as 0x-prefixed hex.
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_address(s)?;
+    u8::try_from(value).map_err(|_| format!("{s:?} doesn't fit in a byte"))
+}
+
+/// Parse an address given in decimal or as 0x-prefixed hex.
+fn parse_address(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid address {s:?}: {e}"))
+}
+
+/// Parse a setting from the config file with the same names its flag takes.
+fn config_enum<T: ValueEnum>(key: &str, value: Option<&str>) -> Result<Option<T>> {
+    value
+        .map(|value| {
+            T::from_str(value, true).map_err(|e| anyhow::anyhow!("{key} in config file: {e}"))
+        })
+        .transpose()
+}
+
+/// Parse a key position given as `ROW,COL`, rejecting positions off the matrix.
+fn parse_position(s: &str) -> Result<(u8, u8), String> {
+    let (row, col) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected ROW,COL, got {s:?}"))?;
+    let parse = |value: &str, limit: usize, what: &str| match value.trim().parse::<u8>() {
+        Ok(n) if (n as usize) < limit => Ok(n),
+        _ => Err(format!("{what} must be 0-{}, got {value:?}", limit - 1)),
+    };
+    Ok((
+        parse(row, ergodox_keymap::ROWS, "row")?,
+        parse(col, ergodox_keymap::COLS, "column")?,
+    ))
+}
+
+/// `layout.svg` -> `layout-layer2.svg`, next to the original.
+fn per_layer_path(path: &Path, layer: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-layer{layer}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-layer{layer}"),
+    };
+    path.with_file_name(name)
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))
+}
+
+/// Check layers picked with `--layers` against a keymap of `count` layers.
+fn check_layers(layers: &[usize], count: usize) -> Result<()> {
+    match layers.iter().find(|&&l| l >= count) {
+        Some(layer) => bail!("no layer {layer}; the keymap has layers 0-{}", count - 1),
+        None => Ok(()),
+    }
+}
+
+/// Load a keymap file from disk, or from git when given as `REV:PATH`.
+fn load_keymap(spec: &str) -> Result<Vec<codegen::Layer>> {
+    keymap_file::parse(Path::new(spec), &read_keymap_source(spec)?)
+        .with_context(|| format!("parsing keymap {spec}"))
+}
+
+/// Load a keymap as `load_keymap` does, or from the keymap table of a
+/// firmware image: a `.hex`, `.elf` or `.bin` file, or an ELF file by its
+/// magic bytes.
+fn load_keymap_or_firmware(spec: &str) -> Result<Vec<codegen::Layer>> {
+    let path = Path::new(spec);
+    let is_firmware = path
+        .extension()
+        .is_some_and(|e| e == "hex" || e == "elf" || e == "bin")
+        || fs::read(path).is_ok_and(|bytes| bytes.starts_with(elf::MAGIC));
+    if !is_firmware {
+        return load_keymap(spec);
+    }
+    let (_, data) = load_firmware(path, 0, &hex::FlattenOptions::default())?;
+    let table = patch::find_table(&data).with_context(|| format!("reading keymap from {spec}"))?;
+    patch::unpack(&data[table.offset..table.offset + table.len])
+        .with_context(|| format!("unpacking keymap from {spec}"))
+}
+
+/// Load a keymap bundle given as a path or REV:PATH.
+fn load_bundle(spec: &str) -> Result<bundle::Bundle> {
+    bundle::parse(&read_keymap_source(spec)?).with_context(|| format!("parsing bundle {spec}"))
+}
+
+/// Read a keymap file given as a path or REV:PATH, from git for the latter.
+fn read_keymap_source(spec: &str) -> Result<String> {
+    let path = Path::new(spec);
+    let contents = match spec.split_once(':') {
+        Some((rev, file)) if !path.exists() => {
+            let output = std::process::Command::new("git")
+                .args(["show", &format!("{rev}:{file}")])
+                .output()
+                .context("running git show")?;
+            if !output.status.success() {
+                bail!(
+                    "git show {spec}: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            String::from_utf8(output.stdout).with_context(|| format!("{spec} is not UTF-8"))?
+        }
+        _ => read_file(path)?,
+    };
+    Ok(contents)
+}
+
+/// Open a key log for appending, writing the CSV header if it's new.
+fn open_key_log(path: PathBuf) -> Result<fs::File> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", heatmap::LOG_HEADER)?;
+    }
+    Ok(file)
+}
+
+/// Print one JSON object per line on stdout.
+fn emit(value: serde_json::Value) {
+    println!("{}", value);
+}
+
+/// A device from `halfkay::list` as `list --json` shows it.
+fn device_json(d: &halfkay::DeviceInfo) -> serde_json::Value {
+    json!({
+        "bus": d.bus,
+        "address": d.address,
+        "port": d.port_path(),
+        "mode": mode_name(d.mode),
+        "serial": d.serial,
+    })
+}
+
+fn mode_name(mode: halfkay::Mode) -> &'static str {
+    match mode {
+        halfkay::Mode::Bootloader => "bootloader",
+        halfkay::Mode::Keyboard => "keyboard",
+        halfkay::Mode::Dfu => "dfu-bootloader",
+        halfkay::Mode::Caterina => "caterina-bootloader",
+    }
+}
+
+/// The firmware's reset cause, with what it means for anything unusual.
+fn reset_description(cause: &str) -> &str {
+    match cause {
+        "brown-out" => {
+            "brown-out: the supply voltage sagged (bad cable or hub?); \
+             EEPROM writes are off until the next power-on"
+        }
+        "watchdog" => {
+            "watchdog: the firmware hung; EEPROM writes are off until the \
+             next power-on"
+        }
+        "unknown" => "unknown (started by the bootloader)",
+        other => other,
+    }
+}
+
+/// Ask a yes/no question on the terminal; anything but yes is no.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Write generated output to a file, or to stdout when no path is given.
+fn write_output(out: Option<&Path>, contents: &[u8]) -> Result<()> {
+    match out {
+        Some(path) => {
+            fs::write(path, contents).with_context(|| format!("writing {}", path.display()))?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => std::io::stdout()
+            .write_all(contents)
+            .context("writing to stdout")?,
+    }
+    Ok(())
+}
+
+/// Open a file with the platform's default handler (usually the browser for .html).
+fn open_in_browser(path: &Path) -> Result<()> {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+
+    std::process::Command::new(opener)
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("launching {} to open {}", opener, path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,406 @@
+//! Decoding of the firmware's debug event stream.
+//!
+//! The firmware queues three-byte events (`firmware/src/debug.rs`) and the
+//! CLI drains them with a vendor request; see `halfkay::DebugChannel`.
+//!
+//! Events carry matrix positions, not keycodes. [`Typed`] looks them up in
+//! the keymap to say what each press types on the host.
+//!
+//! Layer notices are separate: the firmware pushes one on its raw HID
+//! interface whenever the layers or the host's lock LEDs change, for
+//! anything that only wants to show the current layer; see
+//! `halfkay::NoticeChannel`.
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+use ergodox_keymap::typing::{self, Language};
+use ergodox_keymap::{lookup_in, KeyEvent, Keycode, COLS, ROWS};
+use serde_json::{json, Value};
+
+use crate::codegen::Layer;
+
+/// Size of one encoded event in bytes.
+pub const EVENT_SIZE: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A key went down or up, stamped with when the CLI drained it.
+    Key(KeyEvent),
+    Layer(u8),
+    /// A left-half scan failed; the count is consecutive failures so far.
+    I2cError(u8),
+    /// The firmware gave up on the left half after too many I2C errors.
+    LeftHalfLost,
+    /// An I2C bus scan found a device at this address.
+    I2cDevice(u8),
+    /// An I2C bus scan finished; `stuck` means something held the bus and
+    /// the scan was cut short.
+    I2cScanDone {
+        found: u8,
+        stuck: bool,
+    },
+    /// The left half answers again after being lost.
+    LeftHalfBack,
+    /// The firmware's queue filled up and events were lost.
+    Overflow,
+    /// An event kind this CLI doesn't know about (newer firmware).
+    Unknown(u8),
+}
+
+/// Split a drained buffer into events, key events stamped `timestamp`
+/// (milliseconds since the CLI started listening).
+pub fn decode(bytes: &[u8], timestamp: u32) -> Result<Vec<Event>> {
+    if !bytes.len().is_multiple_of(EVENT_SIZE) {
+        bail!(
+            "debug event reply was {} bytes, not a multiple of {}",
+            bytes.len(),
+            EVENT_SIZE
+        );
+    }
+    Ok(bytes
+        .chunks(EVENT_SIZE)
+        .map(|e| match e[0] {
+            0x01 | 0x02 => Event::Key(KeyEvent {
+                row: e[1],
+                col: e[2],
+                pressed: e[0] == 0x01,
+                timestamp,
+            }),
+            0x03 => Event::Layer(e[1]),
+            0x04 => Event::I2cError(e[1]),
+            0x05 => Event::LeftHalfLost,
+            0x06 => Event::I2cDevice(e[1]),
+            0x07 => Event::I2cScanDone {
+                found: e[1],
+                stuck: e[2] != 0,
+            },
+            0x08 => Event::LeftHalfBack,
+            0x7F => Event::Overflow,
+            kind => Event::Unknown(kind),
+        })
+        .collect())
+}
+
+/// What key presses type on the host, as far as the event stream tells.
+///
+/// Keys are looked up on the layer the firmware last reported, and Shift and
+/// AltGr are whatever modifier keys went down and haven't come up since, so
+/// a monitor attached while one was held is off until it's released.
+pub struct Typed {
+    layers: Vec<Layer>,
+    language: Language,
+    layer: usize,
+    /// The keys down, with the keycodes they had when pressed.
+    held: Vec<(u8, u8, Keycode)>,
+}
+
+impl Typed {
+    pub fn new(layers: Vec<Layer>, language: Language) -> Typed {
+        Typed {
+            layers,
+            language,
+            layer: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Take in `event`, returning what it types if it's a key press that
+    /// types a character. Presses with Ctrl or GUI held are shortcuts, not
+    /// typing, and give None.
+    pub fn follow(&mut self, event: Event) -> Option<char> {
+        match event {
+            Event::Layer(layer) => {
+                self.layer = layer as usize;
+                None
+            }
+            Event::Key(KeyEvent {
+                row,
+                col,
+                pressed: true,
+                ..
+            }) => {
+                let kc = self.keycode(row as usize, col as usize)?;
+                let held =
+                    |wanted: &[Keycode]| self.held.iter().any(|(.., kc)| wanted.contains(kc));
+                let shift = held(&[Keycode::LShift, Keycode::RShift]);
+                let altgr = held(&[Keycode::RAlt]);
+                let shortcut =
+                    held(&[Keycode::LCtrl, Keycode::RCtrl, Keycode::LGui, Keycode::RGui]);
+                self.held.push((row, col, kc));
+                if shortcut {
+                    None
+                } else {
+                    typing::to_char(kc, shift, altgr, self.language)
+                }
+            }
+            Event::Key(KeyEvent { row, col, .. }) => {
+                self.held.retain(|&(r, c, _)| (r, c) != (row, col));
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// The keycode at a position on the current layer, falling through
+    /// transparent keys.
+    fn keycode(&self, row: usize, col: usize) -> Option<Keycode> {
+        if self.layers.is_empty() || row >= ROWS || col >= COLS {
+            return None;
+        }
+        let layer = self.layer.min(self.layers.len() - 1);
+        Some(lookup_in(&self.layers[..], layer, row, col))
+    }
+}
+
+impl Event {
+    /// The event as a JSON object, for `monitor --json`.
+    pub fn to_json(self) -> Value {
+        match self {
+            Event::Key(KeyEvent {
+                row, col, pressed, ..
+            }) => {
+                let name = if pressed { "key_down" } else { "key_up" };
+                json!({ "event": name, "row": row, "col": col })
+            }
+            Event::Layer(layer) => json!({ "event": "layer", "layer": layer }),
+            Event::I2cError(count) => json!({ "event": "i2c_error", "count": count }),
+            Event::LeftHalfLost => json!({ "event": "left_half_lost" }),
+            Event::I2cDevice(addr) => json!({ "event": "i2c_device", "address": addr }),
+            Event::I2cScanDone { found, stuck } => {
+                json!({ "event": "i2c_scan_done", "found": found, "bus_stuck": stuck })
+            }
+            Event::LeftHalfBack => json!({ "event": "left_half_back" }),
+            Event::Overflow => json!({ "event": "overflow" }),
+            Event::Unknown(kind) => json!({ "event": "unknown", "kind": kind }),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Key(KeyEvent {
+                row,
+                col,
+                pressed: true,
+                ..
+            }) => write!(f, "key down  row {} col {}", row, col),
+            Event::Key(KeyEvent { row, col, .. }) => write!(f, "key up    row {} col {}", row, col),
+            Event::Layer(layer) => write!(f, "layer {}", layer),
+            Event::I2cError(count) => {
+                write!(f, "I2C error talking to left half ({} in a row)", count)
+            }
+            Event::LeftHalfLost => {
+                write!(f, "left half lost: too many I2C errors, no longer scanned")
+            }
+            Event::I2cDevice(addr) => write!(f, "I2C device at 0x{:02X}", addr),
+            Event::I2cScanDone {
+                found,
+                stuck: false,
+            } => {
+                write!(f, "I2C scan done, {} device(s)", found)
+            }
+            Event::I2cScanDone { found, stuck: true } => {
+                write!(f, "I2C scan stopped after {} device(s): bus stuck", found)
+            }
+            Event::LeftHalfBack => write!(f, "left half back: answering again, scanned as usual"),
+            Event::Overflow => write!(f, "firmware event queue overflowed; some events were lost"),
+            Event::Unknown(kind) => write!(f, "unknown event 0x{:02X}", kind),
+        }
+    }
+}
+
+/// First byte of a layer notice.
+const LAYER_NOTICE: u8 = 0x01;
+
+/// Lock LEDs, by bit in the host's LED report.
+const LOCKS: [&str; 3] = ["Num Lock", "Caps Lock", "Scroll Lock"];
+
+/// The layers and lock state, as the firmware pushes them on its raw HID
+/// interface: `[0x01, active layer, toggled layers (u16 LE), host LEDs, 0...]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerNotice {
+    pub layer: u8,
+    /// Layers toggled on, one bit per layer.
+    pub toggled: u16,
+    /// The host's keyboard LEDs, Num Lock from bit 0.
+    pub host_leds: u8,
+}
+
+impl LayerNotice {
+    /// Read one input report. None for anything but a layer notice.
+    pub fn decode(bytes: &[u8]) -> Option<LayerNotice> {
+        match bytes {
+            [LAYER_NOTICE, layer, lo, hi, leds, ..] => Some(LayerNotice {
+                layer: *layer,
+                toggled: u16::from_le_bytes([*lo, *hi]),
+                host_leds: *leds,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn toggled_layers(&self) -> Vec<u8> {
+        (0..16).filter(|l| self.toggled & 1 << l != 0).collect()
+    }
+
+    /// The lock LEDs the host has on.
+    pub fn locks(&self) -> Vec<&'static str> {
+        LOCKS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.host_leds & 1 << bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({
+            "layer": self.layer,
+            "toggled": self.toggled_layers(),
+            "locks": self.locks(),
+        })
+    }
+}
+
+impl fmt::Display for LayerNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "layer {}", self.layer)?;
+        let toggled = self.toggled_layers();
+        if !toggled.is_empty() {
+            let toggled: Vec<String> = toggled.iter().map(u8::to_string).collect();
+            write!(f, ", toggled on: {}", toggled.join(" "))?;
+        }
+        for lock in self.locks() {
+            write!(f, ", {lock}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_notices_decode_layers_and_locks() {
+        // Pinned like the events below: the firmware's `send_layer_notice`
+        // in firmware/src/hid.rs writes this
+        let notice = LayerNotice::decode(&[0x01, 3, 0b1010, 0, 0b010, 0, 0, 0]).unwrap();
+        assert_eq!(
+            notice,
+            LayerNotice {
+                layer: 3,
+                toggled: 0b1010,
+                host_leds: 0b010,
+            }
+        );
+        assert_eq!(notice.to_string(), "layer 3, toggled on: 1 3, Caps Lock");
+        assert_eq!(
+            LayerNotice::decode(&[0x01, 0, 0, 0, 0])
+                .unwrap()
+                .to_string(),
+            "layer 0"
+        );
+        assert_eq!(LayerNotice::decode(&[0x02, 0, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(LayerNotice::decode(&[0x01, 0]), None);
+    }
+
+    // ========================================================================
+    // Debug event wire format
+    //
+    // These byte values are shared with the firmware's `Event::encode` in
+    // firmware/src/debug.rs. Change one side and the monitor silently
+    // mislabels events, so they're pinned here.
+    // ========================================================================
+
+    fn key(row: u8, col: u8, pressed: bool) -> Event {
+        Event::Key(KeyEvent {
+            row,
+            col,
+            pressed,
+            timestamp: 7,
+        })
+    }
+
+    #[test]
+    fn presses_type_what_the_keymap_and_held_modifiers_say() {
+        let mut base = [[Keycode::Trans; COLS]; ROWS];
+        base[0][0] = Keycode::A;
+        base[0][1] = Keycode::LShift;
+        base[0][2] = Keycode::N2;
+        base[0][3] = Keycode::LCtrl;
+        let mut upper = [[Keycode::Trans; COLS]; ROWS];
+        upper[0][0] = Keycode::Quote;
+        let mut typed = Typed::new(vec![base, upper], Language::Nordic);
+
+        assert_eq!(typed.follow(key(0, 0, true)), Some('a'));
+        assert_eq!(typed.follow(key(0, 0, false)), None);
+        typed.follow(key(0, 1, true));
+        assert_eq!(typed.follow(key(0, 2, true)), Some('"'));
+        typed.follow(key(0, 1, false));
+        assert_eq!(typed.follow(key(0, 2, true)), Some('2'));
+        assert_eq!(typed.follow(Event::Layer(1)), None);
+        assert_eq!(typed.follow(key(0, 0, true)), Some('\u{e4}'));
+        // Falls through to the base layer
+        assert_eq!(typed.follow(key(0, 2, true)), Some('2'));
+        typed.follow(key(0, 3, true));
+        assert_eq!(typed.follow(key(0, 2, true)), None);
+    }
+
+    #[test]
+    fn decodes_each_event_kind() {
+        let bytes = [
+            0x01, 2, 13, // key down
+            0x02, 2, 13, // key up
+            0x03, 1, 0, // layer
+            0x04, 3, 0, // I2C error
+            0x05, 0, 0, // left half lost
+            0x06, 0x20, 0, // I2C device
+            0x07, 1, 1, // I2C scan done, bus stuck
+            0x08, 0, 0, // left half back
+            0x7F, 0, 0, // overflow
+        ];
+        assert_eq!(
+            decode(&bytes, 7).unwrap(),
+            vec![
+                key(2, 13, true),
+                key(2, 13, false),
+                Event::Layer(1),
+                Event::I2cError(3),
+                Event::LeftHalfLost,
+                Event::I2cDevice(0x20),
+                Event::I2cScanDone {
+                    found: 1,
+                    stuck: true
+                },
+                Event::LeftHalfBack,
+                Event::Overflow,
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_kinds_are_kept_not_rejected() {
+        // Newer firmware may add events; an old CLI should keep going.
+        assert_eq!(
+            decode(&[0x42, 0, 0], 0).unwrap(),
+            vec![Event::Unknown(0x42)]
+        );
+    }
+
+    #[test]
+    fn partial_event_is_an_error() {
+        assert!(decode(&[0x01, 2], 0).is_err());
+        assert_eq!(decode(&[], 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn json_names_the_event() {
+        let v = key(1, 2, true).to_json();
+        assert_eq!(v["event"], "key_down");
+        assert_eq!(v["row"], 1);
+        assert_eq!(v["col"], 2);
+    }
+}
@@ -0,0 +1,187 @@
+//! Import ErgoDox EZ layouts from Oryx, ZSA's online configurator.
+//!
+//! Oryx's layout JSON (what its GraphQL API returns for a layout, with or
+//! without the `{"data": ...}` envelope) lists each layer's 76 keys in
+//! `LAYOUT_ergodox` order. Every key has a tap action and optionally a hold
+//! action; they are rewritten as the QMK keycodes Oryx compiles them to and
+//! imported like a QMK keymap (see `qmk.rs`), warnings and all.
+//!
+//! The source download Oryx also offers is a QMK `keymap.c`, for
+//! `import --from qmk`.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::qmk::{self, QmkImport};
+
+/// Import an Oryx layout.
+pub fn import(json: &str) -> Result<QmkImport> {
+    let doc: Value = serde_json::from_str(json).context("invalid JSON")?;
+
+    let layout = doc
+        .pointer("/data/layout")
+        .or_else(|| doc.get("layout"))
+        .unwrap_or(&doc);
+    if let Some(geometry) = layout["geometry"].as_str() {
+        if !geometry.starts_with("ergodox") {
+            bail!("layout is for {geometry:?}, not an ErgoDox EZ");
+        }
+    }
+    let revision = layout.get("revision").unwrap_or(layout);
+
+    let mut layers: Vec<&Value> = revision["layers"]
+        .as_array()
+        .context("missing \"layers\" array")?
+        .iter()
+        .collect();
+    layers.sort_by_key(|layer| layer["position"].as_u64());
+
+    let mut warnings = Vec::new();
+    let mut args = Vec::with_capacity(layers.len());
+    for (l, layer) in layers.iter().enumerate() {
+        let keys = layer["keys"]
+            .as_array()
+            .with_context(|| format!("layer {l} has no \"keys\" array"))?;
+        let mut layer_args = Vec::with_capacity(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            for (field, what) in [("doubleTap", "double-tap"), ("tapHold", "tap-and-hold")] {
+                if !key[field].is_null() {
+                    warnings.push(format!("layer {l} key {i}: {what} action dropped"));
+                }
+            }
+            let (expr, dropped) = keycode(key);
+            if let Some(hold) = dropped {
+                warnings.push(format!("layer {l} key {i}: hold action {hold} dropped"));
+            }
+            layer_args.push(expr);
+        }
+        args.push(layer_args);
+    }
+
+    let mut imported = qmk::import_args(&args)?;
+    warnings.append(&mut imported.warnings);
+    imported.warnings = warnings;
+    Ok(imported)
+}
+
+/// The QMK keycode expression for a key's tap and hold actions, and a hold
+/// action QMK has no shorthand for, which is left out.
+fn keycode(key: &Value) -> (String, Option<String>) {
+    let tap = match action(&key["tap"]) {
+        Some(tap) => tap,
+        None => "KC_TRNS".to_string(),
+    };
+    let hold = &key["hold"];
+    if let Some(layer) = hold["layer"].as_u64() {
+        return (format!("LT({layer}, {tap})"), None);
+    }
+    match hold["code"].as_str() {
+        Some(code) => match mod_tap(code) {
+            Some(mt) => (format!("{mt}({tap})"), None),
+            None => (tap, Some(code.to_string())),
+        },
+        None => (tap, None),
+    }
+}
+
+/// One Oryx action as a QMK keycode: a plain code, a layer function like
+/// `MO` with its layer, or a code with modifiers held.
+fn action(action: &Value) -> Option<String> {
+    let code = action["code"].as_str()?;
+    if let Some(layer) = action["layer"].as_u64() {
+        return Some(format!("{code}({layer})"));
+    }
+    let mut expr = code.to_string();
+    if let Some(mods) = action["modifiers"].as_object() {
+        for (name, wrapper) in MODIFIERS {
+            if mods.get(name).and_then(Value::as_bool) == Some(true) {
+                expr = format!("{wrapper}({expr})");
+            }
+        }
+    }
+    Some(expr)
+}
+
+/// Oryx's modifier flags and the QMK functions that hold them.
+const MODIFIERS: [(&str, &str); 8] = [
+    ("leftCtrl", "LCTL"),
+    ("leftShift", "LSFT"),
+    ("leftAlt", "LALT"),
+    ("leftGui", "LGUI"),
+    ("rightCtrl", "RCTL"),
+    ("rightShift", "RSFT"),
+    ("rightAlt", "RALT"),
+    ("rightGui", "RGUI"),
+];
+
+/// The QMK mod-tap function for a modifier held on a hold.
+fn mod_tap(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "KC_LCTRL" | "KC_LCTL" | "KC_LEFT_CTRL" => "LCTL_T",
+        "KC_LSHIFT" | "KC_LSFT" | "KC_LEFT_SHIFT" => "LSFT_T",
+        "KC_LALT" | "KC_LEFT_ALT" => "LALT_T",
+        "KC_LGUI" | "KC_LEFT_GUI" => "LGUI_T",
+        "KC_RCTRL" | "KC_RCTL" | "KC_RIGHT_CTRL" => "RCTL_T",
+        "KC_RSHIFT" | "KC_RSFT" | "KC_RIGHT_SHIFT" => "RSFT_T",
+        "KC_RALT" | "KC_RIGHT_ALT" => "RALT_T",
+        "KC_RGUI" | "KC_RIGHT_GUI" => "RGUI_T",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::Keycode;
+    use serde_json::json;
+
+    fn layer(position: u64, first: Value) -> Value {
+        let mut keys = vec![json!({ "tap": { "code": "KC_TRANSPARENT" } }); 76];
+        keys[0] = first;
+        json!({ "position": position, "title": "", "keys": keys })
+    }
+
+    #[test]
+    fn tap_and_hold_become_qmk_keycodes() {
+        let plain = json!({ "tap": { "code": "KC_A" }, "hold": null });
+        assert_eq!(keycode(&plain).0, "KC_A");
+        let layer_tap =
+            json!({ "tap": { "code": "KC_SPACE" }, "hold": { "code": "MO", "layer": 2 } });
+        assert_eq!(keycode(&layer_tap).0, "LT(2, KC_SPACE)");
+        let mod_tap = json!({ "tap": { "code": "KC_ESCAPE" }, "hold": { "code": "KC_LCTRL" } });
+        assert_eq!(keycode(&mod_tap).0, "LCTL_T(KC_ESCAPE)");
+        let momentary = json!({ "tap": { "code": "MO", "layer": 1 } });
+        assert_eq!(keycode(&momentary).0, "MO(1)");
+        let shifted = json!({ "tap": { "code": "KC_1", "modifiers": { "leftShift": true } } });
+        assert_eq!(keycode(&shifted).0, "LSFT(KC_1)");
+        assert_eq!(keycode(&json!({ "tap": null })).0, "KC_TRNS");
+        let other = json!({ "tap": { "code": "KC_A" }, "hold": { "code": "KC_B" } });
+        assert_eq!(
+            keycode(&other),
+            ("KC_A".to_string(), Some("KC_B".to_string()))
+        );
+    }
+
+    #[test]
+    fn imports_layers_in_position_order_from_the_api_envelope() {
+        let doc = json!({ "data": { "layout": {
+            "geometry": "ergodox-ez",
+            "revision": { "layers": [
+                layer(1, json!({ "tap": { "code": "KC_F1" } })),
+                layer(0, json!({ "tap": { "code": "KC_EQUAL" }, "doubleTap": { "code": "KC_A" } })),
+            ] },
+        } } });
+        let imported = import(&doc.to_string()).unwrap();
+        assert_eq!(imported.layers.len(), 2);
+        assert_eq!(imported.layers[0][0][0], Keycode::Equal);
+        assert_eq!(imported.layers[1][0][0], Keycode::F1);
+        assert_eq!(imported.warnings.len(), 1);
+        assert!(imported.warnings[0].contains("double-tap"));
+    }
+
+    #[test]
+    fn rejects_other_keyboards() {
+        let doc = json!({ "layout": { "geometry": "moonlander", "revision": { "layers": [] } } });
+        assert!(import(&doc.to_string()).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Rewrite the keymap inside a built firmware image.
+//!
+//! The firmware keeps its keymap in a `KeymapTable` between
+//! `KEYMAP_START_MARKER` and `KEYMAP_END_MARKER`: layer 0 a byte per matrix
+//! position, then the upper layers' non-transparent keys. The table is a
+//! static in program memory, so it's part of the flash image and can be
+//! found and repacked there. The running keyboard hands out the same bytes
+//! when asked, which [`unpack`] turns back into layers.
+
+use anyhow::{bail, Result};
+use ergodox_keymap::{
+    pack, table_size, upper_keys, Layers, PackedLayers, COLS, KEYMAP_END_MARKER,
+    KEYMAP_START_MARKER, ROWS,
+};
+
+use crate::codegen::Layer;
+
+/// Where a keymap table sits in an image.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TableLocation {
+    /// Offset of the first byte after the start marker.
+    pub offset: usize,
+    /// Bytes up to the end marker.
+    pub len: usize,
+    /// How many keys beyond layer 0 the table has room for.
+    pub upper_keys: usize,
+}
+
+/// Find the keymap table in a flattened firmware image.
+pub fn find_table(image: &[u8]) -> Result<TableLocation> {
+    let starts = find_all(image, &KEYMAP_START_MARKER);
+    let start = match starts.as_slice() {
+        [] => bail!("no keymap table in this firmware (built before patch-keymap support?)"),
+        [start] => *start + KEYMAP_START_MARKER.len(),
+        _ => bail!("keymap table start marker appears {} times", starts.len()),
+    };
+    let Some(len) = find_all(&image[start..], &KEYMAP_END_MARKER)
+        .first()
+        .copied()
+    else {
+        bail!("keymap table has no end marker");
+    };
+    let markers = KEYMAP_START_MARKER.len() + KEYMAP_END_MARKER.len();
+    let upper = (len + markers).saturating_sub(table_size(ROWS * COLS, 0)) / 3;
+    if len + markers != table_size(ROWS * COLS, upper) {
+        bail!(
+            "keymap table is {} bytes, not a packed {}-key keymap (built before packed tables?)",
+            len,
+            ROWS * COLS
+        );
+    }
+    Ok(TableLocation {
+        offset: start,
+        len,
+        upper_keys: upper,
+    })
+}
+
+/// Overwrite the keymap table in `image` with `layers`.
+pub fn patch_keymap(image: &mut [u8], layers: &[Layer]) -> Result<TableLocation> {
+    let table = find_table(image)?;
+    if layers.is_empty() {
+        bail!("keymap has no layers");
+    }
+    if !pack(layers, &mut image[table.offset..table.offset + table.len]) {
+        bail!(
+            "keymap has {} keys beyond layer 0 but the firmware was built with room for {}",
+            upper_keys(layers),
+            table.upper_keys
+        );
+    }
+    Ok(table)
+}
+
+/// The layers in a table's bytes between its markers.
+pub fn unpack(table: &[u8]) -> Result<Vec<Layer>> {
+    let positions = ROWS * COLS;
+    let count = table.first().copied().unwrap_or(0) as usize;
+    if count == 0 || table.len() < 1 + positions || !table[1 + positions..].contains(&0xFF) {
+        bail!(
+            "not a packed {positions}-key keymap table ({} bytes)",
+            table.len()
+        );
+    }
+    // Reading past the end finds the 0xFF that ends the upper layers' keys
+    let packed = PackedLayers::<_, ROWS, COLS>::new(|i| table.get(i).copied().unwrap_or(0xFF));
+    Ok((0..count)
+        .map(|l| std::array::from_fn(|row| std::array::from_fn(|col| packed.key(l, row, col))))
+        .collect())
+}
+
+pub(crate) fn find_all(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, w)| *w == needle)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::{Keycode, KeymapTable, Layers, LAYERS, NUM_LAYERS};
+
+    /// A fake firmware image with a `KeymapTable` for `LAYERS` in the middle,
+    /// laid out as `keymap_table_is_markers_around_packed_layers` pins it.
+    fn image() -> Vec<u8> {
+        let table: KeymapTable = KeymapTable::new(&LAYERS);
+        let mut image = vec![0x0C; 300];
+        image.extend_from_slice(&table.bytes);
+        image.extend_from_slice(&[0x95; 100]);
+        image
+    }
+
+    /// The keymap table in `image`, as the firmware reads it.
+    fn table(image: &[u8]) -> KeymapTable<{ ergodox_keymap::KEYMAP_TABLE_SIZE }> {
+        let start = find_table(image).unwrap().offset - KEYMAP_START_MARKER.len();
+        KeymapTable {
+            bytes: image[start..start + ergodox_keymap::KEYMAP_TABLE_SIZE]
+                .try_into()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn finds_the_table() {
+        let table = find_table(&image()).unwrap();
+        assert_eq!(table.offset, 308);
+        assert_eq!(
+            table.upper_keys,
+            upper_keys(&LAYERS) + ergodox_keymap::SPARE_KEYS
+        );
+    }
+
+    #[test]
+    fn patch_replaces_keys_and_drops_missing_layers() {
+        let mut image = image();
+        let mut layer = LAYERS[0];
+        layer[1][1] = Keycode::Z;
+        let location = patch_keymap(&mut image, &[layer]).unwrap();
+
+        let table = table(&image);
+        let layers = table.layers::<ROWS, COLS>();
+        assert_eq!(layers.count(), 1);
+        assert_eq!(layers.key(0, 1, 1), Keycode::Z);
+        assert_eq!(layers.key(1, 0, 1), Keycode::Trans);
+        // Markers and surrounding code untouched
+        assert_eq!(find_table(&image).unwrap(), location);
+        assert_eq!(image[0], 0x0C);
+        assert_eq!(image[image.len() - 1], 0x95);
+    }
+
+    #[test]
+    fn patch_can_add_layers_while_keys_fit() {
+        let mut image = image();
+        let mut layers = vec![[[Keycode::Trans; COLS]; ROWS]; NUM_LAYERS + 1];
+        layers[0] = LAYERS[0];
+        layers[NUM_LAYERS][2][3] = Keycode::F5;
+        patch_keymap(&mut image, &layers).unwrap();
+        let table = table(&image);
+        let packed = table.layers::<ROWS, COLS>();
+        assert_eq!(packed.count(), NUM_LAYERS + 1);
+        assert_eq!(packed.key(NUM_LAYERS, 2, 3), Keycode::F5);
+    }
+
+    #[test]
+    fn too_many_upper_keys_is_an_error() {
+        let layers = vec![LAYERS[0]; NUM_LAYERS + 1];
+        let err = patch_keymap(&mut image(), &layers).unwrap_err();
+        assert!(err.to_string().contains("room for"));
+    }
+
+    #[test]
+    fn unpacks_what_was_packed() {
+        let location = find_table(&image()).unwrap();
+        let body = &image()[location.offset..location.offset + location.len];
+        assert_eq!(unpack(body).unwrap(), LAYERS);
+        assert!(unpack(&body[..10]).is_err());
+        assert!(unpack(&[]).is_err());
+    }
+
+    #[test]
+    fn missing_or_duplicate_marker_is_an_error() {
+        assert!(find_table(&[0u8; 64]).is_err());
+        let mut twice = image();
+        twice.extend(image());
+        assert!(find_table(&twice).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Minimal PDF writer for the printable layout cheat-sheet.
+//!
+//! Only what the cheat-sheet needs: pages of filled/stroked rectangles and
+//! single-line text in the built-in Helvetica fonts. The base-14 fonts are
+//! guaranteed to be present in every PDF viewer, so nothing is embedded and
+//! the output stays a few kilobytes per page.
+
+/// Page dimensions in PDF points (1/72 inch), landscape orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageSize {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// ISO A4, landscape.
+pub const A4: PageSize = PageSize {
+    width: 842.0,
+    height: 595.0,
+};
+
+/// US Letter, landscape.
+pub const LETTER: PageSize = PageSize {
+    width: 792.0,
+    height: 612.0,
+};
+
+/// An RGB color with components in 0.0..=1.0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(pub f64, pub f64, pub f64);
+
+/// Which of the two fonts registered in every document to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Font {
+    Regular,
+    Bold,
+}
+
+impl Font {
+    fn resource_name(self) -> &'static str {
+        match self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+        }
+    }
+}
+
+/// Fill and stroke settings for a rectangle.
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    pub fill: Rgb,
+    pub stroke: Rgb,
+    pub line_width: f64,
+    /// Dash pattern (on, off) in points, or `None` for a solid line.
+    pub dash: Option<(f64, f64)>,
+}
+
+/// A single page's content stream. Coordinates are top-left based, like SVG;
+/// they are flipped into PDF's bottom-left space when emitted.
+pub struct Page {
+    size: PageSize,
+    ops: String,
+}
+
+impl Page {
+    pub fn new(size: PageSize) -> Self {
+        Self {
+            size,
+            ops: String::new(),
+        }
+    }
+
+    /// Draw a filled and stroked rectangle with its top-left corner at (x, y).
+    pub fn rect(&mut self, x: f64, y: f64, w: f64, h: f64, style: &Style) {
+        let Rgb(fr, fg, fb) = style.fill;
+        let Rgb(sr, sg, sb) = style.stroke;
+        let dash = match style.dash {
+            Some((on, off)) => format!("[{} {}] 0 d", num(on), num(off)),
+            None => "[] 0 d".to_string(),
+        };
+        self.ops.push_str(&format!(
+            "{} {} {} rg {} {} {} RG {} w {}\n{} {} {} {} re B\n",
+            num(fr),
+            num(fg),
+            num(fb),
+            num(sr),
+            num(sg),
+            num(sb),
+            num(style.line_width),
+            dash,
+            num(x),
+            num(self.size.height - y - h),
+            num(w),
+            num(h),
+        ));
+    }
+
+    /// Draw text horizontally centered on `cx`, with its baseline at `y`.
+    pub fn text_centered(
+        &mut self,
+        cx: f64,
+        y: f64,
+        size: f64,
+        font: Font,
+        color: Rgb,
+        text: &str,
+    ) {
+        let x = cx - text_width(text, size) / 2.0;
+        self.text(x, y, size, font, color, text);
+    }
+
+    /// Draw left-aligned text with its baseline at (x, y).
+    pub fn text(&mut self, x: f64, y: f64, size: f64, font: Font, color: Rgb, text: &str) {
+        let Rgb(r, g, b) = color;
+        self.ops.push_str(&format!(
+            "BT {} {} {} rg /{} {} Tf {} {} Td ({}) Tj ET\n",
+            num(r),
+            num(g),
+            num(b),
+            font.resource_name(),
+            num(size),
+            num(x),
+            num(self.size.height - y),
+            escape_string(text),
+        ));
+    }
+}
+
+/// Serialize pages into a complete PDF 1.4 document.
+pub fn write_document(pages: &[Page]) -> Vec<u8> {
+    // Object layout: 1 = catalog, 2 = page tree, 3/4 = fonts,
+    // then (page, content stream) pairs starting at 5.
+    let page_obj = |i: usize| 5 + 2 * i;
+
+    let mut objects: Vec<String> = Vec::new();
+    objects.push("<< /Type /Catalog /Pages 2 0 R >>".to_string());
+
+    let kids: Vec<String> = (0..pages.len())
+        .map(|i| format!("{} 0 R", page_obj(i)))
+        .collect();
+    objects.push(format!(
+        "<< /Type /Pages /Kids [{}] /Count {} >>",
+        kids.join(" "),
+        pages.len()
+    ));
+
+    for base in ["Helvetica", "Helvetica-Bold"] {
+        objects.push(format!(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /{base} /Encoding /WinAnsiEncoding >>"
+        ));
+    }
+
+    for (i, page) in pages.iter().enumerate() {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+            num(page.size.width),
+            num(page.size.height),
+            page_obj(i) + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            page.ops.len(),
+            page.ops
+        ));
+    }
+
+    // The binary comment line marks the file as binary for transfer tools.
+    let mut out: Vec<u8> = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, body) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, body).as_bytes());
+    }
+
+    let xref_offset = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n", objects.len() + 1).as_bytes());
+    out.extend_from_slice(b"0000000000 65535 f \n");
+    for offset in offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+    out
+}
+
+/// Format a number compactly: PDF readers accept plain decimals only.
+fn num(v: f64) -> String {
+    let s = format!("{:.2}", v);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// Encode text as a PDF literal string body in WinAnsiEncoding.
+///
+/// Latin-1 characters (å, ö, §, ´ ...) map 1:1 onto WinAnsi, and € has a
+/// slot of its own. Characters the base-14 fonts can't show (the arrow
+/// glyphs) get ASCII stand-ins.
+fn escape_string(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            '\u{a0}'..='\u{ff}' => out.push_str(&format!("\\{:03o}", c as u32)),
+            '\u{20ac}' => out.push_str("\\200"),
+            '\u{2190}' => out.push_str("<-"),
+            '\u{2192}' => out.push_str("->"),
+            '\u{2191}' => out.push('^'),
+            '\u{2193}' => out.push('v'),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+/// Approximate rendered width of `text` in Helvetica at `size` points.
+pub fn text_width(text: &str, size: f64) -> f64 {
+    let units: u32 = text
+        .chars()
+        .map(|c| match c {
+            // "<-" / "->" stand-ins
+            '\u{2190}' | '\u{2192}' => 917,
+            ' '..='~' => HELVETICA_WIDTHS[(c as u8 - b' ') as usize],
+            _ => 556,
+        } as u32)
+        .sum();
+    units as f64 * size / 1000.0
+}
+
+/// Helvetica advance widths (1/1000 em) for ASCII 0x20..=0x7E, from the
+/// Adobe font metrics that every PDF viewer uses for the base-14 font.
+#[rustfmt::skip]
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556,
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556,
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // =========================================================================
+    // Document structure
+    // =========================================================================
+    //
+    // A PDF is a header, a list of numbered objects, and a cross-reference
+    // table giving the byte offset of each object. Readers seek via the xref
+    // table, so a single wrong offset produces a "damaged file" warning or
+    // a blank page.
+
+    #[test]
+    fn document_has_header_and_trailer() {
+        let pdf = write_document(&[Page::new(A4)]);
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+    }
+
+    #[test]
+    fn xref_offsets_point_at_objects() {
+        let mut page = Page::new(A4);
+        page.text(10.0, 10.0, 12.0, Font::Regular, Rgb(0.0, 0.0, 0.0), "Hej");
+        let pdf = write_document(&[page]);
+        let text = String::from_utf8_lossy(&pdf);
+
+        let xref_at = text.find("xref\n").unwrap();
+        let entries: Vec<usize> = text[xref_at..]
+            .lines()
+            .skip(3) // "xref", "0 N", free entry
+            .take_while(|l| l.ends_with(" n "))
+            .map(|l| l[..10].parse().unwrap())
+            .collect();
+
+        assert_eq!(entries.len(), 6, "catalog, pages, 2 fonts, page, content");
+        for (i, offset) in entries.iter().enumerate() {
+            let expected = format!("{} 0 obj", i + 1);
+            assert!(
+                pdf[*offset..].starts_with(expected.as_bytes()),
+                "object {} not at offset {}",
+                i + 1,
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn page_count_matches_pages() {
+        let pdf = write_document(&[Page::new(LETTER), Page::new(LETTER)]);
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Count 2"));
+    }
+
+    // =========================================================================
+    // Text encoding
+    // =========================================================================
+    //
+    // Literal strings are delimited by parentheses, so unbalanced ( ) or a
+    // stray backslash would swallow the rest of the content stream. Non-ASCII
+    // legends (the Nordic keys) must come out as WinAnsi octal escapes.
+
+    #[test]
+    fn parentheses_and_backslash_are_escaped() {
+        assert_eq!(escape_string(r"(a)\"), r"\(a\)\\");
+    }
+
+    #[test]
+    fn latin1_legends_become_octal_escapes() {
+        // å = U+00E5 = 0o345 in WinAnsi
+        assert_eq!(escape_string("\u{e5}"), "\\345");
+        assert_eq!(escape_string("\u{a7}\u{bd}"), "\\247\\275");
+        // € isn't Latin-1 but WinAnsi has it at 0x80
+        assert_eq!(escape_string("\u{20ac}"), "\\200");
+    }
+
+    #[test]
+    fn arrows_fall_back_to_ascii() {
+        assert_eq!(escape_string("\u{2190}\u{2192}"), "<-->");
+    }
+
+    #[test]
+    fn y_axis_is_flipped_to_bottom_left_origin() {
+        let mut page = Page::new(A4);
+        let style = Style {
+            fill: Rgb(1.0, 1.0, 1.0),
+            stroke: Rgb(0.0, 0.0, 0.0),
+            line_width: 1.0,
+            dash: None,
+        };
+        page.rect(0.0, 0.0, 10.0, 20.0, &style);
+        // A rect at the top of the page starts at height - h in PDF space.
+        assert!(page.ops.contains("0 575 10 20 re"));
+    }
+
+    #[test]
+    fn numbers_are_compact_decimals() {
+        assert_eq!(num(1.0), "1");
+        assert_eq!(num(0.5), "0.5");
+        assert_eq!(num(12.345), "12.35");
+        assert_eq!(num(-0.001), "0");
+    }
+}
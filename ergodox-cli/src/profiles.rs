@@ -0,0 +1,219 @@
+//! Saved firmware profiles, for `profiles`: named firmware images kept
+//! under `$XDG_CONFIG_HOME/ergodox/profiles`, so switching between, say, a
+//! "work" and a "gaming" build is one `profiles flash`.
+//!
+//! A profile is a directory named after it with the image as
+//! `firmware.hex`, its keymap as a bundle in `keymap.erk` (see `bundle.rs`)
+//! when the image has a keymap table, and `profile.json` saying when it was
+//! saved and from what. The image is flashed as saved; the bundle is there
+//! to look at with `layout --keymap` or hand on.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::bundle::{self, Bundle};
+use crate::{config, geometry, hex, patch};
+
+const FIRMWARE: &str = "firmware.hex";
+const BUNDLE: &str = "keymap.erk";
+const INFO: &str = "profile.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Info {
+    /// When it was saved, as RFC 3339 UTC.
+    pub saved: String,
+    /// The firmware file it was saved from.
+    pub source: PathBuf,
+    pub base_address: u32,
+    pub bytes: usize,
+    pub crc32: u32,
+}
+
+/// A saved profile, as `list` finds it.
+pub struct Profile {
+    pub name: String,
+    pub info: Info,
+    pub bundle: Option<Bundle>,
+}
+
+/// Where profiles live: `profiles` next to the config file.
+pub fn default_dir() -> Option<PathBuf> {
+    Some(config::dir()?.join("profiles"))
+}
+
+/// Save `data` from `source` as profile `name` in `root`, with `bundle` as
+/// its keymap, replacing any profile of that name.
+pub fn save(
+    root: &Path,
+    name: &str,
+    source: &Path,
+    base_address: u32,
+    data: &[u8],
+    bundle: Option<&Bundle>,
+) -> Result<PathBuf> {
+    let dir = profile_dir(root, name)?;
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let segment = hex::HexSegment {
+        address: base_address,
+        data: data.to_vec(),
+    };
+    write(&dir.join(FIRMWARE), &hex::write_hex(&[segment]))?;
+    let bundle_path = dir.join(BUNDLE);
+    match bundle {
+        Some(bundle) => write(&bundle_path, &bundle::to_toml(bundle)?)?,
+        // Not the keymap of the image it replaces
+        None => match fs::remove_file(&bundle_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing {}", bundle_path.display()))
+            }
+            _ => {}
+        },
+    }
+    let info = Info {
+        saved: crate::flash_log::utc_now(),
+        source: source.to_path_buf(),
+        base_address,
+        bytes: data.len(),
+        crc32: crc32fast::hash(data),
+    };
+    write(&dir.join(INFO), &serde_json::to_string_pretty(&info)?)?;
+    Ok(dir)
+}
+
+/// The profiles in `root`, by name; none if it doesn't exist yet.
+pub fn list(root: &Path) -> Result<Vec<Profile>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", root.display())),
+    };
+    let mut profiles = Vec::new();
+    for entry in entries {
+        let dir = entry
+            .with_context(|| format!("reading {}", root.display()))?
+            .path();
+        let info_path = dir.join(INFO);
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        if !info_path.is_file() || check_name(&name).is_err() {
+            continue;
+        }
+        let info = serde_json::from_str(&read(&info_path)?)
+            .with_context(|| format!("parsing {}", info_path.display()))?;
+        let bundle_path = dir.join(BUNDLE);
+        let bundle = if bundle_path.is_file() {
+            let bundle = bundle::parse(&read(&bundle_path)?)
+                .with_context(|| format!("reading bundle {}", bundle_path.display()))?;
+            Some(bundle)
+        } else {
+            None
+        };
+        profiles.push(Profile {
+            name: name.into_owned(),
+            info,
+            bundle,
+        });
+    }
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(profiles)
+}
+
+/// Load profile `name`'s image as (base address, contiguous data).
+pub fn load(root: &Path, name: &str) -> Result<(u32, Vec<u8>)> {
+    let path = profile_dir(root, name)?.join(FIRMWARE);
+    if !path.is_file() {
+        bail!("no profile {name:?}; `ergodox-cli profiles list` shows the saved ones");
+    }
+    let segments =
+        hex::parse_hex(&read(&path)?).with_context(|| format!("parsing {}", path.display()))?;
+    hex::flatten_segments(&segments, &hex::FlattenOptions::default())
+        .context("flattening HEX segments")
+}
+
+/// A bundle of the keymap in `image`, named `name`, shown with the built-in
+/// geometry and colors; None if the image has no keymap table.
+pub fn bundle_from_image(name: &str, image: &[u8]) -> Result<Option<Bundle>> {
+    let Ok(table) = patch::find_table(image) else {
+        return Ok(None);
+    };
+    let layers = patch::unpack(&image[table.offset..table.offset + table.len])?;
+    let colors = ergodox_keymap::LAYER_COLORS
+        .iter()
+        .take(layers.len())
+        .copied()
+        .collect();
+    Ok(Some(Bundle {
+        name: name.to_string(),
+        author: None,
+        version: None,
+        layers,
+        labels: Vec::new(),
+        colors,
+        geometry: geometry::BUILTIN.to_string(),
+    }))
+}
+
+/// The directory for profile `name`. Names are letters, digits, `-` and
+/// `_`, so one can't reach outside `root`.
+fn profile_dir(root: &Path, name: &str) -> Result<PathBuf> {
+    check_name(name)?;
+    Ok(root.join(name))
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid profile name {name:?}: use letters, digits, `-` and `_`");
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_inside_the_profiles_directory() {
+        assert!(check_name("gaming_v2-test").is_ok());
+        for name in ["", "..", "a/b", "work bench", "/etc"] {
+            assert!(check_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn saved_profiles_list_and_load() {
+        let root = std::env::temp_dir().join(format!("ergodox-profiles-{}", std::process::id()));
+        let data = [0x0C, 0x94, 0x34, 0x12];
+        save(&root, "work", Path::new("fw.hex"), 0x100, &data, None).unwrap();
+        save(&root, "gaming", Path::new("g.hex"), 0, &[1; 8], None).unwrap();
+        fs::create_dir_all(root.join("not-a-profile")).unwrap();
+
+        let profiles = list(&root).unwrap();
+        let names: Vec<_> = profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["gaming", "work"]);
+        let work = &profiles[1].info;
+        assert_eq!((work.base_address, work.bytes), (0x100, 4));
+        assert_eq!(work.crc32, crc32fast::hash(&data));
+        assert!(profiles[1].bundle.is_none());
+        assert_eq!(load(&root, "work").unwrap(), (0x100, data.to_vec()));
+        assert!(load(&root, "missing").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+        assert!(list(&root).unwrap().is_empty());
+    }
+}
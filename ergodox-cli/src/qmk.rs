@@ -0,0 +1,775 @@
+//! Import QMK keymaps written for the ErgoDox EZ, and export ours as one.
+//!
+//! QMK keymaps list keys in the argument order of a `LAYOUT_*` macro rather
+//! than by matrix position. Both ErgoDox EZ layouts (`LAYOUT_ergodox` and
+//! `LAYOUT_ergodox_pretty`) are supported, from either a configurator
+//! `keymap.json` or — best effort — a hand-written `keymap.c`.
+//!
+//! Only plain keycodes, momentary layers, the backlight keys and the
+//! Ctrl/Caps swap have an exact equivalent here.
+//! Everything else is imported as the closest thing that behaves the same
+//! on a tap, with a warning, so nothing is silently dropped. Going the
+//! other way everything translates: our keymaps have nothing but plain
+//! keycodes, momentary layers and keys QMK also has.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::{Keycode, COLS, ROWS};
+use serde_json::{json, Value};
+
+use crate::codegen::Layer;
+
+/// Number of keys in the ErgoDox EZ layout macros.
+const LAYOUT_KEYS: usize = 76;
+
+/// Result of an import: the layers plus anything that didn't translate 1:1.
+pub struct QmkImport {
+    pub layers: Vec<Layer>,
+    pub warnings: Vec<String>,
+}
+
+/// QMK layout macro argument order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    /// Left half first, then right half.
+    Ergodox,
+    /// Row by row across both halves, as the keys appear on the desk.
+    ErgodoxPretty,
+}
+
+impl Layout {
+    fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "LAYOUT_ergodox" => Some(Layout::Ergodox),
+            "LAYOUT_ergodox_pretty" => Some(Layout::ErgodoxPretty),
+            _ => None,
+        }
+    }
+
+    /// Macro arguments in order, as QMK `kRC` coordinates (row, column).
+    fn order(self) -> Vec<(usize, usize)> {
+        let row = |r: usize, cols: std::ops::Range<usize>| cols.map(move |c| (r, c));
+        let left_thumb = [(5, 5), (5, 6), (5, 4), (5, 3), (5, 2), (5, 1)];
+        let right_thumb = [(5, 7), (5, 8), (5, 9), (5, 12), (5, 11), (5, 10)];
+
+        match self {
+            Layout::Ergodox => row(0, 0..7)
+                .chain(row(1, 0..7))
+                .chain(row(2, 0..6))
+                .chain(row(3, 0..7))
+                .chain(row(4, 0..5))
+                .chain(left_thumb)
+                .chain(row(0, 7..14))
+                .chain(row(1, 7..14))
+                .chain(row(2, 8..14))
+                .chain(row(3, 7..14))
+                .chain(row(4, 9..14))
+                .chain(right_thumb)
+                .collect(),
+            Layout::ErgodoxPretty => row(0, 0..14)
+                .chain(row(1, 0..14))
+                .chain(row(2, 0..6))
+                .chain(row(2, 8..14))
+                .chain(row(3, 0..14))
+                .chain(row(4, 0..5))
+                .chain(row(4, 9..14))
+                .chain([(5, 5), (5, 6), (5, 7), (5, 8)])
+                .chain([(5, 4), (5, 9)])
+                .chain([(5, 3), (5, 2), (5, 1), (5, 12), (5, 11), (5, 10)])
+                .collect(),
+        }
+    }
+}
+
+/// Map a QMK `kRC` coordinate onto this firmware's matrix.
+///
+/// Rows 0–4 are wired identically. The thumb clusters are wired to different
+/// columns, so they're matched by physical position instead (see
+/// `geometry/ergodox.toml` for our side).
+fn to_matrix((row, col): (usize, usize)) -> (usize, usize) {
+    if row != 5 {
+        return (row, col);
+    }
+    let ours = match col {
+        // Left: bottom-right small, tall inner, tall outer, mid small,
+        // small above the inner tall key, top small.
+        1 => 0,
+        2 => 2,
+        3 => 3,
+        4 => 1,
+        5 => 5,
+        6 => 4,
+        // Right: mirror image of the above.
+        7 => 9,
+        8 => 8,
+        9 => 12,
+        10 => 10,
+        11 => 11,
+        _ => 13,
+    };
+    (5, ours)
+}
+
+/// Import a QMK configurator `keymap.json`.
+pub fn import_json(json: &str) -> Result<QmkImport> {
+    let doc: Value = serde_json::from_str(json).context("invalid JSON")?;
+
+    let layout_name = doc["layout"].as_str().unwrap_or("LAYOUT_ergodox");
+    let layout = Layout::from_name(layout_name).with_context(|| {
+        format!("unsupported layout {layout_name:?}; expected an ErgoDox EZ layout")
+    })?;
+
+    let layers = doc["layers"]
+        .as_array()
+        .context("missing \"layers\" array")?
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| {
+            layer
+                .as_array()
+                .with_context(|| format!("layer {i} is not an array"))?
+                .iter()
+                .map(|kc| {
+                    kc.as_str()
+                        .map(str::to_string)
+                        .with_context(|| format!("layer {i}: keycode {kc} is not a string"))
+                })
+                .collect::<Result<Vec<String>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    build_layers(layout, &layers, &HashMap::new())
+}
+
+/// Import the `keymaps[]` array from a QMK `keymap.c`.
+///
+/// This is not a C parser: it strips comments, finds each `LAYOUT_ergodox*(`
+/// invocation and splits its arguments on top-level commas. Layer names used
+/// in `[NAME] = LAYOUT...` designators and `MO(NAME)` are resolved from
+/// `enum` declarations and `#define`s when possible.
+pub fn import_c(source: &str) -> Result<QmkImport> {
+    let source = strip_comments(source);
+    let mut names = layer_names(&source);
+
+    let mut layout = None;
+    let mut layers = Vec::new();
+    let mut rest = source.as_str();
+    while let Some(pos) = rest.find("LAYOUT_ergodox") {
+        let after = &rest[pos..];
+        let open = after.find('(').context("LAYOUT macro without arguments")?;
+        let name = after[..open].trim();
+        let this_layout =
+            Layout::from_name(name).with_context(|| format!("unsupported layout macro {name}"))?;
+        if layout.is_some_and(|l| l != this_layout) {
+            bail!("keymap mixes LAYOUT_ergodox and LAYOUT_ergodox_pretty");
+        }
+        layout = Some(this_layout);
+
+        // "[NAME] =" designator just before the macro, if any.
+        if let Some(name) = designator(&rest[..pos]) {
+            names.entry(name).or_insert(layers.len());
+        }
+
+        let (args, consumed) = split_args(&after[open + 1..])?;
+        layers.push(args);
+        rest = &after[open + 1 + consumed..];
+    }
+
+    let layout = layout.context("no LAYOUT_ergodox(...) found in keymap.c")?;
+    build_layers(layout, &layers, &names)
+}
+
+/// Our matrix positions in `LAYOUT_ergodox_pretty` order: row by row across
+/// both halves, as the keys appear on the desk.
+pub(crate) fn pretty_positions() -> Vec<(usize, usize)> {
+    Layout::ErgodoxPretty
+        .order()
+        .into_iter()
+        .map(to_matrix)
+        .collect()
+}
+
+/// Export layers as a QMK configurator `keymap.json` for the ErgoDox EZ,
+/// in `LAYOUT_ergodox_pretty` order.
+pub fn export_json(layers: &[Layer]) -> String {
+    let order = pretty_positions();
+    let layers: Vec<Vec<String>> = layers
+        .iter()
+        .map(|layer| {
+            order
+                .iter()
+                .map(|&(row, col)| qmk_name(layer[row][col]))
+                .collect()
+        })
+        .collect();
+    let doc = json!({
+        "version": 1,
+        "notes": "Exported by ergodox-cli",
+        "keyboard": "ergodox_ez",
+        "keymap": "ergodox-cli",
+        "layout": "LAYOUT_ergodox_pretty",
+        "layers": layers,
+        "author": "ergodox-cli",
+    });
+    let mut out = serde_json::to_string_pretty(&doc).expect("JSON values serialize");
+    out.push('\n');
+    out
+}
+
+/// QMK's name for a keycode, the short alias where there is one.
+fn qmk_name(kc: Keycode) -> String {
+    use Keycode::*;
+
+    let name = match kc {
+        Trans => "KC_TRNS",
+        None => "KC_NO",
+        Enter => "KC_ENT",
+        Escape => "KC_ESC",
+        Backspace => "KC_BSPC",
+        Tab => "KC_TAB",
+        Space => "KC_SPC",
+        Minus => "KC_MINS",
+        Equal => "KC_EQL",
+        LBracket => "KC_LBRC",
+        RBracket => "KC_RBRC",
+        Backslash => "KC_BSLS",
+        Semicolon => "KC_SCLN",
+        Quote => "KC_QUOT",
+        Grave => "KC_GRV",
+        Comma => "KC_COMM",
+        Dot => "KC_DOT",
+        Slash => "KC_SLSH",
+        CapsLock => "KC_CAPS",
+        NonUsBackslash => "KC_NUBS",
+        PrintScreen => "KC_PSCR",
+        ScrollLock => "KC_SCRL",
+        Pause => "KC_PAUS",
+        Insert => "KC_INS",
+        Home => "KC_HOME",
+        PageUp => "KC_PGUP",
+        Delete => "KC_DEL",
+        End => "KC_END",
+        PageDown => "KC_PGDN",
+        Right => "KC_RGHT",
+        Left => "KC_LEFT",
+        Down => "KC_DOWN",
+        Up => "KC_UP",
+        LCtrl => "KC_LCTL",
+        LShift => "KC_LSFT",
+        LAlt => "KC_LALT",
+        LGui => "KC_LGUI",
+        RCtrl => "KC_RCTL",
+        RShift => "KC_RSFT",
+        RAlt => "KC_RALT",
+        RGui => "KC_RGUI",
+        BacklightToggle => "BL_TOGG",
+        BacklightUp => "BL_UP",
+        BacklightDown => "BL_DOWN",
+        BacklightBreathe => "BL_BRTG",
+        SwapCtrlCaps => "CL_TOGG",
+        kc if kc.is_layer() => return format!("MO({})", kc.layer_number()),
+        // Letters, digits and F-keys are named after their display name
+        kc => return format!("KC_{}", kc.display_name()),
+    };
+    name.to_string()
+}
+
+/// Import layers already split into `LAYOUT_ergodox` macro arguments, the
+/// order other ErgoDox EZ tools list keys in (see `oryx.rs`).
+pub(crate) fn import_args(layers: &[Vec<String>]) -> Result<QmkImport> {
+    build_layers(Layout::Ergodox, layers, &HashMap::new())
+}
+
+/// Translate per-layer macro arguments into keymap layers.
+fn build_layers(
+    layout: Layout,
+    layers: &[Vec<String>],
+    names: &HashMap<String, usize>,
+) -> Result<QmkImport> {
+    let order = layout.order();
+    let mut warnings = Vec::new();
+    let mut result = Vec::with_capacity(layers.len());
+
+    for (l, keys) in layers.iter().enumerate() {
+        if keys.len() != LAYOUT_KEYS {
+            bail!("layer {l} has {} keys, expected {LAYOUT_KEYS}", keys.len());
+        }
+
+        let mut layer = [[Keycode::Trans; COLS]; ROWS];
+        for (&qmk_pos, expr) in order.iter().zip(keys) {
+            let (row, col) = to_matrix(qmk_pos);
+            let (kc, warning) = translate(expr.trim(), names);
+            if let Some(w) = warning {
+                warnings.push(format!("layer {l} row {row} col {col}: {w}"));
+            }
+            layer[row][col] = kc;
+        }
+        result.push(layer);
+    }
+
+    Ok(QmkImport {
+        layers: result,
+        warnings,
+    })
+}
+
+/// Translate one QMK keycode expression, with a warning if it's inexact.
+fn translate(expr: &str, names: &HashMap<String, usize>) -> (Keycode, Option<String>) {
+    if let Some(kc) = basic_keycode(expr) {
+        return (kc, None);
+    }
+
+    let Some((func, args)) = parse_call(expr) else {
+        return (
+            Keycode::Trans,
+            Some(format!("unsupported keycode {expr}, left transparent")),
+        );
+    };
+
+    let layer_key = |arg: &str| {
+        let n = arg
+            .parse::<usize>()
+            .ok()
+            .or_else(|| names.get(arg).copied())?;
+        Keycode::layer(n)
+    };
+
+    match (func, args.as_slice()) {
+        ("MO", [layer]) => match layer_key(layer) {
+            Some(kc) => (kc, None),
+            None => (
+                Keycode::Trans,
+                Some(format!(
+                    "{expr}: no momentary key for that layer, left transparent"
+                )),
+            ),
+        },
+        ("TG" | "TO" | "TT" | "OSL" | "DF", [layer]) => match layer_key(layer) {
+            Some(kc) => (kc, Some(format!("{expr} imported as momentary MO()"))),
+            None => (
+                Keycode::Trans,
+                Some(format!("{expr}: unknown layer, left transparent")),
+            ),
+        },
+        ("LT", [_, tap]) => match basic_keycode(tap) {
+            Some(kc) => (kc, Some(format!("{expr} imported as its tap key only"))),
+            None => (
+                Keycode::Trans,
+                Some(format!("unsupported keycode {expr}, left transparent")),
+            ),
+        },
+        (f, [tap]) if f.ends_with("_T") => match basic_keycode(tap) {
+            Some(kc) => (kc, Some(format!("{expr} imported as its tap key only"))),
+            None => (
+                Keycode::Trans,
+                Some(format!("unsupported keycode {expr}, left transparent")),
+            ),
+        },
+        _ => (
+            Keycode::Trans,
+            Some(format!("unsupported keycode {expr}, left transparent")),
+        ),
+    }
+}
+
+/// Split `NAME(a, b)` into its name and top-level arguments.
+fn parse_call(expr: &str) -> Option<(&str, Vec<&str>)> {
+    let open = expr.find('(')?;
+    let inner = expr[open + 1..].strip_suffix(')')?;
+    let name = expr[..open].trim();
+
+    let mut args = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(inner[start..].trim());
+    Some((name, args))
+}
+
+/// Keycodes with an exact equivalent, including QMK's short aliases.
+fn basic_keycode(name: &str) -> Option<Keycode> {
+    numbered_keycode(name).or_else(|| named_keycode(name))
+}
+
+/// `KC_A`..`KC_Z`, `KC_0`..`KC_9` and `KC_F1`..`KC_F12`, which sit in
+/// contiguous HID usage ranges.
+fn numbered_keycode(name: &str) -> Option<Keycode> {
+    let rest = name.strip_prefix("KC_")?;
+    let code = match rest.as_bytes() {
+        [c @ b'A'..=b'Z'] => 0x04 + (c - b'A'),
+        [c @ b'1'..=b'9'] => 0x1E + (c - b'1'),
+        [b'0'] => 0x27,
+        [b'F', ..] => match rest[1..].parse::<u8>() {
+            Ok(n @ 1..=12) => 0x3A + n - 1,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Keycode::from_byte(code)
+}
+
+/// Everything else with an exact equivalent, by QMK's long and short names.
+fn named_keycode(name: &str) -> Option<Keycode> {
+    use Keycode::*;
+
+    let kc = match name {
+        "KC_TRNS" | "KC_TRANSPARENT" | "_______" | "_____" => Trans,
+        // KC_NO blocks fall-through; None is never sent in a report.
+        "KC_NO" | "XXXXXXX" => None,
+        "KC_ENTER" | "KC_ENT" => Enter,
+        "KC_ESCAPE" | "KC_ESC" => Escape,
+        "KC_BACKSPACE" | "KC_BSPC" | "KC_BSPACE" => Backspace,
+        "KC_TAB" => Tab,
+        "KC_SPACE" | "KC_SPC" => Space,
+        "KC_MINUS" | "KC_MINS" => Minus,
+        "KC_EQUAL" | "KC_EQL" => Equal,
+        "KC_LEFT_BRACKET" | "KC_LBRACKET" | "KC_LBRC" => LBracket,
+        "KC_RIGHT_BRACKET" | "KC_RBRACKET" | "KC_RBRC" => RBracket,
+        "KC_BACKSLASH" | "KC_BSLASH" | "KC_BSLS" => Backslash,
+        "KC_SEMICOLON" | "KC_SCOLON" | "KC_SCLN" => Semicolon,
+        "KC_QUOTE" | "KC_QUOT" => Quote,
+        "KC_GRAVE" | "KC_GRV" => Grave,
+        "KC_COMMA" | "KC_COMM" => Comma,
+        "KC_DOT" => Dot,
+        "KC_SLASH" | "KC_SLSH" => Slash,
+        "KC_CAPS_LOCK" | "KC_CAPSLOCK" | "KC_CAPS" => CapsLock,
+        "KC_NONUS_BACKSLASH" | "KC_NONUS_BSLASH" | "KC_NUBS" => NonUsBackslash,
+        "KC_PRINT_SCREEN" | "KC_PSCREEN" | "KC_PSCR" => PrintScreen,
+        "KC_SCROLL_LOCK" | "KC_SCROLLLOCK" | "KC_SCRL" | "KC_SLCK" => ScrollLock,
+        "KC_PAUSE" | "KC_PAUS" | "KC_BRK" => Pause,
+        "KC_INSERT" | "KC_INS" => Insert,
+        "KC_HOME" => Home,
+        "KC_PAGE_UP" | "KC_PGUP" => PageUp,
+        "KC_DELETE" | "KC_DEL" => Delete,
+        "KC_END" => End,
+        "KC_PAGE_DOWN" | "KC_PGDOWN" | "KC_PGDN" => PageDown,
+        "KC_RIGHT" | "KC_RGHT" => Right,
+        "KC_LEFT" => Left,
+        "KC_DOWN" => Down,
+        "KC_UP" => Up,
+        "KC_LEFT_CTRL" | "KC_LCTRL" | "KC_LCTL" => LCtrl,
+        "KC_LEFT_SHIFT" | "KC_LSHIFT" | "KC_LSFT" => LShift,
+        "KC_LEFT_ALT" | "KC_LALT" | "KC_LOPT" => LAlt,
+        "KC_LEFT_GUI" | "KC_LGUI" | "KC_LCMD" | "KC_LWIN" => LGui,
+        "KC_RIGHT_CTRL" | "KC_RCTRL" | "KC_RCTL" => RCtrl,
+        "KC_RIGHT_SHIFT" | "KC_RSHIFT" | "KC_RSFT" => RShift,
+        "KC_RIGHT_ALT" | "KC_RALT" | "KC_ROPT" | "KC_ALGR" => RAlt,
+        "KC_RIGHT_GUI" | "KC_RGUI" | "KC_RCMD" | "KC_RWIN" => RGui,
+        "QK_BACKLIGHT_TOGGLE" | "BL_TOGG" => BacklightToggle,
+        "QK_BACKLIGHT_UP" | "BL_UP" | "BL_INC" => BacklightUp,
+        "QK_BACKLIGHT_DOWN" | "BL_DOWN" | "BL_DEC" => BacklightDown,
+        "QK_BACKLIGHT_TOGGLE_BREATHING" | "BL_BRTG" => BacklightBreathe,
+        "QK_MAGIC_TOGGLE_CONTROL_CAPS_LOCK" | "MAGIC_TOGGLE_CONTROL_CAPSLOCK" | "CL_TOGG" => {
+            SwapCtrlCaps
+        }
+        _ => return Option::None,
+    };
+    Some(kc)
+}
+
+/// Remove `//` and `/* */` comments, keeping string literals intact.
+fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            if c == '\\' {
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Layer names from `#define NAME n` and `enum { A, B = 3, C }` declarations.
+fn layer_names(source: &str) -> HashMap<String, usize> {
+    let mut names = HashMap::new();
+
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("#define"), Some(name), Some(value)) =
+            (words.next(), words.next(), words.next())
+        {
+            if let Ok(n) = value.parse() {
+                names.insert(name.to_string(), n);
+            }
+        }
+    }
+
+    let mut rest = source;
+    while let Some(pos) = rest.find("enum") {
+        rest = &rest[pos + 4..];
+        let (Some(open), Some(close)) = (rest.find('{'), rest.find('}')) else {
+            break;
+        };
+        if open > close {
+            continue;
+        }
+        let mut next = 0usize;
+        for item in rest[open + 1..close].split(',') {
+            let (name, value) = match item.split_once('=') {
+                Some((n, v)) => (n.trim(), v.trim().parse().ok()),
+                None => (item.trim(), None),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.unwrap_or(next);
+            names.insert(name.to_string(), value);
+            next = value + 1;
+        }
+        rest = &rest[close..];
+    }
+
+    names
+}
+
+/// The `NAME` in a trailing `[NAME] =` designator, if `before` ends with one.
+fn designator(before: &str) -> Option<String> {
+    let before = before.trim_end().strip_suffix('=')?.trim_end();
+    let before = before.strip_suffix(']')?;
+    let open = before.rfind('[')?;
+    Some(before[open + 1..].trim().to_string())
+}
+
+/// Split macro arguments up to the matching `)`. Returns the arguments and
+/// the number of bytes consumed, including the closing parenthesis.
+fn split_args(input: &str) -> Result<(Vec<String>, usize)> {
+    let mut args = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                let last = input[start..i].trim();
+                if !last.is_empty() {
+                    args.push(last.to_string());
+                }
+                return Ok((args, i + 1));
+            }
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(input[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    bail!("unterminated LAYOUT macro")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // =========================================================================
+    // Layout macro ordering
+    // =========================================================================
+    //
+    // QMK keymaps are a flat list of 76 keycodes in macro argument order.
+    // Each argument must land on a distinct matrix position that has a
+    // physical switch, or keys would overwrite each other on import.
+
+    #[test]
+    fn both_layouts_cover_every_physical_key_once() {
+        let physical: HashSet<(usize, usize)> = crate::layout::build_keys()
+            .iter()
+            .map(|k| (k.row, k.col))
+            .collect();
+
+        for layout in [Layout::Ergodox, Layout::ErgodoxPretty] {
+            let mapped: Vec<(usize, usize)> = layout.order().into_iter().map(to_matrix).collect();
+            assert_eq!(mapped.len(), LAYOUT_KEYS, "{layout:?}");
+            let unique: HashSet<_> = mapped.iter().copied().collect();
+            assert_eq!(unique, physical, "{layout:?} must hit every switch once");
+        }
+    }
+
+    #[test]
+    fn pretty_and_plain_layouts_agree_on_positions() {
+        // The same QMK coordinates appear in both macros, just reordered.
+        let mut plain = Layout::Ergodox.order();
+        let mut pretty = Layout::ErgodoxPretty.order();
+        plain.sort();
+        pretty.sort();
+        assert_eq!(plain, pretty);
+    }
+
+    // =========================================================================
+    // Keycode translation
+    // =========================================================================
+
+    #[test]
+    fn basic_keycodes_and_aliases() {
+        assert_eq!(basic_keycode("KC_A"), Some(Keycode::A));
+        assert_eq!(basic_keycode("KC_Z"), Some(Keycode::Z));
+        assert_eq!(basic_keycode("KC_1"), Some(Keycode::N1));
+        assert_eq!(basic_keycode("KC_0"), Some(Keycode::N0));
+        assert_eq!(basic_keycode("KC_F12"), Some(Keycode::F12));
+        assert_eq!(basic_keycode("KC_BSPC"), Some(Keycode::Backspace));
+        assert_eq!(basic_keycode("KC_LEFT_CTRL"), Some(Keycode::LCtrl));
+        assert_eq!(basic_keycode("_______"), Some(Keycode::Trans));
+        assert_eq!(basic_keycode("KC_F13"), None);
+    }
+
+    #[test]
+    fn kc_no_blocks_fall_through() {
+        // KC_NO must not be transparent, or the lower layer would leak through.
+        assert_eq!(basic_keycode("KC_NO"), Some(Keycode::None));
+    }
+
+    #[test]
+    fn momentary_layers_translate_exactly() {
+        let (kc, warning) = translate("MO(2)", &HashMap::new());
+        assert_eq!(kc, Keycode::Layer2);
+        assert!(warning.is_none());
+    }
+
+    #[test]
+    fn named_layers_resolve_through_the_name_table() {
+        let names = HashMap::from([("SYMB".to_string(), 1)]);
+        assert_eq!(translate("MO(SYMB)", &names).0, Keycode::Layer1);
+    }
+
+    #[test]
+    fn layer_tap_keeps_the_tap_key_with_a_warning() {
+        let (kc, warning) = translate("LT(1, KC_SPC)", &HashMap::new());
+        assert_eq!(kc, Keycode::Space);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn mod_tap_keeps_the_tap_key_with_a_warning() {
+        let (kc, warning) = translate("LCTL_T(KC_A)", &HashMap::new());
+        assert_eq!(kc, Keycode::A);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn unsupported_keycodes_become_transparent_with_a_warning() {
+        let (kc, warning) = translate("LSFT(KC_1)", &HashMap::new());
+        assert_eq!(kc, Keycode::Trans);
+        assert!(warning.unwrap().contains("LSFT(KC_1)"));
+    }
+
+    // =========================================================================
+    // keymap.json and keymap.c
+    // =========================================================================
+
+    fn layout_args(first: &str) -> Vec<String> {
+        let mut keys = vec!["KC_TRNS".to_string(); LAYOUT_KEYS];
+        keys[0] = first.to_string();
+        keys
+    }
+
+    #[test]
+    fn json_import_places_first_key_top_left() {
+        let json = serde_json::json!({
+            "keyboard": "ergodox_ez",
+            "layout": "LAYOUT_ergodox",
+            "layers": [layout_args("KC_EQL"), layout_args("MO(1)")],
+        });
+        let imported = import_json(&json.to_string()).unwrap();
+        assert_eq!(imported.layers.len(), 2);
+        assert_eq!(imported.layers[0][0][0], Keycode::Equal);
+        assert_eq!(imported.layers[1][0][0], Keycode::Layer1);
+        assert!(imported.warnings.is_empty());
+    }
+
+    #[test]
+    fn json_import_rejects_other_keyboards_layouts() {
+        let json = r#"{"layout": "LAYOUT_60_ansi", "layers": []}"#;
+        assert!(import_json(json).is_err());
+    }
+
+    #[test]
+    fn c_import_handles_comments_designators_and_enums() {
+        let body = layout_args("MO(SYMB)").join(", ");
+        let source = format!(
+            "enum layers {{ BASE, SYMB }};\n\
+             // [SYMB] = LAYOUT_ergodox(nope)\n\
+             const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {{\n\
+             [BASE] = LAYOUT_ergodox({body}),\n\
+             /* symbols */ [SYMB] = LAYOUT_ergodox({}),\n\
+             }};",
+            layout_args("KC_ESC").join(", ")
+        );
+        let imported = import_c(&source).unwrap();
+        assert_eq!(imported.layers.len(), 2);
+        assert_eq!(imported.layers[0][0][0], Keycode::Layer1);
+        assert_eq!(imported.layers[1][0][0], Keycode::Escape);
+    }
+
+    #[test]
+    fn every_keycode_exports_to_a_name_that_imports_back() {
+        for &kc in Keycode::ALL {
+            let name = qmk_name(kc);
+            assert_eq!(translate(&name, &HashMap::new()), (kc, None), "{name}");
+        }
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let mut layers = vec![[[Keycode::Trans; COLS]; ROWS]; 2];
+        layers[0][0][0] = Keycode::Equal;
+        layers[0][5][3] = Keycode::Space;
+        layers[0][5][10] = Keycode::Layer1;
+        layers[1][2][13] = Keycode::NonUsBackslash;
+
+        let json = export_json(&layers);
+        let doc: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(doc["keyboard"], "ergodox_ez");
+        assert_eq!(doc["layers"][0][0], "KC_EQL");
+        let imported = import_json(&json).unwrap();
+        assert_eq!(imported.layers, layers);
+        assert!(imported.warnings.is_empty());
+    }
+
+    #[test]
+    fn wrong_key_count_is_an_error() {
+        let json = r#"{"layout": "LAYOUT_ergodox", "layers": [["KC_A"]]}"#;
+        assert!(import_json(json).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! The running keyboard's raw HID interface, through hidapi (built with
+//! `--features hidapi`).
+//!
+//! On Windows and macOS the OS owns the keyboard's HID interface, and
+//! libusb can't send it the vendor requests in DESIGN.md. The firmware also
+//! takes them on a second, vendor-defined HID interface as feature reports,
+//! which hidapi reaches everywhere without swapping drivers:
+//!
+//! - SET_REPORT with `[bRequest, wValue low, wValue high, 0...]`
+//! - GET_REPORT, for requests that answer: `[length, reply...]`
+//!
+//! The HalfKay bootloader is still driven through libusb.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use hidapi::{HidApi, HidDevice};
+
+use crate::halfkay::{Target, KEYBOARD_PID, KEYBOARD_VID};
+use crate::trace;
+
+/// Usage page of the raw interface (vendor defined).
+const USAGE_PAGE: u16 = 0xFF00;
+
+/// Size of the feature report, without the report ID hidapi puts in front.
+const REPORT_SIZE: usize = 192;
+
+pub struct RawHid {
+    device: HidDevice,
+}
+
+impl RawHid {
+    /// Open the keyboard's raw interface. None if no keyboard has one
+    /// (firmware that predates it), or if `target` picks a device by port,
+    /// which hidapi can't see; the caller falls back to libusb then.
+    pub fn open(target: &Target) -> Result<Option<RawHid>> {
+        if *target != Target::Any {
+            trace::log(1, || {
+                "hidapi can't tell USB ports apart; using libusb for --device/--serial".to_string()
+            });
+            return Ok(None);
+        }
+        let api = HidApi::new().context("failed to initialize hidapi")?;
+        let matching: Vec<_> = api
+            .device_list()
+            .filter(|d| {
+                d.vendor_id() == KEYBOARD_VID
+                    && d.product_id() == KEYBOARD_PID
+                    && d.usage_page() == USAGE_PAGE
+            })
+            .collect();
+        match matching[..] {
+            [] => {
+                trace::log(2, || "no raw HID interface on the bus".to_string());
+                Ok(None)
+            }
+            [info] => {
+                trace::log(1, || {
+                    format!("found raw HID interface at {:?}", info.path())
+                });
+                let device = info
+                    .open_device(&api)
+                    .context("failed to open the keyboard's raw HID interface")?;
+                Ok(Some(RawHid { device }))
+            }
+            _ => bail!(
+                "{} keyboards connected; hidapi can't tell them apart, so unplug all but one",
+                matching.len()
+            ),
+        }
+    }
+
+    /// Send a vendor request that doesn't answer.
+    pub fn send(&self, request: u8, value: u16) -> Result<()> {
+        let mut report = [0u8; 1 + REPORT_SIZE];
+        report[1] = request;
+        report[2..4].copy_from_slice(&value.to_le_bytes());
+        let start = Instant::now();
+        let result = self.device.send_feature_report(&report);
+        trace::log(1, || {
+            format!(
+                "hid SET_REPORT(feature) bRequest=0x{:02X} wValue=0x{:04X} -> {} in {:.2} ms",
+                request,
+                value,
+                match &result {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("failed: {}", e),
+                },
+                start.elapsed().as_secs_f64() * 1000.0
+            )
+        });
+        Ok(result?)
+    }
+
+    /// Wait up to `timeout` for an input report (a layer notice) and read it
+    /// into `buf`. Returns its length, 0 if none came.
+    pub fn read(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        self.device
+            .read_timeout(buf, timeout_ms)
+            .context("failed to read from the keyboard's raw HID interface")
+    }
+
+    /// Send a vendor request and read its answer into `buf`, truncated to
+    /// fit. Returns the answer's length.
+    pub fn request(&self, request: u8, value: u16, buf: &mut [u8]) -> Result<usize> {
+        self.send(request, value)?;
+        let mut report = [0u8; 1 + REPORT_SIZE];
+        let start = Instant::now();
+        let result = self.device.get_feature_report(&mut report);
+        trace::log(1, || {
+            format!(
+                "hid GET_REPORT(feature) -> {} in {:.2} ms",
+                match &result {
+                    Ok(n) => format!("ok, {} bytes", n),
+                    Err(e) => format!("failed: {}", e),
+                },
+                start.elapsed().as_secs_f64() * 1000.0
+            )
+        });
+        let n = result?;
+        // Report ID, then the length the firmware put in front of its reply
+        let len = report[1] as usize;
+        if n < 2 || len > n - 2 {
+            bail!("malformed raw HID reply ({} bytes, length byte {})", n, len);
+        }
+        let len = len.min(buf.len());
+        buf[..len].copy_from_slice(&report[2..2 + len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,136 @@
+//! State for `flash --resume`.
+//!
+//! When a page write fails for good (a bumped cable, a hub that times out),
+//! `flash` records which image it was writing and the first page that didn't
+//! make it in `$XDG_STATE_HOME/ergodox/flash.json`. `flash --resume` checks
+//! that it's been given the same image, then carries on from that page
+//! without erasing, instead of rewriting everything. A flash that completes
+//! removes the file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FlashState {
+    /// The firmware file, so that a bare `flash --resume` can find it again.
+    pub firmware: PathBuf,
+    /// Bootloader protocol, as `--bootloader` names it.
+    pub protocol: String,
+    pub base_address: u32,
+    pub bytes: usize,
+    pub crc32: u32,
+    /// First page that wasn't confirmed written.
+    pub next_address: usize,
+}
+
+impl FlashState {
+    pub fn new(
+        firmware: &Path,
+        protocol: &str,
+        base_address: u32,
+        data: &[u8],
+        next_address: usize,
+    ) -> FlashState {
+        FlashState {
+            firmware: firmware.to_path_buf(),
+            protocol: protocol.to_string(),
+            base_address,
+            bytes: data.len(),
+            crc32: crc32fast::hash(data),
+            next_address,
+        }
+    }
+
+    /// Make sure this state is for flashing `data` at `base_address` through
+    /// `protocol`: resuming with anything else would leave a mix of two
+    /// images in flash.
+    pub fn check(&self, protocol: &str, base_address: u32, data: &[u8]) -> Result<()> {
+        if self.protocol != protocol {
+            bail!(
+                "the interrupted flash was through {}, but {} is waiting",
+                self.protocol,
+                protocol
+            );
+        }
+        if self.base_address != base_address
+            || self.bytes != data.len()
+            || self.crc32 != crc32fast::hash(data)
+        {
+            bail!(
+                "{} has changed since the interrupted flash; flash it from the start",
+                self.firmware.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Where the state lives: `$XDG_STATE_HOME/ergodox/flash.json`, falling back
+/// to `~/.local/state`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(base.join("ergodox").join("flash.json"))
+}
+
+/// The recorded state, or None if no flash was interrupted.
+pub fn load(path: &Path) -> Result<Option<FlashState>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let state =
+        serde_json::from_str(&contents).with_context(|| format!("parsing {}", path.display()))?;
+    Ok(Some(state))
+}
+
+pub fn save(path: &Path, state: &FlashState) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let contents = serde_json::to_string_pretty(state)?;
+    std::fs::write(path, contents).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Forget an interrupted flash, if there was one.
+pub fn clear(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_only_resumes_the_same_image_and_protocol() {
+        let data = [1, 2, 3, 4];
+        let state = FlashState::new(Path::new("fw.hex"), "halfkay", 0, &data, 128);
+        assert!(state.check("halfkay", 0, &data).is_ok());
+        assert!(state.check("dfu", 0, &data).is_err());
+        assert!(state.check("halfkay", 128, &data).is_err());
+        assert!(state.check("halfkay", 0, &[1, 2, 3, 5]).is_err());
+    }
+
+    #[test]
+    fn state_survives_a_round_trip_and_clearing_twice_is_fine() {
+        let path = std::env::temp_dir().join(format!("ergodox-resume-{}.json", std::process::id()));
+        let state = FlashState::new(Path::new("fw.hex"), "caterina", 0, &[0; 8], 256);
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(state));
+        clear(&path).unwrap();
+        clear(&path).unwrap();
+        assert_eq!(load(&path).unwrap(), None);
+    }
+}
@@ -0,0 +1,495 @@
+//! A local HTTP API over the CLI, for `ergodox-cli serve`.
+//!
+//! Browsers with WebHID can talk to the keyboard themselves (see DESIGN.md);
+//! others can't, and none can reach every bootloader. For those, a web
+//! configurator talks to this bridge instead:
+//!
+//! - `GET /devices`: keyboards and bootloaders on the bus, as `list --json`
+//! - `GET /keymap`: the keymap the running keyboard uses, as a TOML keymap
+//!   file in `keymap`
+//! - `PUT /keymap`: patch the TOML keymap in the body into the firmware
+//!   (`--firmware`, or `firmware` from the config file) and flash it
+//! - `POST /flash`: flash the Intel HEX in the body
+//! - `GET /events`: what `monitor --json` prints, as Server-Sent Events,
+//!   which a page reads with `EventSource`
+//!
+//! Every other reply is JSON, `{"status": "ok", ...}` or `{"status":
+//! "error", "error": ...}` as with `--json`.
+//!
+//! Just enough HTTP/1.1 for that is spoken on a `TcpListener`, one request
+//! per connection, rather than pulling a web framework in for five routes.
+//! It listens on a loopback address and answers only connections from this
+//! machine. A request from a web page carries its origin, which has to be
+//! one of `--allow-origin`: otherwise any site the user visits could flash
+//! their keyboard. Every request also has to name localhost in its `Host`
+//! header, so a page whose domain has been pointed at 127.0.0.1 (DNS
+//! rebinding) can't pass for same-origin and read the keymap or events
+//! without sending an origin. A connection that sends nothing for
+//! `READ_TIMEOUT` is dropped, and one that sends more than `MAX_HEAD` bytes
+//! before its body is refused, so neither can hold a thread or fill memory.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::config::FlashConfig;
+use crate::halfkay::{self, Target};
+use crate::{hex, keymap_file, monitor, patch};
+use crate::{Board, Bootloader, FlashOptions};
+
+/// The largest request body taken. A whole firmware image as Intel HEX is
+/// well under it.
+const MAX_BODY: usize = 1 << 20;
+
+/// Header lines past this many make a request malformed.
+const MAX_HEADERS: usize = 64;
+
+/// The most a request line and its headers may take together.
+const MAX_HEAD: u64 = 16 << 10;
+
+/// How long a connection gets to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Host names a request may be addressed to, with the port.
+const LOCAL_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// How often the event stream sends something even with no events, to
+/// notice that its reader has gone.
+const KEEPALIVE: Duration = Duration::from_secs(1);
+
+/// What the API acts on.
+pub(crate) struct Server {
+    target: Target,
+    bootloader: Option<Bootloader>,
+    board: Board,
+    /// The firmware `PUT /keymap` patches.
+    firmware: Option<PathBuf>,
+    /// Origins of the pages allowed to call the API.
+    origins: Vec<String>,
+    /// Check the flash CRC32 after flashing, as `verify` in the config file
+    /// says.
+    verify: bool,
+    /// Where flash attempts are logged, as `log_file` in the config file
+    /// says (see `flash_log.rs`).
+    log_file: Option<PathBuf>,
+    /// Held while talking to the keyboard, so that a flash and the event
+    /// stream don't take turns mid-request.
+    usb: Mutex<()>,
+}
+
+impl Server {
+    pub(crate) fn new(
+        target: Target,
+        bootloader: Option<Bootloader>,
+        board: Board,
+        firmware: Option<PathBuf>,
+        origins: Vec<String>,
+        flash: &FlashConfig,
+    ) -> Server {
+        Server {
+            target,
+            bootloader,
+            board,
+            firmware,
+            origins,
+            verify: flash.verify == Some(true),
+            log_file: flash.log_file.clone(),
+            usb: Mutex::new(()),
+        }
+    }
+
+    /// Answer requests on `listen` until interrupted, each connection on a
+    /// thread of its own.
+    pub(crate) fn run(self, listen: &str) -> Result<()> {
+        let listener =
+            TcpListener::bind(listen).with_context(|| format!("listening on {listen}"))?;
+        if !listener.local_addr()?.ip().is_loopback() {
+            bail!(
+                "--listen has to be a loopback address like 127.0.0.1: the API can flash the \
+                 keyboard, so it's for this machine only"
+            );
+        }
+        println!(
+            "Serving on http://{} (Ctrl-C to stop)",
+            listener.local_addr()?
+        );
+        let port = listener.local_addr()?.port();
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                if let Err(e) = server.handle(stream, port) {
+                    eprintln!("error: {:#}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream, port: u16) -> Result<()> {
+        // The Host header is the client's to write, so it's no proof of
+        // where a request comes from; the socket's address is
+        if !stream.peer_addr()?.ip().is_loopback() {
+            let e = anyhow::anyhow!("only connections from this machine are answered");
+            return respond(&mut stream, 403, None, &error(&e));
+        }
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let request = match read_request(&mut BufReader::new(&stream)) {
+            Ok(request) => request,
+            Err(e) => return respond(&mut stream, 400, None, &error(&e)),
+        };
+        if !local_host(request.header("host"), port) {
+            let e = anyhow::anyhow!("the Host header must be localhost:{port}");
+            return respond(&mut stream, 403, None, &error(&e));
+        }
+        let origin = request.header("origin");
+        if !allowed(origin, &self.origins) {
+            let e = anyhow::anyhow!("origin not allowed; start the bridge with --allow-origin");
+            return respond(&mut stream, 403, None, &error(&e));
+        }
+        if request.method == "OPTIONS" {
+            // The browser asking whether the real request may be sent
+            write!(
+                stream,
+                "HTTP/1.1 204 No Content\r\n{}\
+                 Access-Control-Allow-Methods: GET, PUT, POST\r\n\
+                 Access-Control-Allow-Headers: Content-Type\r\n\
+                 Access-Control-Allow-Private-Network: true\r\n\
+                 Connection: close\r\n\r\n",
+                cors(origin)
+            )?;
+            return Ok(());
+        }
+        println!("{} {}", request.method, request.path);
+        if (request.method.as_str(), request.path.as_str()) == ("GET", "/events") {
+            return self.events(stream, origin);
+        }
+        match self.route(&request) {
+            Ok(Some(reply)) => respond(&mut stream, 200, origin, &reply),
+            Ok(None) => {
+                let e = anyhow::anyhow!("no {} {}", request.method, request.path);
+                respond(&mut stream, 404, origin, &error(&e))
+            }
+            Err(e) => respond(&mut stream, 500, origin, &error(&e)),
+        }
+    }
+
+    /// The reply to a request, or None if there's no such route.
+    fn route(&self, request: &Request) -> Result<Option<Value>> {
+        let reply = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/devices") => {
+                let devices: Vec<Value> = halfkay::list()?.iter().map(crate::device_json).collect();
+                json!({ "status": "ok", "devices": devices })
+            }
+            ("GET", "/keymap") => {
+                let layers = {
+                    let _usb = self.lock();
+                    halfkay::read_keymap(&self.target)?
+                };
+                json!({ "status": "ok", "keymap": keymap_file::to_toml(&layers) })
+            }
+            ("PUT", "/keymap") => {
+                let layers = keymap_file::parse_toml(request.text()?)?;
+                let firmware = self.firmware.as_deref().context(
+                    "no firmware to patch; pass --firmware or set it in the config file",
+                )?;
+                let (base_address, mut data) =
+                    crate::load_firmware(firmware, 0, &hex::FlattenOptions::default())?;
+                patch::patch_keymap(&mut data, &layers)
+                    .with_context(|| format!("patching {}", firmware.display()))?;
+                self.flash(base_address, data)?
+            }
+            ("POST", "/flash") => {
+                let segments = hex::parse_hex(request.text()?).context("parsing Intel HEX")?;
+                let (base_address, data) =
+                    hex::flatten_segments(&segments, &hex::FlattenOptions::default())?;
+                self.flash(base_address, data)?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Flash an image, with its progress on the terminal.
+    fn flash(&self, base_address: u32, mut data: Vec<u8>) -> Result<Value> {
+        let _usb = self.lock();
+        crate::stamp_image(&mut data, false)?;
+        let log_file = self.log_file.as_deref();
+        let kind = crate::enter_bootloader(&self.target, self.bootloader, &data, log_file, false)?;
+        let options = FlashOptions {
+            verify: self.verify,
+            log_file,
+            ..FlashOptions::default()
+        };
+        crate::flash_and_report(
+            &self.target,
+            kind,
+            self.board,
+            base_address,
+            &data,
+            options,
+            false,
+        )?;
+        Ok(json!({ "status": "ok", "protocol": kind.name(), "bytes": data.len() }))
+    }
+
+    /// Stream debug events until the reader goes away or the keyboard does.
+    /// `EventSource` reconnects by itself, e.g. after a flash.
+    fn events(&self, mut stream: TcpStream, origin: Option<&str>) -> Result<()> {
+        let channel = {
+            let _usb = self.lock();
+            halfkay::DebugChannel::open(&self.target).and_then(|channel| {
+                // Whatever piled up before we attached is stale
+                while !channel.poll()?.is_empty() {}
+                Ok(channel)
+            })
+        };
+        let channel = match channel {
+            Ok(channel) => channel,
+            Err(e) => return respond(&mut stream, 500, origin, &error(&e)),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n{}\r\n",
+            cors(origin)
+        )?;
+
+        let start = Instant::now();
+        let mut last_write = start;
+        loop {
+            let bytes = {
+                let _usb = self.lock();
+                channel.poll()?
+            };
+            let elapsed = start.elapsed();
+            for event in monitor::decode(&bytes, elapsed.as_millis() as u32)? {
+                let mut value = event.to_json();
+                value["time"] = json!(elapsed.as_secs_f64());
+                write!(stream, "data: {value}\n\n")?;
+                last_write = Instant::now();
+            }
+            if last_write.elapsed() >= KEEPALIVE {
+                write!(stream, ": keepalive\n\n")?;
+                last_write = Instant::now();
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        // A request that panicked mid-transfer leaves nothing to clean up
+        self.usb.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An HTTP request, as much of it as the API needs.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// Without the query string.
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of header `name`, in any case.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn text(&self) -> Result<&str> {
+        std::str::from_utf8(&self.body).context("request body is not UTF-8")
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut head = reader.take(MAX_HEAD);
+    let mut line = String::new();
+    read_head_line(&mut head, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("malformed request line {:?}", line.trim_end());
+    };
+    let path = target.split('?').next().unwrap_or(target);
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_head_line(&mut head, &mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            bail!("more than {MAX_HEADERS} headers");
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            bail!("malformed header {line:?}");
+        };
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let len = match request.header("content-length") {
+        Some(len) => len.parse::<usize>().context("malformed Content-Length")?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        bail!("request body is {len} bytes, more than the {MAX_BODY} taken");
+    }
+    request.body = vec![0; len];
+    reader
+        .read_exact(&mut request.body)
+        .context("request body cut short")?;
+    Ok(request)
+}
+
+/// Read one line of a request's head, which has to end within `MAX_HEAD`.
+fn read_head_line(head: &mut impl BufRead, line: &mut String) -> Result<()> {
+    head.read_line(line)?;
+    if !line.ends_with('\n') {
+        bail!("request head cut short or longer than {MAX_HEAD} bytes");
+    }
+    Ok(())
+}
+
+/// Whether a request addressed to `host` is for this bridge on `port`
+/// under a loopback name, rather than for some other site's name that
+/// resolves here.
+fn local_host(host: Option<&str>, port: u16) -> bool {
+    host.is_some_and(|host| {
+        LOCAL_HOSTS
+            .iter()
+            .any(|name| host.eq_ignore_ascii_case(&format!("{name}:{port}")))
+    })
+}
+
+/// Whether a request from `origin` may be answered: anything that isn't a
+/// web page (curl, scripts) sends none, and is let through because only
+/// this machine can connect at all.
+fn allowed(origin: Option<&str>, origins: &[String]) -> bool {
+    origin.is_none_or(|origin| origins.iter().any(|o| o == origin))
+}
+
+/// The CORS headers that let the page at `origin` read the reply.
+fn cors(origin: Option<&str>) -> String {
+    match origin {
+        Some(origin) => format!("Access-Control-Allow-Origin: {origin}\r\nVary: Origin\r\n"),
+        None => String::new(),
+    }
+}
+
+fn error(e: &anyhow::Error) -> Value {
+    json!({ "status": "error", "error": format!("{:#}", e) })
+}
+
+fn respond(stream: &mut impl Write, status: u16, origin: Option<&str>, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n{}Connection: close\r\n\r\n{body}",
+        body.len(),
+        cors(origin)
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_request_with_its_body() {
+        let raw = "PUT /keymap?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+                   content-length: 5\r\nOrigin: https://example.com\r\n\r\nhello extra";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("PUT", "/keymap")
+        );
+        assert_eq!(request.header("ORIGIN"), Some("https://example.com"));
+        assert_eq!(request.text().unwrap(), "hello");
+    }
+
+    #[test]
+    fn rejects_malformed_and_oversized_requests() {
+        assert!(read_request(&mut "GET\r\n\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET / HTTP/1.1\r\nno colon\r\n\r\n".as_bytes()).is_err());
+        let huge = format!(
+            "POST /flash HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(read_request(&mut huge.as_bytes()).is_err());
+        let short = "POST /flash HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(read_request(&mut short.as_bytes()).is_err());
+        let endless = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(MAX_HEAD as usize));
+        assert!(read_request(&mut endless.as_bytes()).is_err());
+        assert!(read_request(&mut "GET / HTTP/1.1\r\nHost: x".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn only_listed_origins_get_answers() {
+        let origins = vec!["https://configurator.example".to_string()];
+        assert!(allowed(None, &origins));
+        assert!(allowed(Some("https://configurator.example"), &origins));
+        assert!(!allowed(Some("https://evil.example"), &origins));
+        assert!(!allowed(Some("https://configurator.example"), &[]));
+    }
+
+    #[test]
+    fn only_requests_for_localhost_are_answered() {
+        assert!(local_host(Some("localhost:7780"), 7780));
+        assert!(local_host(Some("127.0.0.1:7780"), 7780));
+        assert!(local_host(Some("[::1]:7780"), 7780));
+        assert!(!local_host(Some("localhost:8080"), 7780));
+        assert!(!local_host(Some("rebound.example:7780"), 7780));
+        assert!(!local_host(Some("localhost"), 7780));
+        assert!(!local_host(None, 7780));
+    }
+
+    #[test]
+    fn replies_are_json_with_cors_for_the_page() {
+        let mut out = Vec::new();
+        respond(
+            &mut out,
+            404,
+            Some("https://a.example"),
+            &json!({ "status": "error" }),
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.contains("Access-Control-Allow-Origin: https://a.example\r\n"));
+        assert!(out.contains("Content-Length: 18\r\n"));
+        assert!(out.ends_with("\r\n\r\n{\"status\":\"error\"}"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Stamp a firmware image with its own CRC32.
+//!
+//! The firmware reserves a slot for it: the marker `EDXCRC32`, then four
+//! bytes left erased (`firmware/src/build_info.rs`). Before flashing, the
+//! CLI computes the CRC32 of the flattened image with the slot still erased
+//! and writes it there, little-endian. The keyboard then reports it in
+//! `version`, which ties what's running back to a file without any build
+//! metadata.
+
+use anyhow::{bail, Result};
+
+use crate::patch::find_all;
+
+/// Marks the CRC slot in a firmware image; must match `IMAGE_CRC` in
+/// `firmware/src/build_info.rs`.
+pub const IMAGE_CRC_MARKER: [u8; 8] = *b"EDXCRC32";
+
+/// Where the four CRC bytes are, if the image has a slot.
+fn slot(image: &[u8]) -> Result<Option<usize>> {
+    match find_all(image, &IMAGE_CRC_MARKER)[..] {
+        [] => Ok(None),
+        [start] if start + IMAGE_CRC_MARKER.len() + 4 <= image.len() => {
+            Ok(Some(start + IMAGE_CRC_MARKER.len()))
+        }
+        [_] => bail!("image CRC slot is cut off by the end of the image"),
+        ref starts => bail!("image CRC marker appears {} times", starts.len()),
+    }
+}
+
+/// Compute the image's CRC and write it into the slot. Returns None for
+/// firmware built without one. Stamping an image twice gives the same CRC.
+pub fn stamp(image: &mut [u8]) -> Result<Option<u32>> {
+    let Some(at) = slot(image)? else {
+        return Ok(None);
+    };
+    image[at..at + 4].fill(0xFF);
+    let crc = crc32fast::hash(image);
+    image[at..at + 4].copy_from_slice(&crc.to_le_bytes());
+    Ok(Some(crc))
+}
+
+/// The CRC already stamped into an image, if any.
+pub fn read(image: &[u8]) -> Option<u32> {
+    let at = slot(image).ok()??;
+    let crc: [u8; 4] = image[at..at + 4].try_into().unwrap();
+    (crc != [0xFF; 4]).then(|| u32::from_le_bytes(crc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0x11; 16];
+        image.extend_from_slice(&IMAGE_CRC_MARKER);
+        image.extend_from_slice(&[0xFF; 4]);
+        image.extend_from_slice(&[0x22; 8]);
+        image
+    }
+
+    #[test]
+    fn crc_is_of_the_image_with_the_slot_erased() {
+        let mut data = image();
+        let expected = crc32fast::hash(&data);
+        assert_eq!(stamp(&mut data).unwrap(), Some(expected));
+        assert_eq!(&data[24..28], &expected.to_le_bytes());
+        assert_eq!(read(&data), Some(expected));
+        // Restamping (say, after patch-keymap) starts from an erased slot again
+        assert_eq!(stamp(&mut data).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn images_without_a_slot_are_left_alone() {
+        let mut data = vec![0x11; 32];
+        assert_eq!(stamp(&mut data).unwrap(), None);
+        assert_eq!(data, vec![0x11; 32]);
+        assert_eq!(read(&image()), None);
+        let mut twice = image();
+        twice.extend_from_slice(&image());
+        assert!(stamp(&mut twice).is_err());
+    }
+}
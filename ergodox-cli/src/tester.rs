@@ -0,0 +1,254 @@
+//! Interactive switch tester: draws the board in the terminal and marks
+//! keys off as the firmware reports them pressed.
+//!
+//! Key positions come from the same geometry as the SVG layout, scaled down
+//! to a character grid. Each key is drawn as `[lbl ]` with its layer-0
+//! legend, plain until it's been pressed once, green after that, and
+//! reversed while it's held down.
+
+use std::collections::BTreeSet;
+
+use ergodox_keymap::{KeyEvent, Keycode, MatrixState, COLS, LAYERS, ROWS};
+
+use crate::layout::{self, Key, S};
+use crate::monitor::Event;
+
+/// Characters per key step horizontally, and lines per key step vertically.
+const CHARS_PER_KEY: f64 = 7.0;
+const LINES_PER_KEY: f64 = 2.0;
+
+/// Legend characters that fit inside the brackets.
+const LABEL_WIDTH: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Plain,
+    Tested,
+    Held,
+}
+
+pub struct Tester {
+    keys: Vec<Key>,
+    tested: MatrixState,
+    held: MatrixState,
+    /// Presses reported at matrix positions with no switch: a wiring fault.
+    stray: BTreeSet<(u8, u8)>,
+    /// The most recent non-key event, shown under the board.
+    last_message: Option<String>,
+}
+
+impl Tester {
+    pub fn new() -> Tester {
+        Tester {
+            keys: layout::build_keys(),
+            tested: [[false; COLS]; ROWS],
+            held: [[false; COLS]; ROWS],
+            stray: BTreeSet::new(),
+            last_message: None,
+        }
+    }
+
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::Key(KeyEvent {
+                row,
+                col,
+                pressed: down,
+                ..
+            }) => {
+                let (r, c) = (row as usize, col as usize);
+                if !self.keys.iter().any(|k| k.row == r && k.col == c) {
+                    if down {
+                        self.stray.insert((row, col));
+                    }
+                    return;
+                }
+                self.held[r][c] = down;
+                self.tested[r][c] |= down;
+            }
+            Event::Layer(_) => {}
+            other => self.last_message = Some(other.to_string()),
+        }
+    }
+
+    /// Number of physical switches pressed at least once.
+    pub fn tested(&self) -> usize {
+        self.keys
+            .iter()
+            .filter(|k| self.tested[k.row][k.col])
+            .count()
+    }
+
+    pub fn total(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn done(&self) -> bool {
+        self.tested() == self.total()
+    }
+
+    /// Draw the board, a progress line and any problems seen so far. With
+    /// `color` off, styles are left out (for tests and dumb terminals).
+    pub fn render(&self, color: bool) -> String {
+        let width = self
+            .keys
+            .iter()
+            .map(|k| grid_x(k.x) + LABEL_WIDTH + 2)
+            .max()
+            .unwrap_or(0);
+        let height = self.keys.iter().map(|k| grid_y(k.y) + 1).max().unwrap_or(0);
+        let mut grid = vec![vec![(' ', Style::Plain); width]; height];
+
+        for key in &self.keys {
+            let style = if self.held[key.row][key.col] {
+                Style::Held
+            } else if self.tested[key.row][key.col] {
+                Style::Tested
+            } else {
+                Style::Plain
+            };
+            let text = format!("[{:<w$}]", legend(key.row, key.col), w = LABEL_WIDTH);
+            let (x, y) = (grid_x(key.x), grid_y(key.y));
+            for (i, ch) in text.chars().enumerate() {
+                grid[y][x + i] = (ch, style);
+            }
+        }
+
+        let mut out = String::new();
+        for line in grid {
+            let mut current = Style::Plain;
+            let mut text = String::new();
+            for (ch, style) in line {
+                if color && style != current {
+                    text.push_str(escape(style));
+                    current = style;
+                }
+                text.push(ch);
+            }
+            if color && current != Style::Plain {
+                text.push_str(escape(Style::Plain));
+            }
+            out.push_str(text.trim_end());
+            out.push('\n');
+        }
+
+        out.push_str(&format!(
+            "\nTested {}/{} switches.\n",
+            self.tested(),
+            self.total()
+        ));
+        for (row, col) in &self.stray {
+            out.push_str(&format!(
+                "Press reported at row {} col {}, where there is no switch: check the wiring.\n",
+                row, col
+            ));
+        }
+        if let Some(message) = &self.last_message {
+            out.push_str(message);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Layer-0 legend for a position, cut to fit.
+fn legend(row: usize, col: usize) -> String {
+    let label = match LAYERS[0][row][col] {
+        Keycode::Trans | Keycode::None => "",
+        kc => kc.display_name(),
+    };
+    label.chars().take(LABEL_WIDTH).collect()
+}
+
+fn grid_x(x: f64) -> usize {
+    (x / S * CHARS_PER_KEY).round() as usize
+}
+
+fn grid_y(y: f64) -> usize {
+    // Thumb-cluster keys sit at half-step offsets; round them consistently
+    (y / S * LINES_PER_KEY + 0.01).round() as usize
+}
+
+fn escape(style: Style) -> &'static str {
+    match style {
+        Style::Plain => "\x1b[0m",
+        Style::Tested => "\x1b[32m",
+        Style::Held => "\x1b[7m",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_switch_is_drawn_once() {
+        let tester = Tester::new();
+        let board = tester.render(false);
+        assert_eq!(board.matches('[').count(), tester.total());
+        assert_eq!(tester.total(), 76);
+    }
+
+    #[test]
+    fn keys_do_not_overlap_on_the_grid() {
+        // Two keys drawn over each other would hide one of them.
+        let keys = layout::build_keys();
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                let same_line = grid_y(a.y) == grid_y(b.y);
+                let apart = grid_x(a.x).abs_diff(grid_x(b.x)) >= LABEL_WIDTH + 2;
+                assert!(
+                    !same_line || apart,
+                    "({},{}) overlaps ({},{})",
+                    a.row,
+                    a.col,
+                    b.row,
+                    b.col
+                );
+            }
+        }
+    }
+
+    fn key(row: u8, col: u8, pressed: bool) -> Event {
+        Event::Key(KeyEvent {
+            row,
+            col,
+            pressed,
+            timestamp: 0,
+        })
+    }
+
+    #[test]
+    fn press_marks_a_key_tested_and_release_keeps_it() {
+        let mut tester = Tester::new();
+        tester.apply(key(1, 1, true));
+        assert_eq!(tester.tested(), 1);
+        assert!(tester.render(true).contains("\x1b[7m"));
+
+        tester.apply(key(1, 1, false));
+        assert_eq!(tester.tested(), 1);
+        let board = tester.render(true);
+        assert!(board.contains("\x1b[32m"));
+        assert!(!board.contains("\x1b[7m"));
+    }
+
+    #[test]
+    fn press_without_a_switch_is_reported_not_counted() {
+        let mut tester = Tester::new();
+        tester.apply(key(5, 6, true));
+        assert_eq!(tester.tested(), 0);
+        assert!(tester
+            .render(false)
+            .contains("row 5 col 6, where there is no switch"));
+    }
+
+    #[test]
+    fn done_after_every_switch() {
+        let mut tester = Tester::new();
+        for k in layout::build_keys() {
+            assert!(!tester.done());
+            tester.apply(key(k.row as u8, k.col as u8, true));
+        }
+        assert!(tester.done());
+    }
+}
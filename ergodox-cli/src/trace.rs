@@ -0,0 +1,205 @@
+//! `-v` tracing of USB traffic, so that a failure on someone else's
+//! machine (a quirky hub, a flaky cable) comes back with more than
+//! "Pipe error".
+//!
+//! Every transfer goes through the wrappers here instead of calling rusb
+//! directly. With `-v`, each one is logged to stderr with its setup fields,
+//! outcome and duration, along with the devices found on the bus; `-vv`
+//! adds the bytes sent and received. Logging goes to stderr so it never
+//! mixes with `--json` output.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use rusb::{DeviceHandle, GlobalContext};
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// How many data bytes `-vv` shows per transfer.
+const MAX_DATA_SHOWN: usize = 32;
+
+/// Set the verbosity: 0 is quiet, 1 logs transfers, 2 adds their data.
+pub fn set_level(level: u8) {
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+fn enabled(level: u8) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level
+}
+
+/// Log a message at `level`.
+pub fn log(level: u8, message: impl FnOnce() -> String) {
+    if enabled(level) {
+        eprintln!("usb: {}", message());
+    }
+}
+
+pub fn read_control(
+    handle: &DeviceHandle<GlobalContext>,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> rusb::Result<usize> {
+    let start = Instant::now();
+    let result = handle.read_control(request_type, request, value, index, buf, timeout);
+    log(1, || {
+        let setup = Setup {
+            request_type,
+            request,
+            value,
+            index,
+            length: buf.len(),
+        };
+        let data = result.as_ref().map_or(&[][..], |&n| &buf[..n]);
+        describe(&setup.to_string(), &result, start.elapsed(), data)
+    });
+    result
+}
+
+pub fn write_control(
+    handle: &DeviceHandle<GlobalContext>,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &[u8],
+    timeout: Duration,
+) -> rusb::Result<usize> {
+    let start = Instant::now();
+    let result = handle.write_control(request_type, request, value, index, buf, timeout);
+    log(1, || {
+        let setup = Setup {
+            request_type,
+            request,
+            value,
+            index,
+            length: buf.len(),
+        };
+        describe(&setup.to_string(), &result, start.elapsed(), buf)
+    });
+    result
+}
+
+pub fn read_bulk(
+    handle: &DeviceHandle<GlobalContext>,
+    endpoint: u8,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> rusb::Result<usize> {
+    let start = Instant::now();
+    let result = handle.read_bulk(endpoint, buf, timeout);
+    log(1, || {
+        let data = result.as_ref().map_or(&[][..], |&n| &buf[..n]);
+        let transfer = format!("bulk IN  ep=0x{:02X} len={}", endpoint, buf.len());
+        describe(&transfer, &result, start.elapsed(), data)
+    });
+    result
+}
+
+pub fn write_bulk(
+    handle: &DeviceHandle<GlobalContext>,
+    endpoint: u8,
+    buf: &[u8],
+    timeout: Duration,
+) -> rusb::Result<usize> {
+    let start = Instant::now();
+    let result = handle.write_bulk(endpoint, buf, timeout);
+    log(1, || {
+        let transfer = format!("bulk OUT ep=0x{:02X} len={}", endpoint, buf.len());
+        describe(&transfer, &result, start.elapsed(), buf)
+    });
+    result
+}
+
+/// The fields of a control transfer's SETUP packet.
+struct Setup {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: usize,
+}
+
+impl std::fmt::Display for Setup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Bit 7 of bmRequestType is the data direction
+        let direction = if self.request_type & 0x80 != 0 {
+            "IN "
+        } else {
+            "OUT"
+        };
+        write!(
+            f,
+            "control {} bmRequestType=0x{:02X} bRequest=0x{:02X} wValue=0x{:04X} wIndex=0x{:04X} wLength={}",
+            direction, self.request_type, self.request, self.value, self.index, self.length
+        )
+    }
+}
+
+/// One log line: the transfer, how it went and how long it took, and at
+/// `-vv` the data.
+fn describe(
+    transfer: &str,
+    result: &rusb::Result<usize>,
+    elapsed: Duration,
+    data: &[u8],
+) -> String {
+    let outcome = match result {
+        Ok(n) => format!("ok, {} bytes", n),
+        Err(e) => format!("failed: {}", e),
+    };
+    let mut line = format!(
+        "{} -> {} in {:.2} ms",
+        transfer,
+        outcome,
+        elapsed.as_secs_f64() * 1000.0
+    );
+    if enabled(2) && !data.is_empty() {
+        let shown: Vec<String> = data
+            .iter()
+            .take(MAX_DATA_SHOWN)
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        line += &format!("\n     data: {}", shown.join(" "));
+        if data.len() > MAX_DATA_SHOWN {
+            line += &format!(" ... ({} bytes)", data.len());
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_transfer_lists_setup_fields_outcome_and_time() {
+        let setup = Setup {
+            request_type: 0xC0,
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        let line = describe(&setup.to_string(), &Ok(4), Duration::from_micros(1250), &[]);
+        assert_eq!(
+            line,
+            "control IN  bmRequestType=0xC0 bRequest=0x01 wValue=0x0000 wIndex=0x0000 \
+             wLength=4 -> ok, 4 bytes in 1.25 ms"
+        );
+    }
+
+    #[test]
+    fn failed_transfer_says_why() {
+        let line = describe(
+            "bulk OUT ep=0x02 len=1",
+            &Err(rusb::Error::Pipe),
+            Duration::ZERO,
+            &[],
+        );
+        assert!(line.ends_with("failed: Pipe error in 0.00 ms"), "{line}");
+    }
+}
@@ -0,0 +1,190 @@
+//! udev rules for opening the keyboard and its bootloaders without root,
+//! for `udev`.
+//!
+//! libusb needs write access to `/dev/bus/usb/BBB/AAA` to talk to a device,
+//! and `--transport hidraw` to `/dev/hidrawN`. Both belong to root unless a
+//! rule says otherwise. The rules tag every device this tool flashes or
+//! talks to with `uaccess`, which gives the logged-in user access to it.
+//!
+//! The diagnosis goes through what's plugged in and says which devices
+//! can't be opened, and whether that's missing rules or a device that was
+//! plugged in before they were installed.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::halfkay::{self, Mode};
+use crate::{caterina, dfu, mode_name};
+
+/// Where `--install` puts the rules. The number sorts them before
+/// systemd's `73-seat-late.rules`, which turns `uaccess` into permissions.
+pub const RULES_PATH: &str = "/etc/udev/rules.d/49-ergodox.rules";
+
+/// The rules file.
+pub fn rules() -> String {
+    let mut out = String::from(
+        "# ErgoDox keyboard and bootloaders, for ergodox-cli without root.\n\
+         # Written by `ergodox-cli udev`.\n",
+    );
+    let hid = [
+        (
+            "Teensy HalfKay bootloader",
+            halfkay::HALFKAY_VID,
+            halfkay::HALFKAY_PID,
+        ),
+        (
+            "ErgoDox keyboard",
+            halfkay::KEYBOARD_VID,
+            halfkay::KEYBOARD_PID,
+        ),
+    ];
+    for (name, vid, pid) in hid {
+        usb_rule(&mut out, name, vid, pid);
+        let _ = writeln!(
+            out,
+            "KERNEL==\"hidraw*\", ATTRS{{idVendor}}==\"{vid:04x}\", \
+             ATTRS{{idProduct}}==\"{pid:04x}\", TAG+=\"uaccess\""
+        );
+    }
+    usb_rule(&mut out, "Atmel DFU bootloader", dfu::VID, dfu::PID);
+    for &(vid, pid) in caterina::IDS {
+        usb_rule(&mut out, "Caterina bootloader", vid, pid);
+    }
+    out
+}
+
+fn usb_rule(out: &mut String, name: &str, vid: u16, pid: u16) {
+    let _ = writeln!(
+        out,
+        "\n# {name} ({vid:04x}:{pid:04x})\n\
+         SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{vid:04x}\", \
+         ATTR{{idProduct}}==\"{pid:04x}\", TAG+=\"uaccess\""
+    );
+}
+
+/// Write the rules to `path` and have udev apply them to what's plugged in.
+pub fn install(path: &Path) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        bail!("udev rules are for Linux");
+    }
+    std::fs::write(path, rules())
+        .with_context(|| format!("writing {} (installing needs root)", path.display()))?;
+    for args in [
+        &["control", "--reload-rules"][..],
+        &[
+            "trigger",
+            "--subsystem-match=usb",
+            "--subsystem-match=hidraw",
+        ],
+    ] {
+        let status = std::process::Command::new("udevadm")
+            .args(args)
+            .status()
+            .context("running udevadm")?;
+        if !status.success() {
+            bail!("udevadm {} failed ({status})", args.join(" "));
+        }
+    }
+    Ok(())
+}
+
+/// Whether this user can open one of the devices plugged in.
+pub struct Access {
+    pub mode: Mode,
+    pub port_path: String,
+    /// The device node libusb opens.
+    pub node: PathBuf,
+    /// The node's permission bits, if it could be looked at.
+    pub permissions: Option<u32>,
+    pub openable: bool,
+}
+
+impl Access {
+    /// What to do about a device that can't be opened, given whether the
+    /// rules are installed.
+    pub fn fix(&self, installed: bool) -> Option<&'static str> {
+        match (self.openable, installed) {
+            (true, _) => None,
+            (false, false) => Some("install the rules with `sudo ergodox-cli udev --install`"),
+            (false, true) => Some(
+                "the rules are installed; unplug the keyboard and plug it in again, or log in \
+                 again if it still can't be opened",
+            ),
+        }
+    }
+}
+
+/// Check every keyboard and bootloader plugged in.
+pub fn diagnose() -> Result<Vec<Access>> {
+    Ok(halfkay::list()?
+        .into_iter()
+        .map(|d| {
+            let node = PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", d.bus, d.address));
+            Access {
+                mode: d.mode,
+                port_path: d.port_path(),
+                permissions: permissions(&node),
+                node,
+                openable: d.openable,
+            }
+        })
+        .collect())
+}
+
+/// Print what `diagnose` found to stderr, keeping stdout for the rules.
+pub fn report(devices: &[Access], installed: bool) {
+    if devices.is_empty() {
+        eprintln!("No keyboards or bootloaders plugged in to check.");
+    }
+    for d in devices {
+        let permissions = d.permissions.map_or(String::new(), |p| format!(" ({p:o})"));
+        let state = if d.openable { "ok" } else { "can't open" };
+        eprintln!(
+            "{} at {}: {}{permissions}: {state}",
+            mode_name(d.mode),
+            d.port_path,
+            d.node.display()
+        );
+        if let Some(fix) = d.fix(installed) {
+            eprintln!("  fix: {fix}");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn permissions(node: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(std::fs::metadata(node).ok()?.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn permissions(_node: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_cover_halfkay_and_the_keyboard() {
+        let rules = rules();
+        assert!(rules.contains("16c0:0478"));
+        assert!(rules.contains("16c0:047e"));
+        assert!(rules.contains(
+            "KERNEL==\"hidraw*\", ATTRS{idVendor}==\"16c0\", ATTRS{idProduct}==\"0478\", \
+             TAG+=\"uaccess\""
+        ));
+        assert!(rules.contains(
+            "SUBSYSTEM==\"usb\", ATTR{idVendor}==\"16c0\", ATTR{idProduct}==\"047e\", \
+             TAG+=\"uaccess\""
+        ));
+        // Every rule tags, and nothing hands devices to everyone
+        assert!(rules
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .all(|l| l.ends_with("TAG+=\"uaccess\"")));
+    }
+}
@@ -0,0 +1,159 @@
+//! Find the newest firmware release on GitHub, for `ergodox-cli update`.
+//!
+//! Releases carry the firmware as Intel HEX assets. A release built for
+//! more than one microcontroller names each hex after its MCU
+//! (`firmware-at90usb1286.hex`); a single hex not named after an MCU is
+//! taken to be for the ATmega32U4, the stock Teensy 2.0's, and nothing
+//! else: the wrong image on a Teensy++ won't run.
+//!
+//! HTTP goes through `curl`, as git goes through `git`, rather than
+//! pulling a TLS stack into the CLI for one request.
+
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Where releases are published.
+pub const DEFAULT_REPO: &str = "rce/ergodox";
+
+/// The MCU an unnamed hex is built for.
+const DEFAULT_MCU: &str = "atmega32u4";
+
+/// How every MCU's name starts, to tell a hex named after one.
+const MCU_PREFIXES: [&str; 2] = ["atmega", "at90"];
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    /// The release notes, in Markdown.
+    pub body: Option<String>,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// The hex asset for `mcu` (clap's name for it, like `atmega32u4`).
+    pub fn firmware_for(&self, mcu: &str) -> Result<&Asset> {
+        let hexes: Vec<&Asset> = self
+            .assets
+            .iter()
+            .filter(|a| a.name.to_ascii_lowercase().ends_with(".hex"))
+            .collect();
+        let named: Vec<&Asset> = hexes
+            .iter()
+            .copied()
+            .filter(|a| a.name.to_ascii_lowercase().contains(mcu))
+            .collect();
+        let unnamed = |a: &Asset| {
+            let name = a.name.to_ascii_lowercase();
+            !MCU_PREFIXES.iter().any(|p| name.contains(p))
+        };
+        match (&named[..], &hexes[..]) {
+            ([asset], _) => Ok(asset),
+            ([], [asset]) if mcu == DEFAULT_MCU && unnamed(asset) => Ok(asset),
+            ([], []) => bail!("release {} has no firmware hex", self.tag_name),
+            _ => bail!(
+                "release {} has no single firmware hex for the {mcu}: {}",
+                self.tag_name,
+                hexes
+                    .iter()
+                    .map(|a| a.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// The newest published release of `repo` (`owner/name`).
+pub fn latest(repo: &str) -> Result<Release> {
+    let url = format!("https://api.github.com/repos/{repo}/releases/latest");
+    let body = curl(&url, &["--header", "Accept: application/vnd.github+json"])
+        .with_context(|| format!("looking up the latest release of {repo}"))?;
+    serde_json::from_slice(&body).context("unexpected reply from GitHub")
+}
+
+/// Download `url`'s contents.
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    curl(url, &[])
+}
+
+fn curl(url: &str, extra_args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(extra_args)
+        .arg(url)
+        .output()
+        .context("running curl")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+/// Whether `tag` names the firmware `version` (`v1.2.0` and `1.2.0` are
+/// the same release).
+pub fn is_running(tag: &str, version: &str) -> bool {
+    tag.trim_start_matches('v') == version.trim_start_matches('v')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(assets: &[&str]) -> Release {
+        let assets: Vec<_> = assets
+            .iter()
+            .map(|name| format!(r#"{{"name": "{name}", "browser_download_url": "u/{name}"}}"#))
+            .collect();
+        serde_json::from_str(&format!(
+            r#"{{"tag_name": "v1.2.0", "body": "Fixes", "assets": [{}]}}"#,
+            assets.join(",")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn a_single_unnamed_hex_is_for_the_atmega32u4() {
+        let r = release(&["firmware.hex", "ergodox-cli-linux.tar.gz"]);
+        assert_eq!(r.firmware_for("atmega32u4").unwrap().name, "firmware.hex");
+    }
+
+    #[test]
+    fn other_mcus_never_get_a_hex_not_named_for_them() {
+        let err = release(&["firmware.hex"])
+            .firmware_for("at90usb1286")
+            .unwrap_err();
+        assert!(err.to_string().contains("firmware.hex"), "{err}");
+        let r = release(&["firmware-at90usb1286.hex"]);
+        assert!(r.firmware_for("atmega32u4").is_err());
+    }
+
+    #[test]
+    fn the_mcus_own_hex_wins() {
+        let r = release(&["firmware-atmega32u4.hex", "firmware-at90usb1286.hex"]);
+        assert_eq!(
+            r.firmware_for("at90usb1286").unwrap().browser_download_url,
+            "u/firmware-at90usb1286.hex"
+        );
+    }
+
+    #[test]
+    fn ambiguous_or_missing_hexes_are_errors() {
+        let r = release(&["left.hex", "right.hex"]);
+        assert!(r.firmware_for("atmega32u4").is_err());
+        assert!(release(&["notes.txt"]).firmware_for("atmega32u4").is_err());
+    }
+
+    #[test]
+    fn tags_match_versions_with_or_without_a_v() {
+        assert!(is_running("v1.2.0", "1.2.0"));
+        assert!(!is_running("v1.3.0", "1.2.0"));
+    }
+}
@@ -0,0 +1,534 @@
+//! Layout alias modules from XKB symbols files, for `xkb-aliases`.
+//!
+//! `ergodox_keymap::layout::nordic` names the keys of a Nordic keyboard by
+//! their legends, so a keymap can say `A_RING` rather than `LBracket`. This
+//! writes the same kind of module for any layout X11 knows, from its
+//! definition under `/usr/share/X11/xkb/symbols`: `de` or `fr(bepo)` picks
+//! a file and a variant, as in `setxkbmap`.
+//!
+//! A variant is read with everything it includes, later definitions of a
+//! key replacing earlier ones, as X11 does. Only the first two levels of
+//! the first group are used (the legends without and with Shift). A key
+//! gets an alias when its unshifted legend differs from the US one at the
+//! same position, which leaves out the letters and digits most layouts
+//! share with US. The output is meant to be pasted into `pub mod layout` in
+//! `ergodox-keymap/src/lib.rs` and reviewed: names come from keysym names
+//! and can usually be improved on.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use ergodox_keymap::Keycode;
+
+/// Where X11 keeps its symbols files.
+pub const DEFAULT_SYMBOLS_DIR: &str = "/usr/share/X11/xkb/symbols";
+
+/// Includes nested deeper than this are taken for a loop.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// XKB key names on the alphanumeric block, with the keycode at each
+/// position and its unshifted US legend (None for the ISO key US lacks).
+const POSITIONS: [(&str, Keycode, Option<char>); 48] = [
+    ("TLDE", Keycode::Grave, Some('`')),
+    ("AE01", Keycode::N1, Some('1')),
+    ("AE02", Keycode::N2, Some('2')),
+    ("AE03", Keycode::N3, Some('3')),
+    ("AE04", Keycode::N4, Some('4')),
+    ("AE05", Keycode::N5, Some('5')),
+    ("AE06", Keycode::N6, Some('6')),
+    ("AE07", Keycode::N7, Some('7')),
+    ("AE08", Keycode::N8, Some('8')),
+    ("AE09", Keycode::N9, Some('9')),
+    ("AE10", Keycode::N0, Some('0')),
+    ("AE11", Keycode::Minus, Some('-')),
+    ("AE12", Keycode::Equal, Some('=')),
+    ("AD01", Keycode::Q, Some('q')),
+    ("AD02", Keycode::W, Some('w')),
+    ("AD03", Keycode::E, Some('e')),
+    ("AD04", Keycode::R, Some('r')),
+    ("AD05", Keycode::T, Some('t')),
+    ("AD06", Keycode::Y, Some('y')),
+    ("AD07", Keycode::U, Some('u')),
+    ("AD08", Keycode::I, Some('i')),
+    ("AD09", Keycode::O, Some('o')),
+    ("AD10", Keycode::P, Some('p')),
+    ("AD11", Keycode::LBracket, Some('[')),
+    ("AD12", Keycode::RBracket, Some(']')),
+    ("BKSL", Keycode::Backslash, Some('\\')),
+    ("AC01", Keycode::A, Some('a')),
+    ("AC02", Keycode::S, Some('s')),
+    ("AC03", Keycode::D, Some('d')),
+    ("AC04", Keycode::F, Some('f')),
+    ("AC05", Keycode::G, Some('g')),
+    ("AC06", Keycode::H, Some('h')),
+    ("AC07", Keycode::J, Some('j')),
+    ("AC08", Keycode::K, Some('k')),
+    ("AC09", Keycode::L, Some('l')),
+    ("AC10", Keycode::Semicolon, Some(';')),
+    ("AC11", Keycode::Quote, Some('\'')),
+    ("LSGT", Keycode::NonUsBackslash, None),
+    ("AB01", Keycode::Z, Some('z')),
+    ("AB02", Keycode::X, Some('x')),
+    ("AB03", Keycode::C, Some('c')),
+    ("AB04", Keycode::V, Some('v')),
+    ("AB05", Keycode::B, Some('b')),
+    ("AB06", Keycode::N, Some('n')),
+    ("AB07", Keycode::M, Some('m')),
+    ("AB08", Keycode::Comma, Some(',')),
+    ("AB09", Keycode::Dot, Some('.')),
+    ("AB10", Keycode::Slash, Some('/')),
+];
+
+/// Keysym names for U+0020..U+007E, in order.
+const ASCII_KEYSYMS: &str = "\
+    space exclam quotedbl numbersign dollar percent ampersand apostrophe parenleft parenright \
+    asterisk plus comma minus period slash 0 1 2 3 4 5 6 7 8 9 colon semicolon less equal \
+    greater question at A B C D E F G H I J K L M N O P Q R S T U V W X Y Z bracketleft \
+    backslash bracketright asciicircum underscore grave a b c d e f g h i j k l m n o p q r s \
+    t u v w x y z braceleft bar braceright asciitilde";
+
+/// Keysym names for U+00A0..U+00FF, in order.
+const LATIN1_KEYSYMS: &str = "\
+    nobreakspace exclamdown cent sterling currency yen brokenbar section diaeresis copyright \
+    ordfeminine guillemotleft notsign hyphen registered macron degree plusminus twosuperior \
+    threesuperior acute mu paragraph periodcentered cedilla onesuperior masculine \
+    guillemotright onequarter onehalf threequarters questiondown Agrave Aacute Acircumflex \
+    Atilde Adiaeresis Aring AE Ccedilla Egrave Eacute Ecircumflex Ediaeresis Igrave Iacute \
+    Icircumflex Idiaeresis ETH Ntilde Ograve Oacute Ocircumflex Otilde Odiaeresis multiply \
+    Oslash Ugrave Uacute Ucircumflex Udiaeresis Yacute THORN ssharp agrave aacute acircumflex \
+    atilde adiaeresis aring ae ccedilla egrave eacute ecircumflex ediaeresis igrave iacute \
+    icircumflex idiaeresis eth ntilde ograve oacute ocircumflex otilde odiaeresis division \
+    oslash ugrave uacute ucircumflex udiaeresis yacute thorn ydiaeresis";
+
+/// Dead keys, by the accent they print on their own.
+const DEAD_KEYSYMS: [(&str, char); 13] = [
+    ("dead_grave", '`'),
+    ("dead_acute", '\u{b4}'),
+    ("dead_circumflex", '^'),
+    ("dead_tilde", '~'),
+    ("dead_macron", '\u{af}'),
+    ("dead_breve", '\u{2d8}'),
+    ("dead_abovedot", '\u{2d9}'),
+    ("dead_diaeresis", '\u{a8}'),
+    ("dead_abovering", '\u{b0}'),
+    ("dead_doubleacute", '\u{2dd}'),
+    ("dead_caron", '\u{2c7}'),
+    ("dead_cedilla", '\u{b8}'),
+    ("dead_ogonek", '\u{2db}'),
+];
+
+/// Shorter words for keysyms in constant names, where the keysym's own name
+/// is long or says more about ASCII than about the legend.
+const NAME_WORDS: [(&str, &str); 14] = [
+    ("asciicircum", "CARET"),
+    ("circumflex", "CARET"),
+    ("asciitilde", "TILDE"),
+    ("asterisk", "STAR"),
+    ("onehalf", "HALF"),
+    ("quotedbl", "QUOTE"),
+    ("numbersign", "HASH"),
+    ("bracketleft", "LBRACKET"),
+    ("bracketright", "RBRACKET"),
+    ("braceleft", "LBRACE"),
+    ("braceright", "RBRACE"),
+    ("parenleft", "LPAREN"),
+    ("parenright", "RPAREN"),
+    ("abovering", "RING"),
+];
+
+/// Accents a Latin-1 letter keysym may end in, split off in constant names
+/// (`aring` is `A_RING`).
+const ACCENTS: [&str; 9] = [
+    "grave",
+    "acute",
+    "circumflex",
+    "tilde",
+    "diaeresis",
+    "ring",
+    "slash",
+    "cedilla",
+    "caron",
+];
+
+/// A key's keysyms by level, by XKB key name.
+type Symbols = BTreeMap<String, Vec<String>>;
+
+/// The Rust module of aliases for layout `spec` (`FILE` or `FILE(VARIANT)`),
+/// named `name`. `read` returns a symbols file's contents by name.
+pub fn aliases(spec: &str, name: &str, read: &dyn Fn(&str) -> Result<String>) -> Result<String> {
+    let mut symbols = Symbols::new();
+    include(spec, read, &mut symbols, 0)?;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "    /// Generated from XKB `{spec}` by `ergodox-cli xkb-aliases`."
+    )?;
+    writeln!(out, "    pub mod {name} {{")?;
+    writeln!(out, "        use super::super::Keycode;")?;
+    let mut used = Vec::new();
+    for (key, keycode, us) in POSITIONS {
+        let Some(levels) = symbols.get(key) else {
+            continue;
+        };
+        let Some(plain) = levels.first() else {
+            continue;
+        };
+        let shifted = levels.get(1).filter(|s| *s != plain && !is_void(s));
+        if is_void(plain) || us.is_some() && keysym_char(plain) == us {
+            continue;
+        }
+        let mut alias = alias_name(plain, shifted.map(String::as_str));
+        if used.contains(&alias) {
+            alias = format!("{alias}_{key}");
+        }
+        writeln!(out)?;
+        writeln!(
+            out,
+            "        /// {}",
+            legend_doc(plain, shifted.map(String::as_str))
+        )?;
+        writeln!(
+            out,
+            "        pub const {alias}: Keycode = Keycode::{keycode:?};"
+        )?;
+        used.push(alias);
+    }
+    writeln!(out, "    }}")?;
+    Ok(out)
+}
+
+/// Apply `spec` (`FILE` or `FILE(VARIANT)`, or several joined by `+`) on
+/// top of `symbols`.
+fn include(
+    spec: &str,
+    read: &dyn Fn(&str) -> Result<String>,
+    symbols: &mut Symbols,
+    depth: usize,
+) -> Result<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("includes nested more than {MAX_INCLUDE_DEPTH} deep at {spec:?}");
+    }
+    for part in spec.split('+').filter(|p| !p.is_empty()) {
+        // A `:N` suffix moves the include to another group; the first is
+        // all that's used here
+        let (part, group) = part.split_once(':').unwrap_or((part, "1"));
+        if group != "1" {
+            continue;
+        }
+        let (file, variant) = match part.split_once('(') {
+            Some((file, variant)) => (file, Some(variant.trim_end_matches(')'))),
+            None => (part, None),
+        };
+        let text = read(file).with_context(|| format!("reading XKB symbols {file:?}"))?;
+        let body = section(&strip_comments(&text), variant).with_context(|| {
+            format!("no variant {:?} in {file:?}", variant.unwrap_or("default"))
+        })?;
+        for statement in statements(&body) {
+            if let Some(rest) = statement.strip_prefix("include") {
+                include(rest.trim().trim_matches('"'), read, symbols, depth + 1)?;
+            } else if let Some((key, levels)) = key_statement(&statement) {
+                symbols.insert(key, levels);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `text` without `//` and `/* */` comments.
+fn strip_comments(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// The body of the `xkb_symbols "VARIANT" { ... }` section, or of the one
+/// marked `default` (else the first) when `variant` is None.
+fn section(text: &str, variant: Option<&str>) -> Option<String> {
+    let mut first = None;
+    let mut rest = text;
+    while let Some(at) = rest.find("xkb_symbols") {
+        let flags = &rest[..at];
+        // The section's flags run from the end of the one before it
+        let flags = &flags[flags.rfind(';').map_or(0, |i| i + 1)..];
+        let after = &rest[at + "xkb_symbols".len()..];
+        let open = after.find('{')?;
+        let name = after[..open].trim().trim_matches('"');
+        let close = matching_brace(&after[open..])?;
+        let body = &after[open + 1..open + close];
+        let default = flags.split_whitespace().any(|flag| flag == "default");
+        match variant {
+            Some(wanted) if wanted == name => return Some(body.to_string()),
+            None if default => return Some(body.to_string()),
+            _ => {}
+        }
+        first.get_or_insert(body);
+        rest = &after[open + close..];
+    }
+    if variant.is_none() {
+        first.map(str::to_string)
+    } else {
+        None
+    }
+}
+
+/// Where the brace opening `text` is closed.
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A section body split at the semicolons outside braces, and after each
+/// `include "..."`, which doesn't take one.
+fn statements(body: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in body.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ';' if depth == 0 => {
+                statements.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            '"' if current.trim_start().starts_with("include") && current.contains('"') => {
+                current.push(c);
+                statements.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    statements
+}
+
+/// `key <NAME> { [ sym, sym, ... ] }`, possibly with `type[Group1]=...`,
+/// `symbols[Group1]=` and the like around the levels.
+fn key_statement(statement: &str) -> Option<(String, Vec<String>)> {
+    let at = statement.find("key <")?;
+    let rest = &statement[at + "key <".len()..];
+    let (name, rest) = rest.split_once('>')?;
+    let mut groups = rest.split('[').skip(1);
+    let levels = groups.find_map(|group| {
+        let (inside, _) = group.split_once(']')?;
+        let inside = inside.trim();
+        (!inside.to_ascii_lowercase().starts_with("group")).then_some(inside)
+    })?;
+    let levels = levels.split(',').map(|s| s.trim().to_string()).collect();
+    Some((name.to_string(), levels))
+}
+
+/// Whether a keysym leaves the level empty.
+fn is_void(keysym: &str) -> bool {
+    matches!(keysym, "NoSymbol" | "VoidSymbol" | "")
+}
+
+/// The character a keysym types (or a dead key prints), if it's one this
+/// knows.
+fn keysym_char(keysym: &str) -> Option<char> {
+    if let Some(i) = ASCII_KEYSYMS.split_whitespace().position(|k| k == keysym) {
+        return char::from_u32(0x20 + i as u32);
+    }
+    if let Some(i) = LATIN1_KEYSYMS.split_whitespace().position(|k| k == keysym) {
+        return char::from_u32(0xA0 + i as u32);
+    }
+    if let Some(&(_, c)) = DEAD_KEYSYMS.iter().find(|(k, _)| *k == keysym) {
+        return Some(c);
+    }
+    match keysym {
+        "EuroSign" => Some('\u{20ac}'),
+        "ooblique" => Some('\u{f8}'),
+        "Ooblique" => Some('\u{d8}'),
+        _ => {
+            let hex = keysym.strip_prefix('U')?;
+            char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+        }
+    }
+}
+
+/// `` `+` (unshifted) / `?` (shifted) ``, or just `` `å` `` for a letter
+/// whose shifted legend is its capital.
+fn legend_doc(plain: &str, shifted: Option<&str>) -> String {
+    let code = |keysym: &str| match keysym_char(keysym) {
+        Some('`') => "`` ` ``".to_string(),
+        Some(c) => format!("`{c}`"),
+        None => format!("`{keysym}`"),
+    };
+    match shifted {
+        Some(shifted) if !is_capital_of(plain, shifted) => {
+            format!("{} (unshifted) / {} (shifted)", code(plain), code(shifted))
+        }
+        _ => code(plain),
+    }
+}
+
+/// The constant's name: the two legends' names joined, or one for a letter.
+fn alias_name(plain: &str, shifted: Option<&str>) -> String {
+    match shifted {
+        Some("greater") if plain == "less" => "ANGLE_BRACKETS".to_string(),
+        Some(shifted) if !is_capital_of(plain, shifted) => {
+            format!("{}_{}", keysym_word(plain), keysym_word(shifted))
+        }
+        _ => keysym_word(plain),
+    }
+}
+
+/// Whether `shifted` is the capital of the letter `plain`.
+fn is_capital_of(plain: &str, shifted: &str) -> bool {
+    match (keysym_char(plain), keysym_char(shifted)) {
+        (Some(p), Some(s)) => p.is_lowercase() && p.to_uppercase().eq([s]),
+        _ => false,
+    }
+}
+
+/// A keysym as a word of a constant name: `dead_acute` is `ACUTE`, and
+/// `odiaeresis` is `O_DIAERESIS`.
+fn keysym_word(keysym: &str) -> String {
+    let keysym = keysym.strip_prefix("dead_").unwrap_or(keysym);
+    if let Some(&(_, word)) = NAME_WORDS.iter().find(|(k, _)| *k == keysym) {
+        return word.to_string();
+    }
+    let letter_accent = keysym
+        .char_indices()
+        .nth(1)
+        .map(|(i, _)| keysym.split_at(i))
+        .filter(|(_, accent)| ACCENTS.contains(accent));
+    let word = match letter_accent {
+        Some((letter, accent)) => format!("{letter}_{accent}"),
+        None if keysym.len() == 1 && keysym.as_bytes()[0].is_ascii_digit() => {
+            format!("N{keysym}")
+        }
+        None => keysym.to_string(),
+    };
+    word.to_ascii_uppercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough of X11's `latin` and `se` files to build the Swedish layout.
+    const LATIN: &str = r#"
+        default partial alphanumeric_keys
+        xkb_symbols "basic" {
+            key <TLDE> { [ grave, asciitilde ] };
+            key <AE11> { [ minus, underscore ] };
+            key <AD11> { [ bracketleft, braceleft ] };
+            key <AC01> { [ a, A ] };
+            key <AB10> { [ slash, question ] };
+        };
+
+        // Nordic punctuation, shared by the Scandinavian layouts
+        partial alphanumeric_keys
+        xkb_symbols "type2" {
+            include "latin"
+            key <AE11> { [ plus, question, backslash, questiondown ] };
+            key <AE12> { [ dead_acute, dead_grave, plusminus, notsign ] };
+            key <AD11> { [ aring, Aring, dead_diaeresis, dead_abovering ] };
+            key <AD12> { [ dead_diaeresis, dead_circumflex, dead_tilde, dead_caron ] };
+            key <AC10> { [ odiaeresis, Odiaeresis, oslash, Oslash ] };
+            key <AC11> { [ adiaeresis, Adiaeresis, ae, AE ] };
+            key <BKSL> { [ apostrophe, asterisk, dead_doubleacute, multiply ] };
+            key <AB10> { [ minus, underscore, dead_belowdot, dead_abovedot ] };
+            key <LSGT> { [ less, greater, bar, brokenbar ] };
+        };
+    "#;
+
+    const SE: &str = r#"
+        default partial alphanumeric_keys
+        xkb_symbols "basic" {
+            include "latin(type2)"
+            include "se(se)"
+            name[Group1]="Swedish";
+        };
+
+        partial alphanumeric_keys
+        xkb_symbols "se" {
+            key <TLDE> { [ section, onehalf, paragraph, threequarters ], type[group1] = "FOUR_LEVEL" };
+            key <AE02> { [ 2, quotedbl, at, oneeighth ] };
+        };
+    "#;
+
+    fn read(file: &str) -> Result<String> {
+        match file {
+            "latin" => Ok(LATIN.to_string()),
+            "se" => Ok(SE.to_string()),
+            _ => bail!("no such file"),
+        }
+    }
+
+    #[test]
+    fn swedish_gives_the_nordic_aliases() {
+        use ergodox_keymap::layout::nordic;
+
+        let module = aliases("se", "swedish", &read).unwrap();
+        let expected = [
+            ("SECTION_HALF", nordic::SECTION_HALF),
+            ("PLUS_QUESTION", nordic::PLUS_QUESTION),
+            ("ACUTE_GRAVE", nordic::ACUTE_GRAVE),
+            ("A_RING", nordic::A_RING),
+            ("DIAERESIS_CARET", nordic::DIAERESIS_CARET),
+            ("APOSTROPHE_STAR", nordic::APOSTROPHE_STAR),
+            ("O_DIAERESIS", nordic::O_DIAERESIS),
+            ("A_DIAERESIS", nordic::A_DIAERESIS),
+            ("ANGLE_BRACKETS", nordic::ANGLE_BRACKETS),
+            ("MINUS_UNDERSCORE", nordic::MINUS_UNDERSCORE),
+        ];
+        for (name, keycode) in expected {
+            let line = format!("pub const {name}: Keycode = Keycode::{keycode:?};");
+            assert!(module.contains(&line), "{line} missing from\n{module}");
+        }
+        assert_eq!(module.matches("pub const").count(), expected.len());
+        assert!(module.contains("/// `\u{a7}` (unshifted) / `\u{bd}` (shifted)\n"));
+        assert!(module.contains("/// `\u{e5}`\n"));
+        assert!(module.contains("(unshifted) / `` ` `` (shifted)"));
+    }
+
+    #[test]
+    fn missing_variants_and_include_loops_are_errors() {
+        assert!(aliases("se(nope)", "x", &read).is_err());
+        let looping = |_: &str| Ok(r#"xkb_symbols "a" { include "loop(a)" };"#.to_string());
+        assert!(aliases("loop(a)", "x", &looping).is_err());
+    }
+
+    #[test]
+    fn keysyms_become_legends_and_name_words() {
+        assert_eq!(keysym_char("section"), Some('\u{a7}'));
+        assert_eq!(keysym_char("U20AC"), Some('\u{20ac}'));
+        assert_eq!(keysym_char("dead_acute"), Some('\u{b4}'));
+        assert_eq!(keysym_word("odiaeresis"), "O_DIAERESIS");
+        assert_eq!(keysym_word("dead_circumflex"), "CARET");
+        assert_eq!(keysym_word("2"), "N2");
+    }
+}
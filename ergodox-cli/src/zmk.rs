@@ -0,0 +1,174 @@
+//! Export the keymap as a ZMK `.keymap` devicetree file.
+//!
+//! ZMK has no ErgoDox shield, so there's no layout to target by name: each
+//! layer's bindings are listed row by row across both halves, in the order
+//! of QMK's `LAYOUT_ergodox_pretty` (see `qmk.rs`). A ZMK board with the
+//! same 76 keys takes them as they are; another board's matrix transform
+//! decides where they go.
+//!
+//! Nearly every keycode has a ZMK equivalent: plain keys are `&kp`,
+//! momentary layers `&mo`, transparent keys `&trans`, backlight keys `&bl`.
+//! ZMK's backlight doesn't breathe and it can't swap Ctrl and Caps Lock at
+//! runtime, so those two keys export as `&none`.
+
+use ergodox_keymap::Keycode;
+
+use crate::codegen::Layer;
+use crate::qmk;
+
+/// Keys per line of bindings, following the rows of the pretty layout:
+/// five main rows (row 2 and the bottom row are short on the inner
+/// columns), then the thumb clusters' top, middle and bottom rows.
+const LINE_LENGTHS: [usize; 8] = [14, 14, 12, 14, 10, 4, 2, 6];
+
+/// Render `layers` as a ZMK keymap.
+pub fn export(layers: &[Layer]) -> String {
+    let positions = qmk::pretty_positions();
+    let bindings: Vec<Vec<String>> = layers
+        .iter()
+        .map(|layer| {
+            positions
+                .iter()
+                .map(|&(row, col)| binding(layer[row][col]))
+                .collect()
+        })
+        .collect();
+
+    // One width per position across all layers keeps every layer aligned alike.
+    let mut widths = vec![0usize; positions.len()];
+    for layer in &bindings {
+        for (i, b) in layer.iter().enumerate() {
+            widths[i] = widths[i].max(b.len());
+        }
+    }
+
+    let mut out = String::from(
+        "/*\n * ErgoDox keymap exported by ergodox-cli. Bindings run row by row\n \
+         * across both halves, in QMK's LAYOUT_ergodox_pretty order.\n */\n\n\
+         #include <behaviors.dtsi>\n\
+         #include <dt-bindings/zmk/backlight.h>\n\
+         #include <dt-bindings/zmk/keys.h>\n\n\
+         / {\n    keymap {\n        compatible = \"zmk,keymap\";\n",
+    );
+    for (l, layer) in bindings.iter().enumerate() {
+        out.push_str(&format!(
+            "\n        layer_{l} {{\n            display-name = \"Layer {l}\";\n            \
+             bindings = <\n"
+        ));
+        let mut start = 0;
+        for len in LINE_LENGTHS {
+            let line: Vec<String> = (start..start + len)
+                .map(|i| format!("{:<w$}", layer[i], w = widths[i]))
+                .collect();
+            out.push_str(&format!("                {}\n", line.join("  ").trim_end()));
+            start += len;
+        }
+        out.push_str("            >;\n        };\n");
+    }
+    out.push_str("    };\n};\n");
+    out
+}
+
+/// The ZMK binding for a keycode.
+fn binding(kc: Keycode) -> String {
+    use Keycode::*;
+
+    let key = match kc {
+        Trans => return "&trans".to_string(),
+        None | BacklightBreathe | SwapCtrlCaps => return "&none".to_string(),
+        BacklightToggle => return "&bl BL_TOG".to_string(),
+        BacklightUp => return "&bl BL_INC".to_string(),
+        BacklightDown => return "&bl BL_DEC".to_string(),
+        kc if kc.is_layer() => return format!("&mo {}", kc.layer_number()),
+        N1 => "N1",
+        N2 => "N2",
+        N3 => "N3",
+        N4 => "N4",
+        N5 => "N5",
+        N6 => "N6",
+        N7 => "N7",
+        N8 => "N8",
+        N9 => "N9",
+        N0 => "N0",
+        Enter => "RET",
+        Escape => "ESC",
+        Backspace => "BSPC",
+        Tab => "TAB",
+        Space => "SPACE",
+        Minus => "MINUS",
+        Equal => "EQUAL",
+        LBracket => "LBKT",
+        RBracket => "RBKT",
+        Backslash => "BSLH",
+        Semicolon => "SEMI",
+        Quote => "SQT",
+        Grave => "GRAVE",
+        Comma => "COMMA",
+        Dot => "DOT",
+        Slash => "FSLH",
+        CapsLock => "CAPS",
+        NonUsBackslash => "NON_US_BSLH",
+        PrintScreen => "PSCRN",
+        ScrollLock => "SLCK",
+        Pause => "PAUSE_BREAK",
+        Insert => "INS",
+        Home => "HOME",
+        PageUp => "PG_UP",
+        Delete => "DEL",
+        End => "END",
+        PageDown => "PG_DN",
+        Right => "RIGHT",
+        Left => "LEFT",
+        Down => "DOWN",
+        Up => "UP",
+        LCtrl => "LCTRL",
+        LShift => "LSHFT",
+        LAlt => "LALT",
+        LGui => "LGUI",
+        RCtrl => "RCTRL",
+        RShift => "RSHFT",
+        RAlt => "RALT",
+        RGui => "RGUI",
+        // Letters and F-keys are named as they're displayed
+        kc => kc.display_name(),
+    };
+    format!("&kp {key}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ergodox_keymap::{COLS, ROWS};
+    use std::collections::HashSet;
+
+    #[test]
+    fn line_lengths_cover_the_layout() {
+        assert_eq!(
+            LINE_LENGTHS.iter().sum::<usize>(),
+            qmk::pretty_positions().len()
+        );
+    }
+
+    #[test]
+    fn every_keycode_has_its_own_binding() {
+        let bindings: HashSet<String> = Keycode::ALL.iter().map(|&kc| binding(kc)).collect();
+        // All but the breathing key and the Ctrl/Caps swap, which share `&none`
+        assert_eq!(bindings.len(), Keycode::ALL.len() - 2);
+        assert_eq!(binding(Keycode::BacklightBreathe), "&none");
+        assert_eq!(binding(Keycode::A), "&kp A");
+        assert_eq!(binding(Keycode::F12), "&kp F12");
+        assert_eq!(binding(Keycode::Layer2), "&mo 2");
+    }
+
+    #[test]
+    fn each_layer_lists_every_key() {
+        let mut layers = vec![[[Keycode::Trans; COLS]; ROWS]; 2];
+        layers[0][0][0] = Keycode::Equal;
+        let keymap = export(&layers);
+        assert_eq!(keymap.matches("bindings = <").count(), 2);
+        assert_eq!(keymap.matches("&trans").count(), 2 * 76 - 1);
+        // The top-left key comes first
+        let first = keymap.lines().find(|l| l.contains("&kp")).unwrap();
+        assert!(first.trim_start().starts_with("&kp EQUAL"));
+    }
+}
@@ -0,0 +1,30 @@
+//! Colemak letters over the rest of [`nordic_qwerty`], for an OS set to a
+//! Nordic layout. Only the letters and `ö` move; the other keys and the
+//! function layer stay where they are.
+
+use super::nordic_qwerty::{self, NordicQwerty};
+use super::*;
+
+pub struct Colemak;
+
+impl Keymap for Colemak {
+    const NAME: &'static str = "colemak";
+    const DESCRIPTION: &'static str = "Colemak letters on the Nordic QWERTY keymap";
+    const LAYERS: &'static [Layer] = &[BASE, nordic_qwerty::LAYERS[1]];
+    const COLORS: &'static [LayerColor] = NordicQwerty::COLORS;
+    const LABELS: &'static [KeyLabel] = NordicQwerty::LABELS;
+}
+
+/// Layer 0: the QWERTY base layer with the letter rows rearranged.
+const BASE: Layer = {
+    use Keycode::*;
+
+    let mut layer = nordic_qwerty::LAYERS[0];
+    // Row 1: top letter row
+    layer[1] = [TAB, Q, W, F, P, G, PGUP, ___, J, L, U, Y, ODIA, ___];
+    // Row 2: home row
+    layer[2] = [LCTL, A, R, S, T, D, LY1, ___, H, N, E, I, O, ADIA];
+    // Row 3: bottom row
+    layer[3] = [ANGB, Z, X, C, V, B, PGDN, ___, K, M, Comma, Dot, MINU, APST];
+    layer
+};
@@ -0,0 +1,92 @@
+//! The keymaps to pick from.
+//!
+//! Each one is a [`Keymap`] in a module of its own. The firmware and the CLI
+//! use the one a `keymap-*` Cargo feature picks, or [`nordic_qwerty`]
+//! without one:
+//!
+//! ```text
+//! make hex FEATURES=keymap-colemak
+//! ```
+//!
+//! A new keymap goes in a new module here, with a feature in this crate's,
+//! the firmware's and the CLI's `Cargo.toml` to pick it, rather than over
+//! someone else's.
+//!
+//! A layer that follows from another is best derived from it in a `const`
+//! with [`mirror`](crate::mirror) or [`overlay`](crate::overlay), so the two
+//! can't drift apart.
+
+use crate::{KeyLabel, Keycode, Layer, LayerColor, Rgb, COLS, ROWS};
+
+pub mod colemak;
+pub mod nordic_qwerty;
+
+/// The keymap this build uses, as selected by the `keymap-*` features.
+#[cfg(not(feature = "keymap-colemak"))]
+pub type Selected = nordic_qwerty::NordicQwerty;
+#[cfg(feature = "keymap-colemak")]
+pub type Selected = colemak::Colemak;
+
+/// A complete keymap: its layers and how to show them.
+pub trait Keymap {
+    /// Name, as in the feature that picks it.
+    const NAME: &'static str;
+    /// What it is, in a line.
+    const DESCRIPTION: &'static str;
+    /// The layers. Layer keys on layer 0 switch between them.
+    const LAYERS: &'static [Layer];
+    const NUM_LAYERS: usize = Self::LAYERS.len();
+    /// A color for each layer.
+    const COLORS: &'static [LayerColor];
+    /// Custom legends, as in [`crate::KEY_LABELS`].
+    const LABELS: &'static [KeyLabel];
+    /// The layer that's game mode while toggled on, if any; the status LED
+    /// double-blinks for it (see [`crate::status_led`]).
+    const GAME_LAYER: Option<usize> = None;
+}
+
+/// A keymap's slice as the array the crate-level statics hold. A keymap
+/// without a color for every layer fails here, at compile time.
+pub(crate) const fn to_array<T: Copy, const N: usize>(items: &[T]) -> [T; N] {
+    assert!(items.len() == N, "keymap lists the wrong number of entries");
+    let mut out = [items[0]; N];
+    let mut i = 1;
+    while i < N {
+        out[i] = items[i];
+        i += 1;
+    }
+    out
+}
+
+/// Key is unused in the matrix position.
+const ___: Keycode = Keycode::Trans;
+
+/// Shorthand aliases for readability.
+const ENT: Keycode = Keycode::Enter;
+const ESC: Keycode = Keycode::Escape;
+const BSP: Keycode = Keycode::Backspace;
+const TAB: Keycode = Keycode::Tab;
+const SPC: Keycode = Keycode::Space;
+const DEL: Keycode = Keycode::Delete;
+const LCTL: Keycode = Keycode::LCtrl;
+const LSFT: Keycode = Keycode::LShift;
+const LALT: Keycode = Keycode::LAlt;
+const LGUI: Keycode = Keycode::LGui;
+const RSFT: Keycode = Keycode::RShift;
+const RALT: Keycode = Keycode::RAlt;
+const PGUP: Keycode = Keycode::PageUp;
+const PGDN: Keycode = Keycode::PageDown;
+const LY1: Keycode = Keycode::Layer1;
+
+// Nordic layout shorthand aliases
+use crate::layout::nordic as Nordic;
+const PLSQ: Keycode = Nordic::PLUS_QUESTION;
+const ACGR: Keycode = Nordic::ACUTE_GRAVE;
+const ARING: Keycode = Nordic::A_RING;
+const DIAC: Keycode = Nordic::DIAERESIS_CARET;
+const APST: Keycode = Nordic::APOSTROPHE_STAR;
+const ODIA: Keycode = Nordic::O_DIAERESIS;
+const ADIA: Keycode = Nordic::A_DIAERESIS;
+const SECT: Keycode = Nordic::SECTION_HALF;
+const ANGB: Keycode = Nordic::ANGLE_BRACKETS;
+const MINU: Keycode = Nordic::MINUS_UNDERSCORE;
@@ -0,0 +1,214 @@
+//! QWERTY for an OS set to a Nordic layout, with function keys and arrows
+//! on a second layer.
+
+use super::*;
+
+pub struct NordicQwerty;
+
+impl Keymap for NordicQwerty {
+    const NAME: &'static str = "nordic-qwerty";
+    const DESCRIPTION: &'static str = "QWERTY with Nordic legends, F-keys and arrows on layer 1";
+    const LAYERS: &'static [Layer] = &LAYERS;
+    const COLORS: &'static [LayerColor] = &[
+        // Layer 0: QWERTY
+        LayerColor {
+            name: "red",
+            rgb: Rgb(0xE9, 0x45, 0x60),
+        },
+        // Layer 1: Function/Symbol
+        LayerColor {
+            name: "blue",
+            rgb: Rgb(0x3B, 0x8E, 0xEA),
+        },
+    ];
+    const LABELS: &'static [KeyLabel] = &[];
+}
+
+/// Number of layers.
+pub const NUM_LAYERS: usize = 2;
+
+/// Keymap layers.
+///
+/// Layer 0: Default QWERTY
+/// Layer 1: Function/Symbol layer
+pub const LAYERS: [[[Keycode; COLS]; ROWS]; NUM_LAYERS] = [
+    // Layer 0: QWERTY
+    [
+        // Row 0: number row
+        //  Left: §½, 1, 2, 3, 4, 5, ___       Right: +?, 6, 7, 8, 9, 0, +?
+        [
+            SECT,
+            Keycode::N1,
+            Keycode::N2,
+            Keycode::N3,
+            Keycode::N4,
+            Keycode::N5,
+            ___,
+            ___,
+            Keycode::N6,
+            Keycode::N7,
+            Keycode::N8,
+            Keycode::N9,
+            Keycode::N0,
+            PLSQ,
+        ],
+        // Row 1: top letter row
+        //  Left: Tab, Q, W, E, R, T, PgUp      Right: ¨^, Y, U, I, O, P, '*
+        [
+            TAB,
+            Keycode::Q,
+            Keycode::W,
+            Keycode::E,
+            Keycode::R,
+            Keycode::T,
+            PGUP,
+            ___,
+            Keycode::Y,
+            Keycode::U,
+            Keycode::I,
+            Keycode::O,
+            Keycode::P,
+            ___,
+        ],
+        // Row 2: home row
+        //  Left: LCtrl, A, S, D, F, G, LY1     Right: _unused, H, J, K, L, ö, ä
+        [
+            LCTL,
+            Keycode::A,
+            Keycode::S,
+            Keycode::D,
+            Keycode::F,
+            Keycode::G,
+            LY1, // ???
+            ___, // ???
+            Keycode::H,
+            Keycode::J,
+            Keycode::K,
+            Keycode::L,
+            ODIA,
+            ADIA,
+        ],
+        // Row 3: bottom row
+        //  Left: <>, Z, X, C, V, B, PgDn   Right: ___, N, M, ,, ., -_, '*
+        [
+            ANGB,
+            Keycode::Z,
+            Keycode::X,
+            Keycode::C,
+            Keycode::V,
+            Keycode::B,
+            PGDN,
+            ___,
+            Keycode::N,
+            Keycode::M,
+            Keycode::Comma,
+            Keycode::Dot,
+            MINU,
+            APST,
+        ],
+        // Row 4: thumb cluster top
+        //  Left: LY1, LAlt, LGui, LAlt, LGui, _unused, _unused
+        //  Right: _unused, _unused, Left, Down, Up, Right, LY1
+        [
+            LY1,
+            ___,
+            ___,
+            LALT,
+            LGUI, // Cmd/Win
+            ___, // ??
+            ___, // ??
+            ___, // ??
+            ___, // ??
+            Keycode::Left,
+            Keycode::Down,
+            Keycode::Up,
+            Keycode::Right,
+            ___,
+        ],
+        // Row 5: thumb cluster bottom
+        //  Left: Esc, _unused, Space, Enter, Home, End, _unused
+        //  Right: _unused, _unused, _unused, RShift, Bksp, _unused, _unused
+        [
+            Keycode::A,
+            ESC, // Esc
+            ENT, // Enter
+            SPC, // Space
+            ___, // Endin alla
+            Keycode::Home, // Home
+            Keycode::End, // End
+            ___, // oikeen puolen 'home'
+            DEL, // oikeen puolen 'end'
+            ___, // ylempi pieni
+            RSFT, // Shift
+            BSP, // Backspace
+            ___, // alempi pieni
+            Keycode::F,
+        ],
+    ],
+    // Layer 1: Function/Symbol
+    [
+        // Row 0
+        [
+            ___,
+            Keycode::F1,
+            Keycode::F2,
+            Keycode::F3,
+            Keycode::F4,
+            Keycode::F5,
+            ___,
+            ___,
+            Keycode::F6,
+            Keycode::F7,
+            Keycode::F8,
+            Keycode::F9,
+            Keycode::F10,
+            ___,
+        ],
+        // Row 1
+        [
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            Keycode::F11,
+            Keycode::F12,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+        ],
+        // Row 2
+        [
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            ___,
+            Keycode::Left,
+            Keycode::Down,
+            Keycode::Up,
+            Keycode::Right,
+            ___,
+            ___,
+        ],
+        // Row 3
+        [
+            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
+        ],
+        // Row 4
+        [
+            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
+        ],
+        // Row 5
+        [
+            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
+        ],
+    ],
+];
@@ -0,0 +1,215 @@
+//! Which layer is active, kept up to date key event by key event.
+//!
+//! Rather than re-reading the layer keys from the whole matrix every scan,
+//! a [`LayerState`] is told about each press and release and remembers
+//! what they did. That's what lets a layer outlast its key: a toggle keeps
+//! its layer on until it's pressed again, and a one-shot layer lasts for
+//! the next key typed after it.
+//!
+//! A layer key is read from the layer that's active when it's pressed
+//! (falling through transparent keys as usual), so a key on layer 1 can
+//! lead on to layer 2. Its release undoes whatever the press did, even if
+//! the layer has changed since. The highest active layer wins.
+
+use crate::{lookup_in, KeyEvent, Keycode, Layers};
+
+/// Layers a [`LayerState`] can track, which is every layer a keycode can
+/// name.
+pub const MAX_LAYERS: usize = 16;
+
+/// Layer keys that can be held at once. Presses past that are ignored.
+const MAX_HELD: usize = 8;
+
+/// What a layer key does to the active layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerAction {
+    /// The layer is on while the key is held.
+    Momentary(u8),
+    /// Each press turns the layer on or off.
+    Toggle(u8),
+    /// The layer is on while the key is held. Released without typing
+    /// anything, it stays on for the next key pressed.
+    OneShot(u8),
+}
+
+impl LayerAction {
+    /// What a keycode does to the layers, if it's a layer key. The keymap's
+    /// layer keys are momentary.
+    pub fn of(kc: Keycode) -> Option<LayerAction> {
+        kc.is_layer()
+            .then(|| LayerAction::Momentary(kc.layer_number() as u8))
+    }
+
+    /// The layer the action switches.
+    pub fn layer(self) -> u8 {
+        match self {
+            LayerAction::Momentary(layer)
+            | LayerAction::Toggle(layer)
+            | LayerAction::OneShot(layer) => layer,
+        }
+    }
+}
+
+/// A layer key that's down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Held {
+    row: u8,
+    col: u8,
+    action: LayerAction,
+}
+
+/// A one-shot layer after its key was released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OneShot {
+    /// Waiting for the next key.
+    Armed(u8),
+    /// Kept on until the key at `row`, `col` is released.
+    Used { layer: u8, row: u8, col: u8 },
+}
+
+impl OneShot {
+    /// Whether the key at `row`, `col` is the one carrying the layer.
+    fn used_by(self, row: u8, col: u8) -> bool {
+        matches!(self, OneShot::Used { row: r, col: c, .. } if (r, c) == (row, col))
+    }
+}
+
+/// The layers turned on by layer keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerState {
+    held: [Option<Held>; MAX_HELD],
+    /// One bit per layer.
+    toggled: u16,
+    one_shot: Option<OneShot>,
+    /// Whether a key was typed while a one-shot key was held, which makes
+    /// it act like a momentary one.
+    one_shot_interrupted: bool,
+}
+
+impl Default for LayerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayerState {
+    /// Only the base layer.
+    pub const fn new() -> Self {
+        Self {
+            held: [None; MAX_HELD],
+            toggled: 0,
+            one_shot: None,
+            one_shot_interrupted: false,
+        }
+    }
+
+    /// The highest active layer, or 0 if only the base layer is.
+    pub fn active(&self) -> usize {
+        let mask = self.mask();
+        if mask == 0 {
+            0
+        } else {
+            (u16::BITS - 1 - mask.leading_zeros()) as usize
+        }
+    }
+
+    /// The layers toggled on, one bit per layer: the ones that stay on
+    /// with no key held.
+    pub fn toggled(&self) -> u16 {
+        self.toggled
+    }
+
+    /// Whether `layer` is on, whether or not a higher one is too.
+    pub fn is_active(&self, layer: usize) -> bool {
+        layer == 0 || (layer < MAX_LAYERS && self.mask() & 1 << layer != 0)
+    }
+
+    /// Apply a key event, reading a pressed key's action from `layers` at
+    /// the layer active now. Layer keys for layers `layers` doesn't have
+    /// do nothing.
+    pub fn update<L, const R: usize, const C: usize>(&mut self, layers: &L, event: KeyEvent)
+    where
+        L: Layers<R, C> + ?Sized,
+    {
+        let (row, col) = (event.row, event.col);
+        if !event.pressed {
+            self.release(row, col);
+            return;
+        }
+        let kc = lookup_in(layers, self.active(), row as usize, col as usize);
+        match LayerAction::of(kc) {
+            Some(action) if (action.layer() as usize) < layers.count() => {
+                self.press(row, col, Some(action))
+            }
+            Some(_) => {}
+            None => self.press(row, col, None),
+        }
+    }
+
+    /// The key at `row`, `col` was pressed, doing `action` to the layers or
+    /// typing something if it's `None`.
+    pub fn press(&mut self, row: u8, col: u8, action: Option<LayerAction>) {
+        match action {
+            Some(action) if action.layer() as usize >= MAX_LAYERS => {}
+            Some(LayerAction::Toggle(layer)) => self.toggled ^= 1 << layer,
+            Some(action) => {
+                if let LayerAction::OneShot(_) = action {
+                    self.one_shot_interrupted = false;
+                }
+                if let Some(slot) = self.held.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(Held { row, col, action });
+                }
+            }
+            None => {
+                let one_shot_held = self
+                    .held
+                    .iter()
+                    .flatten()
+                    .any(|held| matches!(held.action, LayerAction::OneShot(_)));
+                if one_shot_held {
+                    self.one_shot_interrupted = true;
+                }
+                if let Some(OneShot::Armed(layer)) = self.one_shot {
+                    self.one_shot = Some(OneShot::Used { layer, row, col });
+                }
+            }
+        }
+    }
+
+    /// The key at `row`, `col` was released.
+    pub fn release(&mut self, row: u8, col: u8) {
+        if let Some(held) = self.take_held(row, col) {
+            if let LayerAction::OneShot(layer) = held.action {
+                if !self.one_shot_interrupted {
+                    self.one_shot = Some(OneShot::Armed(layer));
+                }
+            }
+        } else if self
+            .one_shot
+            .is_some_and(|one_shot| one_shot.used_by(row, col))
+        {
+            self.one_shot = None;
+        }
+    }
+
+    /// Forget the layer key at `row`, `col`, if it's held.
+    fn take_held(&mut self, row: u8, col: u8) -> Option<Held> {
+        self.held
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(held) if (held.row, held.col) == (row, col)))?
+            .take()
+    }
+
+    /// One bit per active layer above the base one.
+    fn mask(&self) -> u16 {
+        let mut mask = self.toggled;
+        for held in self.held.iter().flatten() {
+            mask |= 1 << held.action.layer();
+        }
+        match self.one_shot {
+            Some(OneShot::Armed(layer)) | Some(OneShot::Used { layer, .. }) => mask |= 1 << layer,
+            None => {}
+        }
+        mask & !1
+    }
+}
@@ -2,10 +2,25 @@
 //!
 //! This crate is `no_std`-compatible so it can be used by both the AVR
 //! firmware and the native CLI tool. Meow!
+//!
+//! The keymaps themselves live in [`keymaps`]; `LAYERS` is whichever one
+//! the build picked.
+//!
+//! `LAYERS` is the ErgoDox's 6×14 matrix, but the lookup functions are
+//! generic over the matrix size (the `*_in` variants), so the same code can
+//! drive a split with a different one.
 
 #![no_std]
 #![allow(dead_code)]
 
+pub mod keymaps;
+pub mod layer_state;
+pub mod status_led;
+pub mod typing;
+
+pub use keymaps::Keymap;
+pub use layer_state::{LayerAction, LayerState};
+
 /// Number of rows in the matrix.
 pub const ROWS: usize = 6;
 /// Number of columns per half.
@@ -18,6 +33,8 @@ pub const COLS: usize = COLS_PER_HALF * 2;
 /// HID keycodes are layout-agnostic — the OS interprets them based on the
 /// active input language. These aliases let you write keymaps using the
 /// labels printed on a Nordic keyboard instead of the US-centric HID names.
+/// `ergodox-cli xkb-aliases` writes a module like `nordic` for another
+/// layout from its XKB definition.
 pub mod layout {
     pub mod nordic {
         use super::super::Keycode;
@@ -155,12 +172,239 @@ pub enum Keycode {
     RAlt = 0xE6,
     RGui = 0xE7,
 
+    // Special: backlight control, handled by the firmware (not real HID
+    // keycodes)
+    BacklightToggle = 0xE8,
+    BacklightUp = 0xE9,
+    BacklightDown = 0xEA,
+    BacklightBreathe = 0xEB,
+
+    // Special: settings the firmware keeps, changed from the keyboard (not
+    // real HID keycodes)
+    /// Caps Lock and left Ctrl trade places, or stop trading them.
+    SwapCtrlCaps = 0xEC,
+
     // Special: layer momentary hold (not a real HID keycode)
     // Encoded as 0xF0 + layer number
     Layer1 = 0xF1,
+    Layer2 = 0xF2,
+    Layer3 = 0xF3,
+    Layer4 = 0xF4,
+    Layer5 = 0xF5,
+    Layer6 = 0xF6,
+    Layer7 = 0xF7,
+}
+
+/// What kind of key a keycode is, for grouping keycodes in palettes, checks
+/// and exports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    Letter,
+    Number,
+    /// Enter, Escape, Space and the other keys that act rather than type.
+    Control,
+    Punctuation,
+    Function,
+    /// Arrows, Home, End, Page Up and Page Down.
+    Navigation,
+    Modifier,
+    Layer,
+    /// Backlight on/off, brightness and breathing.
+    Backlight,
+    /// Keys that change a setting in the firmware.
+    Setting,
+    /// `Trans` and `None`.
+    Special,
+}
+
+impl Category {
+    /// Every category, in the order they're listed.
+    pub const ALL: &'static [Category] = &[
+        Category::Letter,
+        Category::Number,
+        Category::Control,
+        Category::Punctuation,
+        Category::Function,
+        Category::Navigation,
+        Category::Modifier,
+        Category::Layer,
+        Category::Backlight,
+        Category::Setting,
+        Category::Special,
+    ];
+
+    /// A heading for the category's keycodes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Letter => "Letters",
+            Category::Number => "Numbers",
+            Category::Control => "Control",
+            Category::Punctuation => "Punctuation",
+            Category::Function => "Function keys",
+            Category::Navigation => "Navigation",
+            Category::Modifier => "Modifiers",
+            Category::Layer => "Layers",
+            Category::Backlight => "Backlight",
+            Category::Setting => "Settings",
+            Category::Special => "Special",
+        }
+    }
 }
 
 impl Keycode {
+    /// Every keycode, in declaration order.
+    pub const ALL: &'static [Keycode] = &[
+        Keycode::Trans,
+        Keycode::None,
+        Keycode::A,
+        Keycode::B,
+        Keycode::C,
+        Keycode::D,
+        Keycode::E,
+        Keycode::F,
+        Keycode::G,
+        Keycode::H,
+        Keycode::I,
+        Keycode::J,
+        Keycode::K,
+        Keycode::L,
+        Keycode::M,
+        Keycode::N,
+        Keycode::O,
+        Keycode::P,
+        Keycode::Q,
+        Keycode::R,
+        Keycode::S,
+        Keycode::T,
+        Keycode::U,
+        Keycode::V,
+        Keycode::W,
+        Keycode::X,
+        Keycode::Y,
+        Keycode::Z,
+        Keycode::N1,
+        Keycode::N2,
+        Keycode::N3,
+        Keycode::N4,
+        Keycode::N5,
+        Keycode::N6,
+        Keycode::N7,
+        Keycode::N8,
+        Keycode::N9,
+        Keycode::N0,
+        Keycode::Enter,
+        Keycode::Escape,
+        Keycode::Backspace,
+        Keycode::Tab,
+        Keycode::Space,
+        Keycode::Minus,
+        Keycode::Equal,
+        Keycode::LBracket,
+        Keycode::RBracket,
+        Keycode::Backslash,
+        Keycode::Semicolon,
+        Keycode::Quote,
+        Keycode::Grave,
+        Keycode::Comma,
+        Keycode::Dot,
+        Keycode::Slash,
+        Keycode::CapsLock,
+        Keycode::NonUsBackslash,
+        Keycode::F1,
+        Keycode::F2,
+        Keycode::F3,
+        Keycode::F4,
+        Keycode::F5,
+        Keycode::F6,
+        Keycode::F7,
+        Keycode::F8,
+        Keycode::F9,
+        Keycode::F10,
+        Keycode::F11,
+        Keycode::F12,
+        Keycode::PrintScreen,
+        Keycode::ScrollLock,
+        Keycode::Pause,
+        Keycode::Insert,
+        Keycode::Home,
+        Keycode::PageUp,
+        Keycode::Delete,
+        Keycode::End,
+        Keycode::PageDown,
+        Keycode::Right,
+        Keycode::Left,
+        Keycode::Down,
+        Keycode::Up,
+        Keycode::LCtrl,
+        Keycode::LShift,
+        Keycode::LAlt,
+        Keycode::LGui,
+        Keycode::RCtrl,
+        Keycode::RShift,
+        Keycode::RAlt,
+        Keycode::RGui,
+        Keycode::BacklightToggle,
+        Keycode::BacklightUp,
+        Keycode::BacklightDown,
+        Keycode::BacklightBreathe,
+        Keycode::SwapCtrlCaps,
+        Keycode::Layer1,
+        Keycode::Layer2,
+        Keycode::Layer3,
+        Keycode::Layer4,
+        Keycode::Layer5,
+        Keycode::Layer6,
+        Keycode::Layer7,
+    ];
+
+    /// Every keycode, in declaration order.
+    pub fn iter_all() -> impl Iterator<Item = Keycode> {
+        Keycode::ALL.iter().copied()
+    }
+
+    /// Every keycode in `category`, in declaration order.
+    pub fn in_category(category: Category) -> impl Iterator<Item = Keycode> {
+        Keycode::iter_all().filter(move |kc| kc.category() == category)
+    }
+
+    /// Which kind of key this is.
+    pub fn category(self) -> Category {
+        use Keycode::*;
+
+        match self {
+            Trans | None => Category::Special,
+            SwapCtrlCaps => Category::Setting,
+            Enter | Escape | Backspace | Tab | Space | CapsLock => Category::Control,
+            PrintScreen | ScrollLock | Pause | Insert | Delete => Category::Control,
+            Minus | Equal | LBracket | RBracket | Backslash | Semicolon | Quote | Grave
+            | Comma | Dot | Slash | NonUsBackslash => Category::Punctuation,
+            Home | End | PageUp | PageDown | Right | Left | Down | Up => Category::Navigation,
+            kc if kc.is_modifier() => Category::Modifier,
+            kc if kc.is_layer() => Category::Layer,
+            kc if kc.is_backlight() => Category::Backlight,
+            kc => match kc as u8 {
+                0x04..=0x1D => Category::Letter,
+                0x1E..=0x27 => Category::Number,
+                _ => Category::Function,
+            },
+        }
+    }
+
+    /// Check if this is a letter, A to Z.
+    pub fn is_letter(self) -> bool {
+        self.category() == Category::Letter
+    }
+
+    /// Check if this is an arrow, Home, End, Page Up or Page Down.
+    pub fn is_navigation(self) -> bool {
+        self.category() == Category::Navigation
+    }
+
+    /// Check if this is one of F1 to F12.
+    pub fn is_function_key(self) -> bool {
+        self.category() == Category::Function
+    }
+
     /// Check if this keycode is a modifier (LCtrl..RGui).
     pub fn is_modifier(self) -> bool {
         let v = self as u8;
@@ -182,13 +426,46 @@ impl Keycode {
         (0xF0..=0xFF).contains(&v)
     }
 
+    /// Check if this is a backlight key.
+    pub fn is_backlight(self) -> bool {
+        let v = self as u8;
+        (0xE8..=0xEB).contains(&v)
+    }
+
+    /// Check if this is a key the firmware acts on instead of sending: the
+    /// backlight and setting keys.
+    pub fn is_firmware_key(self) -> bool {
+        let v = self as u8;
+        (0xE8..=0xEF).contains(&v)
+    }
+
     /// Get the target layer number for a layer key.
     pub fn layer_number(self) -> usize {
         (self as u8 - 0xF0) as usize
     }
 
+    /// The momentary layer key for layer `n`, if one exists.
+    pub fn layer(n: usize) -> Option<Keycode> {
+        Keycode::iter_all().find(|kc| kc.is_layer() && kc.layer_number() == n)
+    }
+
+    /// The keycode with this byte value, as the firmware stores it.
+    ///
+    /// The firmware calls this for every key lookup, so it's a range check
+    /// rather than a search of `ALL`, which would also cost AVR RAM.
+    pub const fn from_byte(code: u8) -> Option<Keycode> {
+        match code {
+            0x00 | 0x01 | 0x04..=0x31 | 0x33..=0x52 | 0x64 | 0xE0..=0xEC | 0xF1..=0xF7 => {
+                // SAFETY: Keycode is repr(u8), and these are exactly its
+                // discriminants (see `from_byte_knows_every_keycode`)
+                Some(unsafe { core::mem::transmute::<u8, Keycode>(code) })
+            }
+            _ => None,
+        }
+    }
+
     /// Check if this is a transparent key.
-    pub fn is_transparent(self) -> bool {
+    pub const fn is_transparent(self) -> bool {
         self as u8 == 0x00
     }
 
@@ -284,248 +561,393 @@ impl Keycode {
             Keycode::RShift => "RSft",
             Keycode::RAlt => "RAlt",
             Keycode::RGui => "RGui",
+            Keycode::BacklightToggle => "BL",
+            Keycode::BacklightUp => "BL+",
+            Keycode::BacklightDown => "BL-",
+            Keycode::BacklightBreathe => "BLbr",
+            Keycode::SwapCtrlCaps => "CtCp",
             Keycode::Layer1 => "Ly1",
+            Keycode::Layer2 => "Ly2",
+            Keycode::Layer3 => "Ly3",
+            Keycode::Layer4 => "Ly4",
+            Keycode::Layer5 => "Ly5",
+            Keycode::Layer6 => "Ly6",
+            Keycode::Layer7 => "Ly7",
         }
     }
 }
 
-/// Number of layers.
-pub const NUM_LAYERS: usize = 2;
-
-/// Key is unused in the matrix position.
-const ___: Keycode = Keycode::Trans;
-
-/// Shorthand aliases for readability.
-const ENT: Keycode = Keycode::Enter;
-const ESC: Keycode = Keycode::Escape;
-const BSP: Keycode = Keycode::Backspace;
-const TAB: Keycode = Keycode::Tab;
-const SPC: Keycode = Keycode::Space;
-const DEL: Keycode = Keycode::Delete;
-const LCTL: Keycode = Keycode::LCtrl;
-const LSFT: Keycode = Keycode::LShift;
-const LALT: Keycode = Keycode::LAlt;
-const LGUI: Keycode = Keycode::LGui;
-const RSFT: Keycode = Keycode::RShift;
-const RALT: Keycode = Keycode::RAlt;
-const PGUP: Keycode = Keycode::PageUp;
-const PGDN: Keycode = Keycode::PageDown;
-const LY1: Keycode = Keycode::Layer1;
-
-// Nordic layout shorthand aliases
-use layout::nordic as Nordic;
-const PLSQ: Keycode = Nordic::PLUS_QUESTION;
-const ACGR: Keycode = Nordic::ACUTE_GRAVE;
-const ARING: Keycode = Nordic::A_RING;
-const DIAC: Keycode = Nordic::DIAERESIS_CARET;
-const APST: Keycode = Nordic::APOSTROPHE_STAR;
-const ODIA: Keycode = Nordic::O_DIAERESIS;
-const ADIA: Keycode = Nordic::A_DIAERESIS;
-const SECT: Keycode = Nordic::SECTION_HALF;
-const ANGB: Keycode = Nordic::ANGLE_BRACKETS;
-const MINU: Keycode = Nordic::MINUS_UNDERSCORE;
-
-/// Keymap layers.
+/// Number of layers in the selected keymap.
+pub const NUM_LAYERS: usize = <keymaps::Selected as Keymap>::NUM_LAYERS;
+
+/// The selected keymap's game layer, if it has one (see [`status_led`]).
+pub const GAME_LAYER: Option<usize> = <keymaps::Selected as Keymap>::GAME_LAYER;
+
+/// The selected keymap's layers (see [`keymaps`]).
 /// Layout follows the ErgoDox physical matrix:
 ///   Row 0-5, Columns 0-6 = left half, Columns 7-13 = right half.
+pub static LAYERS: [[[Keycode; COLS]; ROWS]; NUM_LAYERS] =
+    keymaps::to_array(<keymaps::Selected as Keymap>::LAYERS);
+
+/// One layer: a keycode for every matrix position. The dimensions default
+/// to the ErgoDox's; other boards (a Dactyl, say) name their own, and the
+/// functions below work on any of them.
+pub type Layer<const R: usize = ROWS, const C: usize = COLS> = [[Keycode; C]; R];
+
+/// `layer` with the halves swapped, for a swap-hands layer: each key moves
+/// to the column mirroring its own. On the ErgoDox that's the same key on
+/// the other hand, thumb clusters included.
+pub const fn mirror<const R: usize, const C: usize>(layer: &Layer<R, C>) -> Layer<R, C> {
+    let mut out = *layer;
+    let mut row = 0;
+    while row < R {
+        let mut col = 0;
+        while col < C {
+            out[row][col] = layer[row][C - 1 - col];
+            col += 1;
+        }
+        row += 1;
+    }
+    out
+}
+
+/// `base` with every key of `patch` that isn't `Trans` laid over it, for a
+/// layer that differs from another in a few keys.
+pub const fn overlay<const R: usize, const C: usize>(
+    base: &Layer<R, C>,
+    patch: &Layer<R, C>,
+) -> Layer<R, C> {
+    let mut out = *base;
+    let mut row = 0;
+    while row < R {
+        let mut col = 0;
+        while col < C {
+            if !patch[row][col].is_transparent() {
+                out[row][col] = patch[row][col];
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+    out
+}
+
+/// Which keys are down: `true` at every matrix position whose switch is
+/// pressed. Sized like [`Layer`].
+pub type MatrixState<const R: usize = ROWS, const C: usize = COLS> = [[bool; C]; R];
+
+/// A key going down or coming back up.
 ///
-/// Layer 0: Default QWERTY
-/// Layer 1: Function/Symbol layer
-pub static LAYERS: [[[Keycode; COLS]; ROWS]; NUM_LAYERS] = [
-    // Layer 0: QWERTY
-    [
-        // Row 0: number row
-        //  Left: §½, 1, 2, 3, 4, 5, ___       Right: +?, 6, 7, 8, 9, 0, +?
-        [
-            SECT,
-            Keycode::N1,
-            Keycode::N2,
-            Keycode::N3,
-            Keycode::N4,
-            Keycode::N5,
-            ___,
-            ___,
-            Keycode::N6,
-            Keycode::N7,
-            Keycode::N8,
-            Keycode::N9,
-            Keycode::N0,
-            PLSQ,
-        ],
-        // Row 1: top letter row
-        //  Left: Tab, Q, W, E, R, T, PgUp      Right: ¨^, Y, U, I, O, P, '*
-        [
-            TAB,
-            Keycode::Q,
-            Keycode::W,
-            Keycode::E,
-            Keycode::R,
-            Keycode::T,
-            PGUP,
-            ___,
-            Keycode::Y,
-            Keycode::U,
-            Keycode::I,
-            Keycode::O,
-            Keycode::P,
-            ___,
-        ],
-        // Row 2: home row
-        //  Left: LCtrl, A, S, D, F, G, LY1     Right: _unused, H, J, K, L, ö, ä
-        [
-            LCTL,
-            Keycode::A,
-            Keycode::S,
-            Keycode::D,
-            Keycode::F,
-            Keycode::G,
-            LY1, // ???
-            ___, // ???
-            Keycode::H,
-            Keycode::J,
-            Keycode::K,
-            Keycode::L,
-            ODIA,
-            ADIA,
-        ],
-        // Row 3: bottom row
-        //  Left: <>, Z, X, C, V, B, PgDn   Right: ___, N, M, ,, ., -_, '*
-        [
-            ANGB,
-            Keycode::Z,
-            Keycode::X,
-            Keycode::C,
-            Keycode::V,
-            Keycode::B,
-            PGDN,
-            ___,
-            Keycode::N,
-            Keycode::M,
-            Keycode::Comma,
-            Keycode::Dot,
-            MINU,
-            APST,
-        ],
-        // Row 4: thumb cluster top
-        //  Left: LY1, LAlt, LGui, LAlt, LGui, _unused, _unused
-        //  Right: _unused, _unused, Left, Down, Up, Right, LY1
-        [
-            LY1,
-            ___,
-            ___,
-            LALT,
-            LGUI, // Cmd/Win
-            ___, // ??
-            ___, // ??
-            ___, // ??
-            ___, // ??
-            Keycode::Left,
-            Keycode::Down,
-            Keycode::Up,
-            Keycode::Right,
-            ___,
-        ],
-        // Row 5: thumb cluster bottom
-        //  Left: Esc, _unused, Space, Enter, Home, End, _unused
-        //  Right: _unused, _unused, _unused, RShift, Bksp, _unused, _unused
-        [
-            Keycode::A,
-            ESC, // Esc
-            ENT, // Enter
-            SPC, // Space
-            ___, // Endin alla
-            Keycode::Home, // Home
-            Keycode::End, // End
-            ___, // oikeen puolen 'home'
-            DEL, // oikeen puolen 'end'
-            ___, // ylempi pieni
-            RSFT, // Shift
-            BSP, // Backspace
-            ___, // alempi pieni
-            Keycode::F,
-        ],
-    ],
-    // Layer 1: Function/Symbol
-    [
-        // Row 0
-        [
-            ___,
-            Keycode::F1,
-            Keycode::F2,
-            Keycode::F3,
-            Keycode::F4,
-            Keycode::F5,
-            ___,
-            ___,
-            Keycode::F6,
-            Keycode::F7,
-            Keycode::F8,
-            Keycode::F9,
-            Keycode::F10,
-            ___,
-        ],
-        // Row 1
-        [
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            Keycode::F11,
-            Keycode::F12,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-        ],
-        // Row 2
-        [
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            ___,
-            Keycode::Left,
-            Keycode::Down,
-            Keycode::Up,
-            Keycode::Right,
-            ___,
-            ___,
-        ],
-        // Row 3
-        [
-            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
-        ],
-        // Row 4
-        [
-            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
-        ],
-        // Row 5
-        [
-            ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___, ___,
-        ],
-    ],
-];
+/// `timestamp` is in milliseconds on the clock of whoever saw it happen: the
+/// firmware counts scans since power-on (about a millisecond each), the CLI
+/// the time since it started listening.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+    pub timestamp: u32,
+}
+
+/// The keys that differ between two matrix states, as events stamped with
+/// `timestamp`, row by row.
+pub fn key_changes<'a, const R: usize, const C: usize>(
+    old: &'a MatrixState<R, C>,
+    new: &'a MatrixState<R, C>,
+    timestamp: u32,
+) -> impl Iterator<Item = KeyEvent> + 'a {
+    (0..R)
+        .flat_map(|row| (0..C).map(move |col| (row, col)))
+        .filter(move |&(row, col)| old[row][col] != new[row][col])
+        .map(move |(row, col)| KeyEvent {
+            row: row as u8,
+            col: col as u8,
+            pressed: new[row][col],
+            timestamp,
+        })
+}
+
+/// A color, 8 bits per channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+/// The color a layer is shown in, so that "blue = function layer" holds in
+/// the layout renderings and on any indicator that can show it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LayerColor {
+    pub name: &'static str,
+    pub rgb: Rgb,
+}
+
+/// Layer colors, indexed like `LAYERS`.
+pub static LAYER_COLORS: [LayerColor; NUM_LAYERS] =
+    keymaps::to_array(<keymaps::Selected as Keymap>::COLORS);
+
+/// A legend for one key, shown by the layout renderings instead of what
+/// its keycode would print.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyLabel {
+    pub layer: usize,
+    pub row: usize,
+    pub col: usize,
+    pub label: &'static str,
+}
+
+/// Custom legends, for keys whose keycode doesn't say what they're for:
+///
+/// ```text
+/// KeyLabel { layer: 1, row: 2, col: 3, label: "Copy" },
+/// ```
+///
+/// Each keymap lists its own. The firmware never reads them, so they cost
+/// no flash.
+pub static KEY_LABELS: &[KeyLabel] = <keymaps::Selected as Keymap>::LABELS;
+
+/// The custom label for a matrix position, if any. Transparent keys show
+/// the label of the key they fall through to, as [`lookup`] does.
+pub fn label(layer: usize, row: usize, col: usize) -> Option<&'static str> {
+    label_in(&LAYERS, KEY_LABELS, layer, row, col)
+}
+
+/// [`label`] over a given set of layers and labels.
+pub fn label_in<const R: usize, const C: usize>(
+    layers: &[Layer<R, C>],
+    labels: &[KeyLabel],
+    layer: usize,
+    row: usize,
+    col: usize,
+) -> Option<&'static str> {
+    let mut l = layer;
+    while l > 0 && layers[l][row][col].is_transparent() {
+        l -= 1;
+    }
+    labels
+        .iter()
+        .find(|k| (k.layer, k.row, k.col) == (l, row, col))
+        .map(|k| k.label)
+}
+
+/// Marks the start of the keymap table in a firmware image.
+pub const KEYMAP_START_MARKER: [u8; 8] = *b"EDXKMAP{";
+/// Marks the end of the keymap table in a firmware image.
+pub const KEYMAP_END_MARKER: [u8; 8] = *b"}EDXKMAP";
+
+/// Keys beyond layer 0 that the firmware's table has room for on top of the
+/// selected keymap's own, so `patch-keymap` can swap in a busier keymap.
+pub const SPARE_KEYS: usize = 32;
+
+/// Size of the firmware's [`KeymapTable`], markers included.
+pub const KEYMAP_TABLE_SIZE: usize = table_size(
+    ROWS * COLS,
+    upper_keys(<keymaps::Selected as Keymap>::LAYERS) + SPARE_KEYS,
+);
+
+/// The keymap as the firmware stores it, packed between two markers:
+///
+/// - the number of layers,
+/// - layer 0, one keycode byte per position,
+/// - every key on the other layers that isn't `Trans`, as `[position,
+///   layer, keycode]` (position is `row * C + col`), by position and then
+///   layer,
+/// - `0xFF` for the rest, at least one byte of it.
+///
+/// Upper layers are mostly transparent, so this is a fraction of a byte per
+/// position per layer. `ergodox-cli patch-keymap` finds the markers in a
+/// built image and packs another keymap in between, so changing the keymap
+/// doesn't need an AVR toolchain.
+#[repr(C)]
+pub struct KeymapTable<const SIZE: usize = KEYMAP_TABLE_SIZE> {
+    pub bytes: [u8; SIZE],
+}
+
+impl<const SIZE: usize> KeymapTable<SIZE> {
+    /// Pack `layers`. Panics, which in a static means it fails to compile,
+    /// if they don't fit.
+    pub const fn new<const R: usize, const C: usize>(layers: &[Layer<R, C>]) -> Self {
+        let mut bytes = [0xFF; SIZE];
+        let (start, rest) = bytes.split_at_mut(KEYMAP_START_MARKER.len());
+        let body_len = SIZE - KEYMAP_START_MARKER.len() - KEYMAP_END_MARKER.len();
+        let (body, end) = rest.split_at_mut(body_len);
+        start.copy_from_slice(&KEYMAP_START_MARKER);
+        end.copy_from_slice(&KEYMAP_END_MARKER);
+        assert!(pack(layers, body), "keymap doesn't fit its table");
+        Self { bytes }
+    }
+
+    /// The layers, read straight from the table.
+    pub fn layers<const R: usize, const C: usize>(
+        &self,
+    ) -> PackedLayers<impl Fn(usize) -> u8 + '_, R, C> {
+        PackedLayers::new(move |i| self.bytes[KEYMAP_START_MARKER.len() + i])
+    }
+}
+
+/// Size of a table for a matrix of `positions` keys, with room for
+/// `upper_keys` keys beyond layer 0, markers included.
+pub const fn table_size(positions: usize, upper_keys: usize) -> usize {
+    KEYMAP_START_MARKER.len() + 1 + positions + 3 * upper_keys + 1 + KEYMAP_END_MARKER.len()
+}
+
+/// How many keys beyond layer 0 aren't `Trans`: what [`pack`] stores of the
+/// upper layers.
+pub const fn upper_keys<const R: usize, const C: usize>(layers: &[Layer<R, C>]) -> usize {
+    let mut count = 0;
+    let mut layer = 1;
+    while layer < layers.len() {
+        let mut row = 0;
+        while row < R {
+            let mut col = 0;
+            while col < C {
+                if !layers[layer][row][col].is_transparent() {
+                    count += 1;
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        layer += 1;
+    }
+    count
+}
+
+/// Pack `layers` into `body`, the bytes between a [`KeymapTable`]'s
+/// markers. Returns false, leaving `body` alone, if they don't fit.
+pub const fn pack<const R: usize, const C: usize>(
+    layers: &[Layer<R, C>],
+    body: &mut [u8],
+) -> bool {
+    let positions = R * C;
+    assert!(!layers.is_empty() && layers.len() <= 0xFF && positions < 0xFF);
+    if body.len() < 1 + positions + 3 * upper_keys(layers) + 1 {
+        return false;
+    }
+
+    body[0] = layers.len() as u8;
+    let mut at = 1;
+    while at < body.len() {
+        body[at] = 0xFF;
+        at += 1;
+    }
+    at = 1;
+    let mut pos = 0;
+    while pos < positions {
+        body[at] = layers[0][pos / C][pos % C] as u8;
+        at += 1;
+        pos += 1;
+    }
+    pos = 0;
+    while pos < positions {
+        let mut layer = 1;
+        while layer < layers.len() {
+            let kc = layers[layer][pos / C][pos % C];
+            if !kc.is_transparent() {
+                body[at] = pos as u8;
+                body[at + 1] = layer as u8;
+                body[at + 2] = kc as u8;
+                at += 3;
+            }
+            layer += 1;
+        }
+        pos += 1;
+    }
+    true
+}
+
+/// Where the lookup functions read keycodes from: layers as they're
+/// written, or a packed [`KeymapTable`].
+pub trait Layers<const R: usize = ROWS, const C: usize = COLS> {
+    /// How many layers there are.
+    fn count(&self) -> usize;
+    /// The keycode at a position on exactly this layer, `Trans` and all.
+    fn key(&self, layer: usize, row: usize, col: usize) -> Keycode;
+}
+
+impl<const R: usize, const C: usize> Layers<R, C> for [Layer<R, C>] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn key(&self, layer: usize, row: usize, col: usize) -> Keycode {
+        self[layer][row][col]
+    }
+}
+
+impl<const L: usize, const R: usize, const C: usize> Layers<R, C> for [Layer<R, C>; L] {
+    fn count(&self) -> usize {
+        L
+    }
+
+    fn key(&self, layer: usize, row: usize, col: usize) -> Keycode {
+        self[layer][row][col]
+    }
+}
+
+/// A packed [`KeymapTable`] read a byte at a time: `read(i)` is the `i`th
+/// byte after the start marker. The firmware keeps its table in program
+/// memory, which the CPU can't index like RAM, and passes a reader for it.
+pub struct PackedLayers<F, const R: usize = ROWS, const C: usize = COLS> {
+    read: F,
+}
+
+impl<F: Fn(usize) -> u8, const R: usize, const C: usize> PackedLayers<F, R, C> {
+    pub const fn new(read: F) -> Self {
+        Self { read }
+    }
+}
+
+impl<F: Fn(usize) -> u8, const R: usize, const C: usize> Layers<R, C> for PackedLayers<F, R, C> {
+    fn count(&self) -> usize {
+        (self.read)(0) as usize
+    }
+
+    fn key(&self, layer: usize, row: usize, col: usize) -> Keycode {
+        let pos = row * C + col;
+        let code = if layer == 0 {
+            (self.read)(1 + pos)
+        } else {
+            // Entries are in position order, and the 0xFF after the last
+            // one is past every position
+            let mut at = 1 + R * C;
+            loop {
+                let entry = (self.read)(at) as usize;
+                if entry > pos {
+                    break Keycode::Trans as u8;
+                }
+                if entry == pos && (self.read)(at + 1) as usize == layer {
+                    break (self.read)(at + 2);
+                }
+                at += 3;
+            }
+        };
+        Keycode::from_byte(code).unwrap_or(Keycode::None)
+    }
+}
 
 /// Resolve which layer is active based on currently pressed keys.
 /// Layer keys are momentary: holding the key activates the layer.
-pub fn resolve_layer(keys: &[[bool; COLS]; ROWS]) -> usize {
+///
+/// This only sees one snapshot of the matrix, so it only reads layer keys
+/// on layer 0. The firmware follows key events with a [`LayerState`]
+/// instead, which can keep a layer on after its key is released.
+pub fn resolve_layer(keys: &MatrixState) -> usize {
+    resolve_layer_in(&LAYERS, keys)
+}
+
+/// [`resolve_layer`] over a given set of layers.
+pub fn resolve_layer_in<L: Layers<R, C> + ?Sized, const R: usize, const C: usize>(
+    layers: &L,
+    keys: &MatrixState<R, C>,
+) -> usize {
     // Check all keys for layer holds, highest layer wins
     let mut active_layer = 0usize;
 
-    for row in 0..ROWS {
-        for col in 0..COLS {
-            if keys[row][col] {
-                let kc = LAYERS[0][row][col]; // Layer keys are always on layer 0
+    for (row, pressed) in keys.iter().enumerate() {
+        for (col, &down) in pressed.iter().enumerate() {
+            if down {
+                let kc = layers.key(0, row, col); // Layer keys are always on layer 0
                 if kc.is_layer() {
                     let layer = kc.layer_number();
-                    if layer > active_layer && layer < NUM_LAYERS {
+                    if layer > active_layer && layer < layers.count() {
                         active_layer = layer;
                     }
                 }
@@ -539,10 +961,20 @@ pub fn resolve_layer(keys: &[[bool; COLS]; ROWS]) -> usize {
 /// Look up the keycode for a matrix position, resolving transparent keys
 /// through the layer stack.
 pub fn lookup(layer: usize, row: usize, col: usize) -> Keycode {
+    lookup_in(&LAYERS, layer, row, col)
+}
+
+/// [`lookup`] over a given set of layers.
+pub fn lookup_in<L: Layers<R, C> + ?Sized, const R: usize, const C: usize>(
+    layers: &L,
+    layer: usize,
+    row: usize,
+    col: usize,
+) -> Keycode {
     // Start at the active layer and fall through on Trans
     let mut l = layer;
     loop {
-        let kc = LAYERS[l][row][col];
+        let kc = layers.key(l, row, col);
         if !kc.is_transparent() || l == 0 {
             return kc;
         }
@@ -550,6 +982,119 @@ pub fn lookup(layer: usize, row: usize, col: usize) -> Keycode {
     }
 }
 
+/// `layers` with Caps Lock and left Ctrl trading places wherever either
+/// appears, if `swap` is set: the firmware's runtime Ctrl/Caps swap
+/// ([`Keycode::SwapCtrlCaps`]), applied to lookups rather than to the
+/// keymap, so no rebuild is needed.
+pub struct SwappedCtrlCaps<'a, L: ?Sized> {
+    pub layers: &'a L,
+    pub swap: bool,
+}
+
+impl<L: Layers<R, C> + ?Sized, const R: usize, const C: usize> Layers<R, C>
+    for SwappedCtrlCaps<'_, L>
+{
+    fn count(&self) -> usize {
+        self.layers.count()
+    }
+
+    fn key(&self, layer: usize, row: usize, col: usize) -> Keycode {
+        match self.layers.key(layer, row, col) {
+            Keycode::CapsLock if self.swap => Keycode::LCtrl,
+            Keycode::LCtrl if self.swap => Keycode::CapsLock,
+            kc => kc,
+        }
+    }
+}
+
+/// A keyboard report that [`build_report`] fills in. The firmware sends the
+/// 6-key [`KeyboardReport`]; another report layout (n-key rollover, say)
+/// only needs its own implementation.
+pub trait HidReport: Default {
+    /// Hold the modifiers in `bits`, laid out as [`Keycode::modifier_bit`].
+    fn add_modifiers(&mut self, bits: u8);
+    /// Add a pressed key. What happens to keys past what the report holds
+    /// is up to the report.
+    fn add_key(&mut self, kc: Keycode);
+}
+
+/// Standard USB HID keyboard report (8 bytes).
+/// Byte 0: modifier keys bitmask
+/// Byte 1: reserved (0x00)
+/// Bytes 2-7: up to 6 simultaneous keycodes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyboardReport {
+    pub modifiers: u8,
+    pub reserved: u8,
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// The usage every key slot holds when more keys are down than fit
+    /// (HID Usage Tables, Keyboard/Keypad page).
+    pub const ERROR_ROLL_OVER: u8 = 0x01;
+
+    pub const fn empty() -> Self {
+        Self {
+            modifiers: 0,
+            reserved: 0,
+            keys: [0; 6],
+        }
+    }
+}
+
+impl HidReport for KeyboardReport {
+    fn add_modifiers(&mut self, bits: u8) {
+        self.modifiers |= bits;
+    }
+
+    fn add_key(&mut self, kc: Keycode) {
+        // More than 6 keys: ErrorRollOver in every slot, as the HID spec
+        // asks, so the host keeps what it had rather than guessing which
+        // keys were dropped
+        match self.keys.iter_mut().find(|k| **k == 0) {
+            Some(slot) => *slot = kc as u8,
+            None => self.keys = [Self::ERROR_ROLL_OVER; 6],
+        }
+    }
+}
+
+/// Build a HID report from the current debounced key state and active layer.
+pub fn build_report<Rep, L, const R: usize, const C: usize>(
+    keys: &MatrixState<R, C>,
+    layers: &L,
+    layer: usize,
+) -> Rep
+where
+    Rep: HidReport,
+    L: Layers<R, C> + ?Sized,
+{
+    let mut report = Rep::default();
+
+    for (row, pressed) in keys.iter().enumerate() {
+        for (col, &down) in pressed.iter().enumerate() {
+            if !down {
+                continue; // Key not pressed
+            }
+
+            let kc = lookup_in(layers, layer, row, col);
+
+            // Skip transparent, none, layer and firmware keys
+            if kc.is_transparent() || kc.is_layer() || kc.is_firmware_key() || kc == Keycode::None {
+                continue;
+            }
+
+            if kc.is_modifier() {
+                report.add_modifiers(kc.modifier_bit());
+            } else {
+                report.add_key(kc);
+            }
+        }
+    }
+
+    report
+}
+
 // =============================================================================
 // Tests — literate contracts for the ErgoDox keymap
 // =============================================================================
@@ -593,6 +1138,58 @@ mod tests {
         }
     }
 
+    // =========================================================================
+    // Keycode table
+    // =========================================================================
+
+    #[test]
+    fn all_lists_each_keycode_once() {
+        // Tools iterate Keycode::ALL to map names and labels back to codes.
+        // A duplicate would make those reverse lookups ambiguous.
+        for (i, a) in Keycode::ALL.iter().enumerate() {
+            for b in &Keycode::ALL[i + 1..] {
+                assert_ne!(a, b, "{:?} listed twice", a);
+            }
+        }
+        assert!(Keycode::ALL.contains(&Keycode::Trans));
+        assert!(Keycode::ALL.contains(&Keycode::Layer1));
+    }
+
+    #[test]
+    fn from_byte_knows_every_keycode() {
+        // from_byte matches byte ranges, which have to be kept in step with
+        // the enum by hand
+        for code in 0..=u8::MAX {
+            let listed = Keycode::ALL.iter().copied().find(|&kc| kc as u8 == code);
+            assert_eq!(Keycode::from_byte(code), listed, "byte 0x{:02X}", code);
+        }
+    }
+
+    #[test]
+    fn categories_group_keycodes_as_the_enum_does() {
+        // Palettes list keycodes by category, so the counts are what a user
+        // sees: 26 letters, 10 digits, F1–F12, 8 modifiers, 7 layer keys.
+        let count = |c| Keycode::in_category(c).count();
+        assert_eq!(count(Category::Letter), 26);
+        assert_eq!(count(Category::Number), 10);
+        assert_eq!(count(Category::Function), 12);
+        assert_eq!(count(Category::Modifier), 8);
+        assert_eq!(count(Category::Layer), 7);
+        assert_eq!(count(Category::Navigation), 8);
+        assert_eq!(count(Category::Backlight), 4);
+        assert_eq!(count(Category::Setting), 1);
+        let total: usize = Category::ALL.iter().map(|&c| count(c)).sum();
+        assert_eq!(total, Keycode::ALL.len());
+
+        assert!(Keycode::Q.is_letter());
+        assert!(!Keycode::N1.is_letter());
+        assert!(Keycode::PageUp.is_navigation());
+        assert!(!Keycode::Delete.is_navigation());
+        assert!(Keycode::F12.is_function_key());
+        assert_eq!(Keycode::NonUsBackslash.category(), Category::Punctuation);
+        assert_eq!(Keycode::Trans.category(), Category::Special);
+    }
+
     // =========================================================================
     // Modifier encoding — USB HID modifier byte
     // =========================================================================
@@ -678,6 +1275,27 @@ mod tests {
         assert_eq!(Keycode::Layer1.layer_number(), 1);
     }
 
+    #[test]
+    fn layer_keys_cover_layers_one_through_seven() {
+        // MO(n) imports and multi-layer keymaps need a key per layer.
+        let layers = [
+            Keycode::Layer1,
+            Keycode::Layer2,
+            Keycode::Layer3,
+            Keycode::Layer4,
+            Keycode::Layer5,
+            Keycode::Layer6,
+            Keycode::Layer7,
+        ];
+        for (i, &kc) in layers.iter().enumerate() {
+            assert!(kc.is_layer());
+            assert_eq!(kc.layer_number(), i + 1);
+            assert_eq!(Keycode::layer(i + 1), Some(kc));
+        }
+        assert_eq!(Keycode::layer(0), None, "layer 0 is the base, not a key");
+        assert_eq!(Keycode::layer(8), None);
+    }
+
     #[test]
     fn trans_is_zero_and_transparent() {
         // 0x00 = "no event" in HID. We use it as "fall through to lower layer."
@@ -752,6 +1370,492 @@ mod tests {
         assert_eq!(lookup(1, 0, 1), Keycode::F1);
     }
 
+    #[test]
+    fn lookup_in_a_patched_table_ignores_layers() {
+        // The firmware looks keys up in its (patchable) KeymapTable rather
+        // than LAYERS. With only a base layer, layer keys do nothing.
+        let base = [LAYERS[0]];
+        let (row, col) = find_layer_key_position();
+        let mut keys = [[false; COLS]; ROWS];
+        keys[row][col] = true;
+        assert_eq!(resolve_layer_in(&base, &keys), 0);
+        assert_eq!(lookup_in(&base, 0, 1, 1), lookup(0, 1, 1));
+    }
+
+    #[test]
+    fn other_matrix_sizes_resolve_the_same_way() {
+        // A board with a 2×3 matrix uses the same lookup code as the ErgoDox.
+        use Keycode::*;
+        let layers: [Layer<2, 3>; 2] = [
+            [[A, B, Layer1], [D, E, F]],
+            [[Trans, F1, Trans], [Trans, Trans, Trans]],
+        ];
+        let mut keys = [[false; 3]; 2];
+        assert_eq!(resolve_layer_in(&layers, &keys), 0);
+        keys[0][2] = true;
+        assert_eq!(resolve_layer_in(&layers, &keys), 1);
+        assert_eq!(lookup_in(&layers, 1, 0, 1), F1);
+        assert_eq!(lookup_in(&layers, 1, 1, 2), F);
+    }
+
+    // =========================================================================
+    // Layer state
+    // =========================================================================
+    //
+    // The firmware follows key events with a LayerState rather than calling
+    // resolve_layer() on each scan. A layer key is read from the layer active
+    // when it's pressed, and its release undoes exactly what the press did,
+    // so layers can nest and outlast their keys.
+
+    fn nested_layers() -> [Layer<1, 4>; 3] {
+        use Keycode::*;
+        [
+            [[A, Layer1, B, C]],
+            [[F1, Trans, Layer2, Trans]],
+            [[F2, Trans, Trans, Trans]],
+        ]
+    }
+
+    fn event(col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent { row: 0, col, pressed, timestamp: 0 }
+    }
+
+    #[test]
+    fn layer_keys_nest_and_release_in_any_order() {
+        let layers = nested_layers();
+        let mut state = LayerState::new();
+        // Col 2 is B on the base layer, and leads on to layer 2 from layer 1
+        state.update(&layers, event(2, true));
+        state.update(&layers, event(2, false));
+        assert_eq!(state.active(), 0);
+
+        state.update(&layers, event(1, true));
+        state.update(&layers, event(2, true));
+        assert_eq!(state.active(), 2);
+        // Letting go of layer 1 first keeps layer 2 until its own key is up
+        state.update(&layers, event(1, false));
+        assert_eq!(state.active(), 2);
+        assert!(!state.is_active(1));
+        state.update(&layers, event(2, false));
+        assert_eq!(state.active(), 0);
+
+        // And the other way round, back down through layer 1
+        state.update(&layers, event(1, true));
+        state.update(&layers, event(2, true));
+        state.update(&layers, event(2, false));
+        assert_eq!(state.active(), 1);
+        state.update(&layers, event(1, false));
+        assert_eq!(state, LayerState::new());
+    }
+
+    #[test]
+    fn toggles_stay_on_until_pressed_again() {
+        let mut state = LayerState::new();
+        state.press(0, 0, Some(LayerAction::Toggle(1)));
+        state.release(0, 0);
+        assert_eq!(state.active(), 1);
+
+        // A momentary layer above it comes and goes on top
+        state.press(0, 1, Some(LayerAction::Momentary(2)));
+        assert_eq!(state.active(), 2);
+        state.release(0, 1);
+        assert_eq!(state.active(), 1);
+
+        state.press(0, 0, Some(LayerAction::Toggle(1)));
+        state.release(0, 0);
+        assert_eq!(state.active(), 0);
+    }
+
+    #[test]
+    fn one_shot_layers_last_for_the_next_key() {
+        let mut state = LayerState::new();
+        state.press(0, 0, Some(LayerAction::OneShot(1)));
+        state.release(0, 0);
+        assert_eq!(state.active(), 1);
+        // The next key is typed on layer 1 for as long as it's held
+        state.press(0, 1, None);
+        assert_eq!(state.active(), 1);
+        state.press(0, 2, None);
+        state.release(0, 2);
+        assert_eq!(state.active(), 1);
+        state.release(0, 1);
+        assert_eq!(state.active(), 0);
+
+        // Typing while the key is still down makes it act momentary
+        state.press(0, 0, Some(LayerAction::OneShot(1)));
+        state.press(0, 1, None);
+        state.release(0, 1);
+        assert_eq!(state.active(), 1);
+        state.release(0, 0);
+        assert_eq!(state.active(), 0);
+    }
+
+    #[test]
+    fn layer_keys_for_missing_layers_do_nothing() {
+        let layers = [nested_layers()[0]];
+        let mut state = LayerState::new();
+        state.update(&layers, event(1, true));
+        assert_eq!(state.active(), 0);
+        state.update(&layers, event(1, false));
+        assert_eq!(state, LayerState::new());
+    }
+
+    // =========================================================================
+    // Layer transforms
+    // =========================================================================
+    //
+    // `mirror` and `overlay` derive a layer from others at compile time, so
+    // the derived layer follows its source whenever that changes.
+
+    #[test]
+    fn mirror_swaps_the_halves() {
+        const MIRRORED: Layer = mirror(&LAYERS[0]);
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                assert_eq!(MIRRORED[row][col], LAYERS[0][row][COLS - 1 - col]);
+            }
+        }
+        assert_eq!(mirror(&MIRRORED), LAYERS[0]);
+    }
+
+    #[test]
+    fn overlay_keeps_the_base_under_transparent_keys() {
+        const PATCH: Layer = {
+            let mut patch = [[Keycode::Trans; COLS]; ROWS];
+            patch[2][3] = Keycode::F3;
+            patch[4][5] = Keycode::None;
+            patch
+        };
+        const LAYER: Layer = overlay(&LAYERS[0], &PATCH);
+        assert_eq!(LAYER[2][3], Keycode::F3);
+        assert_eq!(LAYER[4][5], Keycode::None);
+        let mut expected = LAYERS[0];
+        expected[2][3] = Keycode::F3;
+        expected[4][5] = Keycode::None;
+        assert_eq!(LAYER, expected);
+    }
+
+    // =========================================================================
+    // HID reports
+    // =========================================================================
+    //
+    // `build_report` turns the pressed keys into what the host sees. Every
+    // held modifier lands in the modifier byte, whatever else is down; other
+    // keys fill the six key slots in matrix order, and a seventh turns all
+    // six into ErrorRollOver.
+    // Layer keys, transparent keys with nothing below and unused positions
+    // send nothing.
+
+    /// A 1×8 board: layer 0 holds A–F, then G and H.
+    fn report_layers() -> [Layer<1, 8>; 2] {
+        use Keycode::*;
+        [[[A, B, C, D, E, F, G, H]], [[Trans; 8]]]
+    }
+
+    #[test]
+    fn report_rolls_over_past_six_keys() {
+        let six: MatrixState<1, 8> = [[true, true, true, true, true, true, false, false]];
+        let report: KeyboardReport = build_report(&six, &report_layers(), 0);
+        use Keycode::*;
+        assert_eq!(report.keys, [A, B, C, D, E, F].map(|kc| kc as u8));
+
+        let keys: MatrixState<1, 8> = [[true; 8]];
+        let report: KeyboardReport = build_report(&keys, &report_layers(), 0);
+        assert_eq!(report.keys, [KeyboardReport::ERROR_ROLL_OVER; 6]);
+    }
+
+    #[test]
+    fn report_merges_modifiers_even_when_full() {
+        let mut layers = report_layers();
+        layers[0][0][6] = Keycode::LShift;
+        layers[0][0][7] = Keycode::RCtrl;
+        let keys: MatrixState<1, 8> = [[true; 8]];
+        let report: KeyboardReport = build_report(&keys, &layers, 0);
+        assert_eq!(
+            report.modifiers,
+            Keycode::LShift.modifier_bit() | Keycode::RCtrl.modifier_bit()
+        );
+        assert!(report.keys.iter().all(|&k| k != 0));
+    }
+
+    #[test]
+    fn report_skips_layer_and_backlight_keys_and_falls_through_transparent_ones() {
+        let mut layers = report_layers();
+        layers[0][0][0] = Keycode::Layer1;
+        layers[0][0][1] = Keycode::None;
+        layers[1][0][2] = Keycode::F1;
+        layers[0][0][4] = Keycode::BacklightUp;
+        let keys: MatrixState<1, 8> = [[true, true, true, true, true, false, false, false]];
+        let report: KeyboardReport = build_report(&keys, &layers, 1);
+        assert_eq!(
+            report.keys,
+            [Keycode::F1 as u8, Keycode::D as u8, 0, 0, 0, 0]
+        );
+        assert_eq!(report.modifiers, 0);
+        let nothing: KeyboardReport = build_report(&[[false; 8]], &layers, 0);
+        assert_eq!(nothing, KeyboardReport::empty());
+    }
+
+    #[test]
+    fn ctrl_caps_swap_trades_the_two_keys_on_every_layer() {
+        let mut layers = report_layers();
+        layers[0][0][0] = Keycode::CapsLock;
+        layers[1][0][1] = Keycode::LCtrl;
+        let keys: MatrixState<1, 8> = [[true, true, false, false, false, false, false, false]];
+        let swapped = SwappedCtrlCaps { layers: &layers, swap: true };
+        let report: KeyboardReport = build_report(&keys, &swapped, 1);
+        assert_eq!(report.modifiers, Keycode::LCtrl.modifier_bit());
+        assert_eq!(report.keys, [Keycode::CapsLock as u8, 0, 0, 0, 0, 0]);
+
+        let unswapped = SwappedCtrlCaps { layers: &layers, swap: false };
+        assert_eq!(lookup_in(&unswapped, 1, 0, 0), Keycode::CapsLock);
+        assert_eq!(lookup_in(&swapped, 1, 0, 2), Keycode::C);
+    }
+
+    // =========================================================================
+    // Key events
+    // =========================================================================
+    //
+    // The firmware's debug queue, the monitor and the key tester all describe
+    // key changes as `KeyEvent`s. Only keys that changed produce one, and a
+    // key that went down is `pressed`.
+
+    #[test]
+    fn key_changes_lists_only_the_keys_that_changed() {
+        let old: MatrixState<2, 3> = [[true, false, false], [false, false, true]];
+        let new: MatrixState<2, 3> = [[true, true, false], [false, false, false]];
+        let mut events = key_changes(&old, &new, 42);
+        assert_eq!(
+            events.next(),
+            Some(KeyEvent { row: 0, col: 1, pressed: true, timestamp: 42 })
+        );
+        assert_eq!(
+            events.next(),
+            Some(KeyEvent { row: 1, col: 2, pressed: false, timestamp: 42 })
+        );
+        assert_eq!(events.next(), None);
+    }
+
+    #[test]
+    fn labels_fall_through_like_keys() {
+        let mut layers = LAYERS;
+        layers[1][2][3] = Keycode::Trans;
+        let labels = [KeyLabel {
+            layer: 0,
+            row: 2,
+            col: 3,
+            label: "Copy",
+        }];
+        assert_eq!(label_in(&layers, &labels, 0, 2, 3), Some("Copy"));
+        assert_eq!(label_in(&layers, &labels, 1, 2, 3), Some("Copy"));
+        layers[1][2][3] = Keycode::A;
+        assert_eq!(label_in(&layers, &labels, 1, 2, 3), None);
+        assert_eq!(label_in(&layers, &labels, 0, 2, 4), None);
+    }
+
+    #[test]
+    fn every_layer_has_its_own_color() {
+        for (i, a) in LAYER_COLORS.iter().enumerate() {
+            for b in &LAYER_COLORS[i + 1..] {
+                assert_ne!(a.rgb, b.rgb, "{} and {} look the same", a.name, b.name);
+                assert_ne!(a.name, b.name);
+            }
+        }
+    }
+
+    // =========================================================================
+    // Keymaps
+    // =========================================================================
+    //
+    // Every keymap in `keymaps` can be the one compiled in, so each must
+    // stand on its own: a color per layer, no layer key pointing past its
+    // last layer, and a game layer it has.
+
+    fn check_keymap<K: Keymap>() {
+        assert_eq!(K::COLORS.len(), K::NUM_LAYERS, "{} colors", K::NAME);
+        if let Some(layer) = K::GAME_LAYER {
+            assert!(layer < K::NUM_LAYERS, "{} game layer {}", K::NAME, layer);
+        }
+        for kc in K::LAYERS[0].iter().flatten() {
+            if kc.is_layer() {
+                assert!(kc.layer_number() < K::NUM_LAYERS, "{} {:?}", K::NAME, kc);
+            }
+        }
+    }
+
+    #[test]
+    fn every_keymap_is_complete() {
+        check_keymap::<keymaps::nordic_qwerty::NordicQwerty>();
+        check_keymap::<keymaps::colemak::Colemak>();
+    }
+
+    #[test]
+    fn colemak_moves_keys_without_losing_any() {
+        use keymaps::{colemak::Colemak, nordic_qwerty::NordicQwerty};
+        let sorted = |layer: &Layer| {
+            let mut keys: [u8; ROWS * COLS] = [0; ROWS * COLS];
+            for (i, &kc) in layer.iter().flatten().enumerate() {
+                keys[i] = kc as u8;
+            }
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(sorted(&Colemak::LAYERS[0]), sorted(&NordicQwerty::LAYERS[0]));
+        assert_ne!(Colemak::LAYERS[0], NordicQwerty::LAYERS[0]);
+        assert_eq!(Colemak::LAYERS[1], NordicQwerty::LAYERS[1]);
+    }
+
+    #[test]
+    fn the_selected_keymap_fills_the_statics() {
+        type Selected = keymaps::Selected;
+        assert_eq!(LAYERS[..], Selected::LAYERS[..]);
+        assert_eq!(LAYER_COLORS[..], Selected::COLORS[..]);
+    }
+
+    // =========================================================================
+    // Patchable keymap table
+    // =========================================================================
+    //
+    // `ergodox-cli patch-keymap` rewrites the keymap inside a built firmware
+    // image. It finds the table by its markers and packs the keymap in
+    // between: layer 0 byte for byte, then only the keys the upper layers
+    // don't leave transparent. Reading the table back must give every layer
+    // exactly as written.
+
+    #[test]
+    fn keymap_table_is_markers_around_packed_layers() {
+        // Packed at compile time, as the firmware's is
+        static TABLE: KeymapTable = KeymapTable::new(&LAYERS);
+        let table = &TABLE;
+        assert_eq!(
+            core::mem::size_of::<KeymapTable>(),
+            8 + 1 + ROWS * COLS + 3 * (upper_keys(&LAYERS) + SPARE_KEYS) + 1 + 8
+        );
+        assert_eq!(core::mem::size_of::<Keycode>(), 1);
+        assert_eq!(table.bytes[..8], KEYMAP_START_MARKER);
+        assert_eq!(table.bytes[KEYMAP_TABLE_SIZE - 8..], KEYMAP_END_MARKER);
+        assert_ne!(KEYMAP_START_MARKER, KEYMAP_END_MARKER);
+        assert_eq!(table.bytes[8] as usize, NUM_LAYERS);
+        assert_eq!(table.bytes[9 + COLS + 2], LAYERS[0][1][2] as u8);
+    }
+
+    #[test]
+    fn packed_layers_read_back_as_written() {
+        let mut layers = [LAYERS[0]; 3];
+        layers[1] = [[Keycode::Trans; COLS]; ROWS];
+        layers[1][0][0] = Keycode::F1;
+        layers[1][5][13] = Keycode::None;
+        layers[2] = [[Keycode::Trans; COLS]; ROWS];
+        layers[2][0][0] = Keycode::F2;
+        let table: KeymapTable<{ table_size(ROWS * COLS, 3) }> = KeymapTable::new(&layers);
+        let packed = table.layers();
+        assert_eq!(Layers::<ROWS, COLS>::count(&packed), 3);
+        for layer in 0..3 {
+            for row in 0..ROWS {
+                for col in 0..COLS {
+                    assert_eq!(packed.key(layer, row, col), layers[layer][row][col]);
+                    assert_eq!(
+                        lookup_in(&packed, layer, row, col),
+                        lookup_in(&layers, layer, row, col)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pack_refuses_a_keymap_that_does_not_fit() {
+        let mut layers = [[[Keycode::Trans; COLS]; ROWS]; 2];
+        layers[1][0][0] = Keycode::F1;
+        layers[1][0][1] = Keycode::F2;
+        let mut body = [0u8; 1 + ROWS * COLS + 3 + 1];
+        assert!(!pack(&layers, &mut body));
+        assert_eq!(body, [0u8; 1 + ROWS * COLS + 3 + 1]);
+        layers[1][0][1] = Keycode::Trans;
+        assert!(pack(&layers, &mut body));
+        assert_eq!(body[body.len() - 1], 0xFF);
+    }
+
+    // =========================================================================
+    // Typing text
+    // =========================================================================
+    //
+    // `typing::keystrokes` turns text into what to press, under the host's
+    // layout. The same character is a different key and different modifiers
+    // on a US and a Nordic host, and on a Nordic host accents are dead keys.
+
+    fn assert_types(text: &str, language: typing::Language, expected: &[(u8, Keycode)]) {
+        let mut strokes = typing::keystrokes(text, language);
+        for &(modifiers, key) in expected {
+            let stroke = typing::Keystroke { modifiers, key };
+            assert_eq!(strokes.next(), Some(Ok(stroke)), "typing {:?}", text);
+        }
+        assert_eq!(strokes.next(), None, "typing {:?}", text);
+    }
+
+    #[test]
+    fn typing_shifts_capitals_and_symbols_per_layout() {
+        use typing::Language::{Nordic, Us};
+        let shift = Keycode::LShift.modifier_bit();
+        let altgr = Keycode::RAlt.modifier_bit();
+        assert_types(
+            "Hi!",
+            Us,
+            &[(shift, Keycode::H), (0, Keycode::I), (shift, Keycode::N1)],
+        );
+        assert_types("@", Us, &[(shift, Keycode::N2)]);
+        assert_types("@", Nordic, &[(altgr, Keycode::N2)]);
+        assert_types("-_", Nordic, &[(0, Keycode::Slash), (shift, Keycode::Slash)]);
+        assert_types(" \n", Us, &[(0, Keycode::Space), (0, Keycode::Enter)]);
+    }
+
+    #[test]
+    fn typing_nordic_letters_and_dead_keys() {
+        use typing::Language::{Nordic, Us};
+        let shift = Keycode::LShift.modifier_bit();
+        assert_types(
+            "\u{e5}\u{c4}\u{f6}",
+            Nordic,
+            &[(0, Keycode::LBracket), (shift, Keycode::Quote), (0, Keycode::Semicolon)],
+        );
+        // A dead key on its own needs a space after it
+        assert_types("^", Nordic, &[(shift, Keycode::RBracket), (0, Keycode::Space)]);
+        assert_types("^", Us, &[(shift, Keycode::N6)]);
+        // Accented letters are the dead key, then the letter
+        assert_types("\u{e9}", Nordic, &[(0, Keycode::Equal), (0, Keycode::E)]);
+        assert_types("\u{dc}", Nordic, &[(0, Keycode::RBracket), (shift, Keycode::U)]);
+        let mut untypable = typing::keystrokes("\u{e5}", Us);
+        assert_eq!(untypable.next(), Some(Err('\u{e5}')));
+        assert_eq!(untypable.next(), None);
+    }
+
+    #[test]
+    fn keycodes_type_back_the_characters_they_were_picked_for() {
+        use typing::Language::{Nordic, Us};
+        let shift = Keycode::LShift.modifier_bit();
+        let altgr = Keycode::RAlt.modifier_bit();
+        for language in [Us, Nordic] {
+            for c in "aZ9!@\"'-_/\\<>|\u{e5}\u{c4}\u{a7}\u{20ac}\u{b4} \n".chars() {
+                let Some((stroke, None)) = typing::char_keystrokes(c, language) else {
+                    continue;
+                };
+                let typed = typing::to_char(
+                    stroke.key,
+                    stroke.modifiers & shift != 0,
+                    stroke.modifiers & altgr != 0,
+                    language,
+                );
+                assert_eq!(typed, Some(c), "{:?} under {:?}", stroke, language);
+            }
+        }
+        assert_eq!(typing::to_char(Keycode::Quote, true, false, Nordic), Some('\u{c4}'));
+        assert_eq!(typing::to_char(Keycode::Quote, true, false, Us), Some('"'));
+        assert_eq!(typing::to_char(Keycode::Equal, false, false, Nordic), Some('\u{b4}'));
+        assert_eq!(typing::to_char(Keycode::Up, false, false, Us), None);
+        assert_eq!(typing::to_char(Keycode::LShift, false, false, Nordic), None);
+        assert_eq!(typing::to_char(Keycode::N6, false, true, Nordic), None);
+    }
+
     // =========================================================================
     // Nordic aliases — layout-agnostic keycodes
     // =========================================================================
@@ -794,9 +1898,9 @@ mod tests {
 
     /// Find any Layer1 key position on layer 0.
     fn find_layer_key_position() -> (usize, usize) {
-        for row in 0..ROWS {
-            for col in 0..COLS {
-                if LAYERS[0][row][col] == Keycode::Layer1 {
+        for (row, keys) in LAYERS[0].iter().enumerate() {
+            for (col, &kc) in keys.iter().enumerate() {
+                if kc == Keycode::Layer1 {
                     return (row, col);
                 }
             }
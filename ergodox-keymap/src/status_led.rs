@@ -0,0 +1,128 @@
+//! What the Teensy's one LED shows, and how: the firmware gathers what it
+//! could report in a [`Status`] each main loop pass and plays the
+//! [`Pattern`] of the most important one.
+//!
+//! | status              | pattern                                   |
+//! |---------------------|-------------------------------------------|
+//! | left half offline   | a short flash every second                |
+//! | Caps Lock on        | solid                                     |
+//! | game mode           | two short blinks every second             |
+//! | a layer locked on   | slow blink, half a second each            |
+//! | nothing to report   | off                                       |
+//!
+//! Game mode is the keymap's [`GAME_LAYER`](crate::GAME_LAYER) toggled on.
+//! A layer that's only held doesn't count as locked: the key under the
+//! finger already says it's on.
+
+/// Loop passes in one cycle of a pattern, about a second.
+pub const PERIOD: u16 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Off,
+    On,
+    SlowBlink,
+    DoubleBlink,
+    Flash,
+}
+
+impl Pattern {
+    /// Whether the LED is lit `pass` passes into a cycle.
+    pub fn is_lit(self, pass: u16) -> bool {
+        match self {
+            Pattern::Off => false,
+            Pattern::On => true,
+            Pattern::SlowBlink => pass < PERIOD / 2,
+            Pattern::DoubleBlink => {
+                pass < PERIOD / 10 || (PERIOD / 5..PERIOD * 3 / 10).contains(&pass)
+            }
+            Pattern::Flash => pass < PERIOD / 10,
+        }
+    }
+}
+
+/// What the keyboard could show on the LED.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    pub left_half_offline: bool,
+    pub caps_lock: bool,
+    /// The game layer is toggled on.
+    pub game_mode: bool,
+    /// Some layer is toggled on, not just held.
+    pub locked: bool,
+}
+
+impl Status {
+    /// The pattern for the most important status.
+    pub fn pattern(&self) -> Pattern {
+        if self.left_half_offline {
+            Pattern::Flash
+        } else if self.caps_lock {
+            Pattern::On
+        } else if self.game_mode {
+            Pattern::DoubleBlink
+        } else if self.locked {
+            Pattern::SlowBlink
+        } else {
+            Pattern::Off
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_go_in_priority_order() {
+        let all = Status {
+            left_half_offline: true,
+            caps_lock: true,
+            game_mode: true,
+            locked: true,
+        };
+        // offline > caps > game > locked > off, dropping one at a time
+        assert_eq!(all.pattern(), Pattern::Flash);
+        let caps = Status {
+            left_half_offline: false,
+            ..all
+        };
+        assert_eq!(caps.pattern(), Pattern::On);
+        let game = Status {
+            caps_lock: false,
+            ..caps
+        };
+        assert_eq!(game.pattern(), Pattern::DoubleBlink);
+        let locked = Status {
+            game_mode: false,
+            ..game
+        };
+        assert_eq!(locked.pattern(), Pattern::SlowBlink);
+        assert_eq!(
+            Status {
+                locked: false,
+                ..locked
+            }
+            .pattern(),
+            Pattern::Off
+        );
+        assert_eq!(Status::default().pattern(), Pattern::Off);
+    }
+
+    #[test]
+    fn patterns_differ_in_how_often_they_light() {
+        // Separate lit stretches per cycle, and how long the LED is lit
+        let shape = |pattern: Pattern| {
+            let lit = |pass| pattern.is_lit(pass);
+            let stretches = (0..PERIOD)
+                .filter(|&pass| lit(pass) && (pass == 0 || !lit(pass - 1)))
+                .count();
+            (stretches, (0..PERIOD).filter(|&pass| lit(pass)).count())
+        };
+        assert_eq!(shape(Pattern::Off), (0, 0));
+        assert_eq!(shape(Pattern::On), (1, PERIOD as usize));
+        assert_eq!(shape(Pattern::SlowBlink), (1, PERIOD as usize / 2));
+        assert_eq!(shape(Pattern::DoubleBlink), (2, PERIOD as usize / 5));
+        assert_eq!(shape(Pattern::Flash), (1, PERIOD as usize / 10));
+    }
+}
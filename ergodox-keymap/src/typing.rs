@@ -0,0 +1,255 @@
+//! What keycodes type on the host, and the keystrokes that type a string.
+//!
+//! HID keycodes name key positions; the host's keyboard layout decides the
+//! character. This module holds both directions for the layouts the
+//! keymap is used with: the symbols each key types (for legends) and, from
+//! the same tables, the keystrokes that type a given text (for macros and
+//! anything that simulates typing).
+//!
+//! On the Nordic layout `´`, `` ` ``, `¨`, `^` and `~` are dead keys: they
+//! wait for the next key and put the accent on it. Typing one on its own
+//! takes a space after it, and accented letters like `é` or `ü` are the dead
+//! key followed by the letter.
+
+use crate::Keycode;
+
+/// The host keyboard layout, which decides what symbol each keycode types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    /// Swedish/Finnish, which the keymap is written for.
+    Nordic,
+    /// US English.
+    Us,
+}
+
+/// What a key types on its own, with Shift, and with AltGr. Empty strings
+/// are levels the key types nothing on.
+pub type Symbols = (&'static str, &'static str, &'static str);
+
+/// The symbols `kc` types when the host uses `language`. Keys without extra
+/// symbols just get `display_name()`.
+pub fn symbols(kc: Keycode, language: Language) -> Symbols {
+    match language {
+        Language::Nordic => nordic_symbols(kc),
+        Language::Us => us_symbols(kc),
+    }
+    .unwrap_or((kc.display_name(), "", ""))
+}
+
+/// (tap, shift, AltGr) for the keys of a Nordic board that print symbols.
+fn nordic_symbols(kc: Keycode) -> Option<Symbols> {
+    Some(match kc {
+        Keycode::N1 => ("1", "!", ""),
+        Keycode::N2 => ("2", "\"", "@"),
+        Keycode::N3 => ("3", "#", "\u{a3}"),
+        Keycode::N4 => ("4", "\u{a4}", "$"),
+        Keycode::N5 => ("5", "%", "\u{20ac}"),
+        Keycode::N6 => ("6", "&", ""),
+        Keycode::N7 => ("7", "/", "{"),
+        Keycode::N8 => ("8", "(", "["),
+        Keycode::N9 => ("9", ")", "]"),
+        Keycode::N0 => ("0", "=", "}"),
+        Keycode::E => ("E", "", "\u{20ac}"),
+        Keycode::M => ("M", "", "\u{b5}"),
+        Keycode::Minus => ("+", "?", "\\"),
+        Keycode::Equal => ("\u{b4}", "`", ""),
+        Keycode::RBracket => ("\u{a8}", "^", "~"),
+        Keycode::Backslash => ("'", "*", ""),
+        Keycode::Grave => ("\u{a7}", "\u{bd}", ""),
+        Keycode::NonUsBackslash => ("<", ">", "|"),
+        Keycode::Comma => (",", ";", ""),
+        Keycode::Dot => (".", ":", ""),
+        Keycode::Slash => ("-", "_", ""),
+        _ => return None,
+    })
+}
+
+/// (tap, shift, AltGr) for the keys of a US board that print symbols.
+fn us_symbols(kc: Keycode) -> Option<Symbols> {
+    Some(match kc {
+        Keycode::N1 => ("1", "!", ""),
+        Keycode::N2 => ("2", "@", ""),
+        Keycode::N3 => ("3", "#", ""),
+        Keycode::N4 => ("4", "$", ""),
+        Keycode::N5 => ("5", "%", ""),
+        Keycode::N6 => ("6", "^", ""),
+        Keycode::N7 => ("7", "&", ""),
+        Keycode::N8 => ("8", "*", ""),
+        Keycode::N9 => ("9", "(", ""),
+        Keycode::N0 => ("0", ")", ""),
+        Keycode::Minus => ("-", "_", ""),
+        Keycode::Equal => ("=", "+", ""),
+        Keycode::LBracket => ("[", "{", ""),
+        Keycode::RBracket => ("]", "}", ""),
+        Keycode::Backslash | Keycode::NonUsBackslash => ("\\", "|", ""),
+        Keycode::Semicolon => (";", ":", ""),
+        Keycode::Quote => ("'", "\"", ""),
+        Keycode::Grave => ("`", "~", ""),
+        Keycode::Comma => (",", "<", ""),
+        Keycode::Dot => (".", ">", ""),
+        Keycode::Slash => ("/", "?", ""),
+        _ => return None,
+    })
+}
+
+/// The character `kc` types under `language` with Shift and AltGr held or
+/// not, as a report with those modifiers would. None for keys that don't
+/// type one (modifiers, arrows, F-keys) and empty levels; Shift and AltGr
+/// together aren't in the tables either. A dead key gives its accent, which
+/// is what it types before a space.
+pub fn to_char(kc: Keycode, shift: bool, altgr: bool, language: Language) -> Option<char> {
+    match kc {
+        Keycode::Space => return Some(' '),
+        Keycode::Enter => return Some('\n'),
+        Keycode::Tab => return Some('\t'),
+        _ => {}
+    }
+    let table = match language {
+        Language::Nordic => nordic_symbols(kc),
+        Language::Us => us_symbols(kc),
+    };
+    // å, ö and ä, whose names are their letters
+    let nordic_letter = language == Language::Nordic
+        && matches!(kc, Keycode::LBracket | Keycode::Semicolon | Keycode::Quote);
+    let (tap, shifted, alt) = match table {
+        Some(symbols) => symbols,
+        None if kc.is_letter() || nordic_letter => (kc.display_name(), "", ""),
+        None => return None,
+    };
+    let only = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    match (shift, altgr) {
+        (true, true) => None,
+        (false, true) => only(alt),
+        _ => match only(tap).filter(|c| c.is_alphabetic()) {
+            Some(letter) if shift => letter.to_uppercase().next(),
+            Some(letter) => letter.to_lowercase().next(),
+            None if shift => only(shifted),
+            None => only(tap),
+        },
+    }
+}
+
+/// A key tapped with modifiers held, in the HID modifier byte's bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keystroke {
+    pub modifiers: u8,
+    pub key: Keycode,
+}
+
+impl Keystroke {
+    fn new(modifiers: u8, key: Keycode) -> Keystroke {
+        Keystroke { modifiers, key }
+    }
+}
+
+/// Nordic dead keys, which type nothing until the next key.
+const NORDIC_DEAD_KEYS: [char; 5] = ['\u{b4}', '`', '\u{a8}', '^', '~'];
+
+/// Accented letters the Nordic dead keys make: (letter, dead key, base).
+const NORDIC_COMPOSED: &[(char, char, char)] = &[
+    ('\u{e1}', '\u{b4}', 'a'),
+    ('\u{e9}', '\u{b4}', 'e'),
+    ('\u{ed}', '\u{b4}', 'i'),
+    ('\u{f3}', '\u{b4}', 'o'),
+    ('\u{fa}', '\u{b4}', 'u'),
+    ('\u{fd}', '\u{b4}', 'y'),
+    ('\u{e0}', '`', 'a'),
+    ('\u{e8}', '`', 'e'),
+    ('\u{ec}', '`', 'i'),
+    ('\u{f2}', '`', 'o'),
+    ('\u{f9}', '`', 'u'),
+    ('\u{eb}', '\u{a8}', 'e'),
+    ('\u{ef}', '\u{a8}', 'i'),
+    ('\u{fc}', '\u{a8}', 'u'),
+    ('\u{ff}', '\u{a8}', 'y'),
+    ('\u{e2}', '^', 'a'),
+    ('\u{ea}', '^', 'e'),
+    ('\u{ee}', '^', 'i'),
+    ('\u{f4}', '^', 'o'),
+    ('\u{fb}', '^', 'u'),
+    ('\u{e3}', '~', 'a'),
+    ('\u{f1}', '~', 'n'),
+    ('\u{f5}', '~', 'o'),
+];
+
+/// The keystrokes that type `c` under `language`: usually one, two where a
+/// dead key comes first. `None` if the layout has no way to type it.
+pub fn char_keystrokes(c: char, language: Language) -> Option<(Keystroke, Option<Keystroke>)> {
+    if let Some(key) = direct(c, language) {
+        let dead = language == Language::Nordic && NORDIC_DEAD_KEYS.contains(&c);
+        let space = Keystroke::new(0, Keycode::Space);
+        return Some((key, dead.then_some(space)));
+    }
+    if language != Language::Nordic {
+        return None;
+    }
+    let lower = c.to_lowercase().next()?;
+    let &(_, accent, base) = NORDIC_COMPOSED.iter().find(|&&(l, ..)| l == lower)?;
+    let base = if lower == c {
+        base
+    } else {
+        base.to_ascii_uppercase()
+    };
+    Some((direct(accent, language)?, Some(direct(base, language)?)))
+}
+
+/// The keystrokes that type `text` under `language`, character by
+/// character. A character the layout can't type comes out as an `Err`.
+pub fn keystrokes(
+    text: &str,
+    language: Language,
+) -> impl Iterator<Item = Result<Keystroke, char>> + '_ {
+    text.chars()
+        .flat_map(move |c| match char_keystrokes(c, language) {
+            Some((first, second)) => [Some(Ok(first)), second.map(Ok)],
+            None => [Some(Err(c)), None],
+        })
+        .flatten()
+}
+
+/// The one keystroke that types `c`, if there is one.
+fn direct(c: char, language: Language) -> Option<Keystroke> {
+    let shift = Keycode::LShift.modifier_bit();
+    let altgr = Keycode::RAlt.modifier_bit();
+    match c {
+        ' ' => return Some(Keystroke::new(0, Keycode::Space)),
+        '\n' => return Some(Keystroke::new(0, Keycode::Enter)),
+        '\t' => return Some(Keystroke::new(0, Keycode::Tab)),
+        _ => {}
+    }
+    Keycode::iter_all().find_map(|kc| {
+        let (tap, shifted, alt) = symbols(kc, language);
+        let mut tap_chars = tap.chars();
+        let only = |s: &str| {
+            let mut chars = s.chars();
+            chars.next() == Some(c) && chars.next().is_none()
+        };
+        match (tap_chars.next(), tap_chars.next()) {
+            // Letter keys are labelled with one letter and type it in
+            // lower case, capitals with Shift
+            (Some(letter), None) if letter.is_alphabetic() => {
+                if letter.to_lowercase().eq(core::iter::once(c)) {
+                    return Some(Keystroke::new(0, kc));
+                }
+                if letter.to_uppercase().eq(core::iter::once(c)) {
+                    return Some(Keystroke::new(shift, kc));
+                }
+            }
+            (Some(_), None) if only(tap) => return Some(Keystroke::new(0, kc)),
+            _ => {}
+        }
+        if only(shifted) {
+            Some(Keystroke::new(shift, kc))
+        } else if only(alt) {
+            Some(Keystroke::new(altgr, kc))
+        } else {
+            None
+        }
+    })
+}
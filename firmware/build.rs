@@ -0,0 +1,108 @@
+//! Stamp the firmware with the commit and date it was built from, for
+//! `ergodox-cli version` (see `src/build_info.rs`), and with its version in
+//! the USB descriptors (see `src/hid.rs`).
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rustc-env=ERGODOX_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=ERGODOX_BUILD_DATE={}", build_date());
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    // A commit moves the branch HEAD points at, not HEAD itself. A path that
+    // doesn't exist would rerun this every build, so only watch what's there.
+    let head = std::fs::read_to_string("../.git/HEAD").unwrap_or_default();
+    let branch = head.strip_prefix("ref: ").map(|r| format!("../.git/{}", r.trim()));
+    for path in branch.iter().map(String::as_str).chain(["../.git/packed-refs"]) {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("usb_version.rs");
+    std::fs::write(out, usb_version()).unwrap();
+}
+
+/// The USB descriptor parts that carry the crate version: bcdDevice and
+/// the product string, "Keyboard <version>".
+fn usb_version() -> String {
+    let version = std::env::var("CARGO_PKG_VERSION").unwrap();
+    let part = |name| std::env::var(name).unwrap().parse::<u16>().unwrap();
+    let (major, minor, patch) = (
+        part("CARGO_PKG_VERSION_MAJOR"),
+        part("CARGO_PKG_VERSION_MINOR"),
+        part("CARGO_PKG_VERSION_PATCH"),
+    );
+    if major > 99 || minor > 9 || patch > 9 {
+        println!("cargo:warning=version {version} doesn't fit bcdDevice's JJ.M.N; capping it");
+    }
+    let bcd = |value: u16| value / 10 % 10 << 4 | value % 10;
+    let bcd_device = bcd(major.min(99)) << 8 | minor.min(9) << 4 | patch.min(9);
+
+    let product = format!("Keyboard {version}");
+    let len = 2 + 2 * product.len();
+    let mut bytes = format!("{len}, 3,");
+    for b in product.bytes() {
+        write!(bytes, " b'{}', 0,", b as char).unwrap();
+    }
+    format!(
+        "/// bcdDevice: the crate version, {version}, as BCD.\n\
+         const BCD_DEVICE: u16 = {bcd_device:#06x};\n\n\
+         /// String descriptor 2 (product): \"{product}\"\n\
+         static STRING_DESC_2: [u8; {len}] = [{bytes}];\n"
+    )
+}
+
+/// Short commit hash, with `-dirty` for uncommitted changes, or `unknown`
+/// when building outside a git checkout (or without git installed).
+fn git_hash() -> String {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|out| out.status.success())
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    };
+    match git(&["rev-parse", "--short=8", "HEAD"]) {
+        Some(hash) if git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty()) => {
+            format!("{hash}-dirty")
+        }
+        Some(hash) => hash,
+        None => "unknown".to_string(),
+    }
+}
+
+/// Build date as YYYY-MM-DD (UTC), honouring SOURCE_DATE_EPOCH so
+/// reproducible builds stay reproducible.
+fn build_date() -> String {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    let (y, m, d) = civil_from_days((secs / 86_400) as i64);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's
+/// algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
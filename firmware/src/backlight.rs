@@ -0,0 +1,207 @@
+//! A single-color backlight on PC7, dimmed by timer 4's PWM, for boards with
+//! LEDs under the keys (`backlight` feature).
+//!
+//! PC7 is OC4A, and no wiring profile uses it. Timer 1 has output pins too,
+//! but it's the clock (`bench.rs`) and has to wrap at 16 bits, which as a
+//! PWM period would flicker four times a second. Timer 4 runs 8-bit fast PWM
+//! at 16 MHz / 64 / 256, about 980 Hz.
+//!
+//! The backlight keycodes toggle it, step its brightness through `LEVELS`
+//! levels, and turn breathing on and off: a fade from dark up to the
+//! brightness and back every `BREATHE_PERIOD_MS`.
+//!
+//! The settings are a byte and a check byte in EEPROM, written once they
+//! have stayed put for `SAVE_DELAY_MS`, so stepping through the levels
+//! costs one write rather than one per press. A write cut short, or erased
+//! EEPROM, fails the check and gives the defaults.
+
+use avr_device::atmega32u4::Peripherals;
+use ergodox_keymap::Keycode;
+
+use crate::eeprom::{self, BACKLIGHT_ADDR};
+
+const MASK: u8 = 1 << 7;
+
+/// TCCR4A: PWM on OC4A, cleared on compare match and set at BOTTOM.
+const PWM_A: u8 = 0x82;
+
+/// TCCR4B: clk/64.
+const CLK_DIV_64: u8 = 0x07;
+
+/// Brightness steps, 1 to `LEVELS`.
+const LEVELS: u8 = 5;
+
+/// Duty cycle out of 255 per level, spaced for the eye rather than evenly.
+const DUTY: [u8; LEVELS as usize] = [8, 24, 64, 128, 255];
+
+/// One breath, dark to bright and back.
+const BREATHE_PERIOD_MS: u32 = 4096;
+
+/// How long the settings must stay put before they're saved.
+const SAVE_DELAY_MS: u32 = 5000;
+
+/// Settings plus check byte.
+const IMAGE_SIZE: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Settings {
+    on: bool,
+    level: u8,
+    breathing: bool,
+}
+
+impl Settings {
+    const DEFAULT: Settings = Settings {
+        on: true,
+        level: 3,
+        breathing: false,
+    };
+
+    /// Bit 7 on, bit 6 breathing, the level below, then the check byte.
+    fn encode(self) -> [u8; IMAGE_SIZE] {
+        let byte = (self.on as u8) << 7 | (self.breathing as u8) << 6 | self.level;
+        [byte, check(byte)]
+    }
+
+    fn decode(bytes: [u8; IMAGE_SIZE]) -> Option<Settings> {
+        let [byte, check_byte] = bytes;
+        let level = byte & 0x3F;
+        (check_byte == check(byte) && (1..=LEVELS).contains(&level)).then_some(Settings {
+            on: byte & 0x80 != 0,
+            level,
+            breathing: byte & 0x40 != 0,
+        })
+    }
+}
+
+/// The check byte for a settings byte. Neither 0x00 nor 0xFF checks itself,
+/// so erased or zeroed EEPROM never reads as settings.
+fn check(byte: u8) -> u8 {
+    byte ^ 0x5A
+}
+
+pub struct Backlight {
+    settings: Settings,
+    /// The settings in EEPROM, or being written there.
+    saved: Settings,
+    /// When the settings last changed.
+    changed_at: u32,
+    /// What's being written, and the next byte of it.
+    image: [u8; IMAGE_SIZE],
+    next: usize,
+    /// Off after an unclean reset (see `reset.rs`).
+    writable: bool,
+    /// Dark while idling without a host (`power.rs`).
+    suspended: bool,
+}
+
+impl Backlight {
+    /// Load the saved settings and start timer 4 on PC7. With `writable`
+    /// false changes are never saved.
+    pub fn init(dp: &Peripherals, writable: bool) -> Self {
+        let mut bytes = [0u8; IMAGE_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = eeprom::read(dp, BACKLIGHT_ADDR + i as u16);
+        }
+        let settings = Settings::decode(bytes).unwrap_or(Settings::DEFAULT);
+
+        // Low while the PWM is disconnected
+        dp.PORTC.portc.modify(|r, w| unsafe { w.bits(r.bits() & !MASK) });
+        dp.PORTC.ddrc.modify(|r, w| unsafe { w.bits(r.bits() | MASK) });
+        // TOP of 255. TC4H holds the top two bits of every 10-bit write
+        dp.TC4.tc4h.write(|w| unsafe { w.bits(0) });
+        dp.TC4.ocr4c.write(|w| unsafe { w.bits(0xFF) });
+        // Fast PWM
+        dp.TC4.tccr4d.write(|w| unsafe { w.bits(0) });
+        dp.TC4.tccr4b.write(|w| unsafe { w.bits(CLK_DIV_64) });
+
+        Self {
+            settings,
+            saved: settings,
+            changed_at: 0,
+            image: [0; IMAGE_SIZE],
+            next: IMAGE_SIZE,
+            writable,
+            suspended: false,
+        }
+    }
+
+    /// Act on a pressed key, if it's a backlight key.
+    pub fn press(&mut self, kc: Keycode, now_ms: u32) {
+        let s = &mut self.settings;
+        match kc {
+            Keycode::BacklightToggle => s.on = !s.on,
+            Keycode::BacklightUp => {
+                s.on = true;
+                s.level = (s.level + 1).min(LEVELS);
+            }
+            Keycode::BacklightDown => s.level = (s.level - 1).max(1),
+            Keycode::BacklightBreathe => s.breathing = !s.breathing,
+            _ => return,
+        }
+        self.changed_at = now_ms;
+    }
+
+    /// Go dark while idling, and light up again after.
+    pub fn suspend(&mut self, dp: &Peripherals, suspended: bool) {
+        self.suspended = suspended;
+        if suspended {
+            set_duty(dp, 0);
+        }
+    }
+
+    /// Call once per scan: sets the brightness, starts saving the settings
+    /// when they've settled, and writes the next byte if EEPROM is ready.
+    pub fn tick(&mut self, dp: &Peripherals, now_ms: u32) {
+        set_duty(dp, self.duty(now_ms));
+
+        if !self.writable {
+            return;
+        }
+        if self.next == IMAGE_SIZE
+            && self.settings != self.saved
+            && now_ms.wrapping_sub(self.changed_at) >= SAVE_DELAY_MS
+        {
+            self.image = self.settings.encode();
+            self.next = 0;
+            self.saved = self.settings;
+        }
+        if self.next < IMAGE_SIZE && !eeprom::busy(dp) {
+            eeprom::write(dp, BACKLIGHT_ADDR + self.next as u16, self.image[self.next]);
+            self.next += 1;
+        }
+    }
+
+    fn duty(&self, now_ms: u32) -> u8 {
+        let s = self.settings;
+        if !s.on || self.suspended {
+            return 0;
+        }
+        let top = DUTY[s.level as usize - 1];
+        if !s.breathing {
+            return top;
+        }
+        // Squared, so the fade looks even rather than rushing the dark end
+        let half = BREATHE_PERIOD_MS / 2;
+        let phase = now_ms % BREATHE_PERIOD_MS;
+        let ramp = if phase < half {
+            phase
+        } else {
+            BREATHE_PERIOD_MS - phase
+        };
+        (top as u32 * ramp * ramp / (half * half)) as u8
+    }
+}
+
+/// Set the duty cycle out of 255; 0 is dark.
+fn set_duty(dp: &Peripherals, duty: u8) {
+    if duty == 0 {
+        // Fast PWM still lets a one-tick pulse through at a compare value
+        // of 0, so disconnect the pin and leave it low
+        dp.TC4.tccr4a.write(|w| unsafe { w.bits(0) });
+    } else {
+        dp.TC4.tc4h.write(|w| unsafe { w.bits(0) });
+        dp.TC4.ocr4a.write(|w| unsafe { w.bits(duty) });
+        dp.TC4.tccr4a.write(|w| unsafe { w.bits(PWM_A) });
+    }
+}
@@ -0,0 +1,164 @@
+//! Scan loop timing and key-to-report latency for `ergodox-cli bench`.
+//!
+//! Timer 1 free-runs at clk/64: one tick is 4 µs at 16 MHz, and the 16-bit
+//! counter wraps every 262 ms, far longer than any loop iteration. The main
+//! loop calls `loop_start` once per scan; the host reads the counters, which
+//! starts a new measurement window, with a vendor request (see `hid.rs`).
+//!
+//! For latency, the host asks for a synthetic press at one matrix position.
+//! It is ORed into the raw scan as if the switch had closed, so it goes
+//! through debounce and the keymap like a real press, and the time until the
+//! report carrying it goes out is recorded. Then the key is let go again.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::matrix::{MatrixState, COLS, ROWS};
+
+/// Microseconds per timer tick.
+pub const TICK_US: u8 = 4;
+
+/// Size of the stats reply in bytes.
+pub const STATS_SIZE: usize = 16;
+
+/// Scans to hold an injected press that never reaches a report (a
+/// transparent or layer key) before giving up on it.
+const INJECT_TIMEOUT_SCANS: u8 = 100;
+
+/// Latency reported when the injected press timed out.
+const NO_LATENCY: u16 = 0xFFFF;
+
+/// Start timer 1 counting at clk/64, with no interrupts.
+pub fn init_timer(dp: &Peripherals) {
+    dp.TC1.tccr1a.write(|w| unsafe { w.bits(0) });
+    dp.TC1.tccr1b.write(|w| unsafe { w.bits(0x03) });
+}
+
+/// Timer 1's count, in ticks.
+pub fn now(dp: &Peripherals) -> u16 {
+    dp.TC1.tcnt1.read().bits()
+}
+
+/// A synthetic press being held down.
+struct Injection {
+    row: u8,
+    col: u8,
+    start: u16,
+    scans: u8,
+}
+
+pub struct Bench {
+    /// Timer value at the start of the previous loop iteration.
+    last_loop: Option<u16>,
+    scans: u32,
+    ticks: u32,
+    min_ticks: u16,
+    max_ticks: u16,
+    /// Requested by the host, injected at the next scan.
+    pending: Option<(u8, u8)>,
+    injection: Option<Injection>,
+    /// Ticks from injection to report for the last injected press.
+    latency: u16,
+    /// Bumped for every finished injection, so the host can tell a new
+    /// latency from the previous one.
+    samples: u8,
+}
+
+impl Bench {
+    pub const fn new() -> Self {
+        Self {
+            last_loop: None,
+            scans: 0,
+            ticks: 0,
+            min_ticks: u16::MAX,
+            max_ticks: 0,
+            pending: None,
+            injection: None,
+            latency: NO_LATENCY,
+            samples: 0,
+        }
+    }
+
+    /// Call at the top of every main loop iteration to time the previous one.
+    pub fn loop_start(&mut self, dp: &Peripherals) {
+        let now = now(dp);
+        if let Some(last) = self.last_loop {
+            let elapsed = now.wrapping_sub(last);
+            self.scans = self.scans.saturating_add(1);
+            self.ticks = self.ticks.saturating_add(elapsed as u32);
+            self.min_ticks = core::cmp::min(self.min_ticks, elapsed);
+            self.max_ticks = core::cmp::max(self.max_ticks, elapsed);
+        }
+        self.last_loop = Some(now);
+    }
+
+    /// Queue a synthetic press at `row, col`. Returns false if there is no
+    /// such matrix position.
+    pub fn inject(&mut self, row: u8, col: u8) -> bool {
+        if row as usize >= ROWS || col as usize >= COLS {
+            return false;
+        }
+        self.pending = Some((row, col));
+        true
+    }
+
+    /// Hold the injected key down in a raw (active-low) scan.
+    pub fn apply(&mut self, dp: &Peripherals, raw: &mut MatrixState) {
+        if let Some((row, col)) = self.pending.take() {
+            if self.injection.is_none() {
+                self.injection = Some(Injection {
+                    row,
+                    col,
+                    start: now(dp),
+                    scans: 0,
+                });
+            }
+        }
+        let Some(injection) = &mut self.injection else {
+            return;
+        };
+        raw[injection.row as usize][injection.col as usize] = false;
+        injection.scans += 1;
+        if injection.scans > INJECT_TIMEOUT_SCANS {
+            self.finish(NO_LATENCY);
+        }
+    }
+
+    /// Call after a report went out. If it carries the injected press,
+    /// that's a latency sample, and the key is released.
+    pub fn report_sent(&mut self, dp: &Peripherals, debounced: &MatrixState) {
+        let Some(injection) = &self.injection else {
+            return;
+        };
+        if debounced[injection.row as usize][injection.col as usize] {
+            let latency = now(dp).wrapping_sub(injection.start);
+            self.finish(latency);
+        }
+    }
+
+    fn finish(&mut self, latency: u16) {
+        self.injection = None;
+        self.latency = latency;
+        self.samples = self.samples.wrapping_add(1);
+    }
+
+    /// Loop counters since the last call, and start a new window. Little
+    /// endian: scans (u32), total ticks (u32), min and max loop ticks
+    /// (u16 each), last latency in ticks (u16, 0xFFFF if none), latency
+    /// sample counter (u8), microseconds per tick (u8).
+    pub fn take_stats(&mut self) -> [u8; STATS_SIZE] {
+        let mut out = [0u8; STATS_SIZE];
+        out[0..4].copy_from_slice(&self.scans.to_le_bytes());
+        out[4..8].copy_from_slice(&self.ticks.to_le_bytes());
+        out[8..10].copy_from_slice(&self.min_ticks.to_le_bytes());
+        out[10..12].copy_from_slice(&self.max_ticks.to_le_bytes());
+        out[12..14].copy_from_slice(&self.latency.to_le_bytes());
+        out[14] = self.samples;
+        out[15] = TICK_US;
+
+        self.scans = 0;
+        self.ticks = 0;
+        self.min_ticks = u16::MAX;
+        self.max_ticks = 0;
+        out
+    }
+}
@@ -0,0 +1,64 @@
+//! What this firmware is, for `ergodox-cli version`.
+
+use crate::reset::ResetCause;
+
+/// `<version> <git hash> <build date>`, space-separated, e.g.
+/// `0.1.0 1a2b3c4d 2024-05-01`. The hash and date come from `build.rs`.
+pub const BUILD_INFO: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " ",
+    env!("ERGODOX_GIT_HASH"),
+    " ",
+    env!("ERGODOX_BUILD_DATE"),
+);
+
+/// A slot for the CRC32 of the whole image, which `ergodox-cli` finds by the
+/// marker and fills in before flashing. Until then it's erased flash.
+#[repr(C)]
+struct ImageCrc {
+    marker: [u8; 8],
+    crc: [u8; 4],
+}
+
+#[used]
+static IMAGE_CRC: ImageCrc = ImageCrc {
+    marker: *b"EDXCRC32",
+    crc: [0xFF; 4],
+};
+
+/// The CRC the CLI stamped into this image, if it did.
+///
+/// Read through a volatile pointer: the compiler would otherwise fold in the
+/// unstamped value.
+pub fn image_crc() -> Option<u32> {
+    let slot: *const ImageCrc = &IMAGE_CRC;
+    let crc = unsafe { (*core::ptr::read_volatile(&slot)).crc };
+    (crc != [0xFF; 4]).then(|| u32::from_le_bytes(crc))
+}
+
+/// The longest `reset=<cause>` field, with its leading space.
+const RESET_FIELD_MAX: usize = " reset=brown-out".len();
+
+/// The version reply: `BUILD_INFO`, then the stamped image CRC as eight hex
+/// digits if there is one, then `reset=<cause>` for the last reset (see
+/// `reset.rs`). Returns how much of `buf` was filled.
+pub fn reply(buf: &mut [u8], reset: ResetCause) -> usize {
+    let mut text = [0u8; BUILD_INFO.len() + 9 + RESET_FIELD_MAX];
+    text[..BUILD_INFO.len()].copy_from_slice(BUILD_INFO.as_bytes());
+    let mut len = BUILD_INFO.len();
+    if let Some(crc) = image_crc() {
+        text[len] = b' ';
+        for i in 0..8 {
+            let nibble = (crc >> (28 - 4 * i)) as u8 & 0xF;
+            text[len + 1 + i] = b"0123456789abcdef"[nibble as usize];
+        }
+        len += 9;
+    }
+    for part in [" reset=", reset.name()] {
+        text[len..len + part.len()].copy_from_slice(part.as_bytes());
+        len += part.len();
+    }
+    let n = core::cmp::min(len, buf.len());
+    buf[..n].copy_from_slice(&text[..n]);
+    n
+}
@@ -0,0 +1,45 @@
+//! A millisecond clock, for timing that shouldn't depend on how long a scan
+//! takes.
+//!
+//! There's no timer interrupt: the clock reads timer 1 (free-running at 4 µs
+//! a tick, see `bench.rs`) once per main loop pass and adds up what went by.
+//! That's right as long as no pass takes longer than the timer's 262 ms
+//! wrap, which even a left-half retry with nothing answering stays well
+//! under. A longer one loses time rather than gaining it.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::bench::{self, TICK_US};
+
+/// Timer 1 ticks per millisecond.
+const TICKS_PER_MS: u16 = 1000 / TICK_US as u16;
+
+pub struct Clock {
+    /// Timer value at the previous update.
+    last: u16,
+    /// Ticks since the last whole millisecond.
+    ticks: u16,
+    /// Milliseconds since `start`, wrapping after 49 days.
+    ms: u32,
+}
+
+impl Clock {
+    /// Start counting from now. Timer 1 must be running.
+    pub fn start(dp: &Peripherals) -> Self {
+        Self {
+            last: bench::now(dp),
+            ticks: 0,
+            ms: 0,
+        }
+    }
+
+    /// Catch up with timer 1 and return the time in milliseconds.
+    pub fn update(&mut self, dp: &Peripherals) -> u32 {
+        let now = bench::now(dp);
+        let ticks = self.ticks as u32 + now.wrapping_sub(self.last) as u32;
+        self.last = now;
+        self.ms = self.ms.wrapping_add(ticks / TICKS_PER_MS as u32);
+        self.ticks = (ticks % TICKS_PER_MS as u32) as u16;
+        self.ms
+    }
+}
@@ -0,0 +1,90 @@
+//! Press counters that survive a power cycle (`persist-counts` feature).
+//!
+//! The counts in `DebugLog` live in RAM. With this feature they're loaded
+//! from EEPROM at boot and written back every ten minutes, so a heatmap can
+//! cover weeks of typing. EEPROM is written one byte per scan, and only bytes
+//! that changed: a byte write takes 3.4 ms to complete, which the scan loop
+//! shouldn't wait for, and each cell is only good for 100,000 writes. With ten
+//! minutes between checkpoints, a key typed around the clock wears out its
+//! cell in about two years; real typing and every other byte take far longer.
+//!
+//! After a brown-out or watchdog reset the counts are still loaded, but
+//! nothing is written until the next clean start (see `reset.rs`).
+//!
+//! Layout: `MAGIC` at `COUNTS_ADDR` (see `eeprom.rs`), then the counts as
+//! `press_counts` encodes them. A checkpoint cut short by unplugging leaves a
+//! mix of old and new counts, which is still a fair heatmap.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::debug::DebugLog;
+use crate::eeprom::{self, COUNTS_ADDR};
+use crate::matrix::{COLS, ROWS};
+
+/// Marks EEPROM as holding our counts; erased EEPROM reads 0xFF.
+const MAGIC: [u8; 2] = [0xC0, 0x01];
+
+/// Bytes in a checkpoint, magic included.
+const SIZE: usize = MAGIC.len() + ROWS * COLS * 2;
+
+/// Scans between checkpoints, about ten minutes at a millisecond each.
+const CHECKPOINT_SCANS: u32 = 600_000;
+
+pub struct CountStore {
+    /// What's being written, and the next byte of it.
+    image: [u8; SIZE],
+    next: usize,
+    scans: u32,
+    /// Off after an unclean reset.
+    writable: bool,
+}
+
+impl CountStore {
+    /// Restore the counts from the last checkpoint, if there is one. With
+    /// `writable` false no checkpoints are written.
+    pub fn load(dp: &Peripherals, debug: &mut DebugLog, writable: bool) -> Self {
+        let mut image = [0u8; SIZE];
+        for (addr, byte) in image.iter_mut().enumerate() {
+            *byte = eeprom::read(dp, COUNTS_ADDR + addr as u16);
+        }
+        if image[..MAGIC.len()] == MAGIC {
+            debug.restore_press_counts(image[MAGIC.len()..].try_into().unwrap());
+        }
+        Self {
+            image,
+            next: SIZE,
+            scans: 0,
+            writable,
+        }
+    }
+
+    /// Call once per scan: starts a checkpoint when one is due, and writes
+    /// the next byte of it if EEPROM is ready.
+    pub fn tick(&mut self, dp: &Peripherals, debug: &mut DebugLog) {
+        if !self.writable {
+            return;
+        }
+        self.scans = self.scans.saturating_add(1);
+        // Clearing is written straight away, so it can't come back at the
+        // next power-on
+        let cleared = debug.take_cleared();
+        if self.next == SIZE && (cleared || self.scans >= CHECKPOINT_SCANS) {
+            self.image[..MAGIC.len()].copy_from_slice(&MAGIC);
+            self.image[MAGIC.len()..].copy_from_slice(&debug.press_counts());
+            self.next = 0;
+            self.scans = 0;
+        } else if cleared {
+            // Mid-checkpoint: the next one starts right after
+            self.scans = CHECKPOINT_SCANS;
+        }
+        while self.next < SIZE && !eeprom::busy(dp) {
+            let addr = COUNTS_ADDR + self.next as u16;
+            let byte = self.image[self.next];
+            self.next += 1;
+            if eeprom::read(dp, addr) != byte {
+                eeprom::write(dp, addr, byte);
+                return;
+            }
+        }
+    }
+}
@@ -1,48 +1,118 @@
 //! Per-key debounce logic.
 //!
-//! Each key has a counter that must reach DEBOUNCE_THRESHOLD consecutive
-//! consistent readings before the debounced state changes. This prevents
-//! false triggers from contact bounce.
+//! Debouncing goes by the millisecond clock (`clock.rs`), not by counting
+//! scans, so a scan stretched by I2C retries or USB work doesn't shorten or
+//! lengthen it. Two algorithms, picked at build time with a cargo feature:
+//!
+//! - By default a key's raw reading must differ from its debounced state
+//!   for DEBOUNCE_MS, from the first reading that did to one at least that
+//!   much later, before the debounced state changes. This prevents false
+//!   triggers from contact bounce.
+//! - With `debounce-integrator`, each key has an integrator that counts up
+//!   the time it reads pressed and down the time it reads released, between
+//!   0 and INTEGRATOR_MAX_MS. Only time between two readings that agree
+//!   counts. The key goes down when it climbs to PRESS_AT_MS and up when it
+//!   falls to RELEASE_AT_MS. A stray reading only costs the key a scan or
+//!   two rather than starting it over, so noise on a long TRRS cable delays
+//!   a press instead of losing it, and the gap between the two thresholds
+//!   keeps a noisy key from flickering at either one.
+//!
+//! Either way a single reading, however long the scan after it took, never
+//! changes a key by itself. The matrix size defaults to the ErgoDox's, but
+//! nothing here depends on it.
+//!
+//! A raw reading that flips and then flips back before reaching the
+//! threshold is a bounce the debouncer rejected. Those are counted per key
+//! for `ergodox-cli chatter`: a switch that bounces on more and more of its
+//! presses is wearing out, and one that bounces past the threshold types
+//! double. The integrator counts one when a key that started to move
+//! settles back where it was.
 
 use crate::matrix::{COLS, ROWS};
 
-/// Number of consistent scan cycles required to register a state change.
-/// At ~1ms scan rate, this gives ~5ms debounce time.
-const DEBOUNCE_THRESHOLD: u8 = 5;
+/// How long a key must read differently before its state changes.
+#[cfg(not(feature = "debounce-integrator"))]
+const DEBOUNCE_MS: u8 = 5;
 
-pub struct Debouncer {
+/// Where the integrator saturates: a key held this long is fully down.
+#[cfg(feature = "debounce-integrator")]
+const INTEGRATOR_MAX_MS: u8 = 7;
+/// Integrator value at which a released key goes down, 5 ms of clean
+/// readings from rest like the default.
+#[cfg(feature = "debounce-integrator")]
+const PRESS_AT_MS: u8 = 5;
+/// Integrator value at which a pressed key comes back up, 5 ms of clean
+/// readings from fully down.
+#[cfg(feature = "debounce-integrator")]
+const RELEASE_AT_MS: u8 = 2;
+
+pub struct Debouncer<const R: usize = ROWS, const C: usize = COLS> {
     /// Debounced key states: false = released, true = pressed.
-    state: [[bool; COLS]; ROWS],
-    /// Per-key counters tracking consecutive raw readings that differ from debounced state.
-    counters: [[u8; COLS]; ROWS],
+    state: [[bool; C]; R],
+    /// When each key's raw reading started to differ from its debounced
+    /// state, in milliseconds (the low byte is plenty to time DEBOUNCE_MS).
+    #[cfg(not(feature = "debounce-integrator"))]
+    since: [[Option<u8>; C]; R],
+    /// Per-key integrators, in milliseconds.
+    #[cfg(feature = "debounce-integrator")]
+    levels: [[u8; C]; R],
+    /// Each key's previous raw reading, true = pressed.
+    #[cfg(feature = "debounce-integrator")]
+    last_raw: [[bool; C]; R],
+    /// Whether the integrator has moved away from where the debounced
+    /// state rests since the state last changed.
+    #[cfg(feature = "debounce-integrator")]
+    unsettled: [[bool; C]; R],
+    /// Time of the previous update, in milliseconds.
+    #[cfg(feature = "debounce-integrator")]
+    last_update: u32,
+    /// Rejected bounces per key since power-on, saturating.
+    bounces: [[u16; C]; R],
 }
 
-impl Debouncer {
+impl<const R: usize, const C: usize> Debouncer<R, C> {
     pub const fn new() -> Self {
         Self {
-            state: [[false; COLS]; ROWS],
-            counters: [[0; COLS]; ROWS],
+            state: [[false; C]; R],
+            #[cfg(not(feature = "debounce-integrator"))]
+            since: [[None; C]; R],
+            #[cfg(feature = "debounce-integrator")]
+            levels: [[0; C]; R],
+            #[cfg(feature = "debounce-integrator")]
+            last_raw: [[false; C]; R],
+            #[cfg(feature = "debounce-integrator")]
+            unsettled: [[false; C]; R],
+            #[cfg(feature = "debounce-integrator")]
+            last_update: 0,
+            bounces: [[0; C]; R],
         }
     }
 
-    /// Update the debouncer with a new raw matrix scan.
+    /// Update the debouncer with a new raw matrix scan taken at `now_ms`
+    /// on the millisecond clock.
     /// `raw_state[row][col]`: true = not pressed (active low convention from matrix scan).
     /// Returns the debounced state where true = key is pressed.
-    pub fn update(&mut self, raw_state: &[[bool; COLS]; ROWS]) -> &[[bool; COLS]; ROWS] {
-        for row in 0..ROWS {
-            for col in 0..COLS {
+    #[cfg(not(feature = "debounce-integrator"))]
+    pub fn update(&mut self, raw_state: &[[bool; C]; R], now_ms: u32) -> &[[bool; C]; R] {
+        let now = now_ms as u8;
+        for row in 0..R {
+            for col in 0..C {
                 // Convert from active-low (true=released) to logical (true=pressed)
                 let pressed = !raw_state[row][col];
 
                 if pressed == self.state[row][col] {
-                    // Raw matches debounced state, reset counter
-                    self.counters[row][col] = 0;
+                    // Raw matches debounced state again: if it had just
+                    // changed, that was a bounce.
+                    if self.since[row][col].take().is_some() {
+                        self.bounces[row][col] = self.bounces[row][col].saturating_add(1);
+                    }
                 } else {
-                    // Raw differs from debounced state, increment counter
-                    self.counters[row][col] += 1;
-                    if self.counters[row][col] >= DEBOUNCE_THRESHOLD {
+                    // Raw differs from debounced state: change it once it
+                    // has for long enough
+                    let since = *self.since[row][col].get_or_insert(now);
+                    if now.wrapping_sub(since) >= DEBOUNCE_MS {
                         self.state[row][col] = pressed;
-                        self.counters[row][col] = 0;
+                        self.since[row][col] = None;
                     }
                 }
             }
@@ -50,4 +120,60 @@ impl Debouncer {
 
         &self.state
     }
+
+    /// Update the debouncer with a new raw matrix scan taken at `now_ms`
+    /// on the millisecond clock.
+    /// `raw_state[row][col]`: true = not pressed (active low convention from matrix scan).
+    /// Returns the debounced state where true = key is pressed.
+    #[cfg(feature = "debounce-integrator")]
+    pub fn update(&mut self, raw_state: &[[bool; C]; R], now_ms: u32) -> &[[bool; C]; R] {
+        let elapsed = now_ms.wrapping_sub(self.last_update).min(INTEGRATOR_MAX_MS as u32) as u8;
+        self.last_update = now_ms;
+        for row in 0..R {
+            for col in 0..C {
+                // Convert from active-low (true=released) to logical (true=pressed)
+                let pressed = !raw_state[row][col];
+                self.integrate(row, col, pressed, elapsed);
+            }
+        }
+
+        &self.state
+    }
+
+    /// Move one key's integrator by `elapsed` ms towards `pressed`, if its
+    /// previous reading said the same.
+    #[cfg(feature = "debounce-integrator")]
+    fn integrate(&mut self, row: usize, col: usize, pressed: bool, elapsed: u8) {
+        let step = if pressed == self.last_raw[row][col] {
+            elapsed
+        } else {
+            0
+        };
+        self.last_raw[row][col] = pressed;
+        let level = &mut self.levels[row][col];
+        *level = if pressed {
+            level.saturating_add(step).min(INTEGRATOR_MAX_MS)
+        } else {
+            level.saturating_sub(step)
+        };
+        let state = &mut self.state[row][col];
+        let rest = if *state { INTEGRATOR_MAX_MS } else { 0 };
+        if pressed != *state {
+            self.unsettled[row][col] = true;
+        }
+
+        if !*state && *level >= PRESS_AT_MS || *state && *level <= RELEASE_AT_MS {
+            *state = !*state;
+            self.unsettled[row][col] = false;
+        } else if pressed == *state && *level == rest && self.unsettled[row][col] {
+            // Back where it started without getting to the threshold.
+            self.bounces[row][col] = self.bounces[row][col].saturating_add(1);
+            self.unsettled[row][col] = false;
+        }
+    }
+
+    /// Rejected bounces per key.
+    pub fn bounces(&self) -> &[[u16; C]; R] {
+        &self.bounces
+    }
 }
@@ -0,0 +1,266 @@
+//! Debug event queue for `ergodox-cli monitor`.
+//!
+//! The main loop records what it sees — debounced key changes, layer
+//! switches, trouble talking to the left half — into a small ring buffer.
+//! The host drains it by polling a vendor control request (see `hid.rs`),
+//! so no extra interface or endpoint is needed and the keyboard keeps
+//! working normally while a monitor is attached.
+//!
+//! Each event is three bytes, `[kind, arg0, arg1]`:
+//!
+//! | kind   | event            | args               |
+//! |--------|------------------|--------------------|
+//! | `0x01` | key pressed      | row, col           |
+//! | `0x02` | key released     | row, col           |
+//! | `0x03` | layer changed    | new layer          |
+//! | `0x04` | I2C error        | consecutive errors |
+//! | `0x05` | left half lost   | —                  |
+//! | `0x06` | I2C device found | address            |
+//! | `0x07` | I2C scan done    | devices, bus stuck |
+//! | `0x08` | left half back   | —                  |
+//! | `0x7F` | events dropped   | —                  |
+//!
+//! Alongside the queue, every key press is counted per matrix position, for
+//! `ergodox-cli heatmap --from-keyboard`. The counts start from zero at
+//! power-on, or from the last EEPROM checkpoint with the `persist-counts`
+//! feature (see `counts.rs`), until the host clears them. Presses are also
+//! totted up for `ergodox-cli odometer`, on top of the total kept in EEPROM
+//! (see `odometer.rs`).
+//!
+//! It also keeps the left half's health since power-on for the diagnostics
+//! request: whether it's online, how often it went offline, and how many
+//! I2C errors there were in all.
+
+use ergodox_keymap::key_changes;
+
+use crate::matrix::{MatrixState, COLS, ROWS};
+
+/// Size of one encoded event in bytes.
+pub const EVENT_SIZE: usize = 3;
+
+/// Events kept while nobody is draining the queue. 96 bytes of RAM.
+const CAPACITY: usize = 32;
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    /// A key went down or up.
+    Key { row: u8, col: u8, pressed: bool },
+    Layer(u8),
+    /// A left-half scan failed; the count is consecutive failures so far.
+    I2cError(u8),
+    /// Too many I2C errors: the left half is no longer scanned.
+    LeftHalfLost,
+    /// An I2C bus scan found a device at this address.
+    I2cDevice(u8),
+    /// An I2C bus scan is over. `stuck` means it was cut short because
+    /// something holds the bus.
+    I2cScanDone { found: u8, stuck: bool },
+    /// The left half answers again after being lost.
+    LeftHalfBack,
+    /// The queue was full and events were thrown away.
+    Overflow,
+}
+
+impl Event {
+    fn encode(self) -> [u8; EVENT_SIZE] {
+        match self {
+            Event::Key { row, col, pressed: true } => [0x01, row, col],
+            Event::Key { row, col, pressed: false } => [0x02, row, col],
+            Event::Layer(layer) => [0x03, layer, 0],
+            Event::I2cError(count) => [0x04, count, 0],
+            Event::LeftHalfLost => [0x05, 0, 0],
+            Event::I2cDevice(addr) => [0x06, addr, 0],
+            Event::I2cScanDone { found, stuck } => [0x07, found, stuck as u8],
+            Event::LeftHalfBack => [0x08, 0, 0],
+            Event::Overflow => [0x7F, 0, 0],
+        }
+    }
+}
+
+pub struct DebugLog {
+    events: [[u8; EVENT_SIZE]; CAPACITY],
+    head: u8,
+    len: u8,
+    /// Events were dropped since the last drain.
+    overflowed: bool,
+    /// Presses per matrix position, saturating.
+    presses: [[u16; COLS]; ROWS],
+    /// Presses since power-on, and the total before that (see
+    /// `odometer.rs`).
+    session_keystrokes: u32,
+    earlier_keystrokes: u32,
+    /// The host asked for an I2C bus scan (see `i2c_scan.rs`).
+    i2c_scan_requested: bool,
+    /// Whether the left half is being scanned; None until the first
+    /// `update_left_half`.
+    left_half_online: Option<bool>,
+    /// Times the left half went offline, saturating.
+    left_half_losses: u16,
+    /// Failed left-half scans, saturating.
+    i2c_errors: u16,
+    /// The host cleared the counts since `take_cleared` last looked.
+    #[cfg(feature = "persist-counts")]
+    cleared: bool,
+}
+
+impl DebugLog {
+    pub const fn new() -> Self {
+        Self {
+            events: [[0; EVENT_SIZE]; CAPACITY],
+            head: 0,
+            len: 0,
+            overflowed: false,
+            presses: [[0; COLS]; ROWS],
+            session_keystrokes: 0,
+            earlier_keystrokes: 0,
+            i2c_scan_requested: false,
+            left_half_online: None,
+            left_half_losses: 0,
+            i2c_errors: 0,
+            #[cfg(feature = "persist-counts")]
+            cleared: false,
+        }
+    }
+
+    /// Queue an event, dropping it if the queue is full.
+    pub fn push(&mut self, event: Event) {
+        if self.len as usize == CAPACITY {
+            self.overflowed = true;
+            return;
+        }
+        let tail = (self.head as usize + self.len as usize) % CAPACITY;
+        self.events[tail] = event.encode();
+        self.len += 1;
+    }
+
+    /// Queue a press or release for every key that changed between two
+    /// debounced scans, and count the presses. The host gets no timestamps,
+    /// so the events aren't given one.
+    pub fn push_key_changes(&mut self, old: &MatrixState, new: &MatrixState) {
+        for event in key_changes(old, new, 0) {
+            if event.pressed {
+                let count = &mut self.presses[event.row as usize][event.col as usize];
+                *count = count.saturating_add(1);
+                self.session_keystrokes = self.session_keystrokes.saturating_add(1);
+            }
+            self.push(Event::Key {
+                row: event.row,
+                col: event.col,
+                pressed: event.pressed,
+            });
+        }
+    }
+
+    /// Press counts as `encode_counts` lays them out.
+    pub fn press_counts(&self) -> [u8; ROWS * COLS * 2] {
+        encode_counts(&self.presses)
+    }
+
+    /// Start counting from the bytes `press_counts` once returned.
+    #[cfg(feature = "persist-counts")]
+    pub fn restore_press_counts(&mut self, counts: &[u8; ROWS * COLS * 2]) {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let i = (row * COLS + col) * 2;
+                self.presses[row][col] = u16::from_le_bytes([counts[i], counts[i + 1]]);
+            }
+        }
+    }
+
+    /// Zero every press count, at the host's request.
+    pub fn clear_press_counts(&mut self) {
+        self.presses = [[0; COLS]; ROWS];
+        #[cfg(feature = "persist-counts")]
+        self.cleared = true;
+    }
+
+    /// Whether the counts were cleared since the last call.
+    #[cfg(feature = "persist-counts")]
+    pub fn take_cleared(&mut self) -> bool {
+        core::mem::take(&mut self.cleared)
+    }
+
+    /// Keystrokes before this power-on, from the odometer's checkpoint.
+    pub fn set_earlier_keystrokes(&mut self, total: u32) {
+        self.earlier_keystrokes = total;
+    }
+
+    /// Keystrokes ever, as far as EEPROM remembers.
+    pub fn total_keystrokes(&self) -> u32 {
+        self.earlier_keystrokes.saturating_add(self.session_keystrokes)
+    }
+
+    /// The odometer reply: total and session keystrokes, little-endian
+    /// u32s.
+    pub fn odometer(&self) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&self.total_keystrokes().to_le_bytes());
+        out[4..].copy_from_slice(&self.session_keystrokes.to_le_bytes());
+        out
+    }
+
+    /// Have the main loop scan the I2C bus, at the host's request.
+    pub fn request_i2c_scan(&mut self) {
+        self.i2c_scan_requested = true;
+    }
+
+    /// Whether an I2C bus scan was asked for since the last call.
+    pub fn take_i2c_scan_request(&mut self) -> bool {
+        core::mem::take(&mut self.i2c_scan_requested)
+    }
+
+    /// Record the left half's state after a scan, with `new_errors` failed
+    /// scans since the last call. Going offline or coming back is queued as
+    /// an event.
+    pub fn update_left_half(&mut self, online: bool, new_errors: u8) {
+        self.i2c_errors = self.i2c_errors.saturating_add(new_errors as u16);
+        match (self.left_half_online, online) {
+            (Some(true), false) => {
+                self.left_half_losses = self.left_half_losses.saturating_add(1);
+                self.push(Event::LeftHalfLost);
+            }
+            (Some(false), true) => self.push(Event::LeftHalfBack),
+            _ => {}
+        }
+        self.left_half_online = Some(online);
+    }
+
+    /// The diagnostics reply: left half online (0 or 1), times it went
+    /// offline and I2C errors, both little-endian u16s.
+    pub fn diagnostics(&self) -> [u8; 5] {
+        let [l0, l1] = self.left_half_losses.to_le_bytes();
+        let [e0, e1] = self.i2c_errors.to_le_bytes();
+        [(self.left_half_online == Some(true)) as u8, l0, l1, e0, e1]
+    }
+
+    /// Move as many whole events as fit into `buf`, oldest first, and
+    /// return the number of bytes written. Dropped events are reported
+    /// after everything that was queued before them.
+    pub fn drain(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while self.len > 0 && n + EVENT_SIZE <= buf.len() {
+            buf[n..n + EVENT_SIZE].copy_from_slice(&self.events[self.head as usize]);
+            self.head = ((self.head as usize + 1) % CAPACITY) as u8;
+            self.len -= 1;
+            n += EVENT_SIZE;
+        }
+        if self.len == 0 && self.overflowed && n + EVENT_SIZE <= buf.len() {
+            buf[n..n + EVENT_SIZE].copy_from_slice(&Event::Overflow.encode());
+            self.overflowed = false;
+            n += EVENT_SIZE;
+        }
+        n
+    }
+}
+
+/// Per-key counts as the host reads them: little-endian u16s, row by row.
+pub fn encode_counts(counts: &[[u16; COLS]; ROWS]) -> [u8; ROWS * COLS * 2] {
+    let mut out = [0u8; ROWS * COLS * 2];
+    for row in 0..ROWS {
+        for col in 0..COLS {
+            let i = (row * COLS + col) * 2;
+            out[i..i + 2].copy_from_slice(&counts[row][col].to_le_bytes());
+        }
+    }
+    out
+}
@@ -0,0 +1,50 @@
+//! Byte access to the ATmega32U4's 1 KB of EEPROM, and who owns which part.
+//!
+//! | address         | contents                                        |
+//! |-----------------|-------------------------------------------------|
+//! | `0x000`-`0x0A9` | press counts (`counts.rs`, `persist-counts`)    |
+//! | `0x100`-`0x14F` | keystroke odometer (`odometer.rs`)              |
+//! | `0x180`-`0x181` | backlight settings (`backlight.rs`)             |
+//! | `0x1C0`-`0x1C1` | runtime settings (`settings.rs`)                |
+//!
+//! Writes take 3.4 ms and only one can be in progress, so writers check
+//! `busy` first and go one byte at a time rather than wait.
+
+use avr_device::atmega32u4::Peripherals;
+
+/// Where `counts.rs` keeps its checkpoint.
+#[cfg(feature = "persist-counts")]
+pub const COUNTS_ADDR: u16 = 0x000;
+
+/// Where `odometer.rs` keeps its slots.
+pub const ODOMETER_ADDR: u16 = 0x100;
+
+/// Where `backlight.rs` keeps its settings.
+#[cfg(feature = "backlight")]
+pub const BACKLIGHT_ADDR: u16 = 0x180;
+
+/// Where `settings.rs` keeps its settings.
+pub const SETTINGS_ADDR: u16 = 0x1C0;
+
+/// A write is still in progress.
+pub fn busy(dp: &Peripherals) -> bool {
+    dp.EEPROM.eecr.read().eepe().bit_is_set()
+}
+
+pub fn read(dp: &Peripherals, addr: u16) -> u8 {
+    while busy(dp) {}
+    dp.EEPROM.eear.write(|w| unsafe { w.bits(addr) });
+    dp.EEPROM.eecr.write(|w| w.eere().set_bit());
+    dp.EEPROM.eedr.read().bits()
+}
+
+/// Start writing a byte; EEPROM must not be busy.
+pub fn write(dp: &Peripherals, addr: u16, byte: u8) {
+    dp.EEPROM.eear.write(|w| unsafe { w.bits(addr) });
+    dp.EEPROM.eedr.write(|w| unsafe { w.bits(byte) });
+    // EEPE has to be set within four cycles of EEMPE
+    avr_device::interrupt::free(|_| {
+        dp.EEPROM.eecr.write(|w| w.eempe().set_bit());
+        dp.EEPROM.eecr.write(|w| w.eempe().set_bit().eepe().set_bit());
+    });
+}
@@ -0,0 +1,50 @@
+//! Read-back of the application flash for post-flash verification.
+//!
+//! HalfKay can write flash but never read it, so the CLI can't check what
+//! actually landed on the chip. Instead the firmware checksums its own
+//! application region at boot and reports it over a vendor request; the CLI
+//! computes the same CRC over the image it sent and compares.
+
+/// First byte of the HalfKay bootloader. Everything below is application flash.
+pub const APP_END: u16 = 0x7E00;
+
+/// CRC-32 (IEEE 802.3, reflected, init/xorout 0xFFFFFFFF) of flash
+/// 0x0000..APP_END. Unwritten bytes read as 0xFF, which the CLI accounts for
+/// by padding the image the same way.
+pub fn app_crc32() -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for addr in 0..APP_END {
+        crc = crc32_update(crc, read_byte(addr));
+    }
+    !crc
+}
+
+/// Nibble-wise table for the reflected polynomial 0xEDB88320. Two lookups
+/// per byte is a good trade between 64 bytes of flash and boot time.
+#[rustfmt::skip]
+static CRC_NIBBLE_TABLE: [u32; 16] = [
+    0x0000_0000, 0x1DB7_1064, 0x3B6E_20C8, 0x26D9_30AC,
+    0x76DC_4190, 0x6B6B_51F4, 0x4DB2_6158, 0x5005_713C,
+    0xEDB8_8320, 0xF00F_9344, 0xD6D6_A3E8, 0xCB61_B38C,
+    0x9B64_C2B0, 0x86D3_D2D4, 0xA00A_E278, 0xBDBD_F21C,
+];
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    let crc = crc ^ byte as u32;
+    let crc = (crc >> 4) ^ CRC_NIBBLE_TABLE[(crc & 0x0F) as usize];
+    (crc >> 4) ^ CRC_NIBBLE_TABLE[(crc & 0x0F) as usize]
+}
+
+/// Read one byte of program memory with LPM.
+pub fn read_byte(addr: u16) -> u8 {
+    let byte: u8;
+    unsafe {
+        core::arch::asm!(
+            "lpm {0}, Z",
+            out(reg) byte,
+            in("Z") addr,
+            options(pure, readonly, nostack),
+        );
+    }
+    byte
+}
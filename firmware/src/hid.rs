@@ -2,63 +2,22 @@
 //!
 //! Implements a standard 6KRO (6-key rollover) keyboard using the ATmega32U4's
 //! built-in USB controller. Uses direct register access via avr-device.
+//!
+//! A second, vendor-defined HID interface carries the CLI's vendor requests
+//! as feature reports, for hosts where the OS owns the keyboard interface
+//! and only HID report requests get through (Windows, macOS): SET_REPORT
+//! with `[bRequest, wValue low, wValue high]`, then, for requests that
+//! answer, GET_REPORT returns `[length, reply...]`.
 
 use avr_device::atmega32u4::Peripherals;
+pub use ergodox_keymap::KeyboardReport;
 
-use crate::keymap::Keycode;
-use crate::matrix::{COLS, ROWS};
-
-/// Standard USB HID keyboard report (8 bytes).
-/// Byte 0: modifier keys bitmask
-/// Byte 1: reserved (0x00)
-/// Bytes 2-7: up to 6 simultaneous keycodes
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub struct KeyboardReport {
-    pub modifiers: u8,
-    pub reserved: u8,
-    pub keys: [u8; 6],
-}
-
-impl KeyboardReport {
-    pub const fn empty() -> Self {
-        Self {
-            modifiers: 0,
-            reserved: 0,
-            keys: [0; 6],
-        }
-    }
-}
-
-/// Build a HID keyboard report from the current debounced key state and active layer.
-pub fn build_report(keys: &[[bool; COLS]; ROWS], layer: usize) -> KeyboardReport {
-    let mut report = KeyboardReport::empty();
-    let mut key_idx = 0usize;
-
-    for row in 0..ROWS {
-        for col in 0..COLS {
-            if !keys[row][col] {
-                continue; // Key not pressed
-            }
-
-            let kc = crate::keymap::lookup(layer, row, col);
-
-            // Skip transparent, none, and layer keys
-            if kc.is_transparent() || kc.is_layer() || kc == Keycode::None {
-                continue;
-            }
-
-            if kc.is_modifier() {
-                report.modifiers |= kc.modifier_bit();
-            } else if key_idx < 6 {
-                report.keys[key_idx] = kc as u8;
-                key_idx += 1;
-            }
-            // If more than 6 keys, silently drop (no rollover error for simplicity)
-        }
-    }
-
-    report
-}
+use crate::bench::Bench;
+use crate::build_info;
+use crate::debounce::Debouncer;
+use crate::debug::{self, DebugLog};
+use crate::keymap_table;
+use crate::reset::ResetCause;
 
 // ============================================================================
 // ATmega32U4 USB Register-Level Driver
@@ -67,6 +26,17 @@ pub fn build_report(keys: &[[bool; COLS]; ROWS], layer: usize) -> KeyboardReport
 // USB endpoint configuration for keyboard HID
 const EP0_SIZE: u8 = 64; // Control endpoint size
 const EP1_SIZE: u8 = 8; // Interrupt IN endpoint size (keyboard reports)
+const EP2_SIZE: u8 = 8; // Interrupt IN endpoint size (raw HID layer notices)
+
+/// Interface number of the raw HID interface.
+const RAW_INTERFACE: u8 = 1;
+
+/// First byte of a layer notice on the raw HID interface's input report.
+const LAYER_NOTICE: u8 = 0x01;
+
+/// Size of the raw HID feature report, big enough for the longest vendor
+/// reply (press counts) plus its length byte.
+const RAW_REPORT_SIZE: usize = 192;
 
 /// HID report descriptor for a standard keyboard.
 static HID_REPORT_DESCRIPTOR: [u8; 64] = [
@@ -109,6 +79,24 @@ static HID_REPORT_DESCRIPTOR: [u8; 64] = [
     0xC0, // End Collection
 ];
 
+/// HID report descriptor for the raw HID interface: one vendor-defined
+/// feature report, and an input report for layer notices.
+static RAW_REPORT_DESCRIPTOR: [u8; 28] = [
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, // Usage (0x01)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x02, //   Usage (0x02)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, RAW_REPORT_SIZE as u8, // Report Count
+    0xB1, 0x02, //   Feature (Data, Variable, Absolute)
+    0x09, 0x03, //   Usage (0x03)
+    0x95, EP2_SIZE, // Report Count
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
 // USB descriptors
 static DEVICE_DESCRIPTOR: [u8; 18] = [
     18,   // bLength
@@ -120,19 +108,19 @@ static DEVICE_DESCRIPTOR: [u8; 18] = [
     EP0_SIZE, // bMaxPacketSize0
     0xC0, 0x16, // idVendor (0x16C0 — Van Ooijen Technische Informatica)
     0x7E, 0x04, // idProduct (0x047E — custom keyboard)
-    0x01, 0x00, // bcdDevice (1.0)
+    BCD_DEVICE as u8, (BCD_DEVICE >> 8) as u8, // bcdDevice (crate version)
     1,    // iManufacturer
     2,    // iProduct
     0,    // iSerialNumber
     1,    // bNumConfigurations
 ];
 
-static CONFIG_DESCRIPTOR: [u8; 34] = [
+static CONFIG_DESCRIPTOR: [u8; 59] = [
     // Configuration descriptor
     9,    // bLength
     2,    // bDescriptorType (Configuration)
-    34, 0, // wTotalLength
-    1,    // bNumInterfaces
+    59, 0, // wTotalLength
+    2,    // bNumInterfaces
     1,    // bConfigurationValue
     0,    // iConfiguration
     0x80, // bmAttributes (bus powered)
@@ -162,6 +150,31 @@ static CONFIG_DESCRIPTOR: [u8; 34] = [
     0x03, // bmAttributes (Interrupt)
     EP1_SIZE, 0, // wMaxPacketSize
     10,   // bInterval (10ms polling)
+    // Interface descriptor (raw HID)
+    9,    // bLength
+    4,    // bDescriptorType (Interface)
+    RAW_INTERFACE, // bInterfaceNumber
+    0,    // bAlternateSetting
+    1,    // bNumEndpoints
+    3,    // bInterfaceClass (HID)
+    0,    // bInterfaceSubClass (None)
+    0,    // bInterfaceProtocol (None)
+    0,    // iInterface
+    // HID descriptor
+    9,    // bLength
+    0x21, // bDescriptorType (HID)
+    0x11, 0x01, // bcdHID (1.11)
+    0,    // bCountryCode
+    1,    // bNumDescriptors
+    0x22, // bDescriptorType (Report)
+    RAW_REPORT_DESCRIPTOR.len() as u8, 0, // wDescriptorLength
+    // Endpoint descriptor (EP2 IN — interrupt, layer notices)
+    7,    // bLength
+    5,    // bDescriptorType (Endpoint)
+    0x82, // bEndpointAddress (EP2 IN)
+    0x03, // bmAttributes (Interrupt)
+    EP2_SIZE, 0, // wMaxPacketSize
+    10,   // bInterval (10ms polling)
 ];
 
 /// String descriptor 0 (language ID)
@@ -173,23 +186,46 @@ static STRING_DESC_1: [u8; 16] = [
     b'E', 0, b'r', 0, b'g', 0, b'o', 0, b'D', 0, b'o', 0, b'x', 0,
 ];
 
-/// String descriptor 2 (product): "Keyboard"
-static STRING_DESC_2: [u8; 18] = [
-    18, 3, // bLength, bDescriptorType
-    b'K', 0, b'e', 0, b'y', 0, b'b', 0, b'o', 0, b'a', 0, b'r', 0, b'd', 0,
-];
+// BCD_DEVICE and STRING_DESC_2, the product string "Keyboard <version>",
+// both from the crate version (see `build.rs`)
+include!(concat!(env!("OUT_DIR"), "/usb_version.rs"));
 
 /// USB device state.
 pub struct UsbKeyboard {
     configured: bool,
     last_report: KeyboardReport,
+    /// CRC32 of application flash, computed at boot (see `flash.rs`).
+    flash_crc: u32,
+    /// Why the MCU last reset (see `reset.rs`).
+    reset_cause: ResetCause,
+    /// The last vendor request set over raw HID, answered by the next
+    /// GET_REPORT.
+    raw_request: [u8; 3],
+    /// The keyboard LED output report from the host: Num Lock, Caps Lock,
+    /// Scroll Lock, ... from bit 0.
+    host_leds: u8,
+    /// The idle rate from the host's SET_IDLE, in 4 ms units (0 is
+    /// indefinite). Reports only go out when they change whatever it is;
+    /// it's kept so GET_IDLE can give it back.
+    idle_rate: u8,
+    /// Endpoints the host halted with SET_FEATURE, one bit per endpoint.
+    halted: u8,
+    /// The last layer notice sent on the raw HID interface.
+    last_notice: [u8; EP2_SIZE as usize],
 }
 
 impl UsbKeyboard {
-    pub const fn new() -> Self {
+    pub const fn new(flash_crc: u32, reset_cause: ResetCause) -> Self {
         Self {
             configured: false,
             last_report: KeyboardReport::empty(),
+            flash_crc,
+            reset_cause,
+            raw_request: [0; 3],
+            host_leds: 0,
+            idle_rate: 0,
+            halted: 0,
+            last_notice: [0; EP2_SIZE as usize],
         }
     }
 
@@ -197,6 +233,16 @@ impl UsbKeyboard {
         self.configured
     }
 
+    /// Whether the host has Caps Lock on.
+    pub fn caps_lock(&self) -> bool {
+        self.host_leds & 0x02 != 0
+    }
+
+    /// Whether VBUS is up, i.e. we're plugged into something powered.
+    pub fn vbus(&self, dp: &Peripherals) -> bool {
+        dp.USB_DEVICE.usbsta.read().vbus().bit_is_set()
+    }
+
     /// Initialize the ATmega32U4 USB controller.
     pub fn init(&mut self, dp: &Peripherals) {
         let usb = &dp.USB_DEVICE;
@@ -218,6 +264,14 @@ impl UsbKeyboard {
         // Enable USB clock
         usb.usbcon.modify(|_, w| w.frzclk().clear_bit());
 
+        // After a brown-out or watchdog reset the host may not have noticed
+        // we were gone; stay off the bus long enough that it does, so it
+        // enumerates us from scratch
+        if self.reset_cause.is_unclean() {
+            usb.udcon.modify(|_, w| w.detach().set_bit());
+            crate::delay_ms(500);
+        }
+
         // Attach to bus (clear DETACH)
         usb.udcon.modify(|_, w| w.detach().clear_bit());
 
@@ -228,9 +282,22 @@ impl UsbKeyboard {
     }
 
     /// Poll for USB events and handle them. Call this from the main loop.
-    pub fn poll(&mut self, dp: &Peripherals) {
+    /// `debug` is drained when the host asks for debug events, and `bench`
+    /// serves `ergodox-cli bench`.
+    pub fn poll(
+        &mut self,
+        dp: &Peripherals,
+        debug: &mut DebugLog,
+        bench: &mut Bench,
+        debouncer: &Debouncer,
+    ) {
         let usb = &dp.USB_DEVICE;
 
+        // Unplugged: whatever host configured us is gone
+        if !self.vbus(dp) {
+            self.configured = false;
+        }
+
         let udint = usb.udint.read();
 
         // End of reset
@@ -238,20 +305,28 @@ impl UsbKeyboard {
             usb.udint.modify(|_, w| w.eorsti().clear_bit());
             self.configure_ep0(dp);
             self.configured = false;
+            self.host_leds = 0;
+            self.idle_rate = 0;
+            self.halted = 0;
+            self.last_notice = [0; EP2_SIZE as usize];
         }
 
         // Check for SETUP packet on EP0
         self.select_endpoint(dp, 0);
         let ueintx = usb.ueintx.read();
         if ueintx.rxstpi().bit_is_set() {
-            self.handle_setup(dp);
+            self.handle_setup(dp, debug, bench, debouncer);
         }
     }
 
-    /// Send a keyboard report if it has changed.
-    pub fn send_report(&mut self, dp: &Peripherals, report: &KeyboardReport) {
+    /// Send a keyboard report if it has changed. Returns whether it went out.
+    pub fn send_report(&mut self, dp: &Peripherals, report: &KeyboardReport) -> bool {
         if !self.configured || *report == self.last_report {
-            return;
+            return false;
+        }
+        // A halted endpoint takes nothing until the host clears it
+        if self.halted & 1 << 1 != 0 {
+            return false;
         }
 
         let usb = &dp.USB_DEVICE;
@@ -262,7 +337,7 @@ impl UsbKeyboard {
         while usb.ueintx.read().rwal().bit_is_clear() {
             timeout = timeout.wrapping_sub(1);
             if timeout == 0 {
-                return;
+                return false;
             }
         }
 
@@ -278,6 +353,36 @@ impl UsbKeyboard {
             .modify(|_, w| w.fifocon().clear_bit().txini().clear_bit());
 
         self.last_report = *report;
+        true
+    }
+
+    /// Tell the host the active layer, the layers toggled on (one bit per
+    /// layer) and its own lock LEDs, if any of them changed since the last
+    /// notice. Returns whether a notice went out.
+    ///
+    /// Nothing may be reading the raw HID interface, so this doesn't wait
+    /// for the endpoint: if the last notice is still waiting there, this
+    /// one goes on a later pass.
+    pub fn send_layer_notice(&mut self, dp: &Peripherals, layer: u8, toggled: u16) -> bool {
+        let [toggled_lo, toggled_hi] = toggled.to_le_bytes();
+        let notice = [LAYER_NOTICE, layer, toggled_lo, toggled_hi, self.host_leds, 0, 0, 0];
+        if !self.configured || notice == self.last_notice || self.halted & 1 << 2 != 0 {
+            return false;
+        }
+
+        let usb = &dp.USB_DEVICE;
+        self.select_endpoint(dp, 2);
+        if usb.ueintx.read().rwal().bit_is_clear() {
+            return false;
+        }
+        for byte in notice {
+            usb.uedatx.write(|w| w.bits(byte));
+        }
+        usb.ueintx
+            .modify(|_, w| w.fifocon().clear_bit().txini().clear_bit());
+
+        self.last_notice = notice;
+        true
     }
 
     fn configure_ep0(&self, dp: &Peripherals) {
@@ -301,13 +406,49 @@ impl UsbKeyboard {
         usb.uecfg1x.write(|w| w.epsize().bits(0b000).alloc().set_bit());
     }
 
+    fn configure_ep2(&self, dp: &Peripherals) {
+        let usb = &dp.USB_DEVICE;
+
+        self.select_endpoint(dp, 2);
+        usb.ueconx.write(|w| w.epen().set_bit());
+        // Interrupt IN endpoint, 8 bytes
+        usb.uecfg0x
+            .write(|w| w.eptype().bits(0b11).epdir().set_bit());
+        usb.uecfg1x.write(|w| w.epsize().bits(0b000).alloc().set_bit());
+    }
+
+    /// Halt endpoint `ep` or clear its halt. Clearing also starts its data
+    /// toggle over at DATA0, which the host expects after recovering from
+    /// an error (USB 2.0 9.4.5); without that its next packet is dropped
+    /// as a repeat.
+    fn set_halt(&mut self, dp: &Peripherals, ep: u8, halt: bool) {
+        let usb = &dp.USB_DEVICE;
+
+        self.select_endpoint(dp, ep);
+        if halt {
+            usb.ueconx.modify(|_, w| w.stallrq().set_bit());
+            self.halted |= 1 << ep;
+        } else {
+            usb.ueconx
+                .modify(|_, w| w.stallrqc().set_bit().rstdt().set_bit());
+            self.halted &= !(1 << ep);
+        }
+        self.select_endpoint(dp, 0);
+    }
+
     fn select_endpoint(&self, dp: &Peripherals, ep: u8) {
         dp.USB_DEVICE
             .uenum
             .write(|w| w.bits(ep & 0x07));
     }
 
-    fn handle_setup(&mut self, dp: &Peripherals) {
+    fn handle_setup(
+        &mut self,
+        dp: &Peripherals,
+        debug: &mut DebugLog,
+        bench: &mut Bench,
+        debouncer: &Debouncer,
+    ) {
         let usb = &dp.USB_DEVICE;
 
         // Read 8-byte SETUP packet
@@ -324,7 +465,6 @@ impl UsbKeyboard {
         usb.ueintx.modify(|_, w| w.rxstpi().clear_bit());
 
         let w_length = (w_length_h as u16) << 8 | w_length_l as u16;
-        let _ = w_index_l; // Used for some requests
 
         match (bm_request_type, b_request) {
             // GET_DESCRIPTOR
@@ -361,7 +501,9 @@ impl UsbKeyboard {
                 // Send ZLP
                 usb.ueintx.modify(|_, w| w.txini().clear_bit());
                 self.configure_ep1(dp);
+                self.configure_ep2(dp);
                 self.configured = true;
+                self.halted = 0;
             }
 
             // GET_CONFIGURATION
@@ -372,21 +514,58 @@ impl UsbKeyboard {
                 usb.ueintx.modify(|_, w| w.txini().clear_bit());
             }
 
+            // GET_STATUS (device): bus powered, no remote wakeup
+            (0x80, 0x00) => self.send_descriptor(dp, &[0, 0], w_length),
+
+            // GET_STATUS (interface): nothing to report
+            (0x81, 0x00) if w_index_l <= RAW_INTERFACE => {
+                self.send_descriptor(dp, &[0, 0], w_length)
+            }
+
+            // GET_STATUS (endpoint): whether it's halted. wIndex is the
+            // endpoint's address, direction bit and all
+            (0x82, 0x00) => match w_index_l & 0x7F {
+                ep @ 0..=2 => {
+                    let halted = self.halted >> ep & 1;
+                    self.send_descriptor(dp, &[halted, 0], w_length)
+                }
+                _ => self.stall(dp),
+            },
+
+            // CLEAR_FEATURE and SET_FEATURE (endpoint): ENDPOINT_HALT is
+            // the only endpoint feature. Hosts clear it to recover from
+            // errors, so stalling here would leave the endpoint dead
+            (0x02, request @ (0x01 | 0x03)) if w_value_l == 0 => match w_index_l & 0x7F {
+                ep @ 1..=2 => {
+                    self.set_halt(dp, ep, request == 0x03);
+                    // Send ZLP
+                    usb.ueintx.modify(|_, w| w.txini().clear_bit());
+                }
+                _ => self.stall(dp),
+            },
+
             // HID GET_DESCRIPTOR (interface-level)
             (0x81, 0x06) => {
                 let desc_type = w_value_h;
-                match desc_type {
-                    0x22 => self.send_descriptor(dp, &HID_REPORT_DESCRIPTOR, w_length),
+                match (desc_type, w_index_l) {
+                    (0x22, RAW_INTERFACE) => {
+                        self.send_descriptor(dp, &RAW_REPORT_DESCRIPTOR, w_length)
+                    }
+                    (0x22, _) => self.send_descriptor(dp, &HID_REPORT_DESCRIPTOR, w_length),
                     _ => self.stall(dp),
                 }
             }
 
-            // HID SET_IDLE
+            // HID SET_IDLE: the rate is in wValue's high byte
             (0x21, 0x0A) => {
+                self.idle_rate = w_value_h;
                 // Send ZLP
                 usb.ueintx.modify(|_, w| w.txini().clear_bit());
             }
 
+            // HID GET_IDLE
+            (0xA1, 0x02) => self.send_descriptor(dp, &[self.idle_rate], w_length),
+
             // HID SET_PROTOCOL
             (0x21, 0x0B) => {
                 // Send ZLP
@@ -399,17 +578,187 @@ impl UsbKeyboard {
                 jump_to_bootloader(dp);
             }
 
+            // Vendor requests that answer (see `vendor_reply`)
+            (0xC0, request) => {
+                let mut buf = [0u8; RAW_REPORT_SIZE];
+                // Debug events are only sent whole, and always less than a
+                // full packet so the transfer never needs a trailing ZLP
+                let max = if request == 0x02 {
+                    core::cmp::min(w_length as usize, EP0_SIZE as usize - 1)
+                } else {
+                    buf.len()
+                };
+                let value = u16::from_le_bytes([w_value_l, w_value_h]);
+                let reply = &mut buf[..max];
+                match self.vendor_reply(request, value, debug, bench, debouncer, reply) {
+                    Some(n) => self.send_descriptor(dp, &buf[..n], w_length),
+                    None => self.stall(dp),
+                }
+            }
+
+            // Vendor request: inject a synthetic press at row wValue low,
+            // column wValue high, to time it through to a report
+            (0x40, 0x06) => {
+                if bench.inject(w_value_l, w_value_h) {
+                    usb.ueintx.modify(|_, w| w.txini().clear_bit());
+                } else {
+                    self.stall(dp);
+                }
+            }
+
+            // Vendor request: zero the press counters
+            (0x40, 0x07) => {
+                debug.clear_press_counts();
+                usb.ueintx.modify(|_, w| w.txini().clear_bit());
+            }
+
+            // Vendor request: scan the I2C bus. The main loop does it and
+            // reports through the debug events, so the request itself
+            // returns straight away.
+            (0x40, 0x09) => {
+                debug.request_i2c_scan();
+                usb.ueintx.modify(|_, w| w.txini().clear_bit());
+            }
+
+            // Vendor request: send the layer notice again, for a listener
+            // that just started
+            (0x40, 0x0D) => {
+                self.last_notice = [0; EP2_SIZE as usize];
+                usb.ueintx.modify(|_, w| w.txini().clear_bit());
+            }
+
+            // HID SET_REPORT (Output) on the keyboard interface: the host's
+            // Caps Lock and friends
+            (0x21, 0x09) if w_index_l != RAW_INTERFACE && w_value_h == 0x02 => {
+                let mut leds = [0u8; 1];
+                self.receive(dp, &mut leds, w_length);
+                self.host_leds = leds[0];
+            }
+
+            // HID SET_REPORT (Feature) on the raw interface: a vendor
+            // request. The ones that don't answer are carried out here.
+            (0x21, 0x09) if w_index_l == RAW_INTERFACE => {
+                let mut request = [0u8; 3];
+                self.receive(dp, &mut request, w_length);
+                self.raw_request = request;
+                match request[0] {
+                    0xFF => jump_to_bootloader(dp),
+                    0x06 => {
+                        bench.inject(request[1], request[2]);
+                    }
+                    0x07 => debug.clear_press_counts(),
+                    0x09 => debug.request_i2c_scan(),
+                    0x0D => self.last_notice = [0; EP2_SIZE as usize],
+                    _ => {}
+                }
+            }
+
+            // HID GET_REPORT (Feature) on the raw interface: the answer to
+            // the last vendor request, after its length
+            (0xA1, 0x01) if w_index_l == RAW_INTERFACE => {
+                let mut report = [0u8; RAW_REPORT_SIZE];
+                let n = self
+                    .vendor_reply(
+                        self.raw_request[0],
+                        u16::from_le_bytes([self.raw_request[1], self.raw_request[2]]),
+                        debug,
+                        bench,
+                        debouncer,
+                        &mut report[1..],
+                    )
+                    .unwrap_or(0);
+                report[0] = n as u8;
+                self.send_descriptor(dp, &report, w_length);
+            }
+
             _ => {
                 self.stall(dp);
             }
         }
     }
 
+    /// Answer vendor IN request `request`, whose wValue is `value`, into
+    /// `buf`, truncated to fit.
+    /// Returns the reply's length, or None for a request that doesn't
+    /// answer.
+    fn vendor_reply(
+        &self,
+        request: u8,
+        value: u16,
+        debug: &mut DebugLog,
+        bench: &mut Bench,
+        debouncer: &Debouncer,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        fn put(buf: &mut [u8], data: &[u8]) -> usize {
+            let n = core::cmp::min(data.len(), buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        }
+        Some(match request {
+            // CRC32 of application flash (4 bytes, little-endian)
+            0x01 => put(buf, &self.flash_crc.to_le_bytes()),
+            // Drain queued debug events (see `debug.rs`), whole events only
+            0x02 => debug.drain(buf),
+            // Per-key press counts (ROWS * COLS little-endian u16s, row
+            // by row; see `debug.rs`)
+            0x03 => put(buf, &debug.press_counts()),
+            // Firmware version, git hash, build date, stamped image CRC
+            // and reset cause as ASCII (see `build_info.rs`)
+            0x04 => build_info::reply(buf, self.reset_cause),
+            // Scan loop timing since the last request (see `bench.rs`)
+            0x05 => put(buf, &bench.take_stats()),
+            // Rejected bounces per key since power-on, laid out like the
+            // press counts (see `debounce.rs`)
+            0x08 => put(buf, &debug::encode_counts(debouncer.bounces())),
+            // Left half health since power-on (see `debug.rs`)
+            0x0A => put(buf, &debug.diagnostics()),
+            // Total and session keystrokes (see `odometer.rs`)
+            0x0B => put(buf, &debug.odometer()),
+            // The running keymap table from offset `value`, as much as fits
+            // (see `keymap_table.rs`)
+            0x0C => keymap_table::read(value as usize, buf),
+            _ => return None,
+        })
+    }
+
+    /// Read the data stage of a control OUT request, keeping as much as
+    /// fits in `buf`, and complete the status stage.
+    fn receive(&self, dp: &Peripherals, buf: &mut [u8], length: u16) {
+        let usb = &dp.USB_DEVICE;
+        let mut received = 0;
+        while received < length as usize {
+            while usb.ueintx.read().rxouti().bit_is_clear() {}
+            let count = usb.uebclx.read().bits() as usize;
+            for _ in 0..count {
+                let byte = usb.uedatx.read().bits();
+                if received < buf.len() {
+                    buf[received] = byte;
+                }
+                received += 1;
+            }
+            usb.ueintx.modify(|_, w| w.rxouti().clear_bit());
+            if count == 0 {
+                break;
+            }
+        }
+
+        // Status stage: zero-length IN packet
+        while usb.ueintx.read().txini().bit_is_clear() {}
+        usb.ueintx.modify(|_, w| w.txini().clear_bit());
+    }
+
     fn send_descriptor(&self, dp: &Peripherals, desc: &[u8], max_length: u16) {
         let usb = &dp.USB_DEVICE;
         let len = core::cmp::min(desc.len(), max_length as usize);
         let mut sent = 0;
 
+        // Nothing to send still needs a zero-length data packet
+        if len == 0 {
+            while usb.ueintx.read().txini().bit_is_clear() {}
+            usb.ueintx.modify(|_, w| w.txini().clear_bit());
+        }
+
         while sent < len {
             while usb.ueintx.read().txini().bit_is_clear() {}
 
@@ -456,8 +805,11 @@ fn jump_to_bootloader(dp: &Peripherals) -> ! {
     dp.ADC.adcsra.write(|w| unsafe { w.bits(0) });
     dp.TC0.timsk0.write(|w| unsafe { w.bits(0) });
     dp.TC1.timsk1.write(|w| unsafe { w.bits(0) });
+    dp.TC1.tccr1b.write(|w| unsafe { w.bits(0) });
     dp.TC3.timsk3.write(|w| unsafe { w.bits(0) });
     dp.TC4.timsk4.write(|w| unsafe { w.bits(0) });
+    dp.TC4.tccr4a.write(|w| unsafe { w.bits(0) });
+    dp.TC4.tccr4b.write(|w| unsafe { w.bits(0) });
     dp.USART1.ucsr1b.write(|w| unsafe { w.bits(0) });
     dp.TWI.twcr.write(|w| unsafe { w.bits(0) });
 
@@ -3,41 +3,83 @@
 //! The left half of the ErgoDox uses an MCP23018 I/O expander connected
 //! to the Teensy via I2C over the TRRS cable (SCL=PD0, SDA=PD1).
 //!
-//! # Left half pin mapping (MCP23018)
+//! I2C address: 0x20 (A0-A2 tied to GND on PCB), though any of 0x20-0x27
+//! is found.
 //!
-//! I2C address: 0x20 (A0-A2 tied to GND on PCB).
+//! Which port drives and which port reads comes from the wiring profile
+//! (`wiring.rs`). With the original ErgoDox wiring, GPIOA drives the
+//! columns (active-low, one at a time) and GPIOB reads the rows through its
+//! internal pull-ups.
 //!
-//! GPIOA — column outputs (active-low, one driven at a time):
-//!   GPA0 → col 0    IODIRA = 0x00 (all output)
-//!   GPA1 → col 1
-//!   GPA2 → col 2
-//!   GPA3 → col 3
-//!   GPA4 → col 4
-//!   GPA5 → col 5
-//!   GPA6 → col 6
-//!   GPA7 → (unused)
+//! The expander is set to byte mode (IOCON.SEQOP), where its register
+//! pointer toggles between a port's A and B registers instead of counting
+//! up. Each line of the scan is then a single transfer: write the drive
+//! port's GPIO, repeated start, read a byte, which is the read port's GPIO.
+//! That's 4 bytes on the bus instead of 7 in two transfers, and at 100kHz
+//! takes a left-half scan from about 4.9 ms to 3.5 ms, going by the bus
+//! timing (DESIGN.md has the sums; `bench` measures the real thing).
 //!
-//! GPIOB — row inputs (internal pull-ups enabled):
-//!   GPB0 → row 0    IODIRB = 0xFF (all input), GPPUB = 0xFF (pull-ups)
-//!   GPB1 → row 1
-//!   GPB2 → row 2
-//!   GPB3 → row 3
-//!   GPB4 → row 4
-//!   GPB5 → row 5
-//!   GPB6 → (unused)
-//!   GPB7 → (unused)
+//! Most of the time no key on the left half is down, and scanning it line
+//! by line finds nothing. So after a scan that found every key up, all the
+//! driven lines are left low and the read port's interrupt-on-change is
+//! armed. A key pressed anywhere then pulls a read line low, which latches
+//! in INTF. Until that happens each pass reads INTF alone, one short
+//! transfer instead of a line-by-line scan. Nothing wires the expander's
+//! INT pins to the Teensy, so INTF is polled rather than waited on; the
+//! latch still catches a change that came and went between two polls.
 
 use avr_device::atmega32u4::TWI;
 
+use crate::wiring::ExpanderPort;
+
 /// MCP23018 I2C address. A0-A2 pins are tied to GND on the ErgoDox PCB.
 const MCP23018_BASE_ADDR: u8 = 0x20;
 
 // MCP23018 register addresses (IOCON.BANK = 0, the power-on default)
 const IODIRA: u8 = 0x00; // I/O direction A: 0=output, 1=input
 const IODIRB: u8 = 0x01; // I/O direction B: 0=output, 1=input
+const GPINTENA: u8 = 0x04; // Interrupt-on-change enable A: 1=enabled
+const GPINTENB: u8 = 0x05; // Interrupt-on-change enable B: 1=enabled
+const IOCON: u8 = 0x0A;  // Configuration (also at 0x0B)
+const GPPUA: u8 = 0x0C;  // Pull-up enable A: 1=enabled
 const GPPUB: u8 = 0x0D;  // Pull-up enable B: 1=enabled
-const GPIOA: u8 = 0x12;  // Port A data (write to drive columns)
-const GPIOB: u8 = 0x13;  // Port B data (read to get row states)
+const INTFA: u8 = 0x0E;  // Interrupt flags A: 1=pin changed
+const INTFB: u8 = 0x0F;  // Interrupt flags B: 1=pin changed
+const GPIOA: u8 = 0x12;  // Port A data
+const GPIOB: u8 = 0x13;  // Port B data
+
+/// IOCON.SEQOP: byte mode, where the register pointer toggles between GPIOA
+/// and GPIOB rather than incrementing.
+const IOCON_SEQOP: u8 = 1 << 5;
+
+/// An expander port's IODIR, GPINTEN, GPPU, INTF and GPIO registers.
+struct PortRegisters {
+    iodir: u8,
+    gpinten: u8,
+    gppu: u8,
+    intf: u8,
+    gpio: u8,
+}
+
+const fn registers(port: ExpanderPort) -> PortRegisters {
+    match port {
+        ExpanderPort::A => PortRegisters {
+            iodir: IODIRA,
+            gpinten: GPINTENA,
+            gppu: GPPUA,
+            intf: INTFA,
+            gpio: GPIOA,
+        },
+        ExpanderPort::B => PortRegisters {
+            iodir: IODIRB,
+            gpinten: GPINTENB,
+            gppu: GPPUB,
+            intf: INTFB,
+            gpio: GPIOB,
+        },
+    }
+}
+
 
 /// TWI (I2C) clock prescaler and bit rate for ~100kHz at 16MHz CPU.
 /// SCL freq = CPU_FREQ / (16 + 2 * TWBR * prescaler)
@@ -56,6 +98,13 @@ pub struct Mcp23018 {
     addr: u8,
     initialized: bool,
     errors: u8,
+    /// The port the scan drives.
+    drive: PortRegisters,
+    /// The port the scan reads.
+    read: PortRegisters,
+    /// Whether every driven line is low and a change on the read port is
+    /// waited for instead of scanning (see `changed`).
+    armed: bool,
 }
 
 /// Read the TWI status register, masking out the prescaler bits.
@@ -65,11 +114,16 @@ fn twi_status(twi: &TWI) -> u8 {
 }
 
 impl Mcp23018 {
-    pub const fn new() -> Self {
+    /// An expander scanned by driving the `drive` port's pins and reading
+    /// the `read` port's (see `Wiring::expander_ports`).
+    pub const fn new((drive, read): (ExpanderPort, ExpanderPort)) -> Self {
         Self {
             addr: MCP23018_BASE_ADDR,
             initialized: false,
             errors: 0,
+            drive: registers(drive),
+            read: registers(read),
+            armed: false,
         }
     }
 
@@ -94,22 +148,36 @@ impl Mcp23018 {
                 }
             }
         }
+        // `try_reinit` looks for it at the usual address
+        self.addr = MCP23018_BASE_ADDR;
         None
     }
 
     /// Probe whether a device ACKs at the current address.
     /// Always sends STOP to leave the bus clean for the next attempt.
     fn probe(&self, twi: &TWI) -> bool {
-        let ok = self.i2c_start(twi).is_ok()
-            && self.i2c_write(twi, (self.addr << 1) | 0).is_ok();
+        self.probe_at(twi, self.addr) == Ok(true)
+    }
+
+    /// Probe whether a device ACKs at `addr`. Err means the START itself
+    /// failed: something is holding the bus.
+    fn probe_at(&self, twi: &TWI, addr: u8) -> Result<bool, ()> {
+        let started = self.i2c_start(twi);
+        let ok = started.is_ok() && self.i2c_write(twi, (addr << 1) | 0).is_ok();
         self.i2c_stop(twi);
-        // Wait for STOP to complete
-        let mut timeout: u16 = 0xFFFF;
-        while twi.twcr.read().twsto().bit_is_set() {
-            timeout = timeout.wrapping_sub(1);
-            if timeout == 0 { break; }
+        started.map(|()| ok)
+    }
+
+    /// Probe every address from 0x08 to 0x77 (the rest are reserved) and
+    /// call `found` with each that ACKs. Gives up with Err as soon as the
+    /// bus turns out to be stuck, rather than timing out on every address.
+    pub fn scan_bus(&self, twi: &TWI, mut found: impl FnMut(u8)) -> Result<(), ()> {
+        for addr in 0x08..=0x77 {
+            if self.probe_at(twi, addr)? {
+                found(addr);
+            }
         }
-        ok
+        Ok(())
     }
 
     /// Return the TWI status byte from attempting a START + address write.
@@ -137,17 +205,22 @@ impl Mcp23018 {
         (start_status, addr_status)
     }
 
-    /// Configure MCP23018 I/O direction and pull-ups.
+    /// Configure MCP23018 I/O direction and pull-ups per the wiring profile.
     /// Original ErgoDox wiring: GPIOA = columns (outputs), GPIOB = rows (inputs).
-    fn configure(&self, twi: &TWI) -> Result<(), ()> {
-        // IODIRA = 0x00: all pins output (drive columns)
-        self.write_register(twi, IODIRA, 0x00)?;
-        // IODIRB = 0xFF: all pins input (read rows)
-        self.write_register(twi, IODIRB, 0xFF)?;
-        // GPPUB = 0xFF: enable pull-ups on row inputs
-        self.write_register(twi, GPPUB, 0xFF)?;
-        // Drive all column outputs high initially (inactive)
-        self.write_register(twi, GPIOA, 0xFF)?;
+    fn configure(&mut self, twi: &TWI) -> Result<(), ()> {
+        self.armed = false;
+        // Byte mode, for `scan_line`'s write-then-read
+        self.write_register(twi, IOCON, IOCON_SEQOP)?;
+        // Drive port: all pins output
+        self.write_register(twi, self.drive.iodir, 0x00)?;
+        // Read port: all pins input, with pull-ups
+        self.write_register(twi, self.read.iodir, 0xFF)?;
+        self.write_register(twi, self.read.gppu, 0xFF)?;
+        // Read port: flag any change from the last read (INTCON = 0, the
+        // power-on default), for `changed`
+        self.write_register(twi, self.read.gpinten, 0xFF)?;
+        // Drive all outputs high initially (inactive)
+        self.write_register(twi, self.drive.gpio, 0xFF)?;
         Ok(())
     }
 
@@ -156,6 +229,11 @@ impl Mcp23018 {
         self.initialized
     }
 
+    /// Consecutive failed column scans; reset by the next good one.
+    pub fn error_count(&self) -> u8 {
+        self.errors
+    }
+
     /// Try to re-initialize if the MCP23018 was not detected.
     pub fn try_reinit(&mut self, twi: &TWI) {
         if !self.initialized {
@@ -166,24 +244,18 @@ impl Mcp23018 {
         }
     }
 
-    /// Drive one column low on GPIOA and read rows from GPIOB.
-    /// Returns 8 bits of row data (active low), or 0xFF if not initialized/errored.
-    pub fn scan_column(&mut self, twi: &TWI, col: u8) -> u8 {
+    /// Drive one line (by its bit on the drive port) low and read the read
+    /// port. Returns its 8 bits (active low), or 0xFF if not
+    /// initialized/errored.
+    pub fn scan_line(&mut self, twi: &TWI, bit: u8) -> u8 {
         if !self.initialized {
             return 0xFF; // All keys up
         }
 
-        // Drive the target column low on GPIOA, all others high
-        if self.write_register(twi, GPIOA, !(1u8 << col)).is_err() {
-            self.mark_error();
-            return 0xFF;
-        }
-
-        // Small delay for signal settling
-        tiny_delay();
-
-        // Read row inputs from GPIOB
-        match self.read_register(twi, GPIOB) {
+        // Drive the target line low, all others high, and read the other
+        // port. The repeated start and address byte in between take far
+        // longer than the lines need to settle.
+        match self.write_then_read(twi, self.drive.gpio, !(1u8 << bit)) {
             Ok(val) => {
                 self.errors = 0;
                 val
@@ -195,6 +267,50 @@ impl Mcp23018 {
         }
     }
 
+    /// Whether the left half needs scanning: always, unless the last scan
+    /// found every key up and INTF says no read line has changed since.
+    pub fn changed(&mut self, twi: &TWI) -> bool {
+        if !self.armed {
+            return true;
+        }
+        match self.read_register(twi, self.read.intf) {
+            Ok(0) => {
+                self.errors = 0;
+                false
+            }
+            Ok(_) => {
+                self.armed = false;
+                true
+            }
+            Err(()) => {
+                self.armed = false;
+                self.mark_error();
+                true
+            }
+        }
+    }
+
+    /// Finish a scan, `all_up` if it found no key down. That leaves every
+    /// driven line low with the interrupt cleared, for `changed`, unless a
+    /// key has gone down since; otherwise the driven lines go high.
+    pub fn finish_scan(&mut self, twi: &TWI, all_up: bool) {
+        if !self.initialized {
+            return;
+        }
+        if all_up {
+            // Reading the read port clears any change the scan flagged
+            match self.write_then_read(twi, self.drive.gpio, 0x00) {
+                Ok(0xFF) => {
+                    self.armed = true;
+                    return;
+                }
+                Ok(_) => {}
+                Err(()) => self.mark_error(),
+            }
+        }
+        let _ = self.write_register(twi, self.drive.gpio, 0xFF);
+    }
+
     /// After 10 consecutive I2C errors, disable scanning to avoid phantom keys.
     fn mark_error(&mut self) {
         self.errors = self.errors.saturating_add(1);
@@ -203,10 +319,18 @@ impl Mcp23018 {
         }
     }
 
-    /// Deactivate all column outputs (set high).
-    pub fn deactivate(&self, twi: &TWI) {
+    /// Make the driven pins inputs, while the keyboard idles (see
+    /// `power.rs`).
+    pub fn release(&self, twi: &TWI) {
         if self.initialized {
-            let _ = self.write_register(twi, GPIOA, 0xFF);
+            let _ = self.write_register(twi, self.drive.iodir, 0xFF);
+        }
+    }
+
+    /// Undo `release`.
+    pub fn resume(&mut self, twi: &TWI) {
+        if self.initialized && self.configure(twi).is_err() {
+            self.mark_error();
         }
     }
 
@@ -219,6 +343,22 @@ impl Mcp23018 {
         Ok(())
     }
 
+    /// Write `value` to `reg` and read the register after it, the other
+    /// port's in byte mode, in one transfer.
+    fn write_then_read(&self, twi: &TWI, reg: u8, value: u8) -> Result<u8, ()> {
+        self.i2c_start(twi)?;
+        self.i2c_write(twi, (self.addr << 1) | 0)?;
+        self.i2c_write(twi, reg)?;
+        self.i2c_write(twi, value)?;
+
+        // Repeated start for read, from where the pointer toggled to
+        self.i2c_start(twi)?;
+        self.i2c_write(twi, (self.addr << 1) | 1)?; // Read mode
+        let data = self.i2c_read_nack(twi)?;
+        self.i2c_stop(twi);
+        Ok(data)
+    }
+
     fn read_register(&self, twi: &TWI, reg: u8) -> Result<u8, ()> {
         // Write register address
         self.i2c_start(twi)?;
@@ -288,11 +428,3 @@ impl Mcp23018 {
         }
     }
 }
-
-/// Very short delay (~10us) for I/O settling.
-#[inline(always)]
-fn tiny_delay() {
-    for _ in 0..40u8 {
-        unsafe { core::arch::asm!("nop") };
-    }
-}
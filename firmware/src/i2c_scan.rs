@@ -0,0 +1,80 @@
+//! I2C bus scan, the first thing to check when the left half is dead.
+//!
+//! Every address from 0x08 to 0x77 is probed, and the ones that ACK are
+//! reported as debug events, for `ergodox-cli i2c-scan` (or `monitor`).
+//! The host asks for a scan with a vendor request (see `hid.rs`).
+//!
+//! Holding the right half's top-right key while plugging in (bootmagic)
+//! runs a scan before USB comes up and also blinks the result on the LED,
+//! for when there's no CLI at hand:
+//!
+//! - each device's address in hex, one digit at a time: that many short
+//!   blinks, or one long blink for a zero, with a pause between digits and
+//!   a longer one between devices; the MCP23018 is two short, one long
+//! - nothing found: three long blinks
+//! - bus stuck (SDA or SCL held low): ten quick blinks
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::debug::{DebugLog, Event};
+use crate::delay_ms;
+use crate::i2c::Mcp23018;
+use crate::led;
+
+/// Devices remembered for blinking; more are still reported to the host.
+const BLINK_MAX: usize = 8;
+
+/// Scan the bus and queue what was found, blinking it too if `blink`.
+pub fn run(dp: &Peripherals, mcp: &Mcp23018, debug: &mut DebugLog, blink: bool) {
+    let mut found = [0u8; BLINK_MAX];
+    let mut count = 0u8;
+    let stuck = mcp
+        .scan_bus(&dp.TWI, |addr| {
+            debug.push(Event::I2cDevice(addr));
+            if (count as usize) < BLINK_MAX {
+                found[count as usize] = addr;
+            }
+            count += 1;
+        })
+        .is_err();
+    debug.push(Event::I2cScanDone {
+        found: count,
+        stuck,
+    });
+
+    if !blink {
+        return;
+    }
+    led::set(dp, false);
+    delay_ms(1000);
+    if stuck {
+        flash(dp, 10, 100);
+    } else if count == 0 {
+        flash(dp, 3, 800);
+    }
+    for &addr in &found[..core::cmp::min(count as usize, BLINK_MAX)] {
+        digit(dp, addr >> 4);
+        delay_ms(800);
+        digit(dp, addr & 0x0F);
+        delay_ms(2000);
+    }
+}
+
+/// Blink one hex digit: `value` short blinks, or a long one for zero.
+fn digit(dp: &Peripherals, value: u8) {
+    if value == 0 {
+        flash(dp, 1, 1000);
+    } else {
+        flash(dp, value, 250);
+    }
+}
+
+/// `times` blinks of `ms` on and `ms` off.
+fn flash(dp: &Peripherals, times: u8, ms: u16) {
+    for _ in 0..times {
+        led::set(dp, true);
+        delay_ms(ms);
+        led::set(dp, false);
+        delay_ms(ms);
+    }
+}
@@ -1,3 +0,0 @@
-//! Keymap definitions — re-exported from the shared ergodox-keymap crate.
-
-pub use ergodox_keymap::*;
@@ -0,0 +1,49 @@
+//! The keymap the firmware actually runs with.
+//!
+//! `LAYERS` is packed into a marker-delimited `KeymapTable` so that
+//! `ergodox-cli patch-keymap` can find it in a built .hex and swap in a
+//! different keymap without recompiling. The table stays in program memory
+//! rather than being copied into RAM at startup, and is read with LPM.
+//!
+//! Hosts can read the table back (see `hid.rs`), so a configurator can show
+//! the keymap a keyboard actually runs. They can't write it: only the
+//! bootloader can write flash, so a new keymap is patched into an image and
+//! flashed (see DESIGN.md).
+
+use ergodox_keymap::{
+    KeymapTable, PackedLayers, KEYMAP_END_MARKER, KEYMAP_START_MARKER, KEYMAP_TABLE_SIZE, LAYERS,
+};
+
+use crate::flash;
+
+#[used]
+#[link_section = ".progmem.data"]
+static KEYMAP: KeymapTable = KeymapTable::new(&LAYERS);
+
+/// The keymap layers.
+///
+/// Every read is an LPM the compiler can't see through, so it can't fold
+/// the built-in keymap into the code and leave a patched table unread.
+pub fn layers() -> PackedLayers<impl Fn(usize) -> u8> {
+    let body = body();
+    PackedLayers::new(move |i| flash::read_byte((body + i) as u16))
+}
+
+/// Bytes between the markers.
+const BODY_LEN: usize = KEYMAP_TABLE_SIZE - KEYMAP_START_MARKER.len() - KEYMAP_END_MARKER.len();
+
+/// Copy the table between its markers, from `offset` on, into `buf`.
+/// Returns how many bytes that was: none once `offset` is past the end.
+pub fn read(offset: usize, buf: &mut [u8]) -> usize {
+    let body = body();
+    let n = core::cmp::min(BODY_LEN.saturating_sub(offset), buf.len());
+    for (i, byte) in buf[..n].iter_mut().enumerate() {
+        *byte = flash::read_byte((body + offset + i) as u16);
+    }
+    n
+}
+
+/// Address of the first byte after the start marker.
+fn body() -> usize {
+    core::ptr::addr_of!(KEYMAP) as usize + KEYMAP_START_MARKER.len()
+}
@@ -0,0 +1,45 @@
+//! The Teensy's one LED (PD6), and what it shows.
+//!
+//! Several things want the LED, so each pass the main loop gathers them in
+//! a `Status` and shows the pattern of the most important one; the
+//! priorities and patterns are in `ergodox_keymap::status_led`, where they
+//! can be tested on the host.
+//!
+//! Idling without a host (`power.rs`) turns the LED off outright, and the
+//! bootmagic I2C scan (`i2c_scan.rs`) blinks its result before the main
+//! loop starts.
+
+use avr_device::atmega32u4::Peripherals;
+use ergodox_keymap::status_led::PERIOD;
+pub use ergodox_keymap::status_led::{Pattern, Status};
+
+const MASK: u8 = 1 << 6;
+
+/// Plays patterns, one step per main loop pass.
+pub struct Led {
+    pass: u16,
+}
+
+impl Led {
+    pub const fn new() -> Self {
+        Self { pass: 0 }
+    }
+
+    pub fn show(&mut self, dp: &Peripherals, pattern: Pattern) {
+        self.pass = (self.pass + 1) % PERIOD;
+        set(dp, pattern.is_lit(self.pass));
+    }
+}
+
+/// Make PD6 an output.
+pub fn init(dp: &Peripherals) {
+    dp.PORTD.ddrd.modify(|r, w| unsafe { w.bits(r.bits() | MASK) });
+}
+
+pub fn set(dp: &Peripherals, on: bool) {
+    if on {
+        dp.PORTD.portd.modify(|r, w| unsafe { w.bits(r.bits() | MASK) });
+    } else {
+        dp.PORTD.portd.modify(|r, w| unsafe { w.bits(r.bits() & !MASK) });
+    }
+}
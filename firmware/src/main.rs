@@ -3,17 +3,40 @@
 #![feature(abi_avr_interrupt)]
 #![feature(asm_experimental_arch)]
 
+#[cfg(feature = "backlight")]
+mod backlight;
+mod bench;
+mod build_info;
+mod clock;
+#[cfg(feature = "persist-counts")]
+mod counts;
 mod debounce;
+mod debug;
+mod eeprom;
+mod flash;
 mod hid;
 mod i2c;
-mod keymap;
+mod i2c_scan;
+mod keymap_table;
+mod led;
 mod matrix;
+mod odometer;
+mod power;
+mod reset;
+mod settings;
+mod wiring;
 
 use avr_device::atmega32u4::Peripherals;
 
+use bench::Bench;
+use clock::Clock;
 use debounce::Debouncer;
+use debug::{DebugLog, Event};
 use hid::UsbKeyboard;
 use i2c::Mcp23018;
+use led::Led;
+use power::HostWatch;
+use wiring::WIRING;
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
@@ -23,50 +46,161 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     let dp = unsafe { Peripherals::steal() };
+    let reset_cause = reset::take_cause(&dp);
 
     dp.CPU.clkpr.write(|w| w.clkpce().set_bit());
     dp.CPU.clkpr.write(|w| unsafe { w.bits(0) });
 
-    // LED on PD6
-    dp.PORTD.ddrd.modify(|r, w| unsafe { w.bits(r.bits() | 0x40) });
+    led::init(&dp);
 
     // Init right-half GPIO
-    matrix::init_gpio(&dp);
+    matrix::init_gpio(&dp, &WIRING);
 
     // Init left half via I2C
     delay_ms(100);
-    let mut mcp = Mcp23018::new();
+    let mut mcp = Mcp23018::new(WIRING.expander_ports());
     mcp.init(&dp.TWI);
 
+    // Bootmagic: the right half's top-right key held while plugging in
+    // scans the I2C bus and blinks what answered (see `i2c_scan.rs`)
+    let mut debug_log = DebugLog::new();
+    let boot_keys: matrix::MatrixState = matrix::scan(&dp, &mut mcp, &WIRING);
+    if !boot_keys[0][matrix::COLS - 1] {
+        i2c_scan::run(&dp, &mcp, &mut debug_log, true);
+    }
+
+    // Checksum application flash before USB comes up, so the CLI's
+    // post-flash verification can query it as soon as we enumerate
+    let flash_crc = flash::app_crc32();
+
     // Init USB
-    let mut usb = UsbKeyboard::new();
+    let mut usb = UsbKeyboard::new(flash_crc, reset_cause);
     usb.init(&dp);
 
-    let mut debouncer = Debouncer::new();
+    bench::init_timer(&dp);
+    let mut clock = Clock::start(&dp);
 
-    // LED on
-    dp.PORTD.portd.modify(|r, w| unsafe { w.bits(r.bits() | 0x40) });
+    let mut debouncer = Debouncer::new();
+    #[cfg(feature = "persist-counts")]
+    let mut counts = counts::CountStore::load(&dp, &mut debug_log, !reset_cause.is_unclean());
+    let mut odometer = odometer::Odometer::load(&dp, &mut debug_log, !reset_cause.is_unclean());
+    let mut settings = settings::Settings::load(&dp, !reset_cause.is_unclean());
+    #[cfg(feature = "backlight")]
+    let mut backlight = backlight::Backlight::init(&dp, !reset_cause.is_unclean());
+    let mut bench = Bench::new();
+    let mut host = HostWatch::new();
+    let mut last_keys: matrix::MatrixState = [[false; matrix::COLS]; matrix::ROWS];
+    // Active scans since power-on, about a millisecond each: the key event clock
+    let mut scans: u32 = 0;
+    let mut layers = ergodox_keymap::LayerState::new();
+    let mut last_layer = 0;
+    let mut last_i2c_errors = 0;
+    // Counts loop passes, for left-half retries
+    let mut passes: u16 = 0;
+    let mut status_led = Led::new();
 
     loop {
-        usb.poll(&dp);
-
-        let raw_state = matrix::scan(&dp, &mut mcp);
-        let debounced = debouncer.update(&raw_state);
-        let layer = keymap::resolve_layer(debounced);
-        let report = hid::build_report(debounced, layer);
-        usb.send_report(&dp, &report);
-
-        // LED reflects MCP status: ON = working, OFF = errored out
-        if mcp.is_ok() {
-            dp.PORTD.portd.modify(|r, w| unsafe { w.bits(r.bits() | 0x40) });
-        } else {
-            dp.PORTD.portd.modify(|r, w| unsafe { w.bits(r.bits() & !0x40) });
+        let now_ms = clock.update(&dp);
+        usb.poll(&dp, &mut debug_log, &mut bench, &debouncer);
+
+        // No host to type at: stop scanning until one shows up (see
+        // `power.rs`)
+        match host.update(&dp, &usb) {
+            Some(true) => {
+                mcp.release(&dp.TWI);
+                led::set(&dp, false);
+                #[cfg(feature = "backlight")]
+                backlight.suspend(&dp, true);
+            }
+            Some(false) => {
+                mcp.resume(&dp.TWI);
+                #[cfg(feature = "backlight")]
+                backlight.suspend(&dp, false);
+            }
+            None => {}
+        }
+        if host.is_idle() {
+            delay_ms(power::IDLE_POLL_MS);
+            continue;
+        }
+
+        bench.loop_start(&dp);
+        scans = scans.wrapping_add(1);
+
+        let mut raw_state: matrix::MatrixState = matrix::scan(&dp, &mut mcp, &WIRING);
+        bench.apply(&dp, &mut raw_state);
+        let debounced = debouncer.update(&raw_state, now_ms);
+        let table = keymap_table::layers();
+        let keymap = ergodox_keymap::SwappedCtrlCaps {
+            layers: &table,
+            swap: settings.swap_ctrl_caps(),
+        };
+        for event in ergodox_keymap::key_changes(&last_keys, debounced, scans) {
+            if event.pressed {
+                let (row, col) = (event.row as usize, event.col as usize);
+                let kc = ergodox_keymap::lookup_in(&keymap, layers.active(), row, col);
+                settings.press(kc);
+                #[cfg(feature = "backlight")]
+                backlight.press(kc, now_ms);
+            }
+            layers.update(&keymap, event);
+        }
+        let layer = layers.active();
+        let report: hid::KeyboardReport = ergodox_keymap::build_report(debounced, &keymap, layer);
+        if usb.send_report(&dp, &report) {
+            bench.report_sent(&dp, debounced);
         }
 
+        // Feed `ergodox-cli monitor`
+        debug_log.push_key_changes(&last_keys, debounced);
+        last_keys = *debounced;
+        if layer != last_layer {
+            debug_log.push(Event::Layer(layer as u8));
+            last_layer = layer;
+        }
+        // And any raw HID listener, lock LEDs included
+        usb.send_layer_notice(&dp, layer as u8, layers.toggled());
+        let i2c_errors = mcp.error_count();
+        if i2c_errors > last_i2c_errors {
+            debug_log.push(Event::I2cError(i2c_errors));
+        }
+        debug_log.update_left_half(mcp.is_ok(), i2c_errors.saturating_sub(last_i2c_errors));
+        last_i2c_errors = i2c_errors;
+        #[cfg(feature = "persist-counts")]
+        counts.tick(&dp, &mut debug_log);
+        odometer.tick(&dp, &debug_log);
+        settings.tick(&dp);
+        #[cfg(feature = "backlight")]
+        backlight.tick(&dp, now_ms);
+        if debug_log.take_i2c_scan_request() {
+            i2c_scan::run(&dp, &mcp, &mut debug_log, false);
+        }
+
+        // A lost left half is retried every few seconds, in case it was a
+        // loose cable. Each try can stall the scan for a few tens of
+        // milliseconds if nothing answers, so not more often than that
+        passes = (passes + 1) % LEFT_HALF_RETRY_PASSES;
+        if passes == 0 {
+            mcp.try_reinit(&dp.TWI);
+        }
+
+        let toggled = layers.toggled();
+        let status = led::Status {
+            left_half_offline: !mcp.is_ok(),
+            caps_lock: usb.caps_lock(),
+            game_mode: ergodox_keymap::GAME_LAYER.is_some_and(|l| toggled & (1 << l) != 0),
+            locked: toggled != 0,
+        };
+        status_led.show(&dp, status.pattern());
+
         delay_ms(1);
     }
 }
 
+/// Loop passes between attempts to get a lost left half back, about five
+/// seconds.
+const LEFT_HALF_RETRY_PASSES: u16 = 5000;
+
 fn delay_ms(ms: u16) {
     for _ in 0..ms {
         for _ in 0..4000u16 {
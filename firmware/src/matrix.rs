@@ -1,175 +1,110 @@
 //! Key matrix scanning for the ErgoDox keyboard.
 //!
-//! The ErgoDox has a 6×14 matrix split across two halves:
+//! The ErgoDox has a 6×14 matrix split across two halves (other splits
+//! just have a differently sized `Wiring`):
 //! - Right half: directly wired to Teensy 2.0 GPIO pins
 //! - Left half: connected via MCP23018 I2C I/O expander (see i2c.rs)
 //!
 //! Scanning drives one column LOW at a time and reads which rows are
-//! pulled LOW through the key switch + diode. The result is stored as
-//! `state[row][col]` with active-low convention (true = not pressed).
+//! pulled LOW through the key switch + diode (or the other way round, with
+//! diodes fitted the other way). Which pins those are is up to the wiring
+//! profile in `wiring.rs`. The result is stored as `state[row][col]` with
+//! active-low convention (true = not pressed).
 
 use avr_device::atmega32u4::Peripherals;
 
 use crate::i2c::Mcp23018;
-
-pub use ergodox_keymap::{COLS, COLS_PER_HALF, ROWS};
-
-/// Complete matrix state.
-pub type MatrixState = [[bool; COLS]; ROWS];
-
-// ── Right half pin mapping (Teensy 2.0 / ATmega32U4) ────────────────
-//
-// Column drive pins — directly wired to matrix columns (active-low outputs):
-//   Drive 0 → PB0  (col 7)       PORTB mask: 0x0F = PB0..PB3
-//   Drive 1 → PB1  (col 8)       PORTD mask: 0x0C = PD2..PD3
-//   Drive 2 → PB2  (col 9)       PORTC mask: 0x40 = PC6
-//   Drive 3 → PB3  (col 10)
-//   Drive 4 → PD2  (col 11)
-//   Drive 5 → PD3  (col 12)
-//   Drive 6 → PC6  (col 13)
-//
-// Row read pins — directly wired to matrix rows (inputs with pull-ups):
-//   Read 0 → PF0  (row 0)        PORTF mask: 0xF3 = PF0,PF1,PF4..PF7
-//   Read 1 → PF1  (row 1)
-//   Read 2 → PF4  (row 2)
-//   Read 3 → PF5  (row 3)
-//   Read 4 → PF6  (row 4)
-//   Read 5 → PF7  (row 5)
-//
-// Other pins:
-//   PD0 = I2C SCL (to left half via TRRS)
-//   PD1 = I2C SDA (to left half via TRRS)
-//   PD6 = onboard LED
-
-/// Initialize the Teensy GPIO pins for matrix scanning (right half).
-///
-/// Pin mapping on Teensy 2.0 (ATmega32U4):
-///   Drive pins (active-low outputs): PB0, PB1, PB2, PB3, PD2, PD3, PC6
-///   Read pins (inputs w/ pull-up):   PF0, PF1, PF4, PF5, PF6, PF7
-pub fn init_gpio(dp: &Peripherals) {
-    let portb = &dp.PORTB;
-    let portc = &dp.PORTC;
-    let portd = &dp.PORTD;
-    let portf = &dp.PORTF;
-
-    // Drive pins as outputs, initially high (inactive)
-    // PB0-PB3: output, drive high
-    portb.ddrb.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x0F)
-    });
-    portb.portb.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x0F)
-    });
-    // PD2-PD3: output, drive high
-    portd.ddrd.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x0C)
-    });
-    portd.portd.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x0C)
-    });
-    // PC6: output, drive high
-    portc.ddrc.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x40)
-    });
-    portc.portc.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x40)
-    });
-
-    // Read pins as inputs with pull-ups
-    // PF0, PF1, PF4-PF7: input with pull-up
-    portf.ddrf.modify(|r, w| unsafe {
-        w.bits(r.bits() & !(0x03 | 0xF0))
-    });
-    portf.portf.modify(|r, w| unsafe {
-        w.bits(r.bits() | 0x03 | 0xF0)
-    });
-}
-
-/// Drive a specific column pin low. All other drive pins high.
-fn drive_pin(dp: &Peripherals, index: usize) {
-    let portb = &dp.PORTB;
-    let portc = &dp.PORTC;
-    let portd = &dp.PORTD;
-
-    // All drive pins high first
-    portb.portb.modify(|r, w| unsafe { w.bits(r.bits() | 0x0F) });
-    portd.portd.modify(|r, w| unsafe { w.bits(r.bits() | 0x0C) });
-    portc.portc.modify(|r, w| unsafe { w.bits(r.bits() | 0x40) });
-
-    // Drive selected pin low
-    match index {
-        0 => portb.portb.modify(|r, w| unsafe { w.bits(r.bits() & !0x01) }), // PB0 → col 7
-        1 => portb.portb.modify(|r, w| unsafe { w.bits(r.bits() & !0x02) }), // PB1 → col 8
-        2 => portb.portb.modify(|r, w| unsafe { w.bits(r.bits() & !0x04) }), // PB2 → col 9
-        3 => portb.portb.modify(|r, w| unsafe { w.bits(r.bits() & !0x08) }), // PB3 → col 10
-        4 => portd.portd.modify(|r, w| unsafe { w.bits(r.bits() & !0x04) }), // PD2 → col 11
-        5 => portd.portd.modify(|r, w| unsafe { w.bits(r.bits() & !0x08) }), // PD3 → col 12
-        6 => portc.portc.modify(|r, w| unsafe { w.bits(r.bits() & !0x40) }), // PC6 → col 13
-        _ => {}
+use crate::wiring::{Inputs, Wiring};
+
+pub use ergodox_keymap::{MatrixState, COLS, COLS_PER_HALF, ROWS};
+
+/// CPU clock, for timing the settle delay.
+const CPU_HZ: u32 = 16_000_000;
+
+/// How long a right-half driven line gets to settle, and the read lines
+/// it releases to recover through their pull-ups, before the read.
+const SETTLE_US: u32 = 5;
+
+/// Passes through `settle`'s 3-cycle loop for SETTLE_US.
+const SETTLE_LOOPS: u8 = {
+    let loops = CPU_HZ / 1_000_000 * SETTLE_US / 3;
+    assert!(loops > 0 && loops <= 255, "SETTLE_US out of range");
+    loops as u8
+};
+
+/// Initialize the Teensy GPIO pins for matrix scanning (right half): the
+/// driven lines as outputs, high (inactive), the others as inputs with
+/// pull-ups.
+pub fn init_gpio<const R: usize, const CH: usize>(dp: &Peripherals, wiring: &Wiring<R, CH>) {
+    let (drive, read) = wiring.lines();
+    for pin in drive {
+        pin.make_output(dp);
+    }
+    for pin in read {
+        pin.make_input(dp);
     }
 }
 
-/// Read the 6 row input pins. Returns 6 bits (active low).
-fn read_pins(dp: &Peripherals) -> u8 {
-    let pinf = dp.PORTF.pinf.read().bits();
-
-    // Bit 0 = PF0 (row 0), Bit 1 = PF1 (row 1), Bit 2 = PF4 (row 2),
-    // Bit 3 = PF5 (row 3), Bit 4 = PF6 (row 4), Bit 5 = PF7 (row 5)
-    let p0 = (pinf >> 0) & 1;
-    let p1 = (pinf >> 1) & 1;
-    let p2 = (pinf >> 4) & 1;
-    let p3 = (pinf >> 5) & 1;
-    let p4 = (pinf >> 6) & 1;
-    let p5 = (pinf >> 7) & 1;
-
-    p0 | (p1 << 1) | (p2 << 2) | (p3 << 3) | (p4 << 4) | (p5 << 5)
-}
-
-/// Scan the entire matrix (right half via GPIO, left half via MCP23018).
+/// Scan the entire matrix (right half via GPIO, left half via MCP23018),
+/// `R` rows by `C` columns, `C / 2` on each half.
 ///
-/// Right half: 7 drive pins → 7 columns, 6 read pins → 6 rows.
-/// Left half: GPIOA drives 7 columns, GPIOB reads 6 rows.
-/// Both stored as state[row][col] with active-low convention.
-pub fn scan(dp: &Peripherals, mcp: &mut Mcp23018) -> MatrixState {
+/// Each half drives one line low at a time and reads the others; see
+/// `wiring.rs` for which are which. Both are stored as state[row][col]
+/// with active-low convention.
+pub fn scan<const R: usize, const CH: usize, const C: usize>(
+    dp: &Peripherals,
+    mcp: &mut Mcp23018,
+    wiring: &Wiring<R, CH>,
+) -> [[bool; C]; R] {
+    const { assert!(C == 2 * CH, "a matrix is two halves wide") };
     let twi = &dp.TWI;
-    let mut state = [[true; COLS]; ROWS]; // true = not pressed
-
-    // Right half (Teensy GPIO): 7 columns via drive pins
-    for col in 0..COLS_PER_HALF {
-        drive_pin(dp, col);
-        tiny_delay();
-        let reads = read_pins(dp);
-
-        for row in 0..ROWS {
-            state[row][COLS_PER_HALF + col] = (reads >> row) & 1 != 0;
+    let mut state = [[true; C]; R]; // true = not pressed
+
+    // Right half (Teensy GPIO): one write to pull the driven line low and
+    // one to let it go (it's always high in between), one read of each
+    // port, and a fixed delay, a few microseconds a line
+    let (drive, read) = wiring.lines();
+    for (d, pin) in drive.iter().enumerate() {
+        pin.toggle(dp);
+        settle();
+        let inputs = Inputs::read(dp);
+        pin.toggle(dp);
+        for (r, input) in read.iter().enumerate() {
+            let (row, col) = wiring.position(d, r);
+            state[row][CH + col] = input.is_high_in(&inputs);
         }
     }
 
-    // Deactivate right half drive pins
-    let portb = &dp.PORTB;
-    let portc = &dp.PORTC;
-    let portd = &dp.PORTD;
-    portb.portb.modify(|r, w| unsafe { w.bits(r.bits() | 0x0F) });
-    portd.portd.modify(|r, w| unsafe { w.bits(r.bits() | 0x0C) });
-    portc.portc.modify(|r, w| unsafe { w.bits(r.bits() | 0x40) });
-
-    // Left half (MCP23018): 7 columns via GPIOA
-    for col in 0..COLS_PER_HALF {
-        let reads = mcp.scan_column(twi, col as u8);
-
-        for row in 0..ROWS {
-            state[row][col] = (reads >> row) & 1 != 0;
+    // Left half (MCP23018), unless nothing there has changed since a scan
+    // that found every key up (see `i2c.rs`)
+    if mcp.changed(twi) {
+        let (drive, read) = wiring.expander_lines();
+        let mut all_up = true;
+        for (d, &bit) in drive.iter().enumerate() {
+            let reads = mcp.scan_line(twi, bit);
+            for (r, &input) in read.iter().enumerate() {
+                let (row, col) = wiring.position(d, r);
+                state[row][col] = (reads >> input) & 1 != 0;
+                all_up &= state[row][col];
+            }
         }
+        mcp.finish_scan(twi, all_up);
     }
-    mcp.deactivate(twi);
 
     state
 }
 
-/// Short delay for pin settling (~5us at 16MHz).
+/// Wait SETTLE_US. `dec` takes one cycle and a taken `brne` two, so unlike
+/// a loop of `nop`s the time doesn't depend on what the compiler makes of it.
 #[inline(always)]
-fn tiny_delay() {
-    for _ in 0..20u8 {
-        unsafe { core::arch::asm!("nop") };
+fn settle() {
+    unsafe {
+        core::arch::asm!(
+            "1:",
+            "dec {n}",
+            "brne 1b",
+            n = inout(reg) SETTLE_LOOPS => _,
+            options(nomem, nostack),
+        );
     }
 }
@@ -0,0 +1,124 @@
+//! Total keystrokes, kept across power cycles, for `ergodox-cli odometer`.
+//!
+//! `DebugLog` counts the presses since power-on (the session); this loads
+//! the total from before at boot and checkpoints the sum to EEPROM once a
+//! minute while it's changing.
+//!
+//! Wear leveling: checkpoints go round `SLOTS` slots in turn, so a cell is
+//! written once every 32 minutes of typing at most. Good for 100,000 writes,
+//! that's about 18 years at eight hours of typing a day.
+//!
+//! A slot is the total as a little-endian `u32` and a CRC-8 of it, written
+//! in that order. At boot the highest total in a slot whose check byte matches
+//! wins, so a checkpoint cut short by unplugging costs at most a minute of
+//! keystrokes, and erased EEPROM (all 0xFF) never reads as a total.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::debug::DebugLog;
+use crate::eeprom::{self, ODOMETER_ADDR};
+
+const SLOTS: usize = 32;
+
+/// Total plus check byte.
+const SLOT_SIZE: usize = 5;
+
+/// Scans between checkpoints, about a minute at a millisecond each.
+const CHECKPOINT_SCANS: u32 = 60_000;
+
+pub struct Odometer {
+    /// The slot being written, or next to be.
+    slot: usize,
+    /// What's being written, and the next byte of it.
+    image: [u8; SLOT_SIZE],
+    next: usize,
+    /// The total in the last checkpoint.
+    saved: u32,
+    scans: u32,
+    /// Off after an unclean reset (see `reset.rs`).
+    writable: bool,
+}
+
+impl Odometer {
+    /// Find the last checkpoint and hand its total to `debug`. With
+    /// `writable` false no checkpoints are written.
+    pub fn load(dp: &Peripherals, debug: &mut DebugLog, writable: bool) -> Self {
+        let mut last: Option<(usize, u32)> = None;
+        for slot in 0..SLOTS {
+            let mut bytes = [0u8; SLOT_SIZE];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = eeprom::read(dp, slot_addr(slot) + i as u16);
+            }
+            if let Some(total) = decode(&bytes) {
+                if last.is_none_or(|(_, best)| total > best) {
+                    last = Some((slot, total));
+                }
+            }
+        }
+        let (slot, saved) = match last {
+            Some((slot, total)) => ((slot + 1) % SLOTS, total),
+            None => (0, 0),
+        };
+        debug.set_earlier_keystrokes(saved);
+        Self {
+            slot,
+            image: [0; SLOT_SIZE],
+            next: SLOT_SIZE,
+            saved,
+            scans: 0,
+            writable,
+        }
+    }
+
+    /// Call once per scan: starts a checkpoint when one is due, and writes
+    /// the next byte of it if EEPROM is ready.
+    pub fn tick(&mut self, dp: &Peripherals, debug: &DebugLog) {
+        if !self.writable {
+            return;
+        }
+        self.scans = self.scans.saturating_add(1);
+        let total = debug.total_keystrokes();
+        if self.next == SLOT_SIZE && self.scans >= CHECKPOINT_SCANS && total != self.saved {
+            self.image = encode(total);
+            self.next = 0;
+            self.scans = 0;
+            self.saved = total;
+        }
+        if self.next < SLOT_SIZE && !eeprom::busy(dp) {
+            eeprom::write(dp, slot_addr(self.slot) + self.next as u16, self.image[self.next]);
+            self.next += 1;
+            if self.next == SLOT_SIZE {
+                self.slot = (self.slot + 1) % SLOTS;
+            }
+        }
+    }
+}
+
+fn slot_addr(slot: usize) -> u16 {
+    ODOMETER_ADDR + (slot * SLOT_SIZE) as u16
+}
+
+/// The check byte for a total's four bytes: CRC-8 (polynomial 0x07) from
+/// 0xFF. A checkpoint cut short leaves new low bytes next to old high ones,
+/// which a CRC catches where an XOR of the bytes can cancel out. It's 0xD1
+/// and 0x0F for the all 0x00 and all 0xFF totals, so neither reads as valid
+/// when the check byte is erased or zeroed along with them.
+fn check(total: [u8; 4]) -> u8 {
+    total.iter().fold(0xFF, |mut crc, &b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn encode(total: u32) -> [u8; SLOT_SIZE] {
+    let t = total.to_le_bytes();
+    [t[0], t[1], t[2], t[3], check(t)]
+}
+
+fn decode(bytes: &[u8; SLOT_SIZE]) -> Option<u32> {
+    let t = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    (bytes[4] == check(t)).then(|| u32::from_le_bytes(t))
+}
@@ -0,0 +1,62 @@
+//! Idling while no host is listening.
+//!
+//! Keys typed with no host on the other end go nowhere, so there's no point
+//! scanning for them. The keyboard idles when VBUS is gone (USBSTA.VBUS,
+//! with the pad enabled by `hid.rs`; only possible when the Teensy isn't
+//! bus-powered), or when it's there but no host has configured us for a
+//! couple of seconds: a phone charger, a power bank, a powered-down PC's
+//! port.
+//!
+//! Idling, the main loop only polls USB, every `IDLE_POLL_MS`, and doesn't
+//! scan; the MCP23018's driven pins are released and the LED is off. It
+//! wakes as soon as a host configures us.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::hid::UsbKeyboard;
+
+/// How long to wait between polls while idle.
+pub const IDLE_POLL_MS: u16 = 10;
+
+/// Active loop passes without a configuration before idling, about two
+/// seconds; enumeration takes well under one.
+const UNCONFIGURED_PASSES: u16 = 2000;
+
+pub struct HostWatch {
+    unconfigured: u16,
+    idle: bool,
+}
+
+impl HostWatch {
+    pub const fn new() -> Self {
+        Self {
+            unconfigured: 0,
+            idle: false,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Call once per loop pass, after polling USB. Returns the new state,
+    /// `true` for idle, when it changes.
+    pub fn update(&mut self, dp: &Peripherals, usb: &UsbKeyboard) -> Option<bool> {
+        let configured = usb.is_configured();
+        self.unconfigured = if configured {
+            0
+        } else {
+            self.unconfigured.saturating_add(1)
+        };
+        let idle = if self.idle {
+            !configured
+        } else {
+            !usb.vbus(dp) || self.unconfigured >= UNCONFIGURED_PASSES
+        };
+        if idle == self.idle {
+            return None;
+        }
+        self.idle = idle;
+        Some(idle)
+    }
+}
@@ -0,0 +1,88 @@
+//! Why the MCU last reset, for `ergodox-cli version`.
+//!
+//! MCUSR keeps a flag per reset source until it's cleared, so it's read
+//! (and cleared) first thing in `main`. A brown-out or watchdog reset means
+//! something went wrong: the supply sagged, or the firmware hung. After one,
+//! the firmware plays safe until the next clean start:
+//!
+//! - no EEPROM writes (`counts.rs`), since a write on a sagging supply can
+//!   corrupt the cell, and a hang might have been mid-write
+//! - USB stays detached for a while before enumerating (`hid.rs`), so the
+//!   host drops whatever it thought the keyboard was doing and starts over
+//!
+//! The firmware never arms the watchdog itself, but a watchdog reset leaves
+//! it running, and it would keep resetting the MCU unless turned off here.
+
+use avr_device::atmega32u4::Peripherals;
+
+/// MCUSR flags.
+const PORF: u8 = 1 << 0;
+const EXTRF: u8 = 1 << 1;
+const BORF: u8 = 1 << 2;
+const WDRF: u8 = 1 << 3;
+const JTRF: u8 = 1 << 4;
+
+/// WDTCSR bits.
+const WDE: u8 = 1 << 3;
+const WDCE: u8 = 1 << 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    PowerOn,
+    /// The RESET pin, e.g. the Teensy's button.
+    External,
+    BrownOut,
+    Watchdog,
+    Jtag,
+    /// No flag set: started by a jump rather than a reset, as HalfKay does
+    /// after flashing.
+    Unknown,
+}
+
+impl ResetCause {
+    /// Whether this reset means the firmware should play safe.
+    pub fn is_unclean(self) -> bool {
+        matches!(self, ResetCause::BrownOut | ResetCause::Watchdog)
+    }
+
+    /// The name `ergodox-cli version` shows.
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::External => "external",
+            ResetCause::BrownOut => "brown-out",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Jtag => "jtag",
+            ResetCause::Unknown => "unknown",
+        }
+    }
+}
+
+/// Read and clear the reset flags, and stop the watchdog if it's running.
+///
+/// Several flags can be set at once (a power-on often sets BORF too, and
+/// flags pile up until cleared); the first in the order below wins.
+pub fn take_cause(dp: &Peripherals) -> ResetCause {
+    let flags = dp.CPU.mcusr.read().bits();
+    // WDRF has to be clear before WDE can be
+    dp.CPU.mcusr.write(|w| unsafe { w.bits(0) });
+    // WDE can only be cleared within four cycles of setting WDCE
+    avr_device::interrupt::free(|_| {
+        dp.WDT.wdtcsr.write(|w| unsafe { w.bits(WDCE | WDE) });
+        dp.WDT.wdtcsr.write(|w| unsafe { w.bits(0) });
+    });
+
+    if flags & PORF != 0 {
+        ResetCause::PowerOn
+    } else if flags & BORF != 0 {
+        ResetCause::BrownOut
+    } else if flags & WDRF != 0 {
+        ResetCause::Watchdog
+    } else if flags & EXTRF != 0 {
+        ResetCause::External
+    } else if flags & JTRF != 0 {
+        ResetCause::Jtag
+    } else {
+        ResetCause::Unknown
+    }
+}
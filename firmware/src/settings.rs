@@ -0,0 +1,95 @@
+//! Settings changed from the keyboard at runtime and kept across power
+//! cycles: for now whether Caps Lock and left Ctrl trade places
+//! (`SwapCtrlCaps`), for sharing the board with someone who wants Ctrl
+//! where Caps Lock is.
+//!
+//! The swap goes on the lookups (`ergodox_keymap::SwappedCtrlCaps`) rather
+//! than the keymap, so it follows a patched keymap too and needs no
+//! rebuild.
+//!
+//! The settings are a byte and a check byte in EEPROM, written as soon as a
+//! key changes them. A write cut short, or erased EEPROM, fails the check
+//! and gives the defaults.
+
+use avr_device::atmega32u4::Peripherals;
+use ergodox_keymap::Keycode;
+
+use crate::eeprom::{self, SETTINGS_ADDR};
+
+/// Settings plus check byte.
+const IMAGE_SIZE: usize = 2;
+
+const SWAP_CTRL_CAPS: u8 = 1 << 0;
+
+pub struct Settings {
+    /// Bit flags, `SWAP_CTRL_CAPS` and any added later.
+    flags: u8,
+    /// Whether `flags` changed since it was last written.
+    dirty: bool,
+    /// What's being written, and the next byte of it.
+    image: [u8; IMAGE_SIZE],
+    next: usize,
+    /// Off after an unclean reset (see `reset.rs`).
+    writable: bool,
+}
+
+impl Settings {
+    /// Load the saved settings. With `writable` false changes are never
+    /// saved.
+    pub fn load(dp: &Peripherals, writable: bool) -> Self {
+        let mut bytes = [0u8; IMAGE_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = eeprom::read(dp, SETTINGS_ADDR + i as u16);
+        }
+        Self {
+            flags: decode(bytes).unwrap_or(0),
+            dirty: false,
+            image: [0; IMAGE_SIZE],
+            next: IMAGE_SIZE,
+            writable,
+        }
+    }
+
+    pub fn swap_ctrl_caps(&self) -> bool {
+        self.flags & SWAP_CTRL_CAPS != 0
+    }
+
+    /// Act on a pressed key, if it's a setting key.
+    pub fn press(&mut self, kc: Keycode) {
+        if kc == Keycode::SwapCtrlCaps {
+            self.flags ^= SWAP_CTRL_CAPS;
+            self.dirty = true;
+        }
+    }
+
+    /// Call once per scan: starts saving the settings after a change, and
+    /// writes the next byte if EEPROM is ready.
+    pub fn tick(&mut self, dp: &Peripherals) {
+        if !self.writable {
+            return;
+        }
+        if self.dirty && self.next == IMAGE_SIZE {
+            self.image = encode(self.flags);
+            self.next = 0;
+            self.dirty = false;
+        }
+        if self.next < IMAGE_SIZE && !eeprom::busy(dp) {
+            eeprom::write(dp, SETTINGS_ADDR + self.next as u16, self.image[self.next]);
+            self.next += 1;
+        }
+    }
+}
+
+/// The check byte for the flags. Neither 0x00 nor 0xFF checks itself, so
+/// erased or zeroed EEPROM never reads as settings.
+fn check(flags: u8) -> u8 {
+    flags ^ 0xA5
+}
+
+fn encode(flags: u8) -> [u8; IMAGE_SIZE] {
+    [flags, check(flags)]
+}
+
+fn decode(bytes: [u8; IMAGE_SIZE]) -> Option<u8> {
+    (bytes[1] == check(bytes[0])).then_some(bytes[0])
+}
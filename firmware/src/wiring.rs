@@ -0,0 +1,293 @@
+//! Matrix wiring profiles.
+//!
+//! Which Teensy pins and which MCP23018 pins the matrix lines are soldered
+//! to, and which way the diodes point. The scanning code in `matrix.rs` and
+//! `i2c.rs` only goes through `WIRING`, so a board wired differently needs
+//! a profile here, not changes there.
+//!
+//! A profile is picked at build time with a cargo feature (at most one):
+//!
+//! | feature                   | profile                                    |
+//! |---------------------------|--------------------------------------------|
+//! | (none)                    | `ERGODOX`: the original PCB, also used by  |
+//! |                           | the ErgoDox EZ and most group-buy kits     |
+//! | `wiring-reversed-diodes`  | `REVERSED_DIODES`: same pins, diodes       |
+//! |                           | soldered the other way round               |
+//! | `wiring-expander-swapped` | `EXPANDER_SWAPPED`: left half with rows on |
+//! |                           | GPIOA and columns on GPIOB                 |
+//!
+//! e.g. `make hex FEATURES=wiring-reversed-diodes`.
+//!
+//! In QMK's terms the ERGODOX profile is `ROW2COL` (current flows from the
+//! rows into the driven columns) and `wiring-reversed-diodes` is `COL2ROW`,
+//! for both halves: `Drive` swaps the Teensy pins' roles in `matrix.rs` and
+//! the expander ports' in `i2c.rs` together.
+
+use avr_device::atmega32u4::Peripherals;
+
+use crate::matrix::{COLS_PER_HALF, ROWS};
+
+#[cfg(all(feature = "wiring-reversed-diodes", feature = "wiring-expander-swapped"))]
+compile_error!("pick at most one wiring-* feature");
+
+/// The board's wiring, as selected by the `wiring-*` features.
+#[cfg(not(any(feature = "wiring-reversed-diodes", feature = "wiring-expander-swapped")))]
+pub const WIRING: Wiring = ERGODOX;
+#[cfg(feature = "wiring-reversed-diodes")]
+pub const WIRING: Wiring = REVERSED_DIODES;
+#[cfg(feature = "wiring-expander-swapped")]
+pub const WIRING: Wiring = EXPANDER_SWAPPED;
+
+/// Which matrix lines the scan drives low, one at a time; the others are
+/// read through pull-ups. Diodes must let current flow from the read lines
+/// into the driven one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Columns,
+    Rows,
+}
+
+#[derive(Clone, Copy)]
+pub enum Port {
+    B,
+    C,
+    D,
+    F,
+}
+
+/// A Teensy GPIO pin.
+#[derive(Clone, Copy)]
+pub struct Pin {
+    pub port: Port,
+    pub bit: u8,
+}
+
+const fn pin(port: Port, bit: u8) -> Pin {
+    Pin { port, bit }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExpanderPort {
+    A,
+    B,
+}
+
+/// Pins on one MCP23018 port, by bit number.
+#[derive(Clone, Copy)]
+pub struct ExpanderPins<const N: usize> {
+    pub port: ExpanderPort,
+    pub bits: [u8; N],
+}
+
+/// How a split with `R` rows and `CH` columns per half is wired; the
+/// defaults are the ErgoDox's.
+pub struct Wiring<const R: usize = ROWS, const CH: usize = COLS_PER_HALF> {
+    pub drive: Drive,
+    /// Right half: the Teensy pin for each column, left to right (matrix
+    /// columns CH and up).
+    pub columns: [Pin; CH],
+    /// Right half: the Teensy pin for each row, top to bottom.
+    pub rows: [Pin; R],
+    /// Left half: the expander pin for each column (matrix columns 0 to
+    /// CH - 1).
+    pub expander_columns: ExpanderPins<CH>,
+    /// Left half: the expander pin for each row. Rows and columns must be
+    /// on different ports.
+    pub expander_rows: ExpanderPins<R>,
+}
+
+impl<const R: usize, const CH: usize> Wiring<R, CH> {
+    /// The right half's driven and read pins.
+    pub fn lines(&self) -> (&[Pin], &[Pin]) {
+        match self.drive {
+            Drive::Columns => (&self.columns, &self.rows),
+            Drive::Rows => (&self.rows, &self.columns),
+        }
+    }
+
+    /// The left half's driven and read pins, by bit.
+    pub fn expander_lines(&self) -> (&[u8], &[u8]) {
+        match self.drive {
+            Drive::Columns => (&self.expander_columns.bits, &self.expander_rows.bits),
+            Drive::Rows => (&self.expander_rows.bits, &self.expander_columns.bits),
+        }
+    }
+
+    /// The expander ports the scan drives and reads.
+    pub const fn expander_ports(&self) -> (ExpanderPort, ExpanderPort) {
+        match self.drive {
+            Drive::Columns => (self.expander_columns.port, self.expander_rows.port),
+            Drive::Rows => (self.expander_rows.port, self.expander_columns.port),
+        }
+    }
+
+    /// Row and column (within the half) of the key between driven line `d`
+    /// and read line `r`.
+    pub fn position(&self, d: usize, r: usize) -> (usize, usize) {
+        match self.drive {
+            Drive::Columns => (r, d),
+            Drive::Rows => (d, r),
+        }
+    }
+}
+
+/// The original ErgoDox PCB.
+///
+/// Right half (Teensy 2.0), columns driven:
+///   col 7..13 → PB0, PB1, PB2, PB3, PD2, PD3, PC6
+///   row 0..5  → PF0, PF1, PF4, PF5, PF6, PF7
+///
+/// Left half (MCP23018):
+///   col 0..6  → GPA0..GPA6 (GPA7 unused)
+///   row 0..5  → GPB0..GPB5 (GPB6, GPB7 unused)
+///
+/// PD0/PD1 are I2C to the left half and PD6 is the LED, so no profile may
+/// use them.
+pub const ERGODOX: Wiring = Wiring {
+    drive: Drive::Columns,
+    columns: [
+        pin(Port::B, 0),
+        pin(Port::B, 1),
+        pin(Port::B, 2),
+        pin(Port::B, 3),
+        pin(Port::D, 2),
+        pin(Port::D, 3),
+        pin(Port::C, 6),
+    ],
+    rows: [
+        pin(Port::F, 0),
+        pin(Port::F, 1),
+        pin(Port::F, 4),
+        pin(Port::F, 5),
+        pin(Port::F, 6),
+        pin(Port::F, 7),
+    ],
+    expander_columns: ExpanderPins {
+        port: ExpanderPort::A,
+        bits: [0, 1, 2, 3, 4, 5, 6],
+    },
+    expander_rows: ExpanderPins {
+        port: ExpanderPort::B,
+        bits: [0, 1, 2, 3, 4, 5],
+    },
+};
+
+/// The ErgoDox pins with every diode the other way round, a common kit
+/// build mistake: rows are driven and columns read.
+pub const REVERSED_DIODES: Wiring = Wiring {
+    drive: Drive::Rows,
+    ..ERGODOX
+};
+
+/// The ErgoDox pins, except that the left half's expander has the rows on
+/// GPIOA and the columns on GPIOB, as some handwired halves do.
+pub const EXPANDER_SWAPPED: Wiring = Wiring {
+    expander_columns: ExpanderPins {
+        port: ExpanderPort::B,
+        bits: [0, 1, 2, 3, 4, 5, 6],
+    },
+    expander_rows: ExpanderPins {
+        port: ExpanderPort::A,
+        bits: [0, 1, 2, 3, 4, 5],
+    },
+    ..ERGODOX
+};
+
+const _: () = assert!(
+    !matches!(
+        (WIRING.expander_columns.port, WIRING.expander_rows.port),
+        (ExpanderPort::A, ExpanderPort::A) | (ExpanderPort::B, ExpanderPort::B)
+    ),
+    "expander rows and columns must be on different ports"
+);
+
+/// Run `$body` with `$ddr`, `$out` and `$inp` bound to the port's DDRx,
+/// PORTx and PINx registers.
+macro_rules! on_port {
+    ($dp:expr, $port:expr, |$ddr:ident, $out:ident, $inp:ident| $body:expr) => {
+        match $port {
+            Port::B => {
+                let ($ddr, $out, $inp) = (&$dp.PORTB.ddrb, &$dp.PORTB.portb, &$dp.PORTB.pinb);
+                $body
+            }
+            Port::C => {
+                let ($ddr, $out, $inp) = (&$dp.PORTC.ddrc, &$dp.PORTC.portc, &$dp.PORTC.pinc);
+                $body
+            }
+            Port::D => {
+                let ($ddr, $out, $inp) = (&$dp.PORTD.ddrd, &$dp.PORTD.portd, &$dp.PORTD.pind);
+                $body
+            }
+            Port::F => {
+                let ($ddr, $out, $inp) = (&$dp.PORTF.ddrf, &$dp.PORTF.portf, &$dp.PORTF.pinf);
+                $body
+            }
+        }
+    };
+}
+
+/// The PINx registers of every port, read back to back, so the scan reads
+/// each port once per driven line however many read lines are on it.
+#[derive(Clone, Copy)]
+pub struct Inputs {
+    b: u8,
+    c: u8,
+    d: u8,
+    f: u8,
+}
+
+impl Inputs {
+    pub fn read(dp: &Peripherals) -> Self {
+        Self {
+            b: dp.PORTB.pinb.read().bits(),
+            c: dp.PORTC.pinc.read().bits(),
+            d: dp.PORTD.pind.read().bits(),
+            f: dp.PORTF.pinf.read().bits(),
+        }
+    }
+
+    fn port(&self, port: Port) -> u8 {
+        match port {
+            Port::B => self.b,
+            Port::C => self.c,
+            Port::D => self.d,
+            Port::F => self.f,
+        }
+    }
+}
+
+impl Pin {
+    const fn mask(self) -> u8 {
+        1 << self.bit
+    }
+
+    /// Make the pin an output, driven high (inactive).
+    pub fn make_output(self, dp: &Peripherals) {
+        on_port!(dp, self.port, |ddr, out, _inp| {
+            ddr.modify(|r, w| unsafe { w.bits(r.bits() | self.mask()) });
+            out.modify(|r, w| unsafe { w.bits(r.bits() | self.mask()) });
+        })
+    }
+
+    /// Make the pin an input with its pull-up on.
+    pub fn make_input(self, dp: &Peripherals) {
+        on_port!(dp, self.port, |ddr, out, _inp| {
+            ddr.modify(|r, w| unsafe { w.bits(r.bits() & !self.mask()) });
+            out.modify(|r, w| unsafe { w.bits(r.bits() | self.mask()) });
+        })
+    }
+
+    /// Flip an output between high and low. Writing a one to a PINx bit
+    /// toggles the PORTx bit, so this is a single write, with no
+    /// read-modify-write of the rest of the port.
+    pub fn toggle(self, dp: &Peripherals) {
+        on_port!(dp, self.port, |_ddr, _out, inp| {
+            inp.write(|w| unsafe { w.bits(self.mask()) })
+        })
+    }
+
+    /// Whether an input read high in `inputs`.
+    pub fn is_high_in(self, inputs: &Inputs) -> bool {
+        inputs.port(self.port) & self.mask() != 0
+    }
+}